@@ -0,0 +1,46 @@
+// Measures how much of the openCypher TCK gqlite passes, see gqlite::tck:
+//
+//   cargo run --example tck -- features/backlog
+//
+// Pass -v to also list the scenarios that failed, and why.
+use gqlite::tck::{self, Outcome};
+use gqlite::Result;
+
+fn main() -> Result<()> {
+    let mut verbose = false;
+    let mut dirs = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-v" | "--verbose" => verbose = true,
+            _ => dirs.push(arg),
+        }
+    }
+    if dirs.is_empty() {
+        dirs.push("features/backlog".to_string());
+    }
+
+    // Panics are reported as failed scenarios, there's no need to also print them
+    std::panic::set_hook(Box::new(|_| {}));
+    for dir in dirs {
+        // Some of the TCK queries are large enough that parsing and planning them recurses
+        // deeper than the default stack allows
+        let run_dir = dir.clone();
+        let report = std::thread::Builder::new()
+            .stack_size(256 * 1024 * 1024)
+            .spawn(move || tck::run_dir(run_dir))?
+            .join()
+            .expect("the TCK run panicked")?;
+
+        if verbose {
+            for feature in &report.features {
+                for scenario in &feature.scenarios {
+                    if let Outcome::Failed(reason) = &scenario.outcome {
+                        println!("FAILED {}: {}: {}", feature.name, scenario.name, reason);
+                    }
+                }
+            }
+        }
+        println!("{}:\n{}\n", dir, report);
+    }
+    Ok(())
+}
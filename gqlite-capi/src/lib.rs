@@ -4,7 +4,6 @@
 use gqlite::gramdb::{GramCursor, GramDatabase};
 use gqlite::Error;
 use std::ffi::CStr;
-use std::fs::File;
 use std::os::raw::{c_char, c_int};
 use std::str;
 
@@ -27,14 +26,14 @@ pub unsafe extern "C" fn gqlite_open(raw_url: *const c_char) -> *const database
     let url_bytes = CStr::from_ptr(raw_url).to_bytes();
     let url: &str = str::from_utf8(url_bytes).unwrap();
 
-    match File::open(url) {
-        Ok(file) => Box::into_raw(Box::new(database {
-            db: Some(GramDatabase::open(file).unwrap()),
+    match GramDatabase::options().open(url) {
+        Ok(db) => Box::into_raw(Box::new(database {
+            db: Some(db),
             last_error: None,
         })),
         Err(error) => Box::into_raw(Box::new(database {
             db: None,
-            last_error: Some(error.into()),
+            last_error: Some(error),
         })),
     }
 }
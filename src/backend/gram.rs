@@ -3,55 +3,301 @@
 // things are duct-taped together; we're interested in exploration and learning
 // not a final product.
 
-// It is currently single threaded, and provides no data durability guarantees.
+// It is currently single threaded. Writes are appended to the gram file when a query
+// completes, see GramFile, but there is no rollback of the in-memory graph for failed queries.
+//
+// The gram file part is behind the gram-file feature; without it the graph only lives in memory,
+// which is what lets this backend run in the browser, on wasm32.
 
 use crate::backend::gram::functions::AggregatingFuncSpec;
-use crate::backend::{Backend, BackendCursor, BackendDesc, Token, Tokens};
-use crate::frontend::{Dir, LogicalPlan};
-use crate::{frontend, Error, Row, Slot, Val};
+use crate::backend::{
+    Backend, BackendCursor, BackendDesc, ChangeHook, IndexDesc, IndexHealth, IndexStats, Params,
+    Statistics, Token, Tokens,
+};
+use crate::expiry::now_millis;
+use crate::frontend::{CountOf, Dir, IndexRange, LogicalPlan};
+use crate::metrics::{ExecutionStats, OperatorStats, QuerySummary, Stopwatch};
+#[cfg(feature = "gram-file")]
+use crate::Durability;
+use crate::{
+    frontend, Change, Collation, Error, QueryError, Row, RowRef, RowVisitor, RunOptions, Slot, Val,
+    ValRef,
+};
 use anyhow::Result;
+#[cfg(feature = "gram-file")]
 use rand::Rng;
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Debug, Display, Formatter};
-use std::fs::File;
+#[cfg(feature = "gram-file")]
+use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
-use std::io::{Seek, SeekFrom, Write};
+#[cfg(feature = "gram-file")]
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(feature = "gram-file")]
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
+#[cfg(feature = "gram-file")]
 use std::time::SystemTime;
+#[cfg(feature = "gram-file")]
 use uuid::v1::{Context as UuidContext, Timestamp};
+#[cfg(feature = "gram-file")]
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct GramBackend {
     tokens: Rc<RefCell<Tokens>>,
     g: Rc<RefCell<Graph>>,
-    file: Rc<RefCell<File>>,
+    storage: Rc<RefCell<Storage>>,
     aggregators: HashMap<Token, Box<dyn AggregatingFuncSpec>>,
+    // Set while converting a plan for a cursor that collects stats, see Profiled
+    profiler: RefCell<Option<Profiler>>,
+    changes: Rc<RefCell<ChangeFeed>>,
+    projections: Rc<RefCell<procedures::Projections>>,
+    // See Backend::set_max_intermediate_rows
+    max_rows: u64,
+    // The labels and properties nodes expire by, see Backend::expire_nodes. Like indexes, these
+    // only live in memory, and need registering again each time the database is opened.
+    expiries: Vec<(Token, Token)>,
+    // How strings compare, see Backend::set_collation
+    collation: Collation,
 }
 
 impl GramBackend {
-    pub fn open(mut file: File) -> Result<GramBackend> {
-        let mut tokens = Tokens {
-            table: Default::default(),
-        };
-        let g = parser::load(&mut tokens, &mut file)?;
+    // An empty graph that only lives in memory
+    pub fn in_memory() -> Result<GramBackend> {
+        GramBackend::from_gram("")
+    }
 
+    // A graph that only lives in memory, starting out with the contents of the given gram
+    pub fn from_gram(gram: &str) -> Result<GramBackend> {
+        let mut tokens = Tokens::new();
+        let (g, _) = parser::load(&mut tokens, &[gram], 1)?;
+        Ok(GramBackend::new(tokens, g, Storage::Memory))
+    }
+
+    fn new(mut tokens: Tokens, g: Graph, storage: Storage) -> GramBackend {
         let mut aggregators = HashMap::new();
         for agg in functions::aggregating(&mut tokens) {
             aggregators.insert(agg.signature().name, agg);
         }
 
-        Ok(GramBackend {
+        GramBackend {
             tokens: Rc::new(RefCell::new(tokens)),
             g: Rc::new(RefCell::new(g)),
-            file: Rc::new(RefCell::new(file)),
+            storage: Rc::new(RefCell::new(storage)),
             aggregators,
-        })
+            profiler: RefCell::new(None),
+            changes: Rc::new(RefCell::new(ChangeFeed::default())),
+            projections: Rc::new(RefCell::new(HashMap::new())),
+            max_rows: u64::MAX,
+            expiries: Vec::new(),
+            collation: Collation::Binary,
+        }
+    }
+
+    // For running a query with the given parameters
+    fn context(&self, params: Params) -> Context {
+        Context {
+            tokens: Rc::clone(&self.tokens),
+            g: Rc::clone(&self.g),
+            storage: Rc::clone(&self.storage),
+            params,
+            db_hits: 0,
+            summary: QuerySummary::default(),
+            changes: Rc::clone(&self.changes),
+            projections: Rc::clone(&self.projections),
+            rng: SplitMix64(0),
+            deterministic: false,
+            collation: self.collation.clone(),
+            operator: Rc::new(Cell::new(None)),
+        }
+    }
+
+    #[cfg(feature = "gram-file")]
+    pub fn open(file: File) -> Result<GramBackend> {
+        GramBackend::load(file, None, None, None, None, Durability::Sync, 1)
+    }
+
+    // Open a gram file with an append-only change log next to it. The log is replayed over the
+    // gram file on open, and instead of appending to the gram file, writes are appended to the
+    // log. Use compact() to fold the log back into the gram file.
+    #[cfg(feature = "gram-file")]
+    pub fn open_with_log(file: File, log: File) -> Result<GramBackend> {
+        GramBackend::load(file, None, Some(log), None, None, Durability::Sync, 1)
+    }
+
+    // Open a gram file, and change log if given, syncing commits to disk as durability says.
+    // With an overflow file, large strings are kept there rather than in the gram file, see
+    // PropVal::Overflow. Given the path of the gram file, compact() can replace the file rather
+    // than rewrite it in place, and given the path of the log as well, it can do so while
+    // queries go on. Large files are parsed on up to the given number of threads.
+    #[cfg(feature = "gram-file")]
+    pub fn load(
+        mut file: File,
+        path: Option<PathBuf>,
+        mut log: Option<File>,
+        log_path: Option<PathBuf>,
+        overflow: Option<OverflowFile>,
+        durability: Durability,
+        threads: usize,
+    ) -> Result<GramBackend> {
+        let mut tokens = Tokens::new();
+        let mut sources = vec![parser::read_to_string(&mut file)?];
+        let mut log_generation = 0;
+        if let Some(log) = &mut log {
+            let mut entries = parser::read_to_string(log)?;
+            // See FOLDED_HEADER
+            let folded = header_numbers(&sources[0], FOLDED_HEADER);
+            match (
+                header_numbers(&entries, LOG_HEADER).as_deref(),
+                folded.as_deref(),
+            ) {
+                (Some(&[generation]), _) => log_generation = generation,
+                // A log cleared by compaction gets its number with its first write; until then
+                // it's the one after the folded one
+                (None, Some(&[generation, _])) if entries.is_empty() => {
+                    log_generation = generation + 1
+                }
+                _ => (),
+            }
+            if let Some(&[generation, len]) = folded.as_deref() {
+                let len = (len as usize).min(entries.len());
+                if generation == log_generation && entries.is_char_boundary(len) {
+                    entries.drain(..len);
+                }
+            }
+            // See SEGMENT_HEADER
+            sources.extend(log_segments(&entries).into_iter().map(str::to_string));
+        }
+        let sources: Vec<&str> = sources.iter().map(|s| s.as_str()).collect();
+        let (g, dict) = parser::load(&mut tokens, &sources, threads)?;
+
+        Ok(GramBackend::new(
+            tokens,
+            g,
+            Storage::File(Box::new(GramFile {
+                file,
+                path,
+                log,
+                log_path,
+                log_generation,
+                pending: String::new(),
+                committed: 0,
+                dict_committed: dict.values.len(),
+                dict_start: dict.values.len(),
+                dict,
+                overflow,
+                overflow_synced: true,
+                batching: false,
+                durability,
+                compaction: None,
+            })),
+        ))
+    }
+
+    // Rewrite the gram file from scratch with the current contents of the graph, and clear
+    // the change log, if there is one. The file that comes out has only what's in the graph
+    // now, none of the history of how it got there.
+    //
+    // If the gram file was opened by path, the new one is written next to it and renamed into
+    // place once it's complete, so anyone else reading the file sees either all of the old file
+    // or all of the new one. A crash before then leaves the old file and the log as they were;
+    // see FOLDED_HEADER for a crash after.
+    //
+    // With the path of the log too, the new file is written on a thread of its own, and this
+    // returns as soon as the graph is serialized. Queries go on against the graph in memory
+    // meanwhile, and their writes go on to the log, in a segment of their own, see
+    // SEGMENT_HEADER. The new file is put in place by finish_compaction, along with a log of
+    // just what was written since.
+    //
+    // The overflow file is left as it is, values no longer in the graph and all. Rewriting it
+    // would move the values the old gram file points at, and there's no replacing both files
+    // at once, so a crash in between would leave one of them pointing at the wrong values.
+    #[cfg(feature = "gram-file")]
+    pub fn compact(&mut self) -> Result<()> {
+        self.finish_compaction()?;
+        // A query whose results were never exhausted is committed now, as it would be if the
+        // next query started, so the new file doesn't have writes that may yet roll back
+        self.context(Params::new()).commit()?;
+        match &mut *self.storage.borrow_mut() {
+            Storage::File(file) => {
+                let mut g = self.g.borrow_mut();
+                // Large values from before there was an overflow file are moved out to it now
+                if file.overflow.is_some() {
+                    g.spill(file)?;
+                }
+                let mut dict = Dictionary::for_graph(&g);
+                let gram = serialize_graph(&g, &self.tokens.borrow(), &mut dict)?;
+                file.rewrite(&gram, dict)
+            }
+            Storage::Memory => Ok(()),
+        }
+    }
+
+    // Wait for the new gram file compact() is writing, if it is, and put it in place
+    #[cfg(feature = "gram-file")]
+    pub fn finish_compaction(&mut self) -> Result<()> {
+        match &mut *self.storage.borrow_mut() {
+            Storage::File(file) => file.finish_compaction(),
+            Storage::Memory => Ok(()),
+        }
     }
 
     fn convert(&self, plan: LogicalPlan) -> Result<Box<dyn Operator>> {
+        // Only pay for the per-row overhead of tracing operators if someone is listening
+        let span =
+            tracing::trace_span!("operator", name = plan.name(), rows = tracing::field::Empty);
+        let profiled = self
+            .profiler
+            .borrow_mut()
+            .as_mut()
+            .map(|profiler| profiler.enter(plan.name()));
+        // Convert the sources inside the span, so their spans nest under this one
+        let name = plan.name();
+        // Argument just hands its row over to the operator above, which is a better answer to
+        // where the query is at
+        let watched = !matches!(plan, LogicalPlan::Argument);
+        let mut op = span.in_scope(|| self.convert_operator(plan))?;
+        if watched {
+            op = Box::new(Watched { src: op, name });
+        }
+        if self.max_rows < u64::MAX {
+            op = Box::new(Guarded {
+                src: op,
+                name,
+                max_rows: self.max_rows,
+                rows: 0,
+            });
+        }
+        let op = match profiled {
+            Some(index) => {
+                let mut profiler = self.profiler.borrow_mut();
+                let profiler = profiler.as_mut().expect("profiler is set while converting");
+                profiler.parents.pop();
+                Box::new(Profiled {
+                    src: op,
+                    stats: Rc::clone(&profiler.stats),
+                    index,
+                })
+            }
+            None => op,
+        };
+        if span.is_disabled() {
+            Ok(op)
+        } else {
+            Ok(Box::new(Traced {
+                src: op,
+                span,
+                rows: 0,
+            }))
+        }
+    }
+
+    fn convert_operator(&self, plan: LogicalPlan) -> Result<Box<dyn Operator>> {
         match plan {
             LogicalPlan::Argument => Ok(Box::new(Argument { consumed: false })),
             LogicalPlan::NodeScan { src, slot, labels } => Ok(Box::new(NodeScan {
@@ -60,6 +306,35 @@ impl GramBackend {
                 labels,
                 state: NodeScanState::Idle,
             })),
+            LogicalPlan::NodeIndexSeek {
+                src,
+                slot,
+                label,
+                property,
+                range: IndexRange { lower, upper },
+            } => {
+                // The index is picked up now, so the query keeps it even if it's dropped
+                // while the query runs
+                let index = match self.g.borrow().index(label, property) {
+                    Some(index) => Rc::clone(index),
+                    None => {
+                        let tokens = self.tokens.borrow();
+                        bail!(
+                            "there is no index on :{}({}) to seek",
+                            tokens.lookup(label).unwrap_or("?"),
+                            tokens.lookup(property).unwrap_or("?")
+                        )
+                    }
+                };
+                Ok(Box::new(NodeIndexSeek {
+                    src: self.convert(*src)?,
+                    slot,
+                    index,
+                    lower: lower.map(|b| self.convert_expr(b.value)),
+                    upper: upper.map(|b| self.convert_expr(b.value)),
+                    found: Vec::new(),
+                }))
+            }
             LogicalPlan::Create { src, nodes, rels } => {
                 let mut out_nodes = Vec::with_capacity(nodes.len());
                 for (i, ns) in nodes.into_iter().enumerate() {
@@ -73,6 +348,7 @@ impl GramBackend {
                             slot: ns.slot,
                             labels: ns.labels.iter().copied().collect(),
                             props,
+                            prop_map: ns.prop_map.map(|e| self.convert_expr(e)),
                         },
                     );
                 }
@@ -90,6 +366,7 @@ impl GramBackend {
                             end_node_slot: ns.end_node_slot,
                             rel_type: ns.rel_type,
                             props,
+                            prop_map: ns.prop_map.map(|e| self.convert_expr(e)),
                         },
                     );
                 }
@@ -107,6 +384,7 @@ impl GramBackend {
                 dst_slot,
                 rel_type,
                 dir,
+                predicate,
             } => Ok(Box::new(Expand {
                 src: self.convert(*src)?,
                 src_slot,
@@ -114,6 +392,7 @@ impl GramBackend {
                 dst_slot,
                 rel_type,
                 dir,
+                predicate: predicate.map(|p| self.convert_expr(p)),
                 next_rel_index: 0,
                 state: ExpandState::NextNode,
             })),
@@ -153,6 +432,11 @@ impl GramBackend {
                     aggregated: false,
                 }))
             }
+            LogicalPlan::CountStore { src, slot, count } => Ok(Box::new(CountStore {
+                src: self.convert(*src)?,
+                slot,
+                count,
+            })),
             LogicalPlan::Unwind {
                 src,
                 list_expr,
@@ -160,14 +444,42 @@ impl GramBackend {
             } => Ok(Box::new(Unwind {
                 src: self.convert(*src)?,
                 list_expr: self.convert_expr(list_expr),
-                current_list: None,
-                next_index: 0,
+                current: None,
                 dst: alias,
             })),
-            LogicalPlan::ProduceResult { src, fields } => Ok(Box::new(ProduceResults {
+            LogicalPlan::Call {
+                src,
+                name,
+                args,
+                outputs,
+            } => {
+                let procedure = match self.tokens.borrow().lookup(name) {
+                    Some(name) => procedures::Proc::named(name),
+                    None => None,
+                };
+                let procedure =
+                    procedure.ok_or(anyhow!("the gram backend has no such procedure"))?;
+                let sig = procedure.signature(&mut self.tokens.borrow_mut());
+                let mut conv_outputs = Vec::with_capacity(outputs.len());
+                for (output, slot) in outputs {
+                    let index = sig
+                        .outputs
+                        .iter()
+                        .position(|(o, _)| *o == output)
+                        .ok_or(anyhow!("invalid execution plan, unknown procedure output"))?;
+                    conv_outputs.push((index, slot));
+                }
+                Ok(Box::new(Call {
+                    src: self.convert(*src)?,
+                    procedure,
+                    args: args.into_iter().map(|a| self.convert_expr(a)).collect(),
+                    outputs: conv_outputs,
+                    rows: Vec::new(),
+                    next_row: 0,
+                }))
+            }
+            LogicalPlan::ProduceResult { src, .. } => Ok(Box::new(ProduceResults {
                 src: self.convert(*src)?,
-                fields,
-                print_header: true,
             })),
             LogicalPlan::Project { src, projections } => {
                 let mut converted_projections = Vec::new();
@@ -187,13 +499,18 @@ impl GramBackend {
             LogicalPlan::Sort { src, sort_by } => {
                 let mut conv_sort_by = Vec::with_capacity(sort_by.len());
                 for s in sort_by {
-                    conv_sort_by.push(self.convert_expr(s));
+                    conv_sort_by.push(SortKey {
+                        expr: self.convert_expr(s.expr),
+                        descending: s.descending,
+                        nulls_first: s.nulls_first,
+                    });
                 }
                 Ok(Box::new(Sort {
                     src: self.convert(*src)?,
                     state: SortState::Init,
                     rows: vec![],
                     sort_by: conv_sort_by,
+                    row_budget: None,
                 }))
             }
             LogicalPlan::Limit { src, skip, limit } => {
@@ -228,6 +545,16 @@ impl GramBackend {
                 predicate: self.convert_expr(predicate),
                 initialized: false,
             })),
+            LogicalPlan::ConditionalApply { src, probe } => Ok(Box::new(ConditionalApply {
+                src: self.convert(*src)?,
+                probe: self.convert(*probe)?,
+            })),
+            LogicalPlan::AntiConditionalApply { src, probe } => {
+                Ok(Box::new(AntiConditionalApply {
+                    src: self.convert(*src)?,
+                    probe: self.convert(*probe)?,
+                }))
+            }
         }
     }
 
@@ -259,7 +586,7 @@ impl GramBackend {
 
     fn convert_expr(&self, expr: frontend::Expr) -> Expr {
         match expr {
-            frontend::Expr::String(v) => Expr::Lit(Val::String(v)),
+            frontend::Expr::String(v) => Expr::Lit(Val::String(v.into())),
             frontend::Expr::Int(v) => Expr::Lit(Val::Int(v)),
             frontend::Expr::Float(v) => Expr::Lit(Val::Float(v)),
 
@@ -279,6 +606,19 @@ impl GramBackend {
                     Box::new(self.convert_expr(*left)),
                     Box::new(self.convert_expr(*right)),
                 ),
+                // a < b is b > a, and so on
+                frontend::Op::Lt => Expr::Gt(
+                    Box::new(self.convert_expr(*right)),
+                    Box::new(self.convert_expr(*left)),
+                ),
+                frontend::Op::Gte => Expr::Gte(
+                    Box::new(self.convert_expr(*left)),
+                    Box::new(self.convert_expr(*right)),
+                ),
+                frontend::Op::Lte => Expr::Gte(
+                    Box::new(self.convert_expr(*right)),
+                    Box::new(self.convert_expr(*left)),
+                ),
                 frontend::Op::Mul => Expr::Mul(
                     Box::new(self.convert_expr(*left)),
                     Box::new(self.convert_expr(*right)),
@@ -298,6 +638,10 @@ impl GramBackend {
             },
 
             frontend::Expr::Prop(e, props) => Expr::Prop(Box::new(self.convert_expr(*e)), props),
+            frontend::Expr::Subscript(e, key) => Expr::Subscript(
+                Box::new(self.convert_expr(*e)),
+                Box::new(self.convert_expr(*key)),
+            ),
             frontend::Expr::Slot(s) => Expr::Slot(s),
             frontend::Expr::List(es) => {
                 let mut items = Vec::with_capacity(es.len());
@@ -317,22 +661,37 @@ impl GramBackend {
 
             frontend::Expr::FuncCall { name, args } => {
                 // TODO lol
-                let mut tokens = self.tokens.borrow_mut();
-                if name == tokens.tokenize("not") {
-                    let convargs = args.iter().map(|i| self.convert_expr(i.clone())).collect();
-                    return Expr::Call(functions::Func::Not, convargs);
-                } else if name == tokens.tokenize("abs") {
-                    let convargs = args.iter().map(|i| self.convert_expr(i.clone())).collect();
-                    return Expr::Call(functions::Func::Abs, convargs);
-                } else {
-                    panic!("Unknown function: {:?}", tokens.lookup(name).unwrap(),)
-                }
+                // The tokens are let go of before converting the args, since those may be
+                // function calls too
+                let func = {
+                    let mut tokens = self.tokens.borrow_mut();
+                    if name == tokens.tokenize("not") {
+                        functions::Func::Not
+                    } else if name == tokens.tokenize("abs") {
+                        functions::Func::Abs
+                    } else if name == tokens.tokenize("range") {
+                        functions::Func::Range
+                    } else if name == tokens.tokenize("keys") {
+                        functions::Func::Keys
+                    } else if name == tokens.tokenize("rand") {
+                        functions::Func::Rand
+                    } else {
+                        panic!("Unknown function: {:?}", tokens.lookup(name).unwrap(),)
+                    }
+                };
+                let convargs = args.into_iter().map(|i| self.convert_expr(i)).collect();
+                Expr::Call(func, convargs)
             }
+            frontend::Expr::Null => Expr::Lit(Val::Null),
+            frontend::Expr::Param(name) => Expr::Param(name),
             frontend::Expr::Bool(v) => Expr::Lit(Val::Bool(v)),
 
             frontend::Expr::And(terms) => {
                 Expr::And(terms.iter().map(|e| self.convert_expr(e.clone())).collect())
             }
+            frontend::Expr::Or(terms) => {
+                Expr::Or(terms.iter().map(|e| self.convert_expr(e.clone())).collect())
+            }
 
             frontend::Expr::HasLabel(slot, label) => Expr::HasLabel { slot, label },
 
@@ -349,12 +708,10 @@ impl Backend for GramBackend {
 
     fn new_cursor(&mut self) -> GramCursor {
         GramCursor {
-            ctx: Context {
-                tokens: Rc::clone(&self.tokens),
-                g: Rc::clone(&self.g),
-                file: Rc::clone(&self.file),
-            },
+            ctx: self.context(Params::new()),
             plan: None,
+            collect_stats: false,
+            stats: None,
             slots: vec![],
             row: GramRow { slots: vec![] },
             projection: Row { slots: vec![] },
@@ -365,38 +722,258 @@ impl Backend for GramBackend {
         Rc::clone(&self.tokens)
     }
 
-    fn eval(&mut self, plan: LogicalPlan, cursor: &mut GramCursor) -> Result<(), Error> {
-        let slots = match &plan {
-            LogicalPlan::ProduceResult { fields, .. } => fields.clone(),
-            _ => Vec::new(),
-        };
-        if cursor.projection.slots.len() < slots.len() {
-            cursor.projection.slots.resize(slots.len(), Val::Null);
+    fn eval(
+        &mut self,
+        plan: LogicalPlan,
+        params: Params,
+        options: &RunOptions,
+        cursor: &mut GramCursor,
+    ) -> Result<(), Error> {
+        // Commit whatever the previous query did, in case its results were never exhausted
+        self.context(Params::new()).commit()?;
+
+        // Note that we refill the cursors buffers in place rather than replacing them, so a
+        // cursor that is re-used for many queries stops allocating once it's large enough
+        cursor.reset()?;
+        if let LogicalPlan::ProduceResult { fields, .. } = &plan {
+            cursor.slots.extend_from_slice(fields);
         }
-
-        let plan = self.convert(plan)?;
-        cursor.ctx = Context {
-            tokens: Rc::clone(&self.tokens),
-            g: Rc::clone(&self.g),
-            file: Rc::clone(&self.file),
-        };
-        cursor.slots = slots;
+        cursor
+            .projection
+            .slots
+            .resize(cursor.slots.len(), Val::Null);
+
+        let width = plan.row_width();
+        if cursor.collect_stats {
+            *self.profiler.borrow_mut() = Some(Profiler::default());
+        }
+        let plan = self.convert(plan);
+        cursor.stats = self.profiler.take().map(|p| p.stats);
+        let plan = plan?;
+        cursor.ctx = self.context(params);
+        cursor.ctx.rng = SplitMix64(options.seed());
+        cursor.ctx.deterministic = options.deterministic;
         cursor.plan = Some(plan);
 
-        if cursor.row.slots.len() < 32 {
-            // TODO derive this from the logical plan
-            cursor.row.slots.resize(32, GramVal::Lit(Val::Null));
+        cursor.reserve(width);
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) -> Result<()> {
+        // What the query before the batch wrote is not part of it
+        self.context(Params::new()).commit()?;
+        self.storage.borrow_mut().set_batching(true);
+        self.changes.borrow_mut().batching = true;
+        self.g.borrow_mut().added = Some(vec![]);
+        Ok(())
+    }
+
+    fn end_batch(&mut self) -> Result<()> {
+        self.storage.borrow_mut().set_batching(false);
+        self.changes.borrow_mut().batching = false;
+        self.g.borrow_mut().added = None;
+        self.context(Params::new()).commit()
+    }
+
+    fn abort_batch(&mut self) -> Result<()> {
+        self.storage.borrow_mut().abort_batch();
+        self.changes.borrow_mut().abort_batch();
+        self.g.borrow_mut().remove_added();
+        Ok(())
+    }
+
+    fn set_max_intermediate_rows(&mut self, rows: u64) {
+        self.max_rows = rows;
+    }
+
+    fn on_change(&mut self, hook: ChangeHook) -> Result<()> {
+        self.changes.borrow_mut().hooks.push(hook);
+        Ok(())
+    }
+
+    fn create_index(
+        &mut self,
+        label: &str,
+        property: &str,
+        collation: Option<&Collation>,
+    ) -> Result<()> {
+        let mut tokens = self.tokens.borrow_mut();
+        let (label_tok, property_tok) = (tokens.tokenize(label), tokens.tokenize(property));
+        let mut g = self.g.borrow_mut();
+        if let Some(existing) = g.index(label_tok, property_tok) {
+            let existing = existing.borrow();
+            match collation {
+                Some(c) if *c != existing.collation => bail!(
+                    "there is already an index on :{}({}), collating by {}",
+                    label,
+                    property,
+                    existing.collation.name()
+                ),
+                _ => return Ok(()),
+            }
+        }
+        match collation {
+            Some(c) => g.create_index(label_tok, property_tok, c.clone(), true),
+            None => g.create_index(label_tok, property_tok, self.collation.clone(), false),
+        }
+        Ok(())
+    }
+
+    fn set_collation(&mut self, collation: &Collation) -> Result<()> {
+        self.collation = collation.clone();
+        self.g.borrow_mut().recollate_indexes(collation);
+        Ok(())
+    }
+
+    fn drop_index(&mut self, label: &str, property: &str) -> Result<()> {
+        let mut tokens = self.tokens.borrow_mut();
+        let (label_tok, property_tok) = (tokens.tokenize(label), tokens.tokenize(property));
+        if !self.g.borrow_mut().drop_index(label_tok, property_tok) {
+            bail!("there is no index on :{}({}) to drop", label, property)
+        }
+        Ok(())
+    }
+
+    fn rebuild_index(&mut self, label: &str, property: &str) -> Result<()> {
+        let mut tokens = self.tokens.borrow_mut();
+        let (label_tok, property_tok) = (tokens.tokenize(label), tokens.tokenize(property));
+        if !self.g.borrow_mut().rebuild_index(label_tok, property_tok) {
+            bail!("there is no index on :{}({}) to rebuild", label, property)
+        }
+        Ok(())
+    }
+
+    fn index_health(&self) -> Vec<IndexHealth> {
+        let g = self.g.borrow();
+        let tokens = self.tokens.borrow();
+        let name = |tok| tokens.lookup(tok).unwrap_or("?").to_string();
+        g.indexes
+            .iter()
+            .map(|index| {
+                let index = index.borrow();
+                let expected = g
+                    .nodes
+                    .iter()
+                    .filter(|n| !n.deleted && n.labels.contains(&index.label))
+                    .filter(|n| match n.properties.get(&index.property) {
+                        Some(PropVal::Val(Val::Null)) | None => false,
+                        Some(_) => true,
+                    })
+                    .count();
+                IndexHealth {
+                    label: name(index.label),
+                    property: name(index.property),
+                    entries: index.entries as u64,
+                    expected: expected as u64,
+                    built_at: index.built_at,
+                    rebuilds: index.rebuilds,
+                }
+            })
+            .collect()
+    }
+
+    fn expire_nodes(&mut self, label: &str, property: &str) -> Result<()> {
+        let mut tokens = self.tokens.borrow_mut();
+        let expiry = (tokens.tokenize(label), tokens.tokenize(property));
+        if !self.expiries.contains(&expiry) {
+            self.expiries.push(expiry);
         }
         Ok(())
     }
 
+    fn sweep_expired(&mut self, now: i64) -> Result<QuerySummary> {
+        let mut ctx = self.context(Params::new());
+        // Commit whatever the previous query did, so rolling back the sweep leaves it be
+        ctx.commit()?;
+        let expired: Vec<usize> = {
+            let g = self.g.borrow();
+            ctx.db_hits += g.nodes.len() as u64;
+            let expired = |n: &Node| {
+                self.expiries.iter().any(|(label, property)| {
+                    let expires_at = match n.properties.get(property) {
+                        Some(PropVal::Val(Val::Int(t))) => *t,
+                        _ => return false,
+                    };
+                    n.labels.contains(label) && expires_at <= now
+                })
+            };
+            g.nodes
+                .iter()
+                .filter(|n| !n.deleted && expired(n))
+                .map(|n| n.id)
+                .collect()
+        };
+        // All of them go in one commit, or none of them do. Everything that can fail, writing the
+        // commit included, is done before the graph is touched, since unlike storage and hooks
+        // it can't be rolled back
+        let mut write = || -> Result<()> {
+            let mut recorded = HashSet::new();
+            for &id in &expired {
+                record_deletion(&mut ctx, id, &recorded)?;
+                recorded.insert(id);
+            }
+            self.storage.borrow_mut().commit()
+        };
+        if let Err(e) = write() {
+            self.storage.borrow_mut().rollback();
+            self.changes.borrow_mut().rollback();
+            return Err(e);
+        }
+        for id in expired {
+            let rels = self.g.borrow_mut().delete_node(id) as u64;
+            ctx.db_hits += 1 + rels;
+            ctx.summary.nodes_deleted += 1;
+            ctx.summary.relationships_deleted += rels;
+        }
+        ctx.commit()?;
+        Ok(ctx.summary)
+    }
+
+    fn statistics(&self) -> Option<Statistics> {
+        let g = self.g.borrow();
+        Some(Statistics {
+            nodes: g.count(CountOf::Nodes { label: None }) as u64,
+            label_counts: g
+                .label_counts
+                .iter()
+                .map(|(l, count)| (*l, *count as u64))
+                .collect(),
+            rels: g.count(CountOf::Rels { rel_type: None }) as u64,
+            rel_type_counts: g
+                .rel_type_counts
+                .iter()
+                .map(|(t, count)| (*t, *count as u64))
+                .collect(),
+            indexes: g.indexes.iter().map(|i| i.borrow().stats()).collect(),
+            feedback: HashMap::new(),
+        })
+    }
+
     fn describe(&self) -> Result<BackendDesc, Error> {
-        let mut functions = Vec::new();
+        let mut functions = functions::scalar(&mut self.tokens.borrow_mut());
         for agg in self.aggregators.values() {
             functions.push(agg.signature().clone())
         }
 
-        Ok(BackendDesc::new(functions))
+        let mut desc = BackendDesc::new(functions);
+        for procedure in procedures::Proc::ALL {
+            desc.procedures
+                .push(procedure.signature(&mut self.tokens.borrow_mut()));
+        }
+        // No path values yet, and no constraints
+        desc.types.path = false;
+        for index in &self.g.borrow().indexes {
+            let index = index.borrow();
+            desc.indexes.push(IndexDesc {
+                label: index.label,
+                property: index.property,
+                ordered: true,
+                collation: index.collation.clone(),
+            });
+        }
+        desc.collation = self.collation.clone();
+        desc.digest_schema(&self.tokens.borrow());
+        Ok(desc)
     }
 }
 
@@ -422,6 +999,11 @@ pub struct GramCursor {
     // This maps from row to projection; each value corresponds to a slot in the projection,
     // the token is the name assigned in the query (eg. RETURN 1 as banana)
     slots: Vec<(Token, Slot)>,
+
+    // See BackendCursor::collect_stats; the stats are what the Profiled operators of the plan
+    // measured, and outlive the plan, so they can be had once the result is exhausted
+    collect_stats: bool,
+    stats: Option<Rc<RefCell<Vec<OperatorStats>>>>,
 }
 
 impl BackendCursor for GramCursor {
@@ -438,15 +1020,15 @@ impl BackendCursor for GramCursor {
         if let Some(p) = &mut self.plan {
             // TODO hackety hack: If there are no slots to project, just spin through the tree
             if self.slots.is_empty() {
-                while p.next(&mut self.ctx, &mut self.row)? {
+                while self.ctx.next(p, &mut self.row)? {
                     // ..
                 }
                 return Ok(None);
             }
-            if p.next(&mut self.ctx, &mut self.row)? {
+            if self.ctx.next(p, &mut self.row)? {
                 for slot in 0..self.slots.len() {
-                    self.projection.slots[slot] =
-                        self.row.slots[self.slots[slot].1].project(&mut self.ctx);
+                    self.row.slots[self.slots[slot].1]
+                        .project_into(&mut self.ctx, &mut self.projection.slots[slot])?;
                 }
                 Ok(Some(&self.projection))
             } else {
@@ -456,28 +1038,351 @@ impl BackendCursor for GramCursor {
             Err(anyhow!("This cursor is not associated with a result, try passing the cursor to the run() function"))
         }
     }
+
+    fn visit_next(&mut self, visitor: &mut dyn RowVisitor) -> Result<Option<bool>> {
+        let p = match &mut self.plan {
+            Some(p) => p,
+            None => bail!("This cursor is not associated with a result, try passing the cursor to the run() function"),
+        };
+        if self.slots.is_empty() {
+            while self.ctx.next(p, &mut self.row)? {}
+            return Ok(None);
+        }
+        if !self.ctx.next(p, &mut self.row)? {
+            return Ok(None);
+        }
+        // Plain values and graph entities are lent straight out of the row; lists and maps built
+        // by the query may have entities in them, so those get projected first
+        for (i, (_, slot)) in self.slots.iter().enumerate() {
+            let v = &self.row.slots[*slot];
+            if let GramVal::List(_) | GramVal::Map(_) = v {
+                v.project_into(&mut self.ctx, &mut self.projection.slots[i])?;
+            }
+        }
+        let row = GramRowRef {
+            row: &self.row,
+            slots: &self.slots,
+            projection: &self.projection,
+            g: &self.ctx.g,
+        };
+        Ok(Some(visitor.row(&row)?))
+    }
+
+    fn skip_next(&mut self) -> Result<bool> {
+        let p = match &mut self.plan {
+            Some(p) => p,
+            None => bail!("This cursor is not associated with a result, try passing the cursor to the run() function"),
+        };
+        if self.slots.is_empty() {
+            while self.ctx.next(p, &mut self.row)? {}
+            return Ok(false);
+        }
+        self.ctx.next(p, &mut self.row)
+    }
+
+    fn reserve(&mut self, slots: usize) {
+        if self.row.slots.len() < slots {
+            self.row.slots.resize(slots, GramVal::Lit(Val::Null));
+        }
+        self.projection.slots.reserve(slots);
+        self.slots.reserve(slots);
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        // Dropping a result before it's exhausted still keeps whatever it wrote
+        if self.plan.take().is_some() {
+            self.ctx.commit()?;
+        }
+        self.slots.clear();
+        // The projection is left as-is, since nodes and relationships in it get re-used by the
+        // next result. Internal row values are overwritten, so we don't hold on to the old result
+        for v in self.row.slots.iter_mut() {
+            *v = GramVal::Lit(Val::Null);
+        }
+        Ok(())
+    }
+
+    fn cancel(&mut self) -> Result<()> {
+        if self.plan.take().is_some() {
+            self.ctx.storage.borrow_mut().rollback();
+            self.ctx.changes.borrow_mut().rollback();
+        }
+        self.reset()
+    }
+
+    fn operator(&self) -> Option<Rc<Cell<Option<&'static str>>>> {
+        Some(Rc::clone(&self.ctx.operator))
+    }
+
+    fn collect_stats(&mut self) {
+        self.collect_stats = true;
+    }
+
+    fn stats(&self) -> Option<ExecutionStats> {
+        let measured = self.stats.as_ref()?.borrow();
+        // Each operator measured itself along with its sources; take the sources back out
+        let mut operators = measured.clone();
+        for op in measured.iter() {
+            if let Some(parent) = op.parent {
+                let parent = &mut operators[parent];
+                parent.db_hits = parent.db_hits.saturating_sub(op.db_hits);
+                parent.time = parent.time.saturating_sub(op.time);
+            }
+        }
+        Some(ExecutionStats { operators })
+    }
+
+    fn summary(&self) -> Option<QuerySummary> {
+        Some(self.ctx.summary)
+    }
+}
+
+// A result row as GramCursor::visit_next lends it out
+struct GramRowRef<'a> {
+    row: &'a GramRow,
+    slots: &'a [(Token, Slot)],
+    projection: &'a Row,
+    g: &'a RefCell<Graph>,
+}
+
+impl<'a> RowRef for GramRowRef<'a> {
+    fn columns(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn get(&self, column: usize) -> ValRef<'_> {
+        match &self.row.slots[self.slots[column].1] {
+            GramVal::Lit(v) => ValRef::from(v),
+            GramVal::Node { id } => ValRef::Node(*id),
+            GramVal::Rel { node_id, rel_index } => {
+                ValRef::Rel(self.g.borrow().nodes[*node_id].rels[*rel_index].id)
+            }
+            GramVal::List(_) | GramVal::Map(_) => ValRef::from(&self.projection.slots[column]),
+        }
+    }
+}
+
+// Overwrite dst with the given strings, re-using the allocations already in it
+fn assign_strings<'a>(dst: &mut Vec<String>, src: impl Iterator<Item = &'a str>) {
+    let mut len = 0;
+    for s in src {
+        if len < dst.len() {
+            dst[len].clear();
+            dst[len].push_str(s);
+        } else {
+            dst.push(s.to_string());
+        }
+        len += 1;
+    }
+    dst.truncate(len);
+}
+
+// Overwrite dst with the given properties, re-using the key strings already in it
+fn assign_props(
+    dst: &mut crate::Map,
+    toks: &Tokens,
+    storage: &RefCell<Storage>,
+    src: &HashMap<Token, PropVal>,
+) -> Result<()> {
+    let mut len = 0;
+    for (k, v) in src {
+        let key = toks.lookup(*k).unwrap();
+        let v = v.read(storage)?;
+        if len < dst.len() {
+            dst[len].0.clear();
+            dst[len].0.push_str(key);
+            dst[len].1 = v;
+        } else {
+            dst.push((key.to_string(), v));
+        }
+        len += 1;
+    }
+    dst.truncate(len);
+    Ok(())
 }
 
 #[derive(Debug)]
 struct Context {
     tokens: Rc<RefCell<Tokens>>,
     g: Rc<RefCell<Graph>>,
-    file: Rc<RefCell<File>>,
+    storage: Rc<RefCell<Storage>>,
+    // Values of the $parameters of the query being run
+    params: Params,
+    // How many times the query has gone to the graph so far, see OperatorStats::db_hits
+    db_hits: u64,
+    // What the query has written so far
+    summary: QuerySummary,
+    changes: Rc<RefCell<ChangeFeed>>,
+    // See procedures::Projection
+    projections: Rc<RefCell<procedures::Projections>>,
+    // Where rand() gets its numbers, seeded per query; see RunOptions
+    rng: SplitMix64,
+    // Set for queries run with RunOptions::deterministic
+    deterministic: bool,
+    collation: Collation,
+    // The operator last pulled from, see BackendCursor::operator
+    operator: Rc<Cell<Option<&'static str>>>,
+}
+
+// Small and fast, and the same everywhere, wasm32 included, unlike the rand crate which the
+// gram backend only has with gram files
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // A number in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // A float in [0, 1), from the top 53 bits, which is as many as a float has
+    fn float(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Context {
+    // Pull the next row from the root of a plan, committing or rolling back the writes
+    // of the query once it's done
+    fn next(&mut self, plan: &mut Box<dyn Operator>, row: &mut GramRow) -> Result<bool> {
+        match plan.next(self, row) {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                self.commit()?;
+                Ok(false)
+            }
+            Err(e) => {
+                self.storage.borrow_mut().rollback();
+                self.changes.borrow_mut().rollback();
+                Err(e)
+            }
+        }
+    }
+
+    // Commit the writes of the query, and tell the on_change hooks what they were
+    fn commit(&mut self) -> Result<()> {
+        self.storage.borrow_mut().commit()?;
+        let pending = self.changes.borrow_mut().commit();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut changes = Vec::with_capacity(pending.len());
+        for change in pending {
+            changes.push(match change {
+                PendingChange::Created(v) => match v.project(self)? {
+                    Val::Node(n) => Change::NodeCreated(n),
+                    Val::Rel(r) => Change::RelCreated(r),
+                    other => bail!("expected a created node or rel, got {:?}", other),
+                },
+                PendingChange::Deleted(change) => change,
+            });
+        }
+        // The hooks are taken out while they run, so they can't trip over the borrow
+        let mut hooks = std::mem::take(&mut self.changes.borrow_mut().hooks);
+        for hook in hooks.iter_mut() {
+            hook(&changes);
+        }
+        self.changes.borrow_mut().hooks = hooks;
+        Ok(())
+    }
+}
+
+// What queries created and sweeps deleted, for the hooks registered with Backend::on_change. Like
+// Storage, it holds on to what the running query does until the query commits, and in a batch
+// until the batch ends.
+#[derive(Default)]
+struct ChangeFeed {
+    hooks: Vec<ChangeHook>,
+    // Changes since the last commit, in the order they were made; only kept if there's a hook
+    // to hand them to
+    pending: Vec<PendingChange>,
+    // How much of pending is from queries that committed during a batch
+    committed: usize,
+    batching: bool,
+}
+
+// Created nodes and rels are read when they're committed; deleted ones are gone by then, so
+// they're read before they're deleted
+#[derive(Debug)]
+enum PendingChange {
+    Created(GramVal),
+    Deleted(Change),
+}
+
+impl ChangeFeed {
+    fn created(&mut self, v: GramVal) {
+        if !self.hooks.is_empty() {
+            self.pending.push(PendingChange::Created(v));
+        }
+    }
+
+    fn deleted(&mut self, change: Change) {
+        self.pending.push(PendingChange::Deleted(change));
+    }
+
+    // Is there a hook to hand changes to?
+    fn wanted(&self) -> bool {
+        !self.hooks.is_empty()
+    }
+
+    // Gives back what to hand to the hooks now, if anything
+    fn commit(&mut self) -> Vec<PendingChange> {
+        if self.batching {
+            self.committed = self.pending.len();
+            return vec![];
+        }
+        self.committed = 0;
+        std::mem::take(&mut self.pending)
+    }
+
+    fn rollback(&mut self) {
+        self.pending.truncate(self.committed);
+    }
+
+    fn abort_batch(&mut self) {
+        self.batching = false;
+        self.pending.clear();
+        self.committed = 0;
+    }
+}
+
+impl Debug for ChangeFeed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeFeed")
+            .field("hooks", &self.hooks.len())
+            .field("pending", &self.pending)
+            .field("committed", &self.committed)
+            .field("batching", &self.batching)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone)]
 enum Expr {
     Lit(Val),
+    Param(Token),
     // Lookup a property by id
     Prop(Box<Expr>, Vec<Token>),
+    // Lookup a property by the name the key evaluates to, or a list item by index
+    Subscript(Box<Expr>, Box<Expr>),
     Slot(Slot),
     List(Vec<Expr>),
     Map(Vec<(Token, Expr)>),
 
     Call(functions::Func, Vec<Expr>),
     And(Vec<Expr>),
+    Or(Vec<Expr>),
 
     Gt(Box<Expr>, Box<Expr>),
+    Gte(Box<Expr>, Box<Expr>),
     Equal(Box<Expr>, Box<Expr>),
 
     Mul(Box<Expr>, Box<Expr>),
@@ -497,58 +1402,134 @@ impl Expr {
     ) -> Result<GramVal> {
         let mut v = expr.eval(ctx, row)?;
         for key in prop {
-            v = match v {
-                GramVal::Node { id } => {
-                    GramVal::Lit(ctx.g.borrow().get_node_prop(id, *key).unwrap_or(Val::Null))
-                }
-                GramVal::Rel { node_id, rel_index } => GramVal::Lit(
-                    ctx.g
-                        .borrow()
-                        .get_rel_prop(node_id, rel_index, *key)
-                        .unwrap_or(Val::Null),
-                ),
-                GramVal::Map(es) => es
-                    .iter()
-                    .find(|(ek, _)| ek == key)
-                    .map(|e| e.1.clone())
-                    .unwrap_or(GramVal::Lit(Val::Null)),
-                v => bail!("Gram backend does not yet support {:?}", v),
-            };
+            v = Expr::prop_of(ctx, v, *key)?;
         }
         Ok(v)
     }
 
+    fn prop_of(ctx: &mut Context, v: GramVal, key: Token) -> Result<GramVal> {
+        Ok(match v {
+            GramVal::Node { id } => {
+                ctx.db_hits += 1;
+                let prop = ctx.g.borrow().get_node_prop(id, key);
+                GramVal::Lit(PropVal::read_opt(prop, &ctx.storage)?)
+            }
+            GramVal::Rel { node_id, rel_index } => {
+                ctx.db_hits += 1;
+                let prop = ctx.g.borrow().get_rel_prop(node_id, rel_index, key);
+                GramVal::Lit(PropVal::read_opt(prop, &ctx.storage)?)
+            }
+            GramVal::Map(es) => es
+                .iter()
+                .find(|(ek, _)| *ek == key)
+                .map(|e| e.1.clone())
+                .unwrap_or(GramVal::Lit(Val::Null)),
+            // Nodes and rels that aren't in the graph, like the ones procedures make up, and
+            // maps given as parameters
+            GramVal::Lit(Val::Node(crate::Node { props, .. }))
+            | GramVal::Lit(Val::Rel(crate::Rel { props, .. })) => {
+                GramVal::Lit(Expr::entry(&ctx.tokens.borrow(), &props, key))
+            }
+            GramVal::Lit(Val::Map(props)) => {
+                GramVal::Lit(Expr::entry(&ctx.tokens.borrow(), &props, key))
+            }
+            // Like the missing node of an OPTIONAL MATCH; it has no properties
+            GramVal::Lit(Val::Null) => GramVal::Lit(Val::Null),
+            v => bail!("Gram backend does not yet support {:?}", v),
+        })
+    }
+
+    fn entry(tokens: &Tokens, props: &crate::Map, key: Token) -> Val {
+        let name = tokens.lookup(key);
+        props
+            .iter()
+            .find(|(k, _)| Some(k.as_str()) == name)
+            .map(|(_, v)| v.clone())
+            .unwrap_or(Val::Null)
+    }
+
+    fn eval_subscript(
+        ctx: &mut Context,
+        row: &GramRow,
+        expr: &Expr,
+        key: &Expr,
+    ) -> Result<GramVal> {
+        let v = expr.eval(ctx, row)?;
+        match (v, key.eval(ctx, row)?) {
+            (GramVal::Lit(Val::Null), _) | (_, GramVal::Lit(Val::Null)) => {
+                Ok(GramVal::Lit(Val::Null))
+            }
+            (GramVal::List(items), GramVal::Lit(Val::Int(i))) => Ok(list_item(&items, i)
+                .cloned()
+                .unwrap_or(GramVal::Lit(Val::Null))),
+            (GramVal::Lit(Val::List(items)), GramVal::Lit(Val::Int(i))) => Ok(GramVal::Lit(
+                list_item(&items, i).cloned().unwrap_or(Val::Null),
+            )),
+            (GramVal::List(_), k) | (GramVal::Lit(Val::List(_)), k) => {
+                bail!(QueryError::TypeError {
+                    message: format!("lists are indexed by integers, not {}", k)
+                })
+            }
+            (v, GramVal::Lit(Val::String(name))) => {
+                let key = ctx.tokens.borrow_mut().tokenize(&name);
+                Expr::prop_of(ctx, v, key)
+            }
+            (_, k) => bail!(QueryError::TypeError {
+                message: format!("property keys are strings, not {}", k)
+            }),
+        }
+    }
+
     fn eval(&self, ctx: &mut Context, row: &GramRow) -> Result<GramVal> {
         match self {
             Expr::Prop(expr, props) => Expr::eval_prop(ctx, row, expr, props),
+            Expr::Subscript(expr, key) => Expr::eval_subscript(ctx, row, expr, key),
             Expr::Slot(slot) => Ok(row.slots[*slot].clone()), // TODO not this
             Expr::Lit(v) => Ok(GramVal::Lit(v.clone())),      // TODO not this,
+            Expr::Param(name) => match ctx.params.get(name) {
+                Some(v) => Ok(GramVal::Lit(v.clone())),
+                // The frontend checks that every parameter is given before running a query
+                None => bail!(
+                    "parameter ${} has no value",
+                    ctx.tokens.borrow().lookup(*name).unwrap_or("?")
+                ),
+            },
             Expr::List(vs) => {
                 let mut out = Vec::new();
                 for v in vs {
                     out.push(v.eval(ctx, row)?);
                 }
-                Ok(GramVal::List(out))
+                Ok(GramVal::List(Rc::new(out)))
             }
             Expr::Map(es) => {
                 let mut out = Vec::with_capacity(es.len());
                 for e in es {
                     out.push((e.0, e.1.eval(ctx, row)?));
                 }
-                Ok(GramVal::Map(out))
+                Ok(GramVal::Map(Rc::new(out)))
             }
             Expr::Gt(a, b) => {
                 let a_val = a.eval(ctx, row)?;
                 let b_val = b.eval(ctx, row)?;
-                match a_val.partial_cmp(&b_val) {
+                match a_val.cmp_collated(&b_val, &ctx.collation) {
                     Some(Ordering::Greater) => Ok(GramVal::Lit(Val::Bool(true))),
                     _ => Ok(GramVal::Lit(Val::Bool(false))),
                 }
             }
+            Expr::Gte(a, b) => {
+                let a_val = a.eval(ctx, row)?;
+                let b_val = b.eval(ctx, row)?;
+                match a_val.cmp_collated(&b_val, &ctx.collation) {
+                    Some(Ordering::Greater) | Some(Ordering::Equal) => {
+                        Ok(GramVal::Lit(Val::Bool(true)))
+                    }
+                    _ => Ok(GramVal::Lit(Val::Bool(false))),
+                }
+            }
             Expr::Equal(a, b) => {
                 let a_val = a.eval(ctx, row)?;
                 let b_val = b.eval(ctx, row)?;
-                let eq = a_val.eq(&b_val);
+                let eq = a_val.eq_collated(&b_val, &ctx.collation);
                 Ok(GramVal::Lit(Val::Bool(eq)))
             }
             Expr::Mul(a, b) => {
@@ -556,16 +1537,20 @@ impl Expr {
                 let b_val = b.eval(ctx, row)?;
                 match (&a_val, &b_val) {
                     (GramVal::Lit(Val::Int(a_int)), GramVal::Lit(Val::Int(b_int))) => {
-                        Ok(GramVal::Lit(Val::Int(a_int * b_int)))
+                        match a_int.checked_mul(*b_int) {
+                            Some(v) => Ok(GramVal::Lit(Val::Int(v))),
+                            None => bail!("integer overflow in {} * {}", a_int, b_int),
+                        }
                     }
                     (GramVal::Lit(Val::Float(a_int)), GramVal::Lit(Val::Int(b_int))) => {
                         Ok(GramVal::Lit(Val::Float(a_int * *b_int as f64)))
                     }
-                    _ => bail!(
-                        "gram backend does not support multiplication of {:?} and {:?}",
-                        a_val,
-                        b_val
-                    ),
+                    _ => bail!(QueryError::TypeError {
+                        message: format!(
+                            "gram backend does not support multiplication of {:?} and {:?}",
+                            a_val, b_val
+                        )
+                    }),
                 }
             }
             Expr::Div(a, b) => {
@@ -575,11 +1560,12 @@ impl Expr {
                     (GramVal::Lit(Val::Int(a_int)), GramVal::Lit(Val::Int(b_int))) => {
                         Ok(GramVal::Lit(Val::Float(*a_int as f64 / *b_int as f64)))
                     }
-                    _ => bail!(
-                        "gram backend does not support division of {:?} and {:?}",
-                        a_val,
-                        b_val
-                    ),
+                    _ => bail!(QueryError::TypeError {
+                        message: format!(
+                            "gram backend does not support division of {:?} and {:?}",
+                            a_val, b_val
+                        )
+                    }),
                 }
             }
             Expr::Add(a, b) => {
@@ -587,13 +1573,17 @@ impl Expr {
                 let b_val = b.eval(ctx, row)?;
                 match (&a_val, &b_val) {
                     (GramVal::Lit(Val::Int(a_int)), GramVal::Lit(Val::Int(b_int))) => {
-                        Ok(GramVal::Lit(Val::Int(a_int + b_int)))
+                        match a_int.checked_add(*b_int) {
+                            Some(v) => Ok(GramVal::Lit(Val::Int(v))),
+                            None => bail!("integer overflow in {} + {}", a_int, b_int),
+                        }
                     }
-                    _ => bail!(
-                        "gram backend does not support addition of {:?} and {:?}",
-                        a_val,
-                        b_val
-                    ),
+                    _ => bail!(QueryError::TypeError {
+                        message: format!(
+                            "gram backend does not support addition of {:?} and {:?}",
+                            a_val, b_val
+                        )
+                    }),
                 }
             }
             Expr::Sub(a, b) => {
@@ -601,7 +1591,13 @@ impl Expr {
                 let b_val = b.eval(ctx, row)?;
                 match (&a_val, &b_val) {
                     (GramVal::Lit(Val::Int(a_int)), GramVal::Lit(Val::Int(b_int))) => {
-                        Ok(GramVal::Lit(Val::Int(a_int - b_int)))
+                        match a_int.checked_sub(*b_int) {
+                            Some(v) => Ok(GramVal::Lit(Val::Int(v))),
+                            None => bail!("integer overflow in {} - {}", a_int, b_int),
+                        }
+                    }
+                    (GramVal::Lit(Val::Float(a_float)), GramVal::Lit(Val::Float(b_float))) => {
+                        Ok(GramVal::Lit(Val::Float(a_float - b_float)))
                     }
                     (GramVal::Lit(Val::Int(a_int)), GramVal::Lit(Val::Float(b_float))) => {
                         Ok(GramVal::Lit(Val::Float(*a_int as f64 - *b_float)))
@@ -609,34 +1605,61 @@ impl Expr {
                     (GramVal::Lit(Val::Float(a_float)), GramVal::Lit(Val::Int(b_int))) => {
                         Ok(GramVal::Lit(Val::Float(a_float - *b_int as f64)))
                     }
-                    _ => bail!(
-                        "gram backend does not support subtraction of {:?} and {:?}",
-                        a_val,
-                        b_val
-                    ),
+                    _ => bail!(QueryError::TypeError {
+                        message: format!(
+                            "gram backend does not support subtraction of {:?} and {:?}",
+                            a_val, b_val
+                        )
+                    }),
                 }
             }
+            // Three-valued: false wins over null, and null over true
             Expr::And(terms) => {
+                let mut null = false;
                 for t in terms {
                     match t.eval(ctx, row)? {
-                        GramVal::Lit(Val::Bool(b)) => if !b {
+                        GramVal::Lit(Val::Bool(false)) => {
                             return Ok(GramVal::Lit(Val::Bool(false)))
-                        },
-                        _ => panic!("The gram backend does not know how to do binary logic on non-boolean expressions")
+                        }
+                        GramVal::Lit(Val::Bool(true)) => (),
+                        GramVal::Lit(Val::Null) => null = true,
+                        v => bail!(QueryError::TypeError {
+                            message: format!("AND expects booleans, got {:?}", v)
+                        }),
                     }
                 }
-                return Ok(GramVal::Lit(Val::Bool(true)));
+                Ok(GramVal::Lit(if null { Val::Null } else { Val::Bool(true) }))
+            }
+            // And the other way around: true wins over null, and null over false
+            Expr::Or(terms) => {
+                let mut null = false;
+                for t in terms {
+                    match t.eval(ctx, row)? {
+                        GramVal::Lit(Val::Bool(true)) => return Ok(GramVal::Lit(Val::Bool(true))),
+                        GramVal::Lit(Val::Bool(false)) => (),
+                        GramVal::Lit(Val::Null) => null = true,
+                        v => bail!(QueryError::TypeError {
+                            message: format!("OR expects booleans, got {:?}", v)
+                        }),
+                    }
+                }
+                Ok(GramVal::Lit(if null {
+                    Val::Null
+                } else {
+                    Val::Bool(false)
+                }))
             }
             Expr::Call(f, args) => {
                 let mut argv = Vec::with_capacity(args.len());
                 for a in args {
                     argv.push(a.eval(ctx, row)?);
                 }
-                f.apply(&argv)
+                f.apply(ctx, &argv)
             }
             Expr::HasLabel { slot, label } => {
                 let s: &GramVal = &row.slots[*slot];
-                let node_id = s.as_node_id();
+                let node_id = s.as_node_id()?;
+                ctx.db_hits += 1;
                 let g = ctx.g.borrow();
                 let node = g.nodes.get(node_id).unwrap();
                 return Ok(GramVal::Lit(Val::Bool(node.labels.contains(label))));
@@ -653,33 +1676,33 @@ impl Expr {
 #[derive(Debug, PartialEq, Clone)]
 enum GramVal {
     Lit(Val),
-    List(Vec<GramVal>),
-    Map(Vec<(Token, GramVal)>),
+    List(Rc<Vec<GramVal>>),
+    Map(Rc<Vec<(Token, GramVal)>>),
     Node { id: usize },
     Rel { node_id: usize, rel_index: usize },
 }
 
 impl GramVal {
-    pub fn project(&self, ctx: &mut Context) -> Val {
+    pub fn project(&self, ctx: &mut Context) -> Result<Val> {
         match self {
-            GramVal::Lit(v) => v.clone(),
+            GramVal::Lit(v) => Ok(v.clone()),
             GramVal::List(vs) => {
                 let mut out = Vec::new();
                 out.resize(vs.len(), Val::Null);
                 for i in 0..vs.len() {
-                    out[i] = vs[i].project(ctx);
+                    out[i] = vs[i].project(ctx)?;
                 }
-                return Val::List(out);
+                return Ok(Val::List(out.into()));
             }
             GramVal::Map(es) => {
                 let mut out = Vec::with_capacity(es.len());
                 for i in 0..es.len() {
                     let entry = &es[i];
                     let key = ctx.tokens.borrow().lookup(entry.0).unwrap().to_string();
-                    let val = entry.1.project(ctx);
+                    let val = entry.1.project(ctx)?;
                     out.push((key, val))
                 }
-                return Val::Map(out);
+                return Ok(Val::Map(Arc::new(out)));
             }
             GramVal::Node { id } => {
                 let n = &ctx.g.borrow().nodes[*id];
@@ -687,18 +1710,23 @@ impl GramVal {
                 for (k, v) in &n.properties {
                     props.push((
                         ctx.tokens.borrow().lookup(*k).unwrap().to_string(),
-                        v.clone(),
+                        v.read(&ctx.storage)?,
                     ));
                 }
                 let mut labels = Vec::new();
                 for l in &n.labels {
                     labels.push(ctx.tokens.borrow().lookup(*l).unwrap().to_string());
                 }
-                return Val::Node(crate::Node {
+                // Labels and properties are kept in no particular order
+                if ctx.deterministic {
+                    labels.sort();
+                    props.sort_by(|(a, _), (b, _)| a.cmp(b));
+                }
+                return Ok(Val::Node(crate::Node {
                     id: *id,
                     labels,
                     props,
-                });
+                }));
             }
             GramVal::Rel { node_id, rel_index } => {
                 let n = &ctx.g.borrow().nodes[*node_id];
@@ -714,9 +1742,12 @@ impl GramVal {
                 for (k, v) in rel.properties.iter() {
                     props.push((
                         ctx.tokens.borrow().lookup(*k).unwrap().to_string(),
-                        v.clone(),
+                        v.read(&ctx.storage)?,
                     ));
                 }
+                if ctx.deterministic {
+                    props.sort_by(|(a, _), (b, _)| a.cmp(b));
+                }
 
                 let start;
                 let end;
@@ -730,22 +1761,62 @@ impl GramVal {
                         start = rel.other_node;
                     }
                 }
-                return Val::Rel(crate::Rel {
+                return Ok(Val::Rel(crate::Rel {
+                    id: rel.id,
                     start,
                     end,
                     rel_type,
                     props,
-                });
+                }));
+            }
+        }
+    }
+
+    // Like project, but writes into an existing value. If that value is a node or relationship
+    // from a previous row, its strings and vectors are re-used, so projecting graph entities
+    // doesn't allocate once the cursor has warmed up.
+    pub fn project_into(&self, ctx: &mut Context, out: &mut Val) -> Result<()> {
+        let toks = Rc::clone(&ctx.tokens);
+        let toks = toks.borrow();
+        match (self, out) {
+            (GramVal::Node { id }, Val::Node(out)) => {
+                let n = &ctx.g.borrow().nodes[*id];
+                out.id = *id;
+                assign_strings(
+                    &mut out.labels,
+                    n.labels.iter().map(|l| toks.lookup(*l).unwrap()),
+                );
+                assign_props(&mut out.props, &toks, &ctx.storage, &n.properties)?;
+            }
+            (GramVal::Rel { node_id, rel_index }, Val::Rel(out)) => {
+                let n = &ctx.g.borrow().nodes[*node_id];
+                let rel = &n.rels[*rel_index];
+                out.id = rel.id;
+                match rel.dir {
+                    Dir::Out => {
+                        out.start = *node_id;
+                        out.end = rel.other_node;
+                    }
+                    Dir::In => {
+                        out.end = *node_id;
+                        out.start = rel.other_node;
+                    }
+                }
+                out.rel_type.clear();
+                out.rel_type.push_str(toks.lookup(rel.rel_type).unwrap());
+                assign_props(&mut out.props, &toks, &ctx.storage, &rel.properties)?;
             }
+            (v, out) => *out = v.project(ctx)?,
         }
+        Ok(())
     }
 
-    pub fn as_node_id(&self) -> usize {
+    pub fn as_node_id(&self) -> Result<usize> {
         match self {
-            GramVal::Node { id } => *id,
-            _ => panic!(
-                "invalid execution plan, non-node value feeds into thing expecting node value"
-            ),
+            GramVal::Node { id } => Ok(*id),
+            _ => bail!(QueryError::TypeError {
+                message: format!("expected a node, got {:?}", self)
+            }),
         }
     }
 
@@ -757,6 +1828,134 @@ impl GramVal {
             ),
         }
     }
+
+    // The openCypher ordering of values, used by ORDER BY. Unlike comparison in expressions,
+    // this is total: values of different types order by type, maps < nodes < relationships <
+    // lists < strings < booleans < numbers < null, and NaN is larger than any other number.
+    // Strings order by the collation.
+    pub fn sort_cmp(&self, other: &Self, collation: &Collation) -> Ordering {
+        match (self, other) {
+            (GramVal::Lit(Val::Int(a)), GramVal::Lit(Val::Int(b))) => a.cmp(b),
+            (GramVal::Lit(Val::Int(a)), GramVal::Lit(Val::Float(b))) => cmp_f64(*a as f64, *b),
+            (GramVal::Lit(Val::Float(a)), GramVal::Lit(Val::Int(b))) => cmp_f64(*a, *b as f64),
+            (GramVal::Lit(Val::Float(a)), GramVal::Lit(Val::Float(b))) => cmp_f64(*a, *b),
+            (GramVal::Lit(Val::String(a)), GramVal::Lit(Val::String(b))) => collation.compare(a, b),
+            (GramVal::Lit(Val::Bool(a)), GramVal::Lit(Val::Bool(b))) => a.cmp(b),
+            (GramVal::Node { id: a }, GramVal::Node { id: b }) => a.cmp(b),
+            (
+                GramVal::Rel {
+                    node_id: a_node,
+                    rel_index: a_rel,
+                },
+                GramVal::Rel {
+                    node_id: b_node,
+                    rel_index: b_rel,
+                },
+            ) => (a_node, a_rel).cmp(&(b_node, b_rel)),
+            (a, b) if a.sort_rank() == 3 && b.sort_rank() == 3 => {
+                let (a, b) = (a.sort_items(), b.sort_items());
+                for (x, y) in a.iter().zip(b.iter()) {
+                    match x.sort_cmp(y, collation) {
+                        Ordering::Equal => (),
+                        ord => return ord,
+                    }
+                }
+                a.len().cmp(&b.len())
+            }
+            // Maps, and literal nodes and rels, don't have an order among themselves
+            (a, b) => a.sort_rank().cmp(&b.sort_rank()),
+        }
+    }
+
+    // Comparison in expressions, like partial_cmp but with strings compared by the collation
+    fn cmp_collated(&self, other: &Self, collation: &Collation) -> Option<Ordering> {
+        match (self, other) {
+            (GramVal::Lit(Val::String(a)), GramVal::Lit(Val::String(b))) => {
+                Some(collation.compare(a, b))
+            }
+            (GramVal::List(a), GramVal::List(b)) => {
+                for (x, y) in a.iter().zip(b.iter()) {
+                    match x.cmp_collated(y, collation) {
+                        Some(Ordering::Equal) => (),
+                        ord => return ord,
+                    }
+                }
+                Some(a.len().cmp(&b.len()))
+            }
+            _ => self.partial_cmp(other),
+        }
+    }
+
+    // Equality in expressions, with strings compared by the collation
+    fn eq_collated(&self, other: &Self, collation: &Collation) -> bool {
+        match (self, other) {
+            (GramVal::Lit(Val::String(a)), GramVal::Lit(Val::String(b))) => collation.equal(a, b),
+            (GramVal::List(_), GramVal::List(_))
+            | (GramVal::Lit(Val::List(_)), GramVal::Lit(Val::List(_))) => {
+                let (a, b) = (self.sort_items(), other.sort_items());
+                a.len() == b.len()
+                    && a.iter()
+                        .zip(b.iter())
+                        .all(|(x, y)| x.eq_collated(y, collation))
+            }
+            _ => self == other,
+        }
+    }
+
+    // What the value is equal to others by, under the collation: strings are replaced by their
+    // keys, see collation.rs
+    fn collation_key(&self, collation: &Collation) -> GramVal {
+        match self {
+            GramVal::Lit(Val::String(s)) => match collation.key(s) {
+                Cow::Borrowed(_) => self.clone(),
+                Cow::Owned(key) => GramVal::Lit(Val::String(key.into())),
+            },
+            GramVal::List(items) if !collation.is_binary() => GramVal::List(Rc::new(
+                items.iter().map(|v| v.collation_key(collation)).collect(),
+            )),
+            v => v.clone(),
+        }
+    }
+
+    fn sort_rank(&self) -> u8 {
+        match self {
+            GramVal::Map(_) | GramVal::Lit(Val::Map(_)) => 0,
+            GramVal::Node { .. } | GramVal::Lit(Val::Node(_)) => 1,
+            GramVal::Rel { .. } | GramVal::Lit(Val::Rel(_)) => 2,
+            GramVal::List(_) | GramVal::Lit(Val::List(_)) => 3,
+            GramVal::Lit(Val::String(_)) => 4,
+            GramVal::Lit(Val::Bool(_)) => 5,
+            GramVal::Lit(Val::Int(_)) | GramVal::Lit(Val::Float(_)) => 6,
+            GramVal::Lit(Val::Null) => 7,
+        }
+    }
+
+    // The items of a list, whether it's a literal or made at runtime
+    fn sort_items(&self) -> Vec<GramVal> {
+        match self {
+            GramVal::List(items) => items.to_vec(),
+            GramVal::Lit(Val::List(items)) => items.iter().cloned().map(GramVal::Lit).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+// The item at index i of a list, with negative indexes counting from the end
+fn list_item<T>(items: &[T], i: i64) -> Option<&T> {
+    let i = if i < 0 { items.len() as i64 + i } else { i };
+    if i < 0 {
+        return None;
+    }
+    items.get(i as usize)
+}
+
+fn cmp_f64(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+    }
 }
 
 impl PartialOrd for GramVal {
@@ -831,6 +2030,17 @@ impl Display for GramVal {
 // Physical operator. We have one of these for each Logical operator the frontend emits.
 trait Operator: Debug {
     fn next(&mut self, ctx: &mut Context, row: &mut GramRow) -> Result<bool>;
+
+    // Return the operator, and its sources, to its initial state, so it can be pulled
+    // again from the start; used to re-run sub-plans once per outer row.
+    fn reset(&mut self);
+
+    // Only this many rows will be pulled from the operator, until it's reset; this is how a
+    // LIMIT lets the operators below it know. Scans and expands don't need telling, since they
+    // only produce rows as they're pulled, but operators that have to see all their input before
+    // yielding anything, like Sort, can use this to do less work. Operators that yield one row
+    // per source row pass it on.
+    fn set_row_budget(&mut self, _rows: usize) {}
 }
 
 #[derive(Debug)]
@@ -853,6 +2063,9 @@ struct Expand {
 
     pub dir: Option<Dir>,
 
+    // If set, only rels for which this evaluates to true are yielded
+    pub predicate: Option<Expr>,
+
     // In the current adjacency list, what is the next index we should return?
     pub next_rel_index: usize,
 
@@ -874,41 +2087,69 @@ impl Operator for Expand {
                     self.state = ExpandState::InNode;
                 }
                 ExpandState::InNode => {
-                    let node = out.slots[self.src_slot].as_node_id();
-                    let g = ctx.g.borrow();
-                    let rels = &g.nodes[node].rels;
-                    if self.next_rel_index >= rels.len() {
-                        // No more rels on this node
-                        self.state = ExpandState::NextNode;
-                        self.next_rel_index = 0;
-                        continue;
-                    }
+                    let node = out.slots[self.src_slot].as_node_id()?;
+                    let other_node = {
+                        let g = ctx.g.borrow();
+                        let rels = &g.nodes[node].rels;
+                        if self.next_rel_index >= rels.len() {
+                            // No more rels on this node
+                            self.state = ExpandState::NextNode;
+                            self.next_rel_index = 0;
+                            continue;
+                        }
 
-                    let rel = &rels[self.next_rel_index];
-                    self.next_rel_index += 1;
+                        let rel = &rels[self.next_rel_index];
+                        self.next_rel_index += 1;
+                        ctx.db_hits += 1;
 
-                    if self.rel_type.is_some() {
-                        if rel.rel_type != self.rel_type.unwrap() {
-                            continue;
+                        if self.rel_type.is_some() {
+                            if rel.rel_type != self.rel_type.unwrap() {
+                                continue;
+                            }
                         }
-                    }
 
-                    if self.dir.is_some() && rel.other_node != node {
-                        if rel.dir != self.dir.unwrap() {
-                            continue;
+                        match self.dir {
+                            Some(dir) if rel.dir != dir => continue,
+                            // Both halves of a rel from this node to itself are here; going
+                            // either way, that's still just the one rel
+                            None if rel.other_node == node && rel.dir == Dir::In => continue,
+                            _ => (),
                         }
-                    }
 
-                    out.slots[self.rel_slot] = GramVal::Rel {
-                        node_id: node,
-                        rel_index: self.next_rel_index - 1,
+                        // A rel is always referred to by its outgoing half, so it's the same
+                        // value whichever end it was reached from
+                        out.slots[self.rel_slot] = match rel.dir {
+                            Dir::Out => GramVal::Rel {
+                                node_id: node,
+                                rel_index: self.next_rel_index - 1,
+                            },
+                            Dir::In => GramVal::Rel {
+                                node_id: rel.other_node,
+                                rel_index: rel.other_index,
+                            },
+                        };
+                        rel.other_node
                     };
-                    out.slots[self.dst_slot] = GramVal::Node { id: rel.other_node };
+
+                    if let Some(predicate) = &self.predicate {
+                        match predicate.eval(ctx, out)? {
+                            GramVal::Lit(Val::Bool(true)) => (),
+                            _ => continue,
+                        }
+                    }
+
+                    out.slots[self.dst_slot] = GramVal::Node { id: other_node };
                     return Ok(true);
                 }
             }
         }
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.next_rel_index = 0;
+        self.state = ExpandState::NextNode;
+    }
 }
 
 // For each src row, perform a full no de scan with the specified filters
@@ -929,8 +2170,15 @@ struct NodeScan {
 pub enum NodeScanState {
     // Next call will pull another row from src
     Idle,
-    // We're in the middle of a scan, next call will continue scanning
-    Scanning { next_node: usize },
+    // We're in the middle of a scan, next call will continue scanning. The scan stops at the
+    // nodes that existed when it started, so that nodes created further up the plan, like in
+    // MATCH () CREATE (), don't get scanned in turn; those that took the id of a deleted node
+    // are before the end, and are told apart by how many nodes were added before them.
+    Scanning {
+        next_node: usize,
+        end: usize,
+        nodes_added: u64,
+    },
 }
 
 impl Operator for NodeScan {
@@ -941,13 +2189,28 @@ impl Operator for NodeScan {
                     if !self.src.next(ctx, out)? {
                         return Ok(false);
                     }
-                    self.state = NodeScanState::Scanning { next_node: 0 }
+                    let g = ctx.g.borrow();
+                    self.state = NodeScanState::Scanning {
+                        next_node: 0,
+                        end: g.nodes.len(),
+                        nodes_added: g.nodes_added,
+                    }
                 }
-                NodeScanState::Scanning { next_node } => {
+                NodeScanState::Scanning {
+                    next_node,
+                    end,
+                    nodes_added,
+                } => {
+                    let (end, nodes_added) = (*end, *nodes_added);
                     let g = ctx.g.borrow();
                     let mut node_id = *next_node;
-                    while g.nodes.len() > node_id {
+                    while end > node_id {
                         let node = g.nodes.get(node_id).unwrap();
+                        ctx.db_hits += 1;
+                        if node.deleted || node.added >= nodes_added {
+                            node_id += 1;
+                            continue;
+                        }
                         if let Some(tok) = self.labels {
                             if !node.labels.contains(&tok) {
                                 node_id += 1;
@@ -958,6 +2221,8 @@ impl Operator for NodeScan {
                         out.slots[self.slot] = GramVal::Node { id: node_id };
                         self.state = NodeScanState::Scanning {
                             next_node: node_id + 1,
+                            end,
+                            nodes_added,
                         };
                         return Ok(true);
                     }
@@ -966,6 +2231,62 @@ impl Operator for NodeScan {
             }
         }
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.state = NodeScanState::Idle;
+    }
+}
+
+// Gram indexes are kept in memory only, so the bounds of seeks don't need to be exact: a seek
+// gives every node in the index with a value that may be in the range, and the predicates the
+// range came from sort out the rest, see LogicalPlan::NodeIndexSeek
+#[derive(Debug)]
+struct NodeIndexSeek {
+    src: Box<dyn Operator>,
+    slot: Slot,
+    index: Rc<RefCell<PropertyIndex>>,
+    lower: Option<Expr>,
+    upper: Option<Expr>,
+    // What's left of the nodes found for the current src row, last one first. Found all at once,
+    // so nodes created further up the plan aren't found in turn, like with NodeScan.
+    found: Vec<usize>,
+}
+
+impl Operator for NodeIndexSeek {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        loop {
+            if let Some(id) = self.found.pop() {
+                ctx.db_hits += 1;
+                out.slots[self.slot] = GramVal::Node { id };
+                return Ok(true);
+            }
+            if !self.src.next(ctx, out)? {
+                return Ok(false);
+            }
+            // Anything that isn't a plain value, like a node, can't narrow the range down
+            let mut bound = |e: &Option<Expr>| -> Result<Option<Val>> {
+                Ok(match e {
+                    Some(e) => match e.eval(ctx, out)? {
+                        GramVal::Lit(v) => Some(v),
+                        _ => None,
+                    },
+                    None => None,
+                })
+            };
+            let (lower, upper) = (bound(&self.lower)?, bound(&self.upper)?);
+            ctx.db_hits += 1;
+            self.index
+                .borrow()
+                .seek(lower.as_ref(), upper.as_ref(), &mut self.found);
+            self.found.reverse();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.found.clear();
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -985,6 +2306,10 @@ impl Operator for Argument {
         self.consumed = true;
         return Ok(true);
     }
+
+    fn reset(&mut self) {
+        self.consumed = false;
+    }
 }
 
 // Nodespec is used by the CREATE operator, and differs from Node in that its properties are
@@ -994,6 +2319,8 @@ struct NodeSpec {
     pub slot: usize,
     pub labels: HashSet<Token>,
     pub props: HashMap<Token, Expr>,
+    // A map whose entries are properties too, like the $props in CREATE (n $props)
+    pub prop_map: Option<Expr>,
 }
 
 impl Clone for NodeSpec {
@@ -1002,6 +2329,7 @@ impl Clone for NodeSpec {
             slot: self.slot,
             labels: self.labels.iter().cloned().collect(),
             props: self.props.clone(),
+            prop_map: self.prop_map.clone(),
         }
     }
 
@@ -1018,6 +2346,7 @@ struct RelSpec {
     pub end_node_slot: usize,
     pub rel_type: Token,
     pub props: HashMap<Token, Expr>,
+    pub prop_map: Option<Expr>,
 }
 
 #[derive(Debug)]
@@ -1034,17 +2363,7 @@ impl Operator for Create {
             return Ok(false);
         }
         for node in &self.nodes {
-            let node_properties = node
-                .props
-                .iter()
-                .map(|p| {
-                    if let Ok(GramVal::Lit(val)) = p.1.eval(ctx, out) {
-                        return (*p.0, (val));
-                    } else {
-                        panic!("Property creation expression yielded non-literal?")
-                    }
-                })
-                .collect();
+            let node_properties = property_values(&node.props, &node.prop_map, ctx, out)?;
             out.slots[node.slot] = append_node(
                 ctx,
                 Rc::clone(&self.tokens),
@@ -1053,17 +2372,7 @@ impl Operator for Create {
             )?;
         }
         for rel in &self.rels {
-            let rel_properties = rel
-                .props
-                .iter()
-                .map(|p| {
-                    if let Ok(GramVal::Lit(val)) = p.1.eval(ctx, out) {
-                        return (*p.0, (val));
-                    } else {
-                        panic!("Property creation expression yielded non-literal?")
-                    }
-                })
-                .collect();
+            let rel_properties = property_values(&rel.props, &rel.prop_map, ctx, out)?;
 
             let start_node = match &out.slots[rel.start_node_slot] {
                 GramVal::Node { id } => *id,
@@ -1082,36 +2391,215 @@ impl Operator for Create {
         }
         Ok(true)
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+}
+
+// Evaluate the properties a node or rel is created with: the entries of the map, if there is
+// one, and then the props. Properties hold numbers, strings and booleans, and lists of those;
+// not maps, nodes or rels.
+fn property_values(
+    props: &HashMap<Token, Expr>,
+    prop_map: &Option<Expr>,
+    ctx: &mut Context,
+    row: &mut GramRow,
+) -> Result<HashMap<Token, PropVal>> {
+    let mut vals = Vec::with_capacity(props.len());
+    if let Some(expr) = prop_map {
+        match expr.eval(ctx, row)?.project(ctx)? {
+            Val::Map(entries) => {
+                let mut tokens = ctx.tokens.borrow_mut();
+                for (k, v) in entries.iter() {
+                    vals.push((tokens.tokenize(k), v.clone()));
+                }
+            }
+            v => bail!(QueryError::TypeError {
+                message: format!("expected a map of properties, got {:?}", v)
+            }),
+        }
+    }
+    for (k, expr) in props {
+        vals.push((*k, expr.eval(ctx, row)?.project(ctx)?));
+    }
+    let mut out = HashMap::with_capacity(vals.len());
+    for (k, val) in vals {
+        let storable = |v: &Val| !matches!(v, Val::Map(_) | Val::Node(_) | Val::Rel(_));
+        match &val {
+            Val::List(items) if items.iter().all(storable) => (),
+            v if !matches!(v, Val::List(_)) && storable(v) => (),
+            v => bail!(QueryError::TypeError {
+                message: format!("{:?} can't be stored as a property value", v)
+            }),
+        }
+        out.insert(k, PropVal::Val(val));
+    }
+    Ok(out)
 }
 
 #[derive(Debug)]
 struct ProduceResults {
     pub src: Box<dyn Operator>,
-    pub fields: Vec<(Token, usize)>,
-    print_header: bool,
 }
 
 impl Operator for ProduceResults {
     fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
-        if self.print_header {
-            println!("----");
-            let mut first = true;
-            for (token, _) in &self.fields {
-                let toks = ctx.tokens.borrow();
-                let field_name = toks.lookup(*token).unwrap();
-                if first {
-                    print!("{}", field_name);
-                    first = false
-                } else {
-                    print!(", {}", field_name)
-                }
+        self.src.next(ctx, out)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+}
+
+// Wraps an operator in a tracing span, recording how many rows it produced once it's dropped.
+// The count is not cleared on reset, so for sub-plans that are re-run, it's the total over
+// all runs.
+#[derive(Debug)]
+struct Traced {
+    src: Box<dyn Operator>,
+    span: tracing::Span,
+    rows: u64,
+}
+
+impl Operator for Traced {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        let _enter = self.span.enter();
+        let more = self.src.next(ctx, out)?;
+        if more {
+            self.rows += 1;
+        }
+        Ok(more)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+
+    fn set_row_budget(&mut self, rows: usize) {
+        self.src.set_row_budget(rows)
+    }
+}
+
+// Fails the query once the operator has produced more rows than it's allowed to, see
+// Backend::set_max_intermediate_rows. Like with Traced, the count is over all runs.
+#[derive(Debug)]
+struct Guarded {
+    src: Box<dyn Operator>,
+    name: &'static str,
+    max_rows: u64,
+    rows: u64,
+}
+
+impl Operator for Guarded {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        let more = self.src.next(ctx, out)?;
+        if more {
+            self.rows += 1;
+            if self.rows > self.max_rows {
+                bail!(QueryError::LimitExceeded {
+                    message: format!(
+                        "{} produced more than {} rows, the most any operator of a query may",
+                        self.name, self.max_rows
+                    )
+                })
             }
-            println!();
-            println!("----");
-            self.print_header = false;
         }
+        Ok(more)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+
+    fn set_row_budget(&mut self, rows: usize) {
+        self.src.set_row_budget(rows)
+    }
+}
+
+// Wraps every operator, to keep track of which one the query is at; see BackendCursor::operator
+#[derive(Debug)]
+struct Watched {
+    src: Box<dyn Operator>,
+    name: &'static str,
+}
+
+impl Operator for Watched {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        ctx.operator.set(Some(self.name));
         self.src.next(ctx, out)
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+
+    fn set_row_budget(&mut self, rows: usize) {
+        self.src.set_row_budget(rows)
+    }
+}
+
+impl Drop for Traced {
+    fn drop(&mut self) {
+        // Operators aren't always exhausted, eg. under a LIMIT, so we report when the plan goes away
+        self.span.record("rows", self.rows);
+        tracing::trace!(parent: &self.span, rows = self.rows, "operator done");
+    }
+}
+
+// Gives each operator of a plan being converted its entry in the stats, see Profiled
+#[derive(Debug, Default)]
+struct Profiler {
+    stats: Rc<RefCell<Vec<OperatorStats>>>,
+    // The operators whose sources are being converted, innermost last
+    parents: Vec<usize>,
+}
+
+impl Profiler {
+    fn enter(&mut self, name: &'static str) -> usize {
+        let mut stats = self.stats.borrow_mut();
+        let index = stats.len();
+        stats.push(OperatorStats {
+            name,
+            parent: self.parents.last().copied(),
+            ..Default::default()
+        });
+        self.parents.push(index);
+        index
+    }
+}
+
+// Wraps an operator to measure it for ExecutionStats. What it measures includes the operators it
+// pulls rows from, since they run inside its next(); GramCursor::stats takes those back out.
+#[derive(Debug)]
+struct Profiled {
+    src: Box<dyn Operator>,
+    stats: Rc<RefCell<Vec<OperatorStats>>>,
+    index: usize,
+}
+
+impl Operator for Profiled {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        let db_hits = ctx.db_hits;
+        let started = Stopwatch::start();
+        let more = self.src.next(ctx, out);
+        let stats = &mut self.stats.borrow_mut()[self.index];
+        stats.time += started.elapsed();
+        stats.db_hits += ctx.db_hits - db_hits;
+        if let Ok(true) = more {
+            stats.rows += 1;
+        }
+        more
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+
+    fn set_row_budget(&mut self, rows: usize) {
+        self.src.set_row_budget(rows)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1137,6 +2625,14 @@ impl Operator for Project {
         }
         Ok(false)
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
+
+    fn set_row_budget(&mut self, rows: usize) {
+        self.src.set_row_budget(rows)
+    }
 }
 
 #[derive(Debug)]
@@ -1153,24 +2649,21 @@ impl Operator for Limit {
     fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
         if !self.initialized {
             self.initialized = true;
-            if let Some(skip_expr) = &self.skip {
-                let skip_val = skip_expr.eval(ctx, out)?;
-                let mut skip = if let GramVal::Lit(Val::Int(i)) = skip_val {
-                    i
-                } else {
-                    bail!("SKIP expression must be an integer, got {:?}", skip_val)
-                };
-                while skip > 0 && self.src.next(ctx, out)? {
-                    skip -= 1
-                }
-            }
+            let mut skip = match &self.skip {
+                Some(skip_expr) => row_count("SKIP", skip_expr, ctx, out)?,
+                None => 0,
+            };
             if let Some(limit_expr) = &self.limit {
-                let limit_val = limit_expr.eval(ctx, out)?;
-                self.limit_remaining = if let GramVal::Lit(Val::Int(i)) = limit_val {
-                    Some(i)
-                } else {
-                    bail!("LIMIT expression must be an integer, got {:?}", limit_val)
-                };
+                self.limit_remaining = Some(row_count("LIMIT", limit_expr, ctx, out)?);
+            }
+            match self.limit_remaining {
+                // Nothing to skip to
+                Some(0) => return Ok(false),
+                Some(limit) => self.src.set_row_budget(skip.saturating_add(limit) as usize),
+                None => (),
+            }
+            while skip > 0 && self.src.next(ctx, out)? {
+                skip -= 1
             }
         }
 
@@ -1183,6 +2676,27 @@ impl Operator for Limit {
 
         self.src.next(ctx, out)
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.initialized = false;
+        self.limit_remaining = None;
+    }
+}
+
+// Evaluate the row count of a SKIP or LIMIT; the planner checked it doesn't depend on the row,
+// but a $parameter can still be anything
+fn row_count(clause: &str, expr: &Expr, ctx: &mut Context, out: &mut GramRow) -> Result<i64> {
+    match expr.eval(ctx, out)? {
+        GramVal::Lit(Val::Int(i)) if i >= 0 => Ok(i),
+        GramVal::Lit(Val::Int(i)) => bail!(QueryError::SemanticError {
+            message: format!("{} can't be negative, got {}", clause, i),
+            span: None,
+        }),
+        other => bail!(QueryError::TypeError {
+            message: format!("{} expects an integer, got {:?}", clause, other),
+        }),
+    }
 }
 
 #[derive(Debug)]
@@ -1207,6 +2721,11 @@ impl Operator for Optional {
             self.src.next(ctx, out)
         }
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.initialized = false;
+    }
 }
 
 #[derive(Debug)]
@@ -1229,14 +2748,12 @@ impl Operator for NestLoop {
 
         loop {
             if !self.inner.next(ctx, out)? {
-                // We need the ability to "reset" the inner operator here, which we don't
-                // yet have.. and the TCK does not as-of-yet test any cases where the outer
-                // is more than one row! So just ensure there's no more rows in the input and
-                // then bail.
-                if self.outer.next(ctx, out)? {
-                    panic!("gram backend can't do cartesian product when outer has N > 1 yet")
+                // Inner is done with this outer row; start it over for the next one
+                if !self.outer.next(ctx, out)? {
+                    return Ok(false);
                 }
-                return Ok(false);
+                self.inner.reset();
+                continue;
             }
 
             if self.predicate.eval(ctx, out)?.as_bool()? {
@@ -1244,6 +2761,61 @@ impl Operator for NestLoop {
             }
         }
     }
+
+    fn reset(&mut self) {
+        self.outer.reset();
+        self.inner.reset();
+        self.initialized = false;
+    }
+}
+
+#[derive(Debug)]
+struct ConditionalApply {
+    src: Box<dyn Operator>,
+    probe: Box<dyn Operator>,
+}
+
+impl Operator for ConditionalApply {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        while self.src.next(ctx, out)? {
+            // Only pull the probe once; we just care that there is a match, not how many
+            self.probe.reset();
+            if self.probe.next(ctx, out)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.probe.reset();
+    }
+}
+
+#[derive(Debug)]
+struct AntiConditionalApply {
+    src: Box<dyn Operator>,
+    probe: Box<dyn Operator>,
+}
+
+impl Operator for AntiConditionalApply {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        while self.src.next(ctx, out)? {
+            // The probe reads the src row as its argument; it writes into its own slots, so
+            // the src row survives the probe intact.
+            self.probe.reset();
+            if !self.probe.next(ctx, out)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.probe.reset();
+    }
 }
 
 #[derive(Debug)]
@@ -1251,7 +2823,35 @@ struct Sort {
     src: Box<dyn Operator>,
     state: SortState,
     rows: Vec<GramRow>,
-    sort_by: Vec<Expr>,
+    sort_by: Vec<SortKey>,
+    // Rows past this many are never pulled, so only the first ones need to be kept around
+    row_budget: Option<usize>,
+}
+
+#[derive(Debug)]
+struct SortKey {
+    expr: Expr,
+    descending: bool,
+    nulls_first: bool,
+}
+
+impl SortKey {
+    fn cmp(&self, a: &GramVal, b: &GramVal, collation: &Collation) -> Ordering {
+        // Null placement is independent of direction, so it's sorted out before reversing
+        let ord = match (a, b) {
+            (GramVal::Lit(Val::Null), GramVal::Lit(Val::Null)) => return Ordering::Equal,
+            (GramVal::Lit(Val::Null), _) if self.nulls_first => return Ordering::Less,
+            (GramVal::Lit(Val::Null), _) => return Ordering::Greater,
+            (_, GramVal::Lit(Val::Null)) if self.nulls_first => return Ordering::Greater,
+            (_, GramVal::Lit(Val::Null)) => return Ordering::Less,
+            _ => a.sort_cmp(b, collation),
+        };
+        if self.descending {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -1262,37 +2862,53 @@ enum SortState {
 }
 
 impl Operator for Sort {
-    // TODO lol
     fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
         if let SortState::Init = self.state {
-            let mut rows = Vec::new();
+            // sort_by is stable, so rows that tie on every key stay in input order
+            let sort_by = &self.sort_by;
+            let collation = ctx.collation.clone();
+            let sort = |keyed: &mut Vec<(Vec<GramVal>, GramRow)>| {
+                keyed.sort_by(|(a, _), (b, _)| {
+                    for (i, k) in sort_by.iter().enumerate() {
+                        match k.cmp(&a[i], &b[i], &collation) {
+                            Ordering::Equal => (),
+                            ord => return ord,
+                        }
+                    }
+                    Ordering::Equal
+                })
+            };
+
+            // Evaluate the sort keys once per row up front, rather than on every comparison
+            let mut keyed = Vec::new();
             while self.src.next(ctx, out)? {
-                rows.push(out.clone());
+                let mut keys = Vec::with_capacity(self.sort_by.len());
+                for k in sort_by {
+                    keys.push(k.expr.eval(ctx, out)?);
+                }
+                keyed.push((keys, out.clone()));
+                // With a budget, the rows that sort past it are dropped every so often, so we
+                // keep at most twice the budget around rather than the whole input. Rows that
+                // are kept stay ahead of the ones that come in after them, so ties still keep
+                // their input order.
+                if let Some(budget) = self.row_budget {
+                    if keyed.len() >= budget.saturating_mul(2).max(64) {
+                        sort(&mut keyed);
+                        keyed.truncate(budget);
+                    }
+                }
             }
 
-            if rows.is_empty() {
+            if keyed.is_empty() {
                 self.state = SortState::Done;
                 return Ok(false);
             }
 
-            rows.sort_by(|a, b| {
-                for e in &self.sort_by {
-                    let a_val = e.eval(ctx, a).unwrap();
-                    let b_val = e.eval(ctx, b).unwrap();
-                    let cmp = a_val.partial_cmp(&b_val);
-                    match cmp {
-                        Some(Ordering::Greater) => return Ordering::Greater,
-                        Some(Ordering::Less) => return Ordering::Less,
-                        // TODO: Val should probably implement Ord, because while we don't
-                        //       implement a full Eq, there *is* a total ordering. As it happens,
-                        //       things that aren't technically comparable (NULL > NULL et al)
-                        //       "are equal" when it comes to sorting and grouping.. so this works
-                        _ => (),
-                    }
-                }
-                return Ordering::Equal;
-            });
-            self.rows = rows;
+            sort(&mut keyed);
+            if let Some(budget) = self.row_budget {
+                keyed.truncate(budget);
+            }
+            self.rows = keyed.into_iter().map(|(_, row)| row).collect();
             self.state = SortState::Yielding { next: 0 };
         }
 
@@ -1311,6 +2927,17 @@ impl Operator for Sort {
             Ok(false)
         }
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.state = SortState::Init;
+        self.rows.clear();
+        self.row_budget = None;
+    }
+
+    fn set_row_budget(&mut self, rows: usize) {
+        self.row_budget = Some(rows);
+    }
 }
 
 #[derive(Debug)]
@@ -1329,6 +2956,10 @@ impl Operator for Selection {
         }
         Ok(false)
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
 }
 
 // So, this gets complicated. Cypher defines two types with a lot of baggage: NULL and IEEE-754
@@ -1344,29 +2975,33 @@ impl Operator for Selection {
 //
 // This struct uses the equivalence rules to implement rusts equal and hashcode traits, so
 // you can wrap your stuff with this and have rust follow the cypher equivalence rules.
+//
+// Strings are equivalent if they're equal under the collation, so the key goes by their
+// collation keys; the group gets the values of the first row of it.
 #[derive(Debug, Clone)]
 struct GroupKey {
     vals: Vec<GramVal>,
+    keys: Vec<GramVal>,
+}
+
+impl GroupKey {
+    fn new(vals: Vec<GramVal>, collation: &Collation) -> GroupKey {
+        let keys = vals.iter().map(|v| v.collation_key(collation)).collect();
+        GroupKey { vals, keys }
+    }
 }
 
 impl Eq for GroupKey {}
 
 impl PartialEq for GroupKey {
     fn eq(&self, other: &Self) -> bool {
-        for v in &self.vals {
-            for ov in &other.vals {
-                if v.ne(ov) {
-                    return false;
-                }
-            }
-        }
-        return true;
+        self.keys == other.keys
     }
 }
 
 impl Hash for GroupKey {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        for v in &self.vals {
+        for v in &self.keys {
             match v {
                 GramVal::Lit(lv) => match lv {
                     Val::Null => 0.hash(state),
@@ -1439,12 +3074,11 @@ impl Operator for HashAggregation {
             let src = &mut *self.src;
             while src.next(ctx, row)? {
                 // Calculate the group key
-                let mut key = GroupKey {
-                    vals: Vec::with_capacity(self.grouping.len()),
-                };
+                let mut vals = Vec::with_capacity(self.grouping.len());
                 for group in &self.grouping {
-                    key.vals.push(group.expr.eval(ctx, row)?)
+                    vals.push(group.expr.eval(ctx, row)?)
                 }
+                let key = GroupKey::new(vals, &ctx.collation);
                 // Does an entry like that exist?
                 let maybe_state = self.group_aggregations.get_mut(&key);
                 if let None = maybe_state {
@@ -1468,6 +3102,37 @@ impl Operator for HashAggregation {
             self.aggregated = true;
         }
     }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.group_aggregations.clear();
+        self.group_order.clear();
+        self.consumed = false;
+        self.aggregated = false;
+    }
+}
+
+#[derive(Debug)]
+struct CountStore {
+    src: Box<dyn Operator>,
+    slot: usize,
+    count: CountOf,
+}
+
+impl Operator for CountStore {
+    fn next(&mut self, ctx: &mut Context, out: &mut GramRow) -> Result<bool> {
+        if !self.src.next(ctx, out)? {
+            return Ok(false);
+        }
+        ctx.db_hits += 1;
+        let count = ctx.g.borrow().count(self.count);
+        out.slots[self.slot] = GramVal::Lit(Val::Int(count as i64));
+        Ok(true)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+    }
 }
 
 #[derive(Debug)]
@@ -1475,58 +3140,136 @@ struct Unwind {
     src: Box<dyn Operator>,
     list_expr: Expr,
     dst: Slot,
-    // TODO this should use an iterator
-    current_list: Option<Vec<GramVal>>,
-    next_index: usize,
+    // What's left to unwind for the current src row
+    current: Option<Unwinding>,
+}
+
+#[derive(Debug)]
+enum Unwinding {
+    // The list, and the index of the next item
+    List(Rc<Vec<GramVal>>, usize),
+    Vals(Arc<[Val]>, usize),
+    // UNWIND range(..) counts through the range rather than building the list, so unwinding
+    // a range of millions doesn't first need memory for millions of values
+    Range(functions::Range),
 }
 
 impl Operator for Unwind {
     fn next(&mut self, ctx: &mut Context, row: &mut GramRow) -> Result<bool> {
         loop {
-            if self.current_list.is_none() {
-                let src = &mut *self.src;
-                if !src.next(ctx, row)? {
-                    return Ok(false);
+            let item = match &mut self.current {
+                None => {
+                    if !self.src.next(ctx, row)? {
+                        return Ok(false);
+                    }
+                    self.current = match &self.list_expr {
+                        Expr::Call(functions::Func::Range, args) => {
+                            let mut vals = Vec::with_capacity(args.len());
+                            for arg in args {
+                                vals.push(arg.eval(ctx, row)?);
+                            }
+                            Some(Unwinding::Range(functions::Range::new(&vals)?))
+                        }
+                        expr => match expr.eval(ctx, row)? {
+                            GramVal::List(items) => Some(Unwinding::List(items, 0)),
+                            GramVal::Lit(Val::List(items)) => Some(Unwinding::Vals(items, 0)),
+                            // Unwinding null gives no rows, like unwinding an empty list
+                            GramVal::Lit(Val::Null) => None,
+                            // And anything else that isn't a list is a list of one
+                            v => Some(Unwinding::List(Rc::new(vec![v]), 0)),
+                        },
+                    };
+                    continue;
                 }
-
-                match self.list_expr.eval(ctx, row)? {
-                    GramVal::List(items) => self.current_list = Some(items),
-                    _ => return Err(anyhow!("UNWIND expression must yield a list")),
+                Some(Unwinding::List(items, next)) => {
+                    items.get(*next).cloned().inspect(|_| *next += 1)
+                }
+                Some(Unwinding::Vals(items, next)) => items
+                    .get(*next)
+                    .cloned()
+                    .inspect(|_| *next += 1)
+                    .map(GramVal::Lit),
+                Some(Unwinding::Range(range)) => range.next().map(|i| GramVal::Lit(Val::Int(i))),
+            };
+            match item {
+                Some(v) => {
+                    row.slots[self.dst] = v;
+                    return Ok(true);
                 }
-                self.next_index = 0;
+                None => self.current = None,
             }
+        }
+    }
 
-            if let Some(it) = &mut self.current_list {
-                if self.next_index >= it.len() {
-                    self.current_list = None;
-                    continue;
-                }
+    fn reset(&mut self) {
+        self.src.reset();
+        self.current = None;
+    }
+}
 
-                row.slots[self.dst] = it[self.next_index].clone();
-                self.next_index += 1;
-                return Ok(true);
+#[derive(Debug)]
+struct Call {
+    src: Box<dyn Operator>,
+    procedure: procedures::Proc,
+    args: Vec<Expr>,
+    // Index of the output in the procedure signature, and slot to write it to
+    outputs: Vec<(usize, Slot)>,
+    // Rows the procedure produced for the current src row
+    rows: Vec<Vec<GramVal>>,
+    next_row: usize,
+}
+
+impl Operator for Call {
+    fn next(&mut self, ctx: &mut Context, row: &mut GramRow) -> Result<bool> {
+        while self.next_row >= self.rows.len() {
+            if !self.src.next(ctx, row)? {
+                return Ok(false);
+            }
+            let mut args = Vec::with_capacity(self.args.len());
+            for arg in &self.args {
+                args.push(arg.eval(ctx, row)?);
             }
+            self.rows = self.procedure.call(ctx, &args)?;
+            self.next_row = 0;
+        }
+
+        let out = &self.rows[self.next_row];
+        for (index, slot) in &self.outputs {
+            row.slots[*slot] = out[*index].clone();
         }
+        self.next_row += 1;
+        Ok(true)
+    }
+
+    fn reset(&mut self) {
+        self.src.reset();
+        self.rows.clear();
+        self.next_row = 0;
     }
 }
 
 mod parser {
-    use super::Val;
-    use crate::backend::gram::{Graph, Node};
+    #[cfg(feature = "gram-file")]
+    use super::{record_header, CorruptionError, FOLDED_HEADER, LOG_HEADER, RECORD_HEADER};
+    use crate::backend::gram::{Dictionary, Graph, Node, PropVal, Val};
     use crate::backend::{Token, Tokens};
+    use crate::frontend::Dir;
     use crate::pest::Parser;
     use anyhow::Result;
     use pest::iterators::Pair;
     use std::collections::HashMap;
-    use std::collections::HashSet;
+    #[cfg(feature = "gram-file")]
     use std::fs::File;
-    use std::io::Read;
+    #[cfg(feature = "gram-file")]
+    use std::io::{Read, Seek, SeekFrom};
+    use std::sync::Arc;
 
     #[derive(Parser)]
     #[grammar = "backend/gram.pest"]
     pub struct GramParser;
 
     /// Indicates how large a buffer to pre-allocate before reading the entire file.
+    #[cfg(feature = "gram-file")]
     fn initial_buffer_size(file: &File) -> usize {
         // Allocate one extra byte so the buffer doesn't need to grow before the
         // final `read` call at the end of the file.  Don't worry about `usize`
@@ -1534,9 +3277,11 @@ mod parser {
         file.metadata().map(|m| m.len() as usize + 1).unwrap_or(0)
     }
 
-    fn read_to_string(file: &mut File) -> Result<String> {
+    #[cfg(feature = "gram-file")]
+    pub fn read_to_string(file: &mut File) -> Result<String> {
         //todo lock this (and other io on the file)
         let mut string = String::with_capacity(initial_buffer_size(&file));
+        file.seek(SeekFrom::Start(0))?;
         file.read_to_string(&mut string)?;
         Ok(string)
     }
@@ -1545,55 +3290,282 @@ mod parser {
         anon_id_gen: u32,
         node_ids: Tokens,
         tokens: &'a mut Tokens,
+        // Of the file being loaded; each file has its own
+        dict: Dictionary,
+    }
+
+    // The string an `id` rule refers to, with backticks and escapes removed
+    fn parse_id(id: Pair<Rule>) -> String {
+        let inner = id.into_inner().next().unwrap();
+        match inner.as_rule() {
+            Rule::id_inner => unescape(inner.as_str()),
+            _ => inner.as_str().to_string(),
+        }
+    }
+
+    fn unescape(s: &str) -> String {
+        let mut out = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('b') => out.push('\u{8}'),
+                Some('f') => out.push('\u{c}'),
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            }
+        }
+        out
+    }
+
+    // A value as it's written in the gram file, before dictionary references are looked up
+    #[derive(Debug, PartialEq)]
+    enum RawVal {
+        Val(Val),
+        DictRef(usize),
+        Overflow { offset: u64, len: usize },
+        List(Vec<RawVal>),
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct RawNode {
+        identifier: Option<String>,
+        labels: Vec<String>,
+        props: Vec<(String, RawVal)>,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct RawRel {
+        dir: Dir,
+        rel_type: Option<String>,
+        props: Vec<(String, RawVal)>,
+    }
+
+    // The things a gram file is made of, parsed but not yet added to the graph. Parsing is most
+    // of the work of loading a file, and it doesn't need the graph or the tokens, so large files
+    // are parsed in parallel into these, which are then added to the graph in order; see
+    // load_gram.
+    #[derive(Debug, PartialEq)]
+    enum Item {
+        Node(RawNode),
+        // The nodes of the path, and the rels between them; rels[i] connects nodes[i] and
+        // nodes[i + 1]
+        Path(Vec<RawNode>, Vec<RawRel>),
+        DictEntry(usize, String),
+        // The identifier of a deleted node
+        Deletion(String),
+    }
+
+    fn parse_items(gram: &str) -> Result<Vec<Item>> {
+        let mut parse_result = GramParser::parse(Rule::gram, gram)?;
+        let gram = parse_result.next().unwrap(); // get and unwrap the `file` rule; never fails
+
+        let mut items = Vec::new();
+        for item in gram.into_inner() {
+            match item.as_rule() {
+                Rule::path => {
+                    let mut nodes = Vec::new();
+                    let mut rels = Vec::new();
+                    for part in item.into_inner() {
+                        match part.as_rule() {
+                            Rule::node => nodes.push(parse_node(part)?),
+                            Rule::rel => rels.push(parse_rel(part)?),
+                            _ => panic!("what? {:?} / {}", part.as_rule(), part.as_str()),
+                        }
+                    }
+                    items.push(Item::Path(nodes, rels))
+                }
+                Rule::node => items.push(Item::Node(parse_node(item)?)),
+                Rule::dict_entry => {
+                    let mut parts = item.into_inner();
+                    let index = dict_index(&parts.next().unwrap())?;
+                    let string = parts.next().unwrap().into_inner().next().unwrap();
+                    items.push(Item::DictEntry(index, unescape(string.as_str())))
+                }
+                Rule::deletion => {
+                    let id = item.into_inner().next().unwrap();
+                    items.push(Item::Deletion(parse_id(id)))
+                }
+                _ => (),
+            }
+        }
+        Ok(items)
+    }
+
+    fn parse_val(expr: Pair<Rule>) -> Result<RawVal> {
+        let mut parts = expr.into_inner();
+        let item = parts.next().unwrap();
+        if let Some(typ) = parts.next() {
+            return annotate(item, typ.as_str()).map(RawVal::Val);
+        }
+        match item.as_rule() {
+            Rule::dict_ref => Ok(RawVal::DictRef(dict_index(&item)?)),
+            Rule::overflow_ref => {
+                let (offset, len) = item.as_str()[1..].split_once(':').unwrap();
+                Ok(RawVal::Overflow {
+                    offset: offset.parse()?,
+                    len: len.parse()?,
+                })
+            }
+            Rule::list => Ok(RawVal::List(
+                item.into_inner().map(parse_val).collect::<Result<_>>()?,
+            )),
+            _ => parse_lit(item).map(RawVal::Val),
+        }
+    }
+
+    // The value of a literal annotated with its type, like 'NaN'::float or 1::string; it's an
+    // error if the literal doesn't spell a value of the type, like 'a'::int
+    fn annotate(item: Pair<Rule>, typ: &str) -> Result<Val> {
+        let text = match item.as_rule() {
+            Rule::string => unescape(item.clone().into_inner().next().unwrap().as_str()),
+            Rule::id => parse_id(item.clone()),
+            Rule::num => item.as_str().to_string(),
+            _ => bail!("{} can't be annotated with a type", item.as_str()),
+        };
+        let val = match typ {
+            "int" => text.parse().ok().map(Val::Int),
+            // Rust and gqlite agree on writing the floats that aren't numbers as NaN, inf and -inf
+            "float" => text.parse().ok().map(Val::Float),
+            "bool" => text.parse().ok().map(Val::Bool),
+            _ => Some(Val::String(text.into())),
+        };
+        match val {
+            Some(val) => Ok(val),
+            None => bail!("{} is not a valid {}", item.as_str(), typ),
+        }
     }
 
-    fn parse_node(item: Pair<Rule>, ctx: &mut ParserContext) -> Result<Node> {
-        let mut identifier: Option<String> = None;
-        let mut props: HashMap<Token, Val> = HashMap::new();
-        let mut labels: HashSet<Token> = HashSet::new();
+    fn parse_lit(item: Pair<Rule>) -> Result<Val> {
+        match item.as_rule() {
+            Rule::string => Ok(Val::String(
+                unescape(item.into_inner().next().unwrap().as_str()).into(),
+            )),
+            Rule::num => {
+                let s = item.as_str();
+                if s.contains(['.', 'e', 'E']) {
+                    Ok(Val::Float(s.parse()?))
+                } else {
+                    Ok(Val::Int(s.parse()?))
+                }
+            }
+            // Gram has no boolean or null literals, so those are written as bare identifiers
+            Rule::id => match parse_id(item).as_str() {
+                "true" => Ok(Val::Bool(true)),
+                "false" => Ok(Val::Bool(false)),
+                "null" => Ok(Val::Null),
+                other => Ok(Val::String(other.into())),
+            },
+            _ => bail!("what? {:?} / {}", item.as_rule(), item.as_str()),
+        }
+    }
+
+    fn parse_map(map: Pair<Rule>) -> Result<Vec<(String, RawVal)>> {
+        let mut props = Vec::new();
+        for pair in map.into_inner() {
+            let mut key: Option<String> = None;
+            let mut val = None;
+            for pair_part in pair.into_inner() {
+                match pair_part.as_rule() {
+                    Rule::id => key = Some(parse_id(pair_part)),
+                    Rule::expr => val = Some(parse_val(pair_part)?),
+                    _ => panic!("what? {:?} / {}", pair_part.as_rule(), pair_part.as_str()),
+                }
+            }
+            props.push((key.unwrap(), val.unwrap()));
+        }
+        Ok(props)
+    }
 
+    fn parse_node(item: Pair<Rule>) -> Result<RawNode> {
+        let mut node = RawNode {
+            identifier: None,
+            labels: Vec::new(),
+            props: Vec::new(),
+        };
         for part in item.into_inner() {
             match part.as_rule() {
-                Rule::id => identifier = Some(part.as_str().to_string()),
+                Rule::id => node.identifier = Some(parse_id(part)),
                 Rule::label => {
                     for label in part.into_inner() {
-                        labels.insert(ctx.tokens.tokenize(label.as_str()));
+                        node.labels.push(parse_id(label));
                     }
                 }
-                Rule::map => {
-                    for pair in part.into_inner() {
-                        let mut key: Option<String> = None;
-                        let mut val = None;
-                        match pair.as_rule() {
-                            Rule::map_pair => {
-                                for pair_part in pair.into_inner() {
-                                    match pair_part.as_rule() {
-                                        Rule::id => key = Some(pair_part.as_str().to_string()),
-                                        Rule::expr => {
-                                            let stringval = pair_part.into_inner().next().unwrap();
-                                            let stringval_content =
-                                                stringval.into_inner().next().unwrap();
-                                            val = Some(stringval_content.as_str().to_string())
-                                        }
-                                        _ => panic!(
-                                            "what? {:?} / {}",
-                                            pair_part.as_rule(),
-                                            pair_part.as_str()
-                                        ),
-                                    }
-                                }
-                            }
-                            _ => panic!("what? {:?} / {}", pair.as_rule(), pair.as_str()),
-                        }
-                        let key_str = key.unwrap();
-                        props.insert(ctx.tokens.tokenize(&key_str), Val::String(val.unwrap()));
-                    }
+                Rule::map => node.props = parse_map(part)?,
+                _ => panic!("what? {:?} / {}", part.as_rule(), part.as_str()),
+            }
+        }
+        Ok(node)
+    }
+
+    fn parse_rel(item: Pair<Rule>) -> Result<RawRel> {
+        let mut rel = RawRel {
+            dir: if item.as_str().starts_with('<') {
+                Dir::In
+            } else {
+                Dir::Out
+            },
+            rel_type: None,
+            props: Vec::new(),
+        };
+        for part in item.into_inner() {
+            match part.as_rule() {
+                Rule::id => (),
+                Rule::map => rel.props = parse_map(part)?,
+                Rule::rel_type => {
+                    rel.rel_type = Some(parse_id(part.into_inner().next().unwrap()));
                 }
                 _ => panic!("what? {:?} / {}", part.as_rule(), part.as_str()),
             }
         }
+        Ok(rel)
+    }
+
+    fn resolve_val(val: RawVal, ctx: &ParserContext) -> Result<PropVal> {
+        match val {
+            RawVal::Overflow { offset, len } => Ok(PropVal::Overflow { offset, len }),
+            val => resolve_lit(val, ctx).map(PropVal::Val),
+        }
+    }
+
+    fn resolve_lit(val: RawVal, ctx: &ParserContext) -> Result<Val> {
+        match val {
+            RawVal::Val(v) => Ok(v),
+            RawVal::DictRef(i) => match ctx.dict.values.get(i) {
+                Some(s) => Ok(Val::String(Arc::clone(s))),
+                None => bail!("@{} is not in the dictionary", i),
+            },
+            RawVal::List(items) => Ok(Val::List(
+                items
+                    .into_iter()
+                    .map(|v| resolve_lit(v, ctx))
+                    .collect::<Result<Vec<_>>>()?
+                    .into(),
+            )),
+            RawVal::Overflow { .. } => bail!("overflowed strings can't be in lists"),
+        }
+    }
+
+    fn resolve_props(
+        props: Vec<(String, RawVal)>,
+        ctx: &mut ParserContext,
+    ) -> Result<HashMap<Token, PropVal>> {
+        let mut out = HashMap::with_capacity(props.len());
+        for (k, v) in props {
+            let v = resolve_val(v, ctx)?;
+            out.insert(ctx.tokens.tokenize(&k), v);
+        }
+        Ok(out)
+    }
 
-        let gid_string = identifier.unwrap_or_else(|| {
+    fn resolve_node(node: RawNode, ctx: &mut ParserContext) -> Result<Node> {
+        let gid_string = node.identifier.unwrap_or_else(|| {
             // Entry with no identifier, generate one
             loop {
                 let candidate = format!("anon#{}", ctx.anon_id_gen);
@@ -1603,24 +3575,57 @@ mod parser {
                 ctx.anon_id_gen += 1;
             }
         });
+        let labels = node.labels.iter().map(|l| ctx.tokens.tokenize(l)).collect();
         let gid = ctx.tokens.tokenize(&gid_string);
         let id = ctx.node_ids.tokenize(&gid_string);
         Ok(Node {
             id,
             gid,
             labels,
-            properties: props,
+            properties: resolve_props(node.props, ctx)?,
             rels: vec![],
+            deleted: false,
+            added: 0,
         })
     }
 
-    pub fn load(tokens: &mut Tokens, file: &mut File) -> Result<Graph> {
-        let mut g = Graph { nodes: vec![] };
+    // The same node can show up many times in a gram file, eg. once with its labels and
+    // properties and then by identifier only in each path it is part of; merge all of them
+    fn merge_node(g: &mut Graph, n: Node) {
+        if g.nodes.len() <= n.id {
+            g.add_node(n.id, n);
+            return;
+        }
+        g.add_labels(n.id, n.labels);
+        g.nodes[n.id].properties.extend(n.properties);
+    }
 
-        let query_str = read_to_string(file).unwrap();
-        let mut parse_result = GramParser::parse(Rule::gram, &query_str)?;
+    fn dict_index(dict_ref: &Pair<Rule>) -> Result<usize> {
+        Ok(dict_ref.as_str()[1..].parse()?)
+    }
 
-        let gram = parse_result.next().unwrap(); // get and unwrap the `file` rule; never fails
+    // Load a graph from the contents of one or more gram files; later files can refer to the
+    // nodes in earlier ones, which is how the change log is replayed over the main gram file.
+    // Along with the graph comes the dictionary of the last file, which is the one that is
+    // appended to. Files are parsed on up to the given number of threads, see load_gram.
+    pub fn load(
+        tokens: &mut Tokens,
+        sources: &[&str],
+        threads: usize,
+    ) -> Result<(Graph, Dictionary)> {
+        let mut g = Graph {
+            nodes: vec![],
+            next_rel_id: 0,
+            free_node_ids: vec![],
+            free_rel_ids: vec![],
+            nodes_added: 0,
+            label_counts: HashMap::new(),
+            rel_type_counts: HashMap::new(),
+            indexes: Vec::new(),
+            deleted_nodes: 0,
+            deleted_rels: 0,
+            added: None,
+        };
 
         let node_ids = Tokens {
             table: Default::default(),
@@ -1630,109 +3635,406 @@ mod parser {
             anon_id_gen: 0,
             node_ids,
             tokens,
+            dict: Dictionary::default(),
         };
 
-        for item in gram.into_inner() {
-            match item.as_rule() {
-                Rule::path => {
-                    let mut start_identifier: Option<Token> = None;
-                    let mut end_identifier: Option<Token> = None;
-
-                    let mut rel_type = None;
+        for gram in sources {
+            pc.dict = Dictionary::default();
+            // Without the gram-file feature we can't check checksums, but records are comments
+            // so they load just the same
+            #[cfg(feature = "gram-file")]
+            verify_records(gram)?;
+            load_gram(&mut pc, &mut g, gram, threads)?;
+        }
 
-                    for part in item.into_inner() {
-                        match part.as_rule() {
-                            Rule::node => {
-                                let n = parse_node(part, &mut pc)?;
-                                if start_identifier == None {
-                                    start_identifier = Some(n.id);
-                                } else {
-                                    end_identifier = Some(n.id);
-                                }
-                                // need like a merge_node operation; but for now, insert the
-                                // first occurrence of a node, so subsequent ones won't clear out
-                                // properties
-                                if g.nodes.len() <= n.id {
-                                    g.add_node(n.id, n);
-                                }
-                            }
-                            Rule::rel => {
-                                for rel_part in part.into_inner() {
-                                    match rel_part.as_rule() {
-                                        Rule::map => {}
-                                        Rule::rel_type => {
-                                            let rt_id = rel_part.into_inner().next().unwrap();
-                                            rel_type = Some(pc.node_ids.tokenize(
-                                                rt_id.into_inner().next().unwrap().as_str(),
-                                            ));
-                                        }
-                                        _ => panic!(
-                                            "what? {:?} / {}",
-                                            rel_part.as_rule(),
-                                            rel_part.as_str()
-                                        ),
-                                    }
-                                }
-                            }
-                            _ => panic!("what? {:?} / {}", part.as_rule(), part.as_str()),
-                        }
-                    }
+        Ok((g, pc.dict))
+    }
 
-                    g.add_rel(
-                        start_identifier.unwrap(),
-                        end_identifier.unwrap(),
-                        rel_type.unwrap_or(pc.tokens.tokenize("_")),
-                    );
+    // Check the checksum and length of each record in the file, see frame_record. Headers are
+    // only looked for at the start of lines, and not in the records themselves, so gram with
+    // a string that has a header in it isn't taken for a record.
+    //
+    // A file may start out written by hand, with records appended after, and until there's a
+    // record to go by, a line that only looks like a header may be part of a string written
+    // over several lines; it's taken for gram. Once the file is known to be framed, because it
+    // starts with a record or one has checked out, every header has to check out too.
+    #[cfg(feature = "gram-file")]
+    fn verify_records(gram: &str) -> Result<()> {
+        let mut framed = gram
+            .lines()
+            .find(|l| !(l.is_empty() || l.starts_with(FOLDED_HEADER) || l.starts_with(LOG_HEADER)))
+            .is_some_and(|l| l.starts_with(RECORD_HEADER));
+        let mut at = 0;
+        while at < gram.len() {
+            let line_end = gram[at..].find('\n').map_or(gram.len(), |i| at + i + 1);
+            if !gram[at..line_end].starts_with(RECORD_HEADER) {
+                at = line_end;
+                continue;
+            }
+            match check_record(gram, at, line_end) {
+                Ok(body_end) => {
+                    framed = true;
+                    at = body_end;
                 }
-                Rule::node => {
-                    let n = parse_node(item, &mut pc)?;
-                    g.add_node(n.id, n)
+                Err(reason) if framed => {
+                    return Err(CorruptionError {
+                        offset: at,
+                        reason: reason.to_string(),
+                    }
+                    .into())
                 }
-                _ => (),
+                Err(_) => at = line_end,
             }
         }
+        Ok(())
+    }
 
-        Ok(g)
+    // Check the record with its header on the line from start to line_end, giving back where
+    // it ends, or what's wrong with it
+    #[cfg(feature = "gram-file")]
+    fn check_record(gram: &str, start: usize, line_end: usize) -> Result<usize, &'static str> {
+        if !gram[start..line_end].ends_with('\n') {
+            return Err("record header is truncated");
+        }
+        let (checksum, len) =
+            record_header(&gram[start..line_end]).ok_or("record header is malformed")?;
+        let body_end = line_end + len;
+        let body = match gram.as_bytes().get(line_end..body_end) {
+            Some(body) if gram.is_char_boundary(body_end) => body,
+            _ => return Err("record is truncated"),
+        };
+        if crc32fast::hash(body) != checksum {
+            return Err("record checksum does not match its contents");
+        }
+        Ok(body_end)
     }
-}
 
-#[derive(Debug)]
-pub struct Node {
-    // Identifier for this node, matches it's index into the node vector in g
-    id: usize,
-    // Identifier assigned this node in the gram file
-    gid: Token,
-    labels: HashSet<Token>,
-    properties: HashMap<Token, Val>,
-    rels: Vec<RelHalf>,
+    // Files smaller than this are parsed on the calling thread; starting threads isn't worth it
+    const PARALLEL_PARSE_MIN: usize = 4 * 1024 * 1024;
+
+    // Parse a gram file into the graph, split into as many chunks as there are threads to parse
+    // them on, see DatabaseConfig::threads
+    fn load_gram(pc: &mut ParserContext, g: &mut Graph, gram: &str, threads: usize) -> Result<()> {
+        let chunks = if threads > 1 && gram.len() >= PARALLEL_PARSE_MIN {
+            split(gram, threads)
+        } else {
+            vec![gram]
+        };
+        for items in parse_chunks(gram, &chunks)? {
+            add_items(pc, g, items)?;
+        }
+        Ok(())
+    }
+
+    // Parse each chunk on a thread of its own. A chunk that starts or ends in the middle of an
+    // item fails to parse - say a path written over two lines - and then the file is parsed in
+    // one go instead.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn parse_chunks(gram: &str, chunks: &[&str]) -> Result<Vec<Vec<Item>>> {
+        if chunks.len() == 1 {
+            return Ok(vec![parse_items(gram)?]);
+        }
+        let parsed: Vec<Result<Vec<Item>>> = std::thread::scope(|s| {
+            let workers: Vec<_> = chunks
+                .iter()
+                .map(|chunk| s.spawn(move || parse_items(chunk)))
+                .collect();
+            workers
+                .into_iter()
+                .map(|w| w.join().expect("gram parser thread panicked"))
+                .collect()
+        });
+        match parsed.into_iter().collect() {
+            Ok(items) => Ok(items),
+            // Errors point at lines and columns in the chunk, so parse it all again for one that
+            // points into the file
+            Err(_) => Ok(vec![parse_items(gram)?]),
+        }
+    }
+
+    // No threads on wasm32
+    #[cfg(target_arch = "wasm32")]
+    fn parse_chunks(gram: &str, _chunks: &[&str]) -> Result<Vec<Vec<Item>>> {
+        Ok(vec![parse_items(gram)?])
+    }
+
+    // Split a gram file into about n chunks of about the same size, each of them valid gram on
+    // its own. Chunks end at line breaks, which separate the items gqlite writes, but not at
+    // ones inside strings, identifiers or comments, since those can have anything in them.
+    fn split(gram: &str, n: usize) -> Vec<&str> {
+        #[derive(PartialEq)]
+        enum In {
+            Code,
+            Quoted(u8),
+            Comment,
+        }
+        let target = gram.len() / n.max(1) + 1;
+        let bytes = gram.as_bytes();
+        let mut chunks = Vec::with_capacity(n);
+        let mut start = 0;
+        let mut state = In::Code;
+        let mut i = 0;
+        while i < bytes.len() {
+            let b = bytes[i];
+            match state {
+                In::Code => match b {
+                    b'\'' | b'"' | b'`' => state = In::Quoted(b),
+                    b'/' if bytes.get(i + 1) == Some(&b'/') => state = In::Comment,
+                    b'\n' if i + 1 - start >= target => {
+                        chunks.push(&gram[start..=i]);
+                        start = i + 1;
+                    }
+                    _ => (),
+                },
+                // Skip whatever is escaped, it may be the quote
+                In::Quoted(_) if b == b'\\' => i += 1,
+                In::Quoted(quote) if b == quote => state = In::Code,
+                In::Quoted(_) => (),
+                In::Comment if b == b'\n' => {
+                    state = In::Code;
+                    // Let the line break end the chunk
+                    continue;
+                }
+                In::Comment => (),
+            }
+            i += 1;
+        }
+        if start < gram.len() || chunks.is_empty() {
+            chunks.push(&gram[start..]);
+        }
+        chunks
+    }
+
+    fn add_items(pc: &mut ParserContext, g: &mut Graph, items: Vec<Item>) -> Result<()> {
+        for item in items {
+            match item {
+                Item::Path(nodes, rels) => {
+                    // Each rel connects the nodes on either side of it in the path
+                    let mut prev_node: Option<usize> = None;
+                    let mut rels = rels.into_iter();
+                    for node in nodes {
+                        let n = resolve_node(node, pc)?;
+                        let id = n.id;
+                        merge_node(g, n);
+                        if let Some(prev) = prev_node {
+                            let rel = rels.next().expect("there's a rel between each two nodes");
+                            let rel_type =
+                                pc.tokens.tokenize(rel.rel_type.as_deref().unwrap_or("_"));
+                            let props = resolve_props(rel.props, pc)?;
+                            match rel.dir {
+                                Dir::Out => g.add_rel(prev, id, rel_type, props),
+                                Dir::In => g.add_rel(id, prev, rel_type, props),
+                            };
+                        }
+                        prev_node = Some(id);
+                    }
+                }
+                Item::Node(node) => {
+                    let n = resolve_node(node, pc)?;
+                    merge_node(g, n)
+                }
+                Item::DictEntry(index, string) => {
+                    // Entries are numbered in the order they are written, which is the only
+                    // order they can be referred to in
+                    if index != pc.dict.values.len() {
+                        bail!("dictionary entry @{} is out of order", index)
+                    }
+                    pc.dict.define(string.into());
+                }
+                Item::Deletion(gid) => match pc.node_ids.table.get(&gid) {
+                    Some(id) if !g.nodes[*id].deleted => {
+                        g.delete_node(*id);
+                    }
+                    _ => bail!("deleted node `{}` is not in the graph", gid),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        #[cfg(feature = "gram-file")]
+        fn verifies_records_but_not_record_headers_in_strings() {
+            use super::super::frame_record;
+
+            // Written by hand, on one line and at the start of one
+            let by_hand = "(a {s: 'x //#crc32 0 5'})\n(b {s: 'x\n//#crc32 deadbeef 3\nabc'})\n";
+            assert!(verify_records(by_hand).is_ok());
+            // .. and with records appended to it
+            let record = frame_record("(c {s: 'y\n//#crc32 deadbeef 1\n'})\n");
+            let appended = format!("{}\n{}", by_hand, record);
+            assert!(verify_records(&appended).is_ok());
+
+            // Once there are records, headers have to check out, but not ones in records
+            let framed = format!("\n{}\n{}", record, record);
+            assert!(verify_records(&framed).is_ok());
+            let damaged = framed.replacen("'y", "'z", 1);
+            assert!(verify_records(&damaged).is_err());
+            let damaged = format!("{}\n//#crc32 deadbeef 1\n(d)\n", appended);
+            let err = verify_records(&damaged).unwrap_err();
+            let corruption = err.downcast_ref::<CorruptionError>().unwrap();
+            assert_eq!(corruption.offset, appended.len() + 1);
+        }
+
+        #[test]
+        fn splits_between_items() {
+            let gram = "(a {s: 'x\n(b)'})\n(`c\n`)\n// (d) 'e\n(f {s: \"\\\"\n\"})\n(g)\n";
+            assert_eq!(
+                split(gram, 100),
+                vec![
+                    "(a {s: 'x\n(b)'})\n",
+                    "(`c\n`)\n",
+                    "// (d) 'e\n",
+                    "(f {s: \"\\\"\n\"})\n",
+                    "(g)\n"
+                ]
+            );
+            assert_eq!(split(gram, 1), vec![gram]);
+            assert_eq!(split("", 4), vec![""]);
+        }
+
+        #[test]
+        fn parses_chunks_like_the_whole_file() -> Result<()> {
+            let mut gram = String::from("@0 = 'Sweden'\n");
+            for i in 0..100 {
+                gram.push_str(&format!(
+                    "(`n{}`:Person {{i: {}, country: @0, note: 'a\n(`x`)\n// b'}})\n",
+                    i, i
+                ));
+                gram.push_str(&format!("(`n{}`)-[:NEXT {{w: [1, 2]}}]->(`n0`)\n", i));
+            }
+            let whole = parse_items(&gram)?;
+            let chunks = split(&gram, 8);
+            assert_eq!(chunks.len(), 8);
+            let chunked = parse_chunks(&gram, &chunks)?;
+            assert_eq!(chunked.len(), 8);
+            assert_eq!(chunked.into_iter().flatten().collect::<Vec<_>>(), whole);
+
+            // Items written over several lines can't be parsed in chunks, so they're parsed in
+            // one go
+            gram.push_str("(`n0`)\n-[:LAST]->\n(`n99`)\n");
+            let cut = gram.find("-[:LAST]").unwrap();
+            let chunked = parse_chunks(&gram, &[&gram[..cut], &gram[cut..]])?;
+            assert_eq!(chunked.len(), 1);
+            assert_eq!(chunked[0].len(), whole.len() + 1);
+
+            // Errors point into the file, not the chunk
+            gram.push_str("(oops");
+            let err = parse_chunks(&gram, &split(&gram, 8)).unwrap_err();
+            assert!(err.to_string().contains("--> 405:"), "{}", err);
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Node {
+    // Identifier for this node, matches it's index into the node vector in g
+    id: usize,
+    // Identifier assigned this node in the gram file
+    gid: Token,
+    labels: HashSet<Token>,
+    properties: HashMap<Token, PropVal>,
+    rels: Vec<RelHalf>,
+    // Set once the node is deleted, see Graph::delete_node
+    deleted: bool,
+    // How many nodes were added to the graph before this one, set by Graph::add_node
+    added: u64,
 }
 
 #[derive(Debug)]
 pub struct RelHalf {
+    // Both halves of a rel have the same id
+    id: usize,
     rel_type: Token,
     dir: Dir,
     other_node: usize,
-    properties: Rc<HashMap<Token, Val>>,
+    // Index of the other half of this rel, in the rels of other_node
+    other_index: usize,
+    properties: Rc<HashMap<Token, PropVal>>,
 }
 
 #[derive(Debug)]
 pub struct Graph {
     nodes: Vec<Node>,
+    // Rels are numbered in the order they were added, since unlike nodes there's no list of
+    // them to give them their position in
+    next_rel_id: usize,
+    // Ids of deleted nodes and rels, for new ones to take before nodes and next_rel_id grow, so
+    // a graph with lots of churn doesn't grow without bound; see new_node_id and add_rel. Deleted
+    // nodes stay in nodes, marked deleted, until their id is taken. These ids only live in
+    // memory - the gram file identifies nodes by their gid - so the lists are kept by replaying
+    // the deletions in the file on load, and start out empty for a compacted file, which has no
+    // deleted nodes in it to begin with.
+    free_node_ids: Vec<usize>,
+    free_rel_ids: Vec<usize>,
+    // How many nodes have been added, see Node::added
+    nodes_added: u64,
+    // Nodes per label and rels per type, kept up to date as they're added so count() doesn't
+    // have to go through them all; see LogicalPlan::CountStore. Like the rest of the graph,
+    // these aren't rolled back when a query fails, so they always agree with a scan.
+    label_counts: HashMap<Token, usize>,
+    rel_type_counts: HashMap<Token, usize>,
+    // Kept up to date as nodes are added, see Graph::create_index. Shared with the queries
+    // seeking them, so a query that's running when an index is dropped can finish with it.
+    indexes: Vec<Rc<RefCell<PropertyIndex>>>,
+    // How many of the nodes and rels that were added have since been deleted, see delete_node
+    deleted_nodes: usize,
+    deleted_rels: usize,
+    // While a batch runs, what it added, in order, so a batch that fails can be taken out of
+    // the graph again; see GramBackend::abort_batch
+    added: Option<Vec<Added>>,
+}
+
+// A node or rel added to the graph during a batch
+#[derive(Debug)]
+enum Added {
+    Node(usize),
+    Rel { from: usize, to: usize },
 }
 
 impl Graph {
-    fn get_node_prop(&self, node_id: usize, prop: Token) -> Option<Val> {
+    fn get_node_prop(&self, node_id: usize, prop: Token) -> Option<PropVal> {
         self.nodes[node_id].properties.get(&prop).cloned()
     }
 
-    fn get_rel_prop(&self, node_id: usize, rel_index: usize, prop: Token) -> Option<Val> {
+    fn get_rel_prop(&self, node_id: usize, rel_index: usize, prop: Token) -> Option<PropVal> {
         self.nodes[node_id].rels[rel_index]
             .properties
             .get(&prop)
             .cloned()
     }
 
-    fn add_node(&mut self, id: usize, n: Node) {
+    fn count(&self, count: CountOf) -> usize {
+        match count {
+            CountOf::Nodes { label: None } => self.nodes.len() - self.deleted_nodes,
+            CountOf::Nodes { label: Some(l) } => *self.label_counts.get(&l).unwrap_or(&0),
+            CountOf::Rels { rel_type: None } => self.next_rel_id - self.deleted_rels,
+            CountOf::Rels { rel_type: Some(t) } => *self.rel_type_counts.get(&t).unwrap_or(&0),
+        }
+    }
+
+    fn add_labels(&mut self, id: usize, labels: impl IntoIterator<Item = Token>) {
+        for l in labels {
+            if self.nodes[id].labels.insert(l) {
+                *self.label_counts.entry(l).or_default() += 1;
+            }
+        }
+    }
+
+    // The id for a new node: a deleted node's, if there is one, see free_node_ids
+    fn new_node_id(&self) -> usize {
+        self.free_node_ids
+            .last()
+            .copied()
+            .unwrap_or(self.nodes.len())
+    }
+
+    fn add_node(&mut self, id: usize, mut n: Node) {
         while self.nodes.len() <= id {
             let filler_id = self.nodes.len();
             self.nodes.push(Node {
@@ -1741,32 +4043,566 @@ impl Graph {
                 labels: Default::default(),
                 properties: Default::default(),
                 rels: vec![],
+                deleted: false,
+                added: 0,
             })
         }
+        if self.nodes[id].deleted {
+            self.free_node_ids.retain(|&free| free != id);
+            self.deleted_nodes -= 1;
+        }
+        let labels = std::mem::take(&mut n.labels);
+        for l in &self.nodes[id].labels {
+            *self.label_counts.get_mut(l).unwrap() -= 1;
+        }
         self.nodes[id] = n;
+        self.nodes[id].added = self.nodes_added;
+        self.nodes_added += 1;
+        self.add_labels(id, labels);
+        if let Some(added) = &mut self.added {
+            added.push(Added::Node(id));
+        }
+        let node = &self.nodes[id];
+        for index in &self.indexes {
+            let mut index = index.borrow_mut();
+            if let Some(v) = node.properties.get(&index.property) {
+                if node.labels.contains(&index.label) {
+                    index.insert(id, v);
+                }
+            }
+        }
+    }
+
+    // Index the values of a property of the nodes with a label, from here on. Indexes only live
+    // in memory, so they're built again from the graph each time the database is opened.
+    //
+    // The index is built in full before it's added to the graph, so neither queries nor
+    // describe() ever see one that's half done.
+    //
+    // TODO: Like label_counts, this only keeps up with nodes being added, which is the only
+    //       change to nodes there is for now; merge_node adds labels and properties to nodes
+    //       while loading, before there are any indexes. SET and REMOVE will need to update
+    //       indexes as well.
+    //
+    // Strings are kept by their keys in the given collation; own_collation is for an index that
+    // was given it, rather than having the one the database has, see recollate_indexes.
+    fn create_index(
+        &mut self,
+        label: Token,
+        property: Token,
+        collation: Collation,
+        own_collation: bool,
+    ) {
+        if self.index(label, property).is_some() {
+            return;
+        }
+        let index = self.build_index(label, property, collation, own_collation);
+        self.indexes.push(Rc::new(RefCell::new(index)));
+    }
+
+    fn build_index(
+        &self,
+        label: Token,
+        property: Token,
+        collation: Collation,
+        own_collation: bool,
+    ) -> PropertyIndex {
+        let mut index = PropertyIndex {
+            label,
+            property,
+            collation,
+            own_collation,
+            numbers: BTreeMap::new(),
+            strings: BTreeMap::new(),
+            others: Vec::new(),
+            entries: 0,
+            built_at: now_millis(),
+            rebuilds: 0,
+        };
+        for node in &self.nodes {
+            if let Some(v) = node.properties.get(&property) {
+                if node.labels.contains(&label) {
+                    index.insert(node.id, v);
+                }
+            }
+        }
+        index
+    }
+
+    // Build the indexes that collate like the database again, now that it collates by another
+    // collation. The new ones replace the old ones, so queries already seeking the old ones
+    // finish with them, like with drop_index.
+    fn recollate_indexes(&mut self, collation: &Collation) {
+        for i in 0..self.indexes.len() {
+            let (label, property, rebuilds) = {
+                let index = self.indexes[i].borrow();
+                if index.own_collation || index.collation == *collation {
+                    continue;
+                }
+                (index.label, index.property, index.rebuilds)
+            };
+            let mut index = self.build_index(label, property, collation.clone(), false);
+            index.rebuilds = rebuilds + 1;
+            self.indexes[i] = Rc::new(RefCell::new(index));
+        }
+    }
+
+    // Build an index again from the nodes, in case it's gone stale; the new one replaces the old
+    // one, like in recollate_indexes. Tells if there was such an index.
+    fn rebuild_index(&mut self, label: Token, property: Token) -> bool {
+        let i = match self.indexes.iter().position(|i| {
+            let i = i.borrow();
+            i.label == label && i.property == property
+        }) {
+            Some(i) => i,
+            None => return false,
+        };
+        let (collation, own_collation, rebuilds) = {
+            let index = self.indexes[i].borrow();
+            (index.collation.clone(), index.own_collation, index.rebuilds)
+        };
+        let mut index = self.build_index(label, property, collation, own_collation);
+        index.rebuilds = rebuilds + 1;
+        self.indexes[i] = Rc::new(RefCell::new(index));
+        true
+    }
+
+    // Stop keeping an index; tells if there was one to drop
+    fn drop_index(&mut self, label: Token, property: Token) -> bool {
+        let before = self.indexes.len();
+        self.indexes.retain(|i| {
+            let i = i.borrow();
+            i.label != label || i.property != property
+        });
+        self.indexes.len() < before
+    }
+
+    fn index(&self, label: Token, property: Token) -> Option<&Rc<RefCell<PropertyIndex>>> {
+        self.indexes.iter().find(|i| {
+            let i = i.borrow();
+            i.label == label && i.property == property
+        })
     }
 
     // Add a rel, return the index of the rel from the start nodes perspective
-    fn add_rel(&mut self, from: usize, to: usize, rel_type: Token) -> usize {
-        let props = Rc::new(Default::default());
-        let fromrels = &mut self.nodes[from].rels;
-        fromrels.push(RelHalf {
+    fn add_rel(
+        &mut self,
+        from: usize,
+        to: usize,
+        rel_type: Token,
+        props: HashMap<Token, PropVal>,
+    ) -> usize {
+        let props = Rc::new(props);
+        let id = match self.free_rel_ids.pop() {
+            Some(id) => {
+                self.deleted_rels -= 1;
+                id
+            }
+            None => {
+                self.next_rel_id += 1;
+                self.next_rel_id - 1
+            }
+        };
+        *self.rel_type_counts.entry(rel_type).or_default() += 1;
+        let index = self.nodes[from].rels.len();
+        // For a rel from a node to itself, both halves go on the same node
+        let other_index = if from == to {
+            index + 1
+        } else {
+            self.nodes[to].rels.len()
+        };
+        self.nodes[from].rels.push(RelHalf {
+            id,
             rel_type,
             dir: Dir::Out,
             other_node: to,
+            other_index,
             properties: Rc::clone(&props),
         });
-        let index = fromrels.len() - 1;
         self.nodes[to].rels.push(RelHalf {
+            id,
             rel_type,
             dir: Dir::In,
             other_node: from,
+            other_index: index,
             properties: props,
         });
+        if let Some(added) = &mut self.added {
+            added.push(Added::Rel { from, to });
+        }
         return index;
     }
+
+    // Take out what was added since added was set, latest first, so each rel's halves are the
+    // last rels on their nodes, and each node that didn't take a free id is the last node, when
+    // their turn comes; nothing is deleted during a batch. Ids go back to where they came from.
+    fn remove_added(&mut self) {
+        let added = self.added.take().unwrap_or_default();
+        for a in added.into_iter().rev() {
+            match a {
+                Added::Rel { from, to } => {
+                    let half = self.nodes[from].rels.pop().unwrap();
+                    self.nodes[to].rels.pop();
+                    *self.rel_type_counts.get_mut(&half.rel_type).unwrap() -= 1;
+                    if half.id + 1 == self.next_rel_id {
+                        self.next_rel_id -= 1;
+                    } else {
+                        self.free_rel_ids.push(half.id);
+                        self.deleted_rels += 1;
+                    }
+                }
+                Added::Node(id) => {
+                    self.delete_node(id);
+                    if id + 1 == self.nodes.len() {
+                        self.nodes.pop();
+                        self.free_node_ids.pop();
+                        self.deleted_nodes -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    // Delete a node along with its rels; gives back how many rels that was. The node stays in
+    // nodes, marked deleted and with nothing left on it, so the ids of the nodes after it don't
+    // change; scans skip it, and compaction leaves it out of the file. Its id goes on the
+    // free-list, for a new node to take.
+    //
+    // Rels are addressed by their position among the rels of their node, see GramVal::Rel, so
+    // this must not happen while a query is running that may have rels of this node, or of the
+    // nodes at the other end of them, in its rows.
+    fn delete_node(&mut self, id: usize) -> usize {
+        let mut rels = std::mem::take(&mut self.nodes[id].rels);
+        let mut deleted_rels = 0;
+        for i in 0..rels.len() {
+            let (other_node, other_index) = (rels[i].other_node, rels[i].other_index);
+            // Both halves of a rel from the node to itself are in rels, count it once
+            if other_node == id {
+                if rels[i].dir == Dir::Out {
+                    deleted_rels += 1;
+                    *self.rel_type_counts.get_mut(&rels[i].rel_type).unwrap() -= 1;
+                    self.free_rel_ids.push(rels[i].id);
+                }
+                continue;
+            }
+            deleted_rels += 1;
+            *self.rel_type_counts.get_mut(&rels[i].rel_type).unwrap() -= 1;
+            self.free_rel_ids.push(rels[i].id);
+            // The last rel of the other node takes the place of the removed half, so the other
+            // half of that one needs to know where it went
+            let others = &mut self.nodes[other_node].rels;
+            others.swap_remove(other_index);
+            if let Some(moved) = others.get(other_index) {
+                let (node, index) = (moved.other_node, moved.other_index);
+                if node == id {
+                    rels[index].other_index = other_index;
+                } else {
+                    self.nodes[node].rels[index].other_index = other_index;
+                }
+            }
+        }
+
+        let node = &mut self.nodes[id];
+        let labels = std::mem::take(&mut node.labels);
+        let properties = std::mem::take(&mut node.properties);
+        node.deleted = true;
+        for l in &labels {
+            *self.label_counts.get_mut(l).unwrap() -= 1;
+        }
+        for index in &self.indexes {
+            let mut index = index.borrow_mut();
+            if let Some(v) = properties.get(&index.property) {
+                if labels.contains(&index.label) {
+                    index.remove(id, v);
+                }
+            }
+        }
+        self.deleted_nodes += 1;
+        self.deleted_rels += deleted_rels;
+        self.free_node_ids.push(id);
+        deleted_rels
+    }
+
+    // Move the large values of every node and rel out to the overflow file
+    #[cfg(feature = "gram-file")]
+    fn spill(&mut self, file: &mut GramFile) -> Result<()> {
+        for id in 0..self.nodes.len() {
+            file.spill(&mut self.nodes[id].properties)?;
+            for index in 0..self.nodes[id].rels.len() {
+                let rel = &self.nodes[id].rels[index];
+                if !matches!(rel.dir, Dir::Out) || !rel.properties.values().any(PropVal::overflows)
+                {
+                    continue;
+                }
+                // Both halves share their properties, so they both get the spilled copy
+                let mut props = (*rel.properties).clone();
+                file.spill(&mut props)?;
+                let props = Rc::new(props);
+                let (other_node, other_index) = (rel.other_node, rel.other_index);
+                self.nodes[other_node].rels[other_index].properties = Rc::clone(&props);
+                self.nodes[id].rels[index].properties = props;
+            }
+        }
+        Ok(())
+    }
+}
+
+// An ordered index on the values of a property of the nodes with a label. Numbers and strings are
+// kept in order; everything else, like lists and the strings in the overflow file, goes in
+// others, which every seek gives in full.
+#[derive(Debug)]
+struct PropertyIndex {
+    label: Token,
+    property: Token,
+    // Strings are kept by their keys in this, see collation.rs
+    collation: Collation,
+    // Set if the index was created with a collation of its own, rather than the database's
+    own_collation: bool,
+    numbers: BTreeMap<NumKey, Vec<usize>>,
+    strings: BTreeMap<Arc<str>, Vec<usize>>,
+    others: Vec<usize>,
+    entries: usize,
+    // See IndexHealth
+    built_at: Option<i64>,
+    rebuilds: u64,
 }
 
+impl PropertyIndex {
+    fn insert(&mut self, id: usize, v: &PropVal) {
+        match v {
+            PropVal::Val(Val::Null) => return,
+            PropVal::Val(Val::Int(i)) => self
+                .numbers
+                .entry(NumKey::new(*i as f64))
+                .or_default()
+                .push(id),
+            PropVal::Val(Val::Float(f)) => {
+                self.numbers.entry(NumKey::new(*f)).or_default().push(id)
+            }
+            PropVal::Val(Val::String(s)) => {
+                let key = self.key(s);
+                self.strings.entry(key).or_default().push(id)
+            }
+            _ => self.others.push(id),
+        }
+        self.entries += 1;
+    }
+
+    // What a string is kept by
+    fn key(&self, s: &Arc<str>) -> Arc<str> {
+        match self.collation.key(s) {
+            Cow::Borrowed(_) => Arc::clone(s),
+            Cow::Owned(key) => key.into(),
+        }
+    }
+
+    // Take a node back out, given the value it was inserted with
+    fn remove(&mut self, id: usize, v: &PropVal) {
+        fn remove_from<K: Ord>(map: &mut BTreeMap<K, Vec<usize>>, key: K, id: usize) -> bool {
+            let ids = match map.get_mut(&key) {
+                Some(ids) => ids,
+                None => return false,
+            };
+            let before = ids.len();
+            ids.retain(|i| *i != id);
+            let removed = ids.len() < before;
+            if ids.is_empty() {
+                map.remove(&key);
+            }
+            removed
+        }
+        let removed = match v {
+            PropVal::Val(Val::Null) => false,
+            PropVal::Val(Val::Int(i)) => remove_from(&mut self.numbers, NumKey::new(*i as f64), id),
+            PropVal::Val(Val::Float(f)) => remove_from(&mut self.numbers, NumKey::new(*f), id),
+            PropVal::Val(Val::String(s)) => {
+                let key = self.key(s);
+                remove_from(&mut self.strings, key, id)
+            }
+            _ => {
+                let before = self.others.len();
+                self.others.retain(|i| *i != id);
+                self.others.len() < before
+            }
+        };
+        if removed {
+            self.entries -= 1;
+        }
+    }
+
+    // Add the nodes that may have a value between lower and upper to out. Comparisons order
+    // strings before numbers, so a string lower bound lets all numbers through, and a number
+    // upper bound all strings. Bounds that are neither, like null, don't narrow anything down.
+    fn seek(&self, lower: Option<&Val>, upper: Option<&Val>, out: &mut Vec<usize>) {
+        use std::ops::Bound::{Included, Unbounded};
+        let mut numbers = Some((Unbounded, Unbounded));
+        let mut strings = Some((Unbounded, Unbounded));
+        match lower {
+            Some(Val::Int(i)) => {
+                numbers = numbers.map(|(_, hi)| (Included(NumKey::new(*i as f64)), hi));
+                strings = None;
+            }
+            Some(Val::Float(f)) => {
+                numbers = numbers.map(|(_, hi)| (Included(NumKey::new(*f)), hi));
+                strings = None;
+            }
+            Some(Val::String(s)) => strings = strings.map(|(_, hi)| (Included(self.key(s)), hi)),
+            _ => (),
+        }
+        match upper {
+            Some(Val::Int(i)) => {
+                numbers = numbers.map(|(lo, _)| (lo, Included(NumKey::new(*i as f64))))
+            }
+            Some(Val::Float(f)) => numbers = numbers.map(|(lo, _)| (lo, Included(NumKey::new(*f)))),
+            Some(Val::String(s)) => {
+                strings = strings.map(|(lo, _)| (lo, Included(self.key(s))));
+                numbers = None;
+            }
+            _ => (),
+        }
+        // BTreeMap::range panics on a range that ends before it starts
+        fn ordered<T: Ord>(range: &(std::ops::Bound<T>, std::ops::Bound<T>)) -> bool {
+            match range {
+                (Included(lo), Included(hi)) => lo <= hi,
+                _ => true,
+            }
+        }
+        if let Some(range) = strings.filter(ordered) {
+            self.strings
+                .range(range)
+                .for_each(|(_, ids)| out.extend(ids));
+        }
+        if let Some(range) = numbers.filter(ordered) {
+            self.numbers
+                .range(range)
+                .for_each(|(_, ids)| out.extend(ids));
+        }
+        out.extend(&self.others);
+    }
+
+    fn stats(&self) -> IndexStats {
+        IndexStats {
+            label: self.label,
+            property: self.property,
+            entries: self.entries as u64,
+            distinct: (self.numbers.len() + self.strings.len() + self.others.len()) as u64,
+        }
+    }
+}
+
+// A number as indexes keep it: ints and floats in one order, like comparisons see them. Big ints
+// lose precision as floats, which is one reason seeks include the ends of their ranges.
+#[derive(Debug, Clone, Copy)]
+struct NumKey(f64);
+
+impl NumKey {
+    fn new(v: f64) -> NumKey {
+        // -0.0 = 0.0, so they go under one key
+        NumKey(if v == 0.0 { 0.0 } else { v })
+    }
+}
+
+impl PartialEq for NumKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for NumKey {}
+
+impl PartialOrd for NumKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NumKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// A property value as the graph holds it
+#[derive(Debug, Clone, PartialEq)]
+enum PropVal {
+    Val(Val),
+    // A string too large to keep in the gram file, and in memory, that lives in the overflow
+    // file instead; it's read from there when it's asked for. That keeps loading and scanning a
+    // graph with the odd big document in it from going through all those bytes. Written in gram
+    // as #<offset>:<len>, in bytes into the overflow file.
+    Overflow { offset: u64, len: usize },
+}
+
+// Strings longer than this, in bytes, go in the overflow file, if there is one
+#[cfg(feature = "gram-file")]
+const OVERFLOW_THRESHOLD: usize = 4096;
+
+// The file large values overflow to, see PropVal::Overflow
+#[cfg(feature = "gram-file")]
+#[derive(Debug)]
+pub enum OverflowFile {
+    Open(File),
+    // Not there yet; it's created here when the first large value is written, so a database
+    // that never has one doesn't get an empty overflow file next to it
+    At(PathBuf),
+}
+
+#[cfg(feature = "gram-file")]
+impl OverflowFile {
+    // The file, which is created if it isn't there yet
+    fn file(&mut self) -> Result<&mut File> {
+        if let OverflowFile::At(path) = self {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            *self = OverflowFile::Open(file);
+        }
+        match self {
+            OverflowFile::Open(file) => Ok(file),
+            OverflowFile::At(_) => unreachable!(),
+        }
+    }
+}
+
+impl PropVal {
+    fn read(&self, storage: &RefCell<Storage>) -> Result<Val> {
+        match self {
+            PropVal::Val(v) => Ok(v.clone()),
+            PropVal::Overflow { offset, len } => storage.borrow_mut().read_overflow(*offset, *len),
+        }
+    }
+
+    // A property that isn't there is null
+    fn read_opt(prop: Option<PropVal>, storage: &RefCell<Storage>) -> Result<Val> {
+        match prop {
+            Some(p) => p.read(storage),
+            None => Ok(Val::Null),
+        }
+    }
+
+    // Should this go in the overflow file?
+    #[cfg(feature = "gram-file")]
+    fn overflows(&self) -> bool {
+        matches!(self, PropVal::Val(Val::String(s)) if s.len() > OVERFLOW_THRESHOLD)
+    }
+}
+
+#[cfg(feature = "gram-file")]
+fn new_gram_identifier(_id: usize) -> String {
+    generate_uuid().to_hyphenated().to_string()
+}
+
+// Without gram files there's no other graph we need to stay unique against; there's also no
+// clock or random number generator to make UUIDs with on wasm32
+#[cfg(not(feature = "gram-file"))]
+fn new_gram_identifier(id: usize) -> String {
+    format!("_{}", id)
+}
+
+#[cfg(feature = "gram-file")]
 fn generate_uuid() -> Uuid {
     // TODO: there should be a single context for the whole backend
     let context = UuidContext::new(42);
@@ -1783,128 +4619,829 @@ fn append_node(
     ctx: &mut Context,
     tokens_in: Rc<RefCell<Tokens>>,
     labels: HashSet<Token>,
-    node_properties: HashMap<Token, Val>,
+    mut node_properties: HashMap<Token, PropVal>,
 ) -> Result<GramVal, Error> {
-    let p = serialize_props(ctx, &node_properties);
-    let gram_identifier = generate_uuid().to_hyphenated().to_string();
+    ctx.storage.borrow_mut().spill(&mut node_properties)?;
+    ctx.db_hits += 1;
+    ctx.summary.nodes_created += 1;
+    ctx.summary.labels_added += labels.len() as u64;
+    ctx.summary.properties_set += count_set(&node_properties);
+    let id = ctx.g.borrow().new_node_id();
+    let gram_identifier = new_gram_identifier(id);
     let mut tokens = tokens_in.borrow_mut();
-    let gram_string = if !labels.is_empty() {
-        let labels_gram_ready: Vec<&str> =
-            labels.iter().map(|l| tokens.lookup(*l).unwrap()).collect();
-        format!(
-            "(`{}`:{} {})\n",
-            gram_identifier,
-            labels_gram_ready.join(":"),
-            p
-        )
-    } else {
-        format!("(`{}` {})\n", gram_identifier, p)
-    };
-
-    let id = ctx.g.borrow().nodes.len();
+    let gid = tokens.tokenize(&gram_identifier);
     let out_node = Node {
         id,
-        gid: tokens.tokenize(&gram_identifier),
+        gid,
         labels,
         properties: node_properties,
         rels: vec![],
+        deleted: false,
+        added: 0,
     };
+    ctx.storage
+        .borrow_mut()
+        .append(|dict| serialize_node(&tokens, &out_node, dict))?;
 
     ctx.g.borrow_mut().add_node(id, out_node);
+    ctx.changes.borrow_mut().created(GramVal::Node { id });
+    Ok(GramVal::Node { id })
+}
+
+fn append_rel(
+    ctx: &mut Context,
+    start_node: usize,
+    end_node: usize,
+    rel_type: Token,
+    mut props: HashMap<Token, PropVal>,
+) -> Result<GramVal, Error> {
+    ctx.storage.borrow_mut().spill(&mut props)?;
+    ctx.db_hits += 1;
+    ctx.summary.relationships_created += 1;
+    ctx.summary.properties_set += count_set(&props);
+    let mut g = ctx.g.borrow_mut();
+    let rel_index = g.add_rel(start_node, end_node, rel_type, props);
+    ctx.storage.borrow_mut().append(|dict| {
+        serialize_rel(
+            &ctx.tokens.borrow(),
+            &g,
+            start_node,
+            &g.nodes[start_node].rels[rel_index],
+            dict,
+        )
+    })?;
+    let rel = GramVal::Rel {
+        node_id: start_node,
+        rel_index,
+    };
+    ctx.changes.borrow_mut().created(rel.clone());
+    Ok(rel)
+}
+
+// Tell hooks and storage about the deletion of a node and its rels, leaving it to the caller to
+// delete them from the graph, see Graph::delete_node. Rels to the nodes in recorded were told
+// about along with those.
+fn record_deletion(ctx: &mut Context, id: usize, recorded: &HashSet<usize>) -> Result<()> {
+    // Hooks are told what was deleted as it was, so read it before it's gone
+    if ctx.changes.borrow().wanted() {
+        let halves: Vec<(usize, Dir, usize)> = ctx.g.borrow().nodes[id]
+            .rels
+            .iter()
+            .enumerate()
+            .map(|(i, rel)| (i, rel.dir, rel.other_node))
+            .collect();
+        for (rel_index, dir, other_node) in halves {
+            // A rel from the node to itself has both its halves here
+            if (other_node == id && dir != Dir::Out) || recorded.contains(&other_node) {
+                continue;
+            }
+            if let Val::Rel(r) = (GramVal::Rel {
+                node_id: id,
+                rel_index,
+            })
+            .project(ctx)?
+            {
+                ctx.changes.borrow_mut().deleted(Change::RelDeleted(r));
+            }
+        }
+        if let Val::Node(n) = (GramVal::Node { id }).project(ctx)? {
+            ctx.changes.borrow_mut().deleted(Change::NodeDeleted(n));
+        }
+    }
+
+    let g = ctx.g.borrow();
+    ctx.storage
+        .borrow_mut()
+        .append(|_| Ok(serialize_deletion(&ctx.tokens.borrow(), &g.nodes[id])))
+}
+
+// How many of the properties aren't null, see QuerySummary::properties_set
+fn count_set(props: &HashMap<Token, PropVal>) -> u64 {
+    props
+        .values()
+        .filter(|v| !matches!(v, PropVal::Val(Val::Null)))
+        .count() as u64
+}
+
+// The whole graph as gram; all nodes first, followed by all rels
+fn serialize_graph(g: &Graph, tokens: &Tokens, dict: &mut Dictionary) -> Result<String> {
+    let mut out = String::new();
+    for n in g.nodes.iter().filter(|n| !n.deleted) {
+        out.push_str(&serialize_node(tokens, n, dict)?);
+    }
+    for n in &g.nodes {
+        for rel in &n.rels {
+            // Each rel is stored on both its nodes, only write it out from the start node
+            if let Dir::Out = rel.dir {
+                out.push_str(&serialize_rel(tokens, g, n.id, rel, dict)?);
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Serialize a node, preceded by any dictionary entries its properties need
+fn serialize_node(tokens: &Tokens, n: &Node, dict: &mut Dictionary) -> Result<String> {
+    let mut out = String::new();
+    let mut node = format!("({}", serialize_id(tokens.lookup(n.gid).unwrap()));
+    for l in &n.labels {
+        node.push(':');
+        node.push_str(&serialize_id(tokens.lookup(*l).unwrap()));
+    }
+    let props = serialize_props(tokens, &n.properties, dict, &mut out)?;
+    out.push_str(&format!("{} {})\n", node, props));
+    Ok(out)
+}
+
+// Serialize the deletion of a node, see Graph::delete_node
+fn serialize_deletion(tokens: &Tokens, n: &Node) -> String {
+    format!("-({})\n", serialize_id(tokens.lookup(n.gid).unwrap()))
+}
+
+// Serialize an outgoing rel from the given node; the gram file only holds the identifiers of
+// the rel endpoints, they are declared separately by serialize_node
+fn serialize_rel(
+    tokens: &Tokens,
+    g: &Graph,
+    node_id: usize,
+    rel: &RelHalf,
+    dict: &mut Dictionary,
+) -> Result<String> {
+    let mut out = String::new();
+    let startgid = tokens.lookup(g.nodes[node_id].gid).unwrap();
+    let endgid = tokens.lookup(g.nodes[rel.other_node].gid).unwrap();
+    let reltype_str = tokens.lookup(rel.rel_type).unwrap();
+    let props = serialize_props(tokens, &rel.properties, dict, &mut out)?;
+    out.push_str(&format!(
+        "({})-[:{} {}]->({})\n",
+        serialize_id(startgid),
+        serialize_id(reltype_str),
+        props,
+        serialize_id(endgid),
+    ));
+    Ok(out)
+}
+
+// Serialize a property map, adding the dictionary entries it refers to, if they're new, to
+// entries
+fn serialize_props(
+    tokens: &Tokens,
+    props: &HashMap<Token, PropVal>,
+    dict: &mut Dictionary,
+    entries: &mut String,
+) -> Result<String> {
+    let mut out = String::new();
+    let mut first = true;
+    out.push('{');
+    for (k, v) in props {
+        // Cypher has no null properties; setting one to null is the same as not having it
+        if *v == PropVal::Val(Val::Null) {
+            continue;
+        }
+        if !first {
+            out.push_str(", ");
+        } else {
+            first = false;
+        }
+        out.push_str(&serialize_id(tokens.lookup(*k).unwrap()));
+        out.push_str(": ");
+        match v {
+            PropVal::Val(Val::String(s)) => out.push_str(&dict.serialize(s, entries)),
+            PropVal::Val(v) => out.push_str(&serialize_val(v)?),
+            PropVal::Overflow { offset, len } => out.push_str(&format!("#{}:{}", offset, len)),
+        }
+    }
+    out.push('}');
+    Ok(out)
+}
+
+fn serialize_id(id: &str) -> String {
+    format!("`{}`", id.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+fn serialize_val(v: &Val) -> Result<String> {
+    match v {
+        Val::String(s) => Ok(format!(
+            "'{}'",
+            s.replace('\\', "\\\\").replace('\'', "\\'")
+        )),
+        Val::Int(v) => Ok(format!("{}", v)),
+        // Debug formatting always includes a decimal point or exponent, so this reads back as a float
+        Val::Float(v) if v.is_finite() => Ok(format!("{:?}", v)),
+        // NaN and the infinities have no way to be written as numbers, so these need annotating
+        Val::Float(v) => Ok(format!("'{:?}'::float", v)),
+        Val::Bool(v) => Ok(format!("{}", v)),
+        Val::Null => Ok("null".to_string()),
+        Val::List(items) => Ok(format!(
+            "[{}]",
+            items
+                .iter()
+                .map(serialize_val)
+                .collect::<Result<Vec<_>>>()?
+                .join(", ")
+        )),
+        _ => bail!(QueryError::TypeError {
+            message: format!("the gram backend can't store {:?} as a property value", v)
+        }),
+    }
+}
+
+// Lots of properties are enum-like; a status, a country, a category. Rather than writing the
+// same string over and over, strings that keep coming up are written once, as a numbered entry,
+// and referred to by number after that:
+//
+//   @0 = 'Sweden'
+//   (`a` {`country`: @0})
+//   (`b` {`country`: @0})
+//
+// Each gram file - and change log - has its own dictionary, so a log never depends on the
+// entries of the gram file it's replayed over, which compaction renumbers.
+#[derive(Debug, Default)]
+struct Dictionary {
+    // The strings, by entry number
+    values: Vec<Arc<str>>,
+    index: HashMap<Arc<str>, usize>,
+    // How many times strings that could go in the dictionary, but aren't in it yet, have been
+    // written out
+    seen: HashMap<Arc<str>, u32>,
+}
+
+// Shorter strings take up less room written out than referred to, and longer ones are
+// unlikely to be enum-like
+const DICT_MIN_LEN: usize = 4;
+const DICT_MAX_LEN: usize = 64;
+// Strings are added to the dictionary when they are written for the second time
+const DICT_MIN_USES: u32 = 2;
+// Stop keeping track of new strings past this many, so lots of unique values don't eat up memory
+const DICT_MAX_SEEN: usize = 100_000;
+
+impl Dictionary {
+    // A dictionary for writing out the given graph, with an entry for every string that's used
+    // more than once
+    #[cfg(feature = "gram-file")]
+    fn for_graph(g: &Graph) -> Dictionary {
+        let mut counts: HashMap<Arc<str>, u32> = HashMap::new();
+        let rel_props = g.nodes.iter().flat_map(|n| {
+            n.rels
+                .iter()
+                .filter(|r| matches!(r.dir, Dir::Out))
+                .flat_map(|r| r.properties.values())
+        });
+        for v in g
+            .nodes
+            .iter()
+            .flat_map(|n| n.properties.values())
+            .chain(rel_props)
+        {
+            if let PropVal::Val(Val::String(s)) = v {
+                if Dictionary::fits(s) {
+                    *counts.entry(Arc::clone(s)).or_default() += 1;
+                }
+            }
+        }
+        counts.retain(|_, count| *count >= DICT_MIN_USES);
+        Dictionary {
+            seen: counts,
+            ..Default::default()
+        }
+    }
+
+    fn fits(s: &str) -> bool {
+        (DICT_MIN_LEN..=DICT_MAX_LEN).contains(&s.chars().count())
+    }
+
+    fn define(&mut self, s: Arc<str>) -> usize {
+        let n = self.values.len();
+        self.index.insert(Arc::clone(&s), n);
+        self.values.push(s);
+        n
+    }
+
+    // The string as it should be written out; if that's as a new entry, the entry is added to
+    // entries, to be written out before the string is
+    fn serialize(&mut self, s: &Arc<str>, entries: &mut String) -> String {
+        if let Some(n) = self.index.get(s) {
+            return format!("@{}", n);
+        }
+        let quoted = format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"));
+        if !Dictionary::fits(s) {
+            return quoted;
+        }
+        if !self.seen.contains_key(s) && self.seen.len() >= DICT_MAX_SEEN {
+            return quoted;
+        }
+        let seen = self.seen.entry(Arc::clone(s)).or_default();
+        *seen += 1;
+        if *seen < DICT_MIN_USES {
+            return quoted;
+        }
+        self.seen.remove(s);
+        let n = self.define(Arc::clone(s));
+        entries.push_str(&format!("@{} = {}\n", n, quoted));
+        format!("@{}", n)
+    }
+
+    // Forget the entries past the first len, whose definitions were never written
+    #[cfg(feature = "gram-file")]
+    fn truncate(&mut self, len: usize) {
+        for s in self.values.drain(len..) {
+            self.index.remove(&s);
+        }
+    }
+}
+
+// Everything gqlite writes to gram files is framed as a record, a header comment with the CRC32
+// checksum and length of the gram that follows it:
+//
+//   //#crc32 1c291ca3 44
+//   (`a` {`name`: 'a'})
+//   (`b` {`name`: 'b'})
+//
+// so that on load we can tell a damaged or partially written record from valid gram. Gram
+// outside of records, eg. in files written by hand, is loaded without verification.
+#[cfg(feature = "gram-file")]
+const RECORD_HEADER: &str = "//#crc32 ";
+
+// Clearing the change log once it's folded into a new gram file by compaction is a separate
+// step from putting the new file in place, so a crash in between would leave a log to replay
+// over a gram file that already has everything in it. To tell when that's the case, logs are
+// numbered, in a header on their first line:
+//
+//   //#log 4
+//
+// and a gram file that has a log folded into it starts with the number of that log and how
+// many bytes of it were folded:
+//
+//   //#folded 3 1024
+//
+// On load, if the log has the number of the folded one, those bytes of it are skipped. A log
+// cleared by compaction is empty until it's written to, at which point it gets the next number.
+// Logs without a header, like those from before compaction numbered them, are number 0.
+#[cfg(feature = "gram-file")]
+const FOLDED_HEADER: &str = "//#folded ";
+#[cfg(feature = "gram-file")]
+const LOG_HEADER: &str = "//#log ";
+
+// While compaction writes a new gram file in the background, queries keep writing to the log.
+// Once the new file is in place, it skips the part of the log it folded - and with that part
+// go the dictionary entries it defined - so what comes after can't refer to them. Compaction
+// starts a new segment of the log for it, with a dictionary of its own, on a line of its own
+// between records:
+//
+//   //#segment
+//
+// Each segment is loaded like a file of its own, see parser::load.
+#[cfg(feature = "gram-file")]
+const SEGMENT_HEADER: &str = "//#segment";
+
+// Split a log into its segments. Only lines between records are looked at, so a segment header
+// in a string in a record isn't mistaken for one.
+#[cfg(feature = "gram-file")]
+fn log_segments(log: &str) -> Vec<&str> {
+    let mut segments = vec![];
+    let (mut start, mut at) = (0, 0);
+    while at < log.len() {
+        let line_end = log[at..].find('\n').map_or(log.len(), |i| at + i + 1);
+        let line = &log[at..line_end];
+        if line.trim_end() == SEGMENT_HEADER {
+            segments.push(&log[start..at]);
+            start = line_end;
+        }
+        at = match record_header(line) {
+            // A truncated record is reported when the segment is verified
+            Some((_, len)) if log.is_char_boundary(line_end + len) => line_end + len,
+            Some(_) => log.len(),
+            None => line_end,
+        };
+    }
+    segments.push(&log[start..]);
+    segments
+}
+
+// The checksum and length of the record a line is the header of, if it is one, see
+// frame_record
+#[cfg(feature = "gram-file")]
+fn record_header(line: &str) -> Option<(u32, usize)> {
+    let mut parts = line.strip_prefix(RECORD_HEADER)?.trim_end().split(' ');
+    let checksum = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let len = parts.next()?.parse().ok()?;
+    Some((checksum, len))
+}
+
+// The numbers on the first line of gram, if it starts with the given header
+#[cfg(feature = "gram-file")]
+fn header_numbers(gram: &str, header: &str) -> Option<Vec<u64>> {
+    let line = gram.strip_prefix(header)?.lines().next()?;
+    line.split(' ').map(|n| n.parse().ok()).collect()
+}
+
+#[cfg(feature = "gram-file")]
+fn frame_record(gram: &str) -> String {
+    format!(
+        "{}{:08x} {}\n{}",
+        RECORD_HEADER,
+        crc32fast::hash(gram.as_bytes()),
+        gram.len(),
+        gram
+    )
+}
+
+// Returned, wrapped in an anyhow::Error, when a gram file or change log fails verification
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptionError {
+    // Byte offset of the damaged record in the file
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl Display for CorruptionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gram file is corrupt at byte offset {}: {}",
+            self.offset, self.reason
+        )
+    }
+}
+
+impl std::error::Error for CorruptionError {}
+
+// Where the writes of queries end up
+#[derive(Debug)]
+enum Storage {
+    // Nowhere; the graph only lives in memory
+    Memory,
+    #[cfg(feature = "gram-file")]
+    File(Box<GramFile>),
+}
+
+impl Storage {
+    // Record a write of the current query; the gram is only generated if it's going somewhere
+    #[cfg_attr(not(feature = "gram-file"), allow(unused_variables))]
+    fn append(&mut self, gram: impl FnOnce(&mut Dictionary) -> Result<String>) -> Result<()> {
+        match self {
+            Storage::Memory => Ok(()),
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) => {
+                let gram = gram(&mut file.dict)?;
+                file.pending.push_str(&gram);
+                Ok(())
+            }
+        }
+    }
+
+    // Move the large values among props out to the overflow file, see PropVal::Overflow
+    #[cfg_attr(not(feature = "gram-file"), allow(unused_variables))]
+    fn spill(&mut self, props: &mut HashMap<Token, PropVal>) -> Result<()> {
+        match self {
+            Storage::Memory => Ok(()),
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) => file.spill(props),
+        }
+    }
+
+    #[cfg_attr(not(feature = "gram-file"), allow(unused_variables))]
+    fn read_overflow(&mut self, offset: u64, len: usize) -> Result<Val> {
+        match self {
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) if matches!(file.overflow, Some(OverflowFile::Open(_))) => {
+                let overflow = file.overflow.as_mut().unwrap().file()?;
+                let mut buf = vec![0; len];
+                overflow.seek(SeekFrom::Start(offset))?;
+                overflow.read_exact(&mut buf).map_err(|e| {
+                    anyhow!(e).context(format!(
+                        "failed to read {} bytes at offset {} of the overflow file",
+                        len, offset
+                    ))
+                })?;
+                Ok(Val::String(String::from_utf8(buf)?.into()))
+            }
+            _ => bail!(
+                "the graph refers to #{}:{} in an overflow file, but it was opened without one",
+                offset,
+                len
+            ),
+        }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        match self {
+            Storage::Memory => Ok(()),
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) => file.commit(),
+        }
+    }
 
-    println!("--- About to write ---");
-    println!("{}", gram_string);
-    println!("------");
-    // TODO: actually write to the file:
+    fn rollback(&mut self) {
+        match self {
+            Storage::Memory => (),
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) => file.rollback(),
+        }
+    }
 
-    ctx.file
-        .borrow_mut()
-        .seek(SeekFrom::End(0))
-        .expect("seek error");
+    #[cfg_attr(not(feature = "gram-file"), allow(unused_variables))]
+    fn set_batching(&mut self, batching: bool) {
+        match self {
+            Storage::Memory => (),
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) => {
+                file.batching = batching;
+                file.dict_start = file.dict.values.len();
+            }
+        }
+    }
 
-    match ctx.file.borrow_mut().write_all(gram_string.as_bytes()) {
-        Ok(_) => Ok(GramVal::Node { id }),
-        Err(e) => Err(Error::new(e)),
+    fn abort_batch(&mut self) {
+        match self {
+            Storage::Memory => (),
+            #[cfg(feature = "gram-file")]
+            Storage::File(file) => file.abort_batch(),
+        }
     }
 }
 
-fn append_rel(
-    ctx: &mut Context,
-    start_node: usize,
-    end_node: usize,
-    rel_type: Token,
-    props: HashMap<Token, Val>,
-) -> Result<GramVal, Error> {
-    let p = serialize_props(ctx, &props);
-    let mut g = ctx.g.borrow_mut();
-    let tokens = ctx.tokens.borrow();
-
-    let startgid = tokens.lookup(g.nodes[start_node].gid).unwrap();
-    let endgid = tokens.lookup(g.nodes[end_node].gid).unwrap();
-    let reltype_str = tokens.lookup(rel_type).unwrap();
+// The gram file backing the graph, and the change log next to it, if there is one. Mutations
+// are buffered here as gram text while a query runs, and appended to the log - or the gram file
+// itself, if there is no log - when the query commits, which is when its results are exhausted
+// or, if they never are, when the next query starts. A query that fails does not commit.
+//
+// In a batch, see Backend::begin_batch, committed queries stay buffered too, and are appended
+// as one record when the batch ends.
+//
+// Large values go to the overflow file as soon as they're written, and the file is synced
+// before the record that refers to them is appended. Values of queries that didn't commit are
+// left in the overflow file; for now nothing reclaims that space, compaction included.
+#[cfg(feature = "gram-file")]
+#[derive(Debug)]
+struct GramFile {
+    file: File,
+    // Where the file is, if we know; see GramBackend::compact
+    path: Option<PathBuf>,
+    log: Option<File>,
+    // Where the log is, if we know; likewise
+    log_path: Option<PathBuf>,
+    // See FOLDED_HEADER
+    log_generation: u64,
+    pending: String,
+    // How much of pending is from queries that committed during a batch
+    committed: usize,
+    batching: bool,
+    durability: Durability,
+    // Of the file that's appended to
+    dict: Dictionary,
+    // How many of the dictionary entries are written out, or committed during a batch; the
+    // rest were added by the current query
+    dict_committed: usize,
+    // How many dictionary entries there were when the current batch began
+    dict_start: usize,
+    overflow: Option<OverflowFile>,
+    // False when values were added to the overflow file since it was last synced
+    overflow_synced: bool,
+    // The new gram file, while it's being written, see GramBackend::compact
+    compaction: Option<Compaction>,
+}
 
-    let gram_string = format!(
-        "(`{}`)-[:`{}` {}]->(`{}`)\n",
-        startgid, reltype_str, p, endgid,
-    );
+#[cfg(feature = "gram-file")]
+#[derive(Debug)]
+struct Compaction {
+    // Gives back the new file, written in full and synced, at next_path
+    writer: std::thread::JoinHandle<Result<File>>,
+    next_path: PathBuf,
+    // How much of the log the new file has folded into it; the rest was written since
+    folded: u64,
+}
 
-    println!("--- About to write ---");
-    println!("{}", gram_string);
-    println!("------");
+#[cfg(feature = "gram-file")]
+impl GramFile {
+    fn commit(&mut self) -> Result<()> {
+        if self.batching {
+            self.committed = self.pending.len();
+            self.dict_committed = self.dict.values.len();
+            return Ok(());
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(OverflowFile::Open(overflow)) = &mut self.overflow {
+            if !self.overflow_synced && self.durability == Durability::Sync {
+                overflow.sync_data()?;
+            }
+            self.overflow_synced = true;
+        }
+        let numbered = self.log.is_some() && self.log_generation > 0;
+        let out = self.log.as_mut().unwrap_or(&mut self.file);
+        // See FOLDED_HEADER
+        if out.seek(SeekFrom::End(0))? == 0 && numbered {
+            out.write_all(format!("{}{}\n", LOG_HEADER, self.log_generation).as_bytes())?;
+        }
+        // The file may not end in a newline if it was written by hand
+        out.write_all(b"\n")?;
+        out.write_all(frame_record(&self.pending).as_bytes())?;
+        if self.durability == Durability::Sync {
+            out.sync_data()?;
+        }
+        self.pending.clear();
+        self.committed = 0;
+        self.dict_committed = self.dict.values.len();
+        Ok(())
+    }
 
-    let rel_index = g.add_rel(start_node, end_node, rel_type);
+    fn spill(&mut self, props: &mut HashMap<Token, PropVal>) -> Result<()> {
+        let overflow = match &mut self.overflow {
+            Some(overflow) => overflow,
+            None => return Ok(()),
+        };
+        for v in props.values_mut() {
+            if let PropVal::Val(Val::String(s)) = v {
+                if s.len() > OVERFLOW_THRESHOLD {
+                    let overflow = overflow.file()?;
+                    let offset = overflow.seek(SeekFrom::End(0))?;
+                    overflow.write_all(s.as_bytes())?;
+                    self.overflow_synced = false;
+                    *v = PropVal::Overflow {
+                        offset,
+                        len: s.len(),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
 
-    ctx.file
-        .borrow_mut()
-        .seek(SeekFrom::End(0))
-        .expect("seek error");
+    // Drop the writes of a failed query. Note that we don't yet undo the changes to the graph
+    // in memory, so until the file is re-opened the in-memory graph will still have them.
+    fn rollback(&mut self) {
+        self.pending.truncate(self.committed);
+        self.dict.truncate(self.dict_committed);
+    }
 
-    match ctx.file.borrow_mut().write_all(gram_string.as_bytes()) {
-        Ok(_) => Ok(GramVal::Rel {
-            node_id: start_node,
-            rel_index: rel_index,
-        }),
-        Err(e) => Err(Error::new(e)),
+    // Drop everything written since the batch began; that started with nothing pending, see
+    // GramBackend::begin_batch, and so with the dictionary as it is in the file.
+    fn abort_batch(&mut self) {
+        self.batching = false;
+        self.pending.clear();
+        self.committed = 0;
+        self.dict.truncate(self.dict_start);
+        self.dict_committed = self.dict_start;
     }
-}
 
-fn serialize_props(ctx: &mut Context, props: &HashMap<Token, Val>) -> String {
-    let mut out = String::new();
-    let mut first = true;
-    out.push_str("{");
-    for (k, v) in props {
-        if !first {
-            out.push_str(", ");
+    // Replace the gram file with the given gram, which should contain everything committed
+    // so far and was written with the given dictionary, and clear the log. With the paths of
+    // both, the new file is written in the background, see GramBackend::compact.
+    fn rewrite(&mut self, gram: &str, dict: Dictionary) -> Result<()> {
+        let mut contents = String::new();
+        let mut folded = 0;
+        if let Some(log) = &mut self.log {
+            folded = log.seek(SeekFrom::End(0))?;
+            contents.push_str(&format!(
+                "{}{} {}\n",
+                FOLDED_HEADER, self.log_generation, folded
+            ));
+        }
+        contents.push_str(&frame_record(gram));
+        match (&self.path, &mut self.log, &self.log_path) {
+            (Some(path), Some(log), Some(_)) => {
+                // What's written from here on goes to a segment of its own, with a dictionary
+                // that doesn't depend on the part of the log the new file folds
+                if folded > 0 {
+                    log.write_all(format!("\n{}\n", SEGMENT_HEADER).as_bytes())?;
+                    self.dict = Dictionary::default();
+                    self.dict_committed = 0;
+                }
+                let next_path = sibling_path(path, ".compacting");
+                let writing = next_path.clone();
+                self.compaction = Some(Compaction {
+                    writer: std::thread::spawn(move || write_synced(&writing, contents.as_bytes())),
+                    next_path,
+                    folded,
+                });
+                return Ok(());
+            }
+            (Some(path), _, _) => {
+                let next_path = sibling_path(path, ".compacting");
+                let next = write_synced(&next_path, contents.as_bytes())?;
+                replace(&next_path, path)?;
+                self.file = next;
+            }
+            (None, _, _) => {
+                self.file.set_len(0)?;
+                self.file.seek(SeekFrom::Start(0))?;
+                self.file.write_all(contents.as_bytes())?;
+                self.file.sync_data()?;
+            }
+        }
+
+        // Appends go to the log if there is one, and that starts over with a dictionary of its own
+        self.dict = if self.log.is_some() {
+            Dictionary::default()
         } else {
-            first = false;
+            dict
+        };
+        self.dict_committed = self.dict.values.len();
+        if let Some(log) = &mut self.log {
+            self.log_generation += 1;
+            log.set_len(0)?;
+            log.sync_data()?;
         }
-        {
-            let toks = ctx.tokens.borrow_mut();
-            let key = toks.lookup(*k).unwrap();
-            out.push_str(key);
+        Ok(())
+    }
+
+    // Put the new gram file rewrite started writing in place, once it's written, and with it a
+    // log of the next generation with what was written to the log since. The gram file goes
+    // first: until the log is replaced too, the new file skips what it folded of the old log,
+    // see FOLDED_HEADER, and after, the new log has nothing the new file folded.
+    fn finish_compaction(&mut self) -> Result<()> {
+        let Compaction {
+            writer,
+            next_path,
+            folded,
+        } = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => return Ok(()),
+        };
+        let next = writer.join().expect("compaction thread panicked")?;
+        let (path, log, log_path) = match (&self.path, &mut self.log, &self.log_path) {
+            (Some(path), Some(log), Some(log_path)) => (path, log, log_path),
+            _ => unreachable!("compactions only run in the background with both paths"),
+        };
+        replace(&next_path, path)?;
+        self.file = next;
+
+        let mut written_since = Vec::new();
+        log.seek(SeekFrom::Start(folded))?;
+        log.read_to_end(&mut written_since)?;
+        self.log_generation += 1;
+        let mut contents = Vec::new();
+        // An empty log gets its number with its first write, like one cleared by rewrite
+        if !written_since.is_empty() {
+            contents.extend(format!("{}{}\n", LOG_HEADER, self.log_generation).as_bytes());
+            contents.extend(written_since);
         }
-        out.push_str(": ");
-        out.push_str(&serialize_val(ctx, &v))
+        let next_log_path = sibling_path(log_path, ".compacting");
+        let next_log = write_synced(&next_log_path, &contents)?;
+        replace(&next_log_path, log_path)?;
+        self.log = Some(next_log);
+        Ok(())
     }
-    out.push_str("}");
-    return out;
 }
 
-fn serialize_val(_ctx: &mut Context, v: &Val) -> String {
-    match v {
-        Val::String(s) => format!("\'{}\'", s),
-        Val::Int(v) => format!("{}", v),
-        Val::Bool(v) => format!("{}", v),
-        _ => panic!("Don't know how to serialize {:?}", v),
+// Write a file in full and sync it, for renaming into place with replace
+#[cfg(feature = "gram-file")]
+fn write_synced(path: &Path, contents: &[u8]) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+// Rename a file over another, so anyone opening it sees either all of the old file or all of
+// the new one
+#[cfg(feature = "gram-file")]
+fn replace(from: &Path, to: &Path) -> Result<()> {
+    std::fs::rename(from, to)?;
+    // The rename itself is only durable once the directory is synced; there's no opening a
+    // directory to do that with outside of unix
+    #[cfg(unix)]
+    match to.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all()?,
+        _ => File::open(".")?.sync_all()?,
+    }
+    Ok(())
+}
+
+// The path of a file next to the given one, with the suffix tacked on to its name
+#[cfg(feature = "gram-file")]
+pub(crate) fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(feature = "gram-file")]
+impl Drop for GramFile {
+    fn drop(&mut self) {
+        // Last chance to commit a query whose results were never exhausted, and to put a
+        // compaction in place; there's no one to report an error to at this point, so this is
+        // best effort
+        self.batching = false;
+        let _ = self.commit();
+        let _ = self.finish_compaction();
     }
 }
 
 mod functions {
-    use super::{Context, Expr, GramRow, GramVal, Val};
+    use super::{Context, Expr, GramRow, GramVal, PropVal, Val};
     use crate::backend::{FuncSignature, FuncType, Tokens};
-    use crate::{Result, Type};
+    use crate::{QueryError, Result, Type};
     use std::cmp::Ordering;
     use std::fmt::Debug;
+    use std::rc::Rc;
 
     pub(super) fn aggregating(tokens: &mut Tokens) -> Vec<Box<dyn AggregatingFuncSpec>> {
         let mut out: Vec<Box<dyn AggregatingFuncSpec>> = Default::default();
@@ -1914,14 +5451,56 @@ mod functions {
         return out;
     }
 
+    // Signatures of the scalar functions, for describe; convert_expr is what maps the names
+    // to a Func
+    pub(super) fn scalar(tokens: &mut Tokens) -> Vec<FuncSignature> {
+        let mut sig = |name: &str, args: &[(&str, Type)], optional, returns| FuncSignature {
+            func_type: FuncType::Scalar,
+            name: tokens.tokenize(name),
+            returns,
+            args: args
+                .iter()
+                .map(|(arg, t)| (tokens.tokenize(arg), t.clone()))
+                .collect(),
+            optional,
+        };
+        vec![
+            sig("not", &[("v", Type::Boolean)], 0, Type::Boolean),
+            sig("abs", &[("v", Type::Number)], 0, Type::Number),
+            sig(
+                "range",
+                &[
+                    ("start", Type::Integer),
+                    ("end", Type::Integer),
+                    ("step", Type::Integer),
+                ],
+                1,
+                Type::List(Box::new(Type::Integer)),
+            ),
+            sig(
+                "keys",
+                &[("v", Type::Any)],
+                0,
+                Type::List(Box::new(Type::String)),
+            ),
+            sig("rand", &[], 0, Type::Float),
+        ]
+    }
+
     #[derive(Debug, Clone)]
     pub(super) enum Func {
         Not,
         Abs,
+        // range(start, end[, step]), both ends included
+        Range,
+        // keys(n), the names of the properties of a node, rel or map
+        Keys,
+        // rand(), a float in [0, 1); the same ones each run if the query is given a seed
+        Rand,
     }
 
     impl Func {
-        pub fn apply(&self, args: &Vec<GramVal>) -> Result<GramVal> {
+        pub fn apply(&self, ctx: &mut Context, args: &Vec<GramVal>) -> Result<GramVal> {
             match self {
                 Func::Not => match args.get(0).ok_or(anyhow!("NOT takes one argument"))? {
                     GramVal::Lit(v) => match v {
@@ -1935,7 +5514,111 @@ mod functions {
                     GramVal::Lit(Val::Float(v)) => Ok(GramVal::Lit(Val::Float(v.abs()))),
                     v => bail!("don't know how to take ABS({:?})", v),
                 },
+                Func::Range => {
+                    let items: Vec<GramVal> = Range::new(args)?
+                        .map(|i| GramVal::Lit(Val::Int(i)))
+                        .collect();
+                    Ok(GramVal::List(Rc::new(items)))
+                }
+                Func::Keys => {
+                    let names = |props: &crate::Map| props.iter().map(|(k, _)| k.clone()).collect();
+                    let keys: Vec<String> = match args.first() {
+                        Some(GramVal::Node { .. }) | Some(GramVal::Rel { .. }) => {
+                            ctx.db_hits += 1;
+                            let g = ctx.g.borrow();
+                            let props = match &args[0] {
+                                GramVal::Node { id } => &g.nodes[*id].properties,
+                                GramVal::Rel { node_id, rel_index } => {
+                                    &*g.nodes[*node_id].rels[*rel_index].properties
+                                }
+                                _ => unreachable!(),
+                            };
+                            let tokens = ctx.tokens.borrow();
+                            // A property set to null isn't there
+                            let mut keys: Vec<String> = props
+                                .iter()
+                                .filter(|(_, v)| !matches!(v, PropVal::Val(Val::Null)))
+                                .filter_map(|(k, _)| tokens.lookup(*k).map(str::to_string))
+                                .collect();
+                            // Properties are kept in no particular order, so sort them to give
+                            // the same answer every time
+                            keys.sort();
+                            keys
+                        }
+                        Some(GramVal::Map(es)) => {
+                            let tokens = ctx.tokens.borrow();
+                            es.iter()
+                                .filter_map(|(k, _)| tokens.lookup(*k).map(str::to_string))
+                                .collect()
+                        }
+                        Some(GramVal::Lit(Val::Map(props))) => names(props),
+                        Some(GramVal::Lit(Val::Node(n))) => names(&n.props),
+                        Some(GramVal::Lit(Val::Rel(r))) => names(&r.props),
+                        Some(GramVal::Lit(Val::Null)) => return Ok(GramVal::Lit(Val::Null)),
+                        v => bail!(QueryError::TypeError {
+                            message: format!(
+                                "keys() expects a node, relationship or map, got {:?}",
+                                v
+                            )
+                        }),
+                    };
+                    let keys: Vec<Val> = keys.into_iter().map(|k| Val::String(k.into())).collect();
+                    Ok(GramVal::Lit(Val::List(keys.into())))
+                }
+                Func::Rand => {
+                    if !args.is_empty() {
+                        bail!("rand() takes no arguments")
+                    }
+                    Ok(GramVal::Lit(Val::Float(ctx.rng.float())))
+                }
+            }
+        }
+    }
+
+    // The integers of range(start, end[, step]), counted out one at a time
+    #[derive(Debug)]
+    pub(super) struct Range {
+        next: Option<i64>,
+        end: i64,
+        step: i64,
+    }
+
+    impl Range {
+        pub fn new(args: &[GramVal]) -> Result<Range> {
+            let int = |i: usize| match args.get(i) {
+                Some(GramVal::Lit(Val::Int(v))) => Ok(*v),
+                v => bail!(QueryError::TypeError {
+                    message: format!("range() expects integer arguments, got {:?}", v)
+                }),
+            };
+            if args.len() < 2 || args.len() > 3 {
+                bail!("range() takes a start, an end and optionally a step")
+            }
+            let step = if args.len() == 3 { int(2)? } else { 1 };
+            if step == 0 {
+                bail!(QueryError::SemanticError {
+                    message: "range() can't have a step of 0".to_string(),
+                    span: None,
+                })
+            }
+            Ok(Range {
+                next: Some(int(0)?),
+                end: int(1)?,
+                step,
+            })
+        }
+    }
+
+    impl Iterator for Range {
+        type Item = i64;
+
+        fn next(&mut self) -> Option<i64> {
+            let i = self.next?;
+            if (self.step > 0 && i > self.end) || (self.step < 0 && i < self.end) {
+                return None;
             }
+            self.next = i.checked_add(self.step);
+            Some(i)
         }
     }
 
@@ -1980,6 +5663,7 @@ mod functions {
                     name: tok_min,
                     returns: Type::Any,
                     args: vec![(tokens.tokenize("v"), Type::Any)],
+                    optional: 0,
                 },
             }
         }
@@ -2024,7 +5708,7 @@ mod functions {
                 if v == GramVal::Lit(Val::Null) {
                     return Ok(());
                 }
-                if let Some(Ordering::Less) = v.partial_cmp(&current_min) {
+                if let Some(Ordering::Less) = v.cmp_collated(current_min, &ctx.collation) {
                     self.min = Some(v);
                 }
             } else {
@@ -2058,6 +5742,7 @@ mod functions {
                     name: tok_max,
                     returns: Type::Any,
                     args: vec![(tokens.tokenize("v"), Type::Any)],
+                    optional: 0,
                 },
             }
         }
@@ -2069,7 +5754,6 @@ mod functions {
         }
 
         fn init(&self, mut args: Vec<Expr>) -> Box<dyn AggregatingFunc> {
-            println!("Init max({:?})", args);
             Box::new(Max {
                 arg: args.pop().expect("max takes 1 argument"),
             })
@@ -2103,7 +5787,7 @@ mod functions {
                 if v == GramVal::Lit(Val::Null) {
                     return Ok(());
                 }
-                if let Some(Ordering::Greater) = v.partial_cmp(&current_max) {
+                if let Some(Ordering::Greater) = v.cmp_collated(current_max, &ctx.collation) {
                     self.max = Some(v);
                 }
             } else {
@@ -2135,8 +5819,10 @@ mod functions {
                 sig: FuncSignature {
                     func_type: FuncType::Aggregating,
                     name: fn_name,
-                    returns: Type::Any,
-                    args: vec![],
+                    returns: Type::Integer,
+                    // count(*) is count without one
+                    args: vec![(tokens.tokenize("v"), Type::Any)],
+                    optional: 1,
                 },
             }
         }
@@ -2147,17 +5833,21 @@ mod functions {
             return &self.sig;
         }
 
-        fn init(&self, _args: Vec<Expr>) -> Box<dyn AggregatingFunc> {
-            Box::new(Count {})
+        fn init(&self, mut args: Vec<Expr>) -> Box<dyn AggregatingFunc> {
+            // count(*) has no argument, and counts every row
+            Box::new(Count { arg: args.pop() })
         }
     }
 
     #[derive(Debug)]
-    struct Count {}
+    struct Count {
+        arg: Option<Expr>,
+    }
 
     impl AggregatingFunc for Count {
         fn init(&mut self, _ctx: &mut Context) -> Box<dyn Aggregation> {
             Box::new(CountAggregation {
+                arg: self.arg.clone(),
                 counter: 0,
                 out: GramVal::Lit(Val::Int(0)),
             })
@@ -2166,12 +5856,19 @@ mod functions {
 
     #[derive(Debug)]
     struct CountAggregation {
+        arg: Option<Expr>,
         counter: i64,
         out: GramVal,
     }
 
     impl Aggregation for CountAggregation {
-        fn apply(&mut self, _ctx: &mut Context, _row: &mut GramRow) -> Result<()> {
+        fn apply(&mut self, ctx: &mut Context, row: &mut GramRow) -> Result<()> {
+            // Nulls aren't counted, like the missing matches of an OPTIONAL MATCH
+            if let Some(arg) = &self.arg {
+                if arg.eval(ctx, row)? == GramVal::Lit(Val::Null) {
+                    return Ok(());
+                }
+            }
             self.counter += 1;
             Ok(())
         }
@@ -2182,3 +5879,545 @@ mod functions {
         }
     }
 }
+
+mod procedures {
+    use super::{append_node, append_rel, Context, Dir, GramVal, PropVal, SplitMix64, Val};
+    use crate::backend::{ProcSignature, Token, Tokens};
+    use crate::{Result, Type};
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::rc::Rc;
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Copy)]
+    pub(super) enum Proc {
+        // A graph describing the graph: one node per label, and one rel per combination of
+        // start label, rel type and end label that occurs in the data
+        SchemaVisualization,
+        // Add a random graph of some topology, for trying queries and indexes out on
+        GenGraph,
+        // Take a Projection of the graph, for the algo procedures to run on
+        GraphProject,
+        AlgoPageRank,
+    }
+
+    impl Proc {
+        pub const ALL: [Proc; 4] = [
+            Proc::SchemaVisualization,
+            Proc::GenGraph,
+            Proc::GraphProject,
+            Proc::AlgoPageRank,
+        ];
+
+        pub fn named(name: &str) -> Option<Proc> {
+            Proc::ALL.iter().find(|p| p.name() == name).copied()
+        }
+
+        fn name(&self) -> &'static str {
+            match self {
+                Proc::SchemaVisualization => "db.schema.visualization",
+                Proc::GenGraph => "gen.graph",
+                Proc::GraphProject => "graph.project",
+                Proc::AlgoPageRank => "algo.pageRank",
+            }
+        }
+
+        pub fn signature(&self, tokens: &mut Tokens) -> ProcSignature {
+            match self {
+                Proc::SchemaVisualization => ProcSignature {
+                    name: tokens.tokenize(self.name()),
+                    args: vec![],
+                    outputs: vec![
+                        (tokens.tokenize("nodes"), Type::List(Box::new(Type::Node))),
+                        (
+                            tokens.tokenize("relationships"),
+                            Type::List(Box::new(Type::Relationship)),
+                        ),
+                    ],
+                    writes: false,
+                },
+                Proc::GenGraph => ProcSignature {
+                    name: tokens.tokenize(self.name()),
+                    args: vec![
+                        (tokens.tokenize("topology"), Type::String),
+                        (tokens.tokenize("nodes"), Type::Integer),
+                        (tokens.tokenize("edgesPerNode"), Type::Integer),
+                        (tokens.tokenize("config"), Type::Map),
+                    ],
+                    outputs: vec![
+                        (tokens.tokenize("nodes"), Type::Integer),
+                        (tokens.tokenize("relationships"), Type::Integer),
+                    ],
+                    writes: true,
+                },
+                Proc::GraphProject => ProcSignature {
+                    name: tokens.tokenize(self.name()),
+                    args: vec![
+                        (tokens.tokenize("graphName"), Type::String),
+                        (tokens.tokenize("config"), Type::Map),
+                    ],
+                    outputs: vec![
+                        (tokens.tokenize("nodes"), Type::Integer),
+                        (tokens.tokenize("relationships"), Type::Integer),
+                    ],
+                    writes: false,
+                },
+                Proc::AlgoPageRank => ProcSignature {
+                    name: tokens.tokenize(self.name()),
+                    args: vec![
+                        (tokens.tokenize("graphName"), Type::String),
+                        (tokens.tokenize("config"), Type::Map),
+                    ],
+                    outputs: vec![
+                        (tokens.tokenize("node"), Type::Node),
+                        (tokens.tokenize("score"), Type::Float),
+                    ],
+                    writes: false,
+                },
+            }
+        }
+
+        // Each row holds one value per output, in the order of the signature
+        pub fn call(&self, ctx: &mut Context, args: &[GramVal]) -> Result<Vec<Vec<GramVal>>> {
+            match self {
+                Proc::SchemaVisualization => Ok(vec![schema_visualization(ctx)?]),
+                Proc::GenGraph => Ok(vec![gen_graph(ctx, args)?]),
+                Proc::GraphProject => Ok(vec![graph_project(ctx, args)?]),
+                Proc::AlgoPageRank => page_rank(ctx, args),
+            }
+        }
+    }
+
+    // Projections by the name they were given
+    pub(super) type Projections = HashMap<String, Rc<Projection>>;
+
+    // A copy of part of the graph - the nodes with some labels, and the rels of some types
+    // between them - laid out for algorithms that go over all of it many times, in compressed
+    // sparse row form: flat arrays of numbers rather than nodes with properties. It's a snapshot;
+    // what's written to the graph after it's taken isn't in it, project again for that.
+    #[derive(Debug)]
+    pub(super) struct Projection {
+        // Id in the graph of each projected node. Algorithms number nodes by their position here.
+        nodes: Vec<usize>,
+        // The outgoing rels of node i are those from offsets[i] up to offsets[i + 1]
+        offsets: Vec<usize>,
+        // The node each rel points to
+        targets: Vec<usize>,
+        // The weight of each rel, if the projection was asked to weigh them
+        weights: Option<Vec<f64>>,
+    }
+
+    impl Projection {
+        fn rels(&self, node: usize) -> std::ops::Range<usize> {
+            self.offsets[node]..self.offsets[node + 1]
+        }
+
+        fn weight(&self, rel: usize) -> f64 {
+            self.weights.as_ref().map_or(1.0, |w| w[rel])
+        }
+    }
+
+    // CALL graph.project(graphName, config):
+    //
+    //   config.labels    nodes with any of these labels are projected; all nodes if not given
+    //   config.relTypes  rels of these types between projected nodes are; all of them if not given
+    //   config.weight    rel property that weighs the rels; rels without it weigh 1.0
+    //
+    // A projection with the same name is replaced. Gives back how many nodes and rels the
+    // projection has.
+    fn graph_project(ctx: &mut Context, args: &[GramVal]) -> Result<Vec<GramVal>> {
+        let name = match args[0].project(ctx)? {
+            Val::String(s) => s.to_string(),
+            v => bail!("graph.project expects a graph name, got {:?}", v),
+        };
+        let config = args[1].project(ctx)?;
+        let names = |v: &Val, what| -> Result<HashSet<Token>> {
+            let mut tokens = ctx.tokens.borrow_mut();
+            match v {
+                Val::List(vs) => vs
+                    .iter()
+                    .map(|v| match v {
+                        Val::String(s) => Ok(tokens.tokenize(s)),
+                        v => bail!("graph.project expects {} to be strings, got {:?}", what, v),
+                    })
+                    .collect(),
+                v => bail!("graph.project expects {} to be a list, got {:?}", what, v),
+            }
+        };
+        let mut labels = None;
+        let mut rel_types = None;
+        let mut weight = None;
+        match config {
+            Val::Map(config) => {
+                for (key, v) in config.iter() {
+                    match (key.as_str(), v) {
+                        ("labels", v) => labels = Some(names(v, "labels")?),
+                        ("relTypes", v) => rel_types = Some(names(v, "relTypes")?),
+                        ("weight", Val::String(k)) => {
+                            weight = Some(ctx.tokens.borrow_mut().tokenize(k))
+                        }
+                        (key, v) => {
+                            bail!("graph.project has no {} option that takes {:?}", key, v)
+                        }
+                    }
+                }
+            }
+            Val::Null => (),
+            v => bail!("graph.project expects a config map, got {:?}", v),
+        }
+
+        let g = ctx.g.borrow();
+        let mut index = vec![None; g.nodes.len()];
+        let mut nodes = Vec::new();
+        for node in g.nodes.iter().filter(|n| !n.deleted) {
+            if let Some(labels) = &labels {
+                if node.labels.is_disjoint(labels) {
+                    continue;
+                }
+            }
+            index[node.id] = Some(nodes.len());
+            nodes.push(node.id);
+        }
+        ctx.db_hits += g.nodes.len() as u64;
+
+        let mut offsets = Vec::with_capacity(nodes.len() + 1);
+        let mut targets = Vec::new();
+        let mut weights = weight.map(|_| Vec::new());
+        offsets.push(0);
+        for id in &nodes {
+            for rel in &g.nodes[*id].rels {
+                ctx.db_hits += 1;
+                if rel.dir != Dir::Out {
+                    continue;
+                }
+                if let Some(rel_types) = &rel_types {
+                    if !rel_types.contains(&rel.rel_type) {
+                        continue;
+                    }
+                }
+                let target = match index[rel.other_node] {
+                    Some(target) => target,
+                    None => continue,
+                };
+                targets.push(target);
+                if let (Some(key), Some(weights)) = (weight, &mut weights) {
+                    ctx.db_hits += 1;
+                    let prop = rel.properties.get(&key).cloned();
+                    weights.push(match PropVal::read_opt(prop, &ctx.storage)? {
+                        Val::Null => 1.0,
+                        Val::Int(w) => w as f64,
+                        Val::Float(w) => w,
+                        v => bail!("graph.project expects weights to be numbers, got {:?}", v),
+                    });
+                }
+            }
+            offsets.push(targets.len());
+        }
+
+        let out = vec![
+            GramVal::Lit(Val::Int(nodes.len() as i64)),
+            GramVal::Lit(Val::Int(targets.len() as i64)),
+        ];
+        let projection = Projection {
+            nodes,
+            offsets,
+            targets,
+            weights,
+        };
+        ctx.projections
+            .borrow_mut()
+            .insert(name, Rc::new(projection));
+        Ok(out)
+    }
+
+    fn projection(ctx: &Context, proc: &str, name: &GramVal) -> Result<Rc<Projection>> {
+        let name = match name {
+            GramVal::Lit(Val::String(s)) => s,
+            v => bail!("{} expects a graph name, got {:?}", proc, v),
+        };
+        match ctx.projections.borrow().get(name.as_ref()) {
+            Some(p) => Ok(Rc::clone(p)),
+            None => bail!(
+                "there is no projected graph named '{}', make one with graph.project",
+                name
+            ),
+        }
+    }
+
+    // CALL algo.pageRank(graphName, config) YIELD node, score: how likely a random walk along
+    // the rels of the projection is to be at each node, with config.dampingFactor being the
+    // chance of following a rel rather than jumping to a random node, 0.85 by default, and
+    // config.iterations how many steps to take towards the answer, 20 by default. With a
+    // weighted projection, walks follow heavier rels more often. Scores add up to 1, less what's
+    // lost to nodes without outgoing rels.
+    fn page_rank(ctx: &mut Context, args: &[GramVal]) -> Result<Vec<Vec<GramVal>>> {
+        let projection = projection(ctx, "algo.pageRank", &args[0])?;
+        let mut iterations = 20;
+        let mut damping = 0.85;
+        match args[1].project(ctx)? {
+            Val::Map(config) => {
+                for (key, v) in config.iter() {
+                    match (key.as_str(), v) {
+                        ("iterations", Val::Int(n)) if *n >= 0 => iterations = *n,
+                        ("dampingFactor", Val::Float(d)) if (0.0..=1.0).contains(d) => damping = *d,
+                        (key, v) => {
+                            bail!("algo.pageRank has no {} option that takes {:?}", key, v)
+                        }
+                    }
+                }
+            }
+            Val::Null => (),
+            v => bail!("algo.pageRank expects a config map, got {:?}", v),
+        }
+
+        let n = projection.nodes.len();
+        let out_weight: Vec<f64> = (0..n)
+            .map(|i| projection.rels(i).map(|r| projection.weight(r)).sum())
+            .collect();
+        let mut scores = vec![1.0 / n as f64; n];
+        let mut next = vec![0.0; n];
+        for _ in 0..iterations {
+            next.iter_mut()
+                .for_each(|s| *s = (1.0 - damping) / n as f64);
+            for i in 0..n {
+                if out_weight[i] == 0.0 {
+                    continue;
+                }
+                let share = damping * scores[i] / out_weight[i];
+                for r in projection.rels(i) {
+                    next[projection.targets[r]] += share * projection.weight(r);
+                }
+            }
+            std::mem::swap(&mut scores, &mut next);
+        }
+
+        Ok(projection
+            .nodes
+            .iter()
+            .zip(scores)
+            .map(|(id, score)| vec![GramVal::Node { id: *id }, GramVal::Lit(Val::Float(score))])
+            .collect())
+    }
+
+    // CALL gen.graph(topology, nodes, edgesPerNode, config):
+    //
+    //   'barabasi'  each node links to edgesPerNode of the nodes before it, picking well-connected
+    //               ones more often, which gives the few-hubs-many-leaves shape of social graphs
+    //   'random'    nodes * edgesPerNode rels between nodes picked uniformly at random
+    //
+    // Nodes get the labels in config.labels, :Node by default, and an id property counting up
+    // from 0; rels are of type config.relType, LINK by default. The same config.seed gives the
+    // same graph every time.
+    fn gen_graph(ctx: &mut Context, args: &[GramVal]) -> Result<Vec<GramVal>> {
+        let topology = match args[0].project(ctx)? {
+            Val::String(s) => s,
+            v => bail!("gen.graph expects a topology name, got {:?}", v),
+        };
+        let count = |v: Val, what| match v {
+            Val::Int(n) if n >= 0 => Ok(n as usize),
+            v => Err(anyhow!(
+                "gen.graph expects {} to be a positive integer, got {:?}",
+                what,
+                v
+            )),
+        };
+        let nodes = count(args[1].project(ctx)?, "nodes")?;
+        let edges_per_node = count(args[2].project(ctx)?, "edgesPerNode")?;
+
+        let mut labels = vec!["Node".to_string()];
+        let mut rel_type = "LINK".to_string();
+        let mut seed = 0;
+        match args[3].project(ctx)? {
+            Val::Map(config) => {
+                for (key, v) in config.iter() {
+                    match (key.as_str(), v) {
+                        ("labels", Val::List(ls)) => {
+                            labels.clear();
+                            for l in ls.iter() {
+                                match l {
+                                    Val::String(l) => labels.push(l.to_string()),
+                                    l => {
+                                        bail!("gen.graph expects labels to be strings, got {:?}", l)
+                                    }
+                                }
+                            }
+                        }
+                        ("relType", Val::String(t)) => rel_type = t.to_string(),
+                        ("seed", Val::Int(s)) => seed = *s as u64,
+                        (key, v) => bail!("gen.graph has no {} option that takes {:?}", key, v),
+                    }
+                }
+            }
+            Val::Null => (),
+            v => bail!("gen.graph expects a config map, got {:?}", v),
+        }
+
+        let labels: HashSet<_> = {
+            let mut tokens = ctx.tokens.borrow_mut();
+            labels.iter().map(|l| tokens.tokenize(l)).collect()
+        };
+        let rel_type = ctx.tokens.borrow_mut().tokenize(&rel_type);
+        let id_key = ctx.tokens.borrow_mut().tokenize("id");
+
+        let mut ids = Vec::with_capacity(nodes);
+        for i in 0..nodes {
+            let mut props = HashMap::new();
+            props.insert(id_key, PropVal::Val(Val::Int(i as i64)));
+            match append_node(ctx, Rc::clone(&ctx.tokens), labels.clone(), props)? {
+                GramVal::Node { id } => ids.push(id),
+                v => unreachable!("appending a node gave {:?}", v),
+            }
+        }
+
+        let mut rng = SplitMix64(seed);
+        let mut rels = Vec::new();
+        match topology.as_ref() {
+            "barabasi" => {
+                // Every rel puts both its nodes in here, so picking from it picks nodes in
+                // proportion to how many rels they have
+                let mut ends: Vec<usize> = Vec::new();
+                let mut picked = Vec::with_capacity(edges_per_node);
+                for i in 1..nodes {
+                    picked.clear();
+                    if i <= edges_per_node {
+                        picked.extend(0..i);
+                    } else {
+                        // Every node before i has a rel by now, so there are enough to pick from
+                        while picked.len() < edges_per_node {
+                            let other = ends[rng.below(ends.len())];
+                            if !picked.contains(&other) {
+                                picked.push(other);
+                            }
+                        }
+                    }
+                    for other in &picked {
+                        rels.push((i, *other));
+                        ends.push(i);
+                        ends.push(*other);
+                    }
+                }
+            }
+            "random" => {
+                if nodes > 1 {
+                    for _ in 0..nodes * edges_per_node {
+                        let start = rng.below(nodes);
+                        // Anything but start, so there are no rels from a node to itself
+                        let end = (start + 1 + rng.below(nodes - 1)) % nodes;
+                        rels.push((start, end));
+                    }
+                }
+            }
+            _ => bail!(
+                "gen.graph knows the topologies 'barabasi' and 'random', not '{}'",
+                topology
+            ),
+        }
+        for (start, end) in &rels {
+            append_rel(ctx, ids[*start], ids[*end], rel_type, HashMap::new())?;
+        }
+
+        Ok(vec![
+            GramVal::Lit(Val::Int(nodes as i64)),
+            GramVal::Lit(Val::Int(rels.len() as i64)),
+        ])
+    }
+
+    fn schema_visualization(ctx: &mut Context) -> Result<Vec<GramVal>> {
+        let g = ctx.g.borrow();
+        let tokens = ctx.tokens.borrow();
+        let name = |tok| {
+            tokens
+                .lookup(tok)
+                .map(|s| s.to_string())
+                .ok_or_else(|| anyhow!("unknown token {}", tok))
+        };
+
+        // Sorted, so the meta-graph comes out the same every time
+        let mut labels = BTreeSet::new();
+        let mut combinations = BTreeSet::new();
+        for node in &g.nodes {
+            for label in &node.labels {
+                labels.insert(name(*label)?);
+            }
+            for rel in node.rels.iter().filter(|r| r.dir == super::Dir::Out) {
+                let other = &g.nodes[rel.other_node];
+                for start in &node.labels {
+                    for end in &other.labels {
+                        combinations.insert((name(*start)?, name(rel.rel_type)?, name(*end)?));
+                    }
+                }
+            }
+        }
+
+        // The meta-nodes aren't in the graph; their ids are their position in the list
+        let labels: Vec<String> = labels.into_iter().collect();
+        let id_of = |label: &String| labels.binary_search(label).unwrap_or_default();
+        let nodes = labels
+            .iter()
+            .enumerate()
+            .map(|(id, label)| {
+                GramVal::Lit(Val::Node(crate::Node {
+                    id,
+                    labels: vec![label.clone()],
+                    props: vec![("name".to_string(), Val::String(Arc::from(label.as_str())))],
+                }))
+            })
+            .collect();
+        let rels = combinations
+            .iter()
+            .enumerate()
+            .map(|(id, (start, rel_type, end))| {
+                GramVal::Lit(Val::Rel(crate::Rel {
+                    id,
+                    start: id_of(start),
+                    end: id_of(end),
+                    rel_type: rel_type.clone(),
+                    props: vec![],
+                }))
+            })
+            .collect();
+        Ok(vec![
+            GramVal::List(Rc::new(nodes)),
+            GramVal::List(Rc::new(rels)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rebuilds_stale_indexes() -> Result<()> {
+        let mut backend =
+            GramBackend::from_gram("(:Person {age: 1}) (:Person {age: 2}) (:Person)")?;
+        backend.create_index("Person", "age", None)?;
+        let health = backend.index_health();
+        assert_eq!((health[0].entries, health[0].expected), (2, 2));
+        assert!(!health[0].is_stale());
+
+        // Change a node behind the index's back, the way merge_node does while loading
+        let (person, age) = {
+            let mut tokens = backend.tokens.borrow_mut();
+            (tokens.tokenize("Person"), tokens.tokenize("age"))
+        };
+        backend.g.borrow_mut().nodes[2]
+            .properties
+            .insert(age, PropVal::Val(Val::Int(3)));
+        let health = backend.index_health();
+        assert_eq!((health[0].entries, health[0].expected), (2, 3));
+        assert!(health[0].is_stale());
+
+        backend.rebuild_index("Person", "age")?;
+        let health = backend.index_health();
+        assert!(!health[0].is_stale());
+        assert_eq!(health[0].rebuilds, 1);
+        let mut found = Vec::new();
+        let index = Rc::clone(backend.g.borrow().index(person, age).unwrap());
+        index
+            .borrow()
+            .seek(Some(&Val::Int(3)), Some(&Val::Int(3)), &mut found);
+        assert_eq!(found, vec![2]);
+        assert!(backend.rebuild_index("Person", "name").is_err());
+        Ok(())
+    }
+}
@@ -2,14 +2,19 @@
 // Backends implement the actual storage of graphs, and provide implementations of the
 // logical operators the frontend emits that can act on that storage.
 //
+use crate::frontend::fingerprint::fnv1a;
 use crate::frontend::LogicalPlan;
-use crate::{Error, Row, Type};
-use anyhow::Result;
-use std::cell::RefCell;
+use crate::metrics::{ExecutionStats, QuerySummary};
+use crate::{Change, Collation, Error, Row, RowVisitor, RunOptions, Type, Val};
+use anyhow::{bail, Result};
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::rc::Rc;
 
+// Called with the changes of each commit, see Backend::on_change
+pub type ChangeHook = Box<dyn FnMut(&[Change])>;
+
 // I don't know if any of this makes any sense, but the thoughts here is like.. lets make it
 // easy to build experimental backends, that can convert a logical plan tree into something that
 // can be executed. I've tried really hard to avoid making this trait have generics on it,
@@ -23,13 +28,110 @@ pub trait Backend: Debug {
 
     fn tokens(&self) -> Rc<RefCell<Tokens>>;
 
-    // Evaluate a logical plan and set the cursor up to process the result
-    fn eval(&mut self, plan: LogicalPlan, cursor: &mut Self::Cursor) -> Result<()>;
+    // Evaluate a logical plan and set the cursor up to process the result, with the $parameters
+    // the plan refers to bound to the given values
+    fn eval(
+        &mut self,
+        plan: LogicalPlan,
+        params: Params,
+        options: &RunOptions,
+        cursor: &mut Self::Cursor,
+    ) -> Result<()>;
 
     // Describe this backend for the frontends benefit
     fn describe(&self) -> Result<BackendDesc, Error>;
+
+    // A lot of queries are coming, and their writes may be committed together - once, at
+    // end_batch - rather than one query at a time; for bulk loads, where making each query
+    // durable on its own is slow. A query that fails still has its own writes undone.
+    fn begin_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Commit the writes of the queries run since begin_batch
+    fn end_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // Undo the writes of the queries run since begin_batch, committed or not, and end the batch
+    fn abort_batch(&mut self) -> Result<()> {
+        bail!("this backend can't roll back a batch")
+    }
+
+    // Fail queries with QueryError::LimitExceeded once any one of their operators has produced
+    // more than this many rows, see Database::set_max_intermediate_rows
+    fn set_max_intermediate_rows(&mut self, _rows: u64) {}
+
+    // Compare strings by the collation from here on, see collation.rs; describe() tells the
+    // planner which one it is. Indexes created without a collation of their own follow along.
+    fn set_collation(&mut self, collation: &Collation) -> Result<()> {
+        if !collation.is_binary() {
+            bail!("this backend only compares strings by code point")
+        }
+        Ok(())
+    }
+
+    // Call the hook with what each commit changed, once it's committed; see Database::on_change
+    fn on_change(&mut self, _hook: ChangeHook) -> Result<()> {
+        bail!("this backend can't report changes")
+    }
+
+    // Start keeping an index on a property of nodes with a label, see Database::create_index.
+    // Once this returns, describe() lists the index; until it returns, nothing does, and if it
+    // fails nothing should, so the planner never sees an index that's half built.
+    //
+    // Strings in the index collate by the given collation, or, without one, by the database's,
+    // also when that changes; see set_collation.
+    fn create_index(
+        &mut self,
+        _label: &str,
+        _property: &str,
+        _collation: Option<&Collation>,
+    ) -> Result<()> {
+        bail!("this backend does not support indexes")
+    }
+
+    // Stop keeping an index, see Database::drop_index. Queries already planned to seek the index
+    // should either be able to finish with it or fail, not find only some of what they seek.
+    fn drop_index(&mut self, _label: &str, _property: &str) -> Result<()> {
+        bail!("this backend does not support indexes")
+    }
+
+    // Build an index again from the nodes there are, see Database::rebuild_index. Like with
+    // create_index, queries don't see the new index until it's done.
+    fn rebuild_index(&mut self, _label: &str, _property: &str) -> Result<()> {
+        bail!("this backend does not support indexes")
+    }
+
+    // How each index is holding up, see Database::index_health
+    fn index_health(&self) -> Vec<IndexHealth> {
+        Vec::new()
+    }
+
+    // Delete nodes with the label once the point in time in the property has passed, see
+    // Database::expire_nodes. The property holds milliseconds since the Unix epoch; nodes where
+    // it's anything else, or missing, don't expire.
+    fn expire_nodes(&mut self, _label: &str, _property: &str) -> Result<()> {
+        bail!("this backend does not support expiry")
+    }
+
+    // Delete the nodes that expired at or before now, in milliseconds since the Unix epoch, along
+    // with their rels, all in one commit; gives back how many there were. Must not be called
+    // while queries are running, since they may have the nodes in their rows.
+    fn sweep_expired(&mut self, _now: i64) -> Result<QuerySummary> {
+        Ok(QuerySummary::default())
+    }
+
+    // How much data there is, as of now, for the planner to choose between plans by; None if
+    // the backend doesn't keep count
+    fn statistics(&self) -> Option<Statistics> {
+        None
+    }
 }
 
+// Values of the $parameters of a query, by parameter name
+pub type Params = HashMap<Token, Val>;
+
 // To allow each backend to own how values are represented, and to let them optimize
 // iteration to fit their own desires, backends describe this cursor interface that sits
 // just below a thin veil of the public API.
@@ -44,6 +146,56 @@ pub trait BackendCursor {
 
     // Move to the next record; if result is happy, you can access the record with the accessor methods
     fn next(&mut self) -> Result<Option<&Row>>;
+
+    // Move to the next record and hand it to the visitor, without copying its values out; gives
+    // back what the visitor returned, or None once the result is exhausted. Backends that can
+    // lend out their internal values should override this.
+    fn visit_next(&mut self, visitor: &mut dyn RowVisitor) -> Result<Option<bool>> {
+        match self.next()? {
+            Some(row) => Ok(Some(visitor.row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Move to the next record without making it available, for when only how many rows there are
+    // matters; gives back false once the result is exhausted. Backends that copy values out of
+    // their rows in next() should override this to skip that.
+    fn skip_next(&mut self) -> Result<bool> {
+        Ok(self.next()?.is_some())
+    }
+
+    // Make room for rows of at least this many slots up front, so running queries into this
+    // cursor doesn't need to grow its buffers
+    fn reserve(&mut self, slots: usize);
+
+    // Let go of the current result, if any, keeping allocated buffers around for the next query
+    fn reset(&mut self) -> Result<()>;
+
+    // Let go of the current result of a query that was killed; backends that can should throw
+    // away what it wrote, like they do for queries that fail
+    fn cancel(&mut self) -> Result<()> {
+        self.reset()
+    }
+
+    // Where the backend keeps the name of the operator the query evaluated into this cursor
+    // last pulled rows from, for as long as the query runs, if it keeps track; see RunningQuery
+    fn operator(&self) -> Option<Rc<Cell<Option<&'static str>>>> {
+        None
+    }
+
+    // Collect ExecutionStats for the queries evaluated into this cursor from now on. Backends
+    // that can't measure their operators ignore this, and have no stats to give.
+    fn collect_stats(&mut self) {}
+
+    // What the query evaluated into this cursor cost so far, if the cursor collects stats
+    fn stats(&self) -> Option<ExecutionStats> {
+        None
+    }
+
+    // What the query evaluated into this cursor wrote so far, if the backend keeps count
+    fn summary(&self) -> Option<QuerySummary> {
+        None
+    }
 }
 
 // Describes, for the frontend, the layout of the backend. This is intended to include things
@@ -53,11 +205,31 @@ pub trait BackendCursor {
 #[derive(Debug)]
 pub struct BackendDesc {
     pub functions: Vec<FuncSignature>,
+    // Procedures that can be invoked with CALL
+    pub procedures: Vec<ProcSignature>,
     // Fast lookup of functions that aggregate
     pub aggregates: HashSet<Token>,
+    // Indexes the backend maintains, which the planner may use to avoid scanning
+    pub indexes: Vec<IndexDesc>,
+    // Constraints the backend enforces, which the planner may rely on
+    pub constraints: Vec<ConstraintDesc>,
+    // The LogicalPlan operators this backend can execute, by LogicalPlan::name
+    pub operators: HashSet<&'static str>,
+    // The kinds of values this backend can store and compute with
+    pub types: TypeSupport,
+    // As of when the query being planned was planned, see Backend::statistics
+    pub statistics: Option<Statistics>,
+    // How the backend compares strings, see Backend::set_collation
+    pub collation: Collation,
+    // Identifies the indexes and constraints above, so plans can tell whether they were made
+    // for the schema there is now; see digest_schema
+    pub schema_digest: u64,
 }
 
 impl BackendDesc {
+    // Describe a backend with the given functions, that can execute every operator and handle
+    // every kind of value, but has no indexes or constraints. Backends that can do less, or more,
+    // adjust the description from there.
     pub fn new(functions: Vec<FuncSignature>) -> BackendDesc {
         let mut aggregates = HashSet::new();
         for f in &functions {
@@ -67,7 +239,233 @@ impl BackendDesc {
         }
         BackendDesc {
             functions,
+            procedures: Vec::new(),
             aggregates,
+            indexes: Vec::new(),
+            constraints: Vec::new(),
+            operators: LogicalPlan::OPERATORS.iter().copied().collect(),
+            types: TypeSupport::default(),
+            statistics: None,
+            collation: Collation::Binary,
+            schema_digest: 0,
+        }
+    }
+
+    // Work out schema_digest, once indexes and constraints are filled in. It goes by the names
+    // of labels and properties rather than their tokens, so it's the same for the same schema
+    // in another process, and by what's there rather than by the order it was created in.
+    // Collations only go in when they aren't binary, so schemas from before there were
+    // collations digest the same.
+    pub fn digest_schema(&mut self, t: &Tokens) {
+        let name = |tok: Token| t.lookup(tok).unwrap_or("?");
+        let collate = |c: &Collation| match c {
+            Collation::Binary => String::new(),
+            c => format!(" collate {}", c.name()),
+        };
+        let mut schema: Vec<String> = self
+            .indexes
+            .iter()
+            .map(|i| {
+                let kind = if i.ordered { "ordered" } else { "hashed" };
+                format!(
+                    "index {} :{}({}){}",
+                    kind,
+                    name(i.label),
+                    name(i.property),
+                    collate(&i.collation)
+                )
+            })
+            .chain(self.constraints.iter().map(|c| {
+                let kind = match c.kind {
+                    ConstraintKind::Unique => "unique",
+                    ConstraintKind::Exists => "exists",
+                };
+                format!(
+                    "constraint {} :{}({})",
+                    kind,
+                    name(c.label),
+                    name(c.property)
+                )
+            }))
+            .collect();
+        if !self.collation.is_binary() {
+            schema.push(format!("collation {}", self.collation.name()));
+        }
+        schema.sort();
+        self.schema_digest = fnv1a(schema.join("\n").as_bytes());
+    }
+
+    pub fn procedure(&self, name: Token) -> Option<&ProcSignature> {
+        self.procedures.iter().find(|p| p.name == name)
+    }
+
+    pub fn supports_operator(&self, name: &str) -> bool {
+        self.operators.contains(name)
+    }
+
+    // Find an index on the given label and property, if the backend has one
+    pub fn index(&self, label: Token, property: Token) -> Option<&IndexDesc> {
+        self.indexes
+            .iter()
+            .find(|i| i.label == label && i.property == property)
+    }
+
+    // The indexes on properties of nodes with the given label
+    pub fn indexes_on(&self, label: Token) -> impl Iterator<Item = &IndexDesc> {
+        self.indexes.iter().filter(move |i| i.label == label)
+    }
+
+    pub fn has_constraint(&self, kind: ConstraintKind, label: Token, property: Token) -> bool {
+        self.constraints
+            .iter()
+            .any(|c| c.kind == kind && c.label == label && c.property == property)
+    }
+}
+
+// An index on a property of nodes with a given label
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexDesc {
+    pub label: Token,
+    pub property: Token,
+    // Ordered indexes keep their entries sorted by value, so they can find the nodes in a range,
+    // like for n.age > 30, not just those with one given value
+    pub ordered: bool,
+    // How the index keeps strings, which may not be how the backend compares them
+    pub collation: Collation,
+}
+
+// Counts the planner goes by when there's more than one way to run a query, like scanning the
+// nodes with a label versus seeking an index on them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statistics {
+    pub nodes: u64,
+    // Nodes per label; labels no node has may be left out
+    pub label_counts: HashMap<Token, u64>,
+    pub rels: u64,
+    // Rels per type, like label_counts
+    pub rel_type_counts: HashMap<Token, u64>,
+    pub indexes: Vec<IndexStats>,
+    // What operators did when queries were profiled, by the operator as describe.rs writes it
+    // with its slots renumbered; backends leave this empty, the database fills it in. See
+    // Database::cardinality_feedback.
+    pub feedback: HashMap<String, Observed>,
+}
+
+impl Statistics {
+    pub fn nodes_with(&self, label: Token) -> u64 {
+        self.label_counts.get(&label).copied().unwrap_or(0)
+    }
+
+    pub fn rels_with(&self, rel_type: Token) -> u64 {
+        self.rel_type_counts.get(&rel_type).copied().unwrap_or(0)
+    }
+
+    pub fn index(&self, label: Token, property: Token) -> Option<&IndexStats> {
+        self.indexes
+            .iter()
+            .find(|i| i.label == label && i.property == property)
+    }
+}
+
+// What an operator did over the profiled runs of the queries it was part of, next to what the
+// planner thought it would do
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Observed {
+    pub runs: u64,
+    // Rows the operator got from its source, and rows it produced, over all the runs
+    pub rows_in: u64,
+    pub rows_out: u64,
+    // Rows the planner expected the operator to produce, over all the runs
+    pub estimated_rows: f64,
+}
+
+impl Observed {
+    // Rows produced per row in; for a Selection, the share of rows that pass it
+    pub fn rows_per_input(&self) -> f64 {
+        self.rows_out as f64 / self.rows_in.max(1) as f64
+    }
+
+    // How far off the planner was, as the factor between estimated and actual rows: 1.0 for
+    // spot on, 10.0 for ten times too many or too few
+    pub fn misestimate(&self) -> f64 {
+        misestimate(self.estimated_rows, self.rows_out as f64)
+    }
+}
+
+// The factor between an estimate and the actual number; one is added to both, so expecting
+// no rows and getting one is not infinitely wrong
+pub fn misestimate(estimated: f64, actual: f64) -> f64 {
+    let (estimated, actual) = (estimated.max(0.0) + 1.0, actual + 1.0);
+    estimated.max(actual) / estimated.min(actual)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStats {
+    pub label: Token,
+    pub property: Token,
+    // Nodes in the index, which is the nodes with the label that have the property
+    pub entries: u64,
+    // How many different values those nodes have between them
+    pub distinct: u64,
+}
+
+// How an index is holding up, see Database::index_health
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexHealth {
+    pub label: String,
+    pub property: String,
+    // Nodes in the index
+    pub entries: u64,
+    // Nodes with the label that have the property, as found by going through them; an index
+    // that doesn't have as many entries is stale
+    pub expected: u64,
+    // When the index was last built from scratch, in milliseconds since the Unix epoch; None if
+    // there was no clock to tell by, like on wasm32
+    pub built_at: Option<i64>,
+    // Times the index was built again since it was created, see Database::rebuild_index
+    pub rebuilds: u64,
+}
+
+impl IndexHealth {
+    // Has the index lost track of some of the nodes, or kept some it shouldn't have? Queries
+    // that seek a stale index may miss nodes; rebuild it, see Database::rebuild_index.
+    pub fn is_stale(&self) -> bool {
+        self.entries != self.expected
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstraintKind {
+    // No two nodes with the label have the same value for the property
+    Unique,
+    // All nodes with the label have the property
+    Exists,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstraintDesc {
+    pub kind: ConstraintKind,
+    pub label: Token,
+    pub property: Token,
+}
+
+// Which of the openCypher value types a backend supports; the simple ones, like integers and
+// strings, are assumed to be supported by everyone.
+#[derive(Debug, Clone)]
+pub struct TypeSupport {
+    pub float: bool,
+    pub list: bool,
+    pub map: bool,
+    pub path: bool,
+}
+
+impl Default for TypeSupport {
+    fn default() -> Self {
+        TypeSupport {
+            float: true,
+            list: true,
+            map: true,
+            path: true,
         }
     }
 }
@@ -112,6 +510,20 @@ pub struct FuncSignature {
     pub returns: Type,
     // Named arguments
     pub args: Vec<(Token, Type)>,
+    // How many of the last args can be left out, like the step of range(start, end, step)
+    pub optional: usize,
+}
+
+// Procedures are invoked with CALL, like CALL db.schema.visualization(). Unlike functions, they
+// yield rows rather than a value, each row having one value per output.
+#[derive(Debug, Clone)]
+pub struct ProcSignature {
+    // Fully qualified name, dots and all
+    pub name: Token,
+    pub args: Vec<(Token, Type)>,
+    pub outputs: Vec<(Token, Type)>,
+    // Does calling this change the graph? Read-only databases refuse to call ones that do
+    pub writes: bool,
 }
 
 // gql databases are filled with short string keys. Both things stored in the graph, like property
@@ -0,0 +1,308 @@
+//
+// How strings compare: which are equal, and which sort before which. A database compares strings
+// by one collation throughout - in WHERE clauses, ORDER BY, DISTINCT and grouping, min() and
+// max(), and the indexes on string properties - picked when it's opened:
+//
+//   let db = GramDatabase::options()
+//       .collation(Collation::CaseInsensitive)
+//       .open("graph.gram")?;
+//
+// An index can collate by another one, see Database::create_index_with_collation; the planner
+// then only has it find nodes for queries when it finds every node the database's collation
+// would.
+//
+// Every collation comes down to a key per string, with strings equal if their keys are, and
+// ordered by their keys; that's what indexes keep, and what rows are grouped by. Collations other
+// than the built in ones can be plugged in through Collator.
+//
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+
+#[derive(Clone, Default)]
+pub enum Collation {
+    // By unicode code point, same as comparing Rust strs; the default
+    #[default]
+    Binary,
+    // Like Binary, but with letters folded to lower case first, so 'Alice' = 'ALICE'
+    CaseInsensitive,
+    // The way a language sorts, by its tag, like "sv" or "de-CH"; see Collation::locale
+    #[cfg(feature = "locale-collation")]
+    Locale(Arc<str>),
+    Custom(Arc<dyn Collator>),
+}
+
+// A collation to plug in, like one backed by ICU
+pub trait Collator {
+    // What the collation is known as; collations with the same name must collate the same
+    fn name(&self) -> &str;
+
+    // The key a string compares by; see the top of this file
+    fn key(&self, s: &str) -> String;
+}
+
+impl Collation {
+    #[cfg(feature = "locale-collation")]
+    pub fn locale(tag: &str) -> Collation {
+        Collation::Locale(tag.into())
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Collation::Binary => "binary",
+            Collation::CaseInsensitive => "case_insensitive",
+            #[cfg(feature = "locale-collation")]
+            Collation::Locale(tag) => tag,
+            Collation::Custom(c) => c.name(),
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Collation::Binary)
+    }
+
+    pub fn key<'a>(&self, s: &'a str) -> Cow<'a, str> {
+        match self {
+            Collation::Binary => Cow::Borrowed(s),
+            // Folded a char at a time, like compare does; str::to_lowercase knows about the end
+            // of words, and would make the final sigma of 'ΑΣ' a 'ς' where compare has 'σ'
+            Collation::CaseInsensitive if s.chars().all(|c| c.to_lowercase().eq([c])) => {
+                Cow::Borrowed(s)
+            }
+            Collation::CaseInsensitive => {
+                Cow::Owned(s.chars().flat_map(char::to_lowercase).collect())
+            }
+            #[cfg(feature = "locale-collation")]
+            Collation::Locale(tag) => Cow::Owned(locale::key(tag, s)),
+            Collation::Custom(c) => Cow::Owned(c.key(s)),
+        }
+    }
+
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            Collation::CaseInsensitive => a
+                .chars()
+                .flat_map(char::to_lowercase)
+                .cmp(b.chars().flat_map(char::to_lowercase)),
+            _ => self.key(a).cmp(&self.key(b)),
+        }
+    }
+
+    pub fn equal(&self, a: &str, b: &str) -> bool {
+        self.compare(a, b) == Ordering::Equal
+    }
+
+    // Tells if an index kept in this collation can find the strings equal to a given one under
+    // the other collation. It may find more besides, which is fine, since the planner leaves the
+    // predicates in place to weed those out; it mustn't find fewer.
+    pub fn finds_equal(&self, other: &Collation) -> bool {
+        self == other || (matches!(self, Collation::CaseInsensitive) && other.is_binary())
+    }
+}
+
+impl PartialEq for Collation {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Debug for Collation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "Collation({})", self.name())
+    }
+}
+
+// A collation for languages written in the latin alphabet, without pulling in all of ICU: letters
+// compare by their base letter first, then by their accents, then by case, so a < á < A < b. Other
+// scripts compare by code point, after latin. Languages that sort some accented letters as
+// letters of their own, after z or right after their base letter, have them sorted that way;
+// other languages sort like English.
+#[cfg(feature = "locale-collation")]
+mod locale {
+    // Keys have the base letters of the whole string, then the accents, then the case, each
+    // level separated by this, which sorts before anything else, so shorter strings come first
+    const LEVEL: char = '\u{0}';
+    // Letters sorted as letters of their own are base letters followed by one of these, which
+    // sort after any other character, in the order the letters are listed in
+    const OWN_LETTER: u32 = 0x10FFF0;
+
+    pub fn key(tag: &str, s: &str) -> String {
+        let own_letters = own_letters(tag);
+        let mut base = String::with_capacity(s.len());
+        let mut accents = String::with_capacity(s.len());
+        let mut case = String::with_capacity(s.len());
+        for c in s.chars() {
+            let lower = c.to_lowercase().next().unwrap_or(c);
+            if let Some((i, (_, after))) = own_letters
+                .iter()
+                .enumerate()
+                .find(|(_, (letters, _))| letters.contains(lower))
+            {
+                base.push(*after);
+                base.push(char::from_u32(OWN_LETTER + i as u32).unwrap_or(char::MAX));
+                accents.push(accent_char(0));
+            } else {
+                match decompose(lower) {
+                    Some((letters, accent)) => {
+                        base.push_str(letters);
+                        accents.push(accent_char(accent));
+                    }
+                    None => {
+                        base.extend(c.to_lowercase());
+                        accents.push(accent_char(0));
+                    }
+                }
+            }
+            case.push(if c.is_uppercase() { '1' } else { '0' });
+        }
+        base.push(LEVEL);
+        base.push_str(&accents);
+        base.push(LEVEL);
+        base.push_str(&case);
+        base
+    }
+
+    fn accent_char(accent: u8) -> char {
+        char::from(b' ' + accent)
+    }
+
+    // Letters that sort as letters of their own, each group of letters sorting right after the
+    // given base letter, in order
+    fn own_letters(tag: &str) -> &'static [(&'static str, char)] {
+        let language = tag.split(['-', '_']).next().unwrap_or("");
+        match language.to_lowercase().as_str() {
+            "sv" | "fi" => &[("å", 'z'), ("äæ", 'z'), ("öø", 'z')],
+            "da" | "nb" | "nn" | "no" => &[("æä", 'z'), ("øö", 'z'), ("å", 'z')],
+            "es" => &[("ñ", 'n')],
+            _ => &[],
+        }
+    }
+
+    // The base letters and accent of a lower case latin letter
+    fn decompose(c: char) -> Option<(&'static str, u8)> {
+        const GRAVE: u8 = 1;
+        const ACUTE: u8 = 2;
+        const CIRCUMFLEX: u8 = 3;
+        const TILDE: u8 = 4;
+        const DIAERESIS: u8 = 5;
+        const RING: u8 = 6;
+        const CEDILLA: u8 = 7;
+        const STROKE: u8 = 8;
+        const CARON: u8 = 9;
+        const DOT: u8 = 10;
+        const OGONEK: u8 = 11;
+        Some(match c {
+            'à' => ("a", GRAVE),
+            'á' => ("a", ACUTE),
+            'â' => ("a", CIRCUMFLEX),
+            'ã' => ("a", TILDE),
+            'ä' => ("a", DIAERESIS),
+            'å' => ("a", RING),
+            'ą' => ("a", OGONEK),
+            'æ' => ("ae", 0),
+            'ç' => ("c", CEDILLA),
+            'č' => ("c", CARON),
+            'ð' => ("d", STROKE),
+            'è' => ("e", GRAVE),
+            'é' => ("e", ACUTE),
+            'ê' => ("e", CIRCUMFLEX),
+            'ë' => ("e", DIAERESIS),
+            'ę' => ("e", OGONEK),
+            'ě' => ("e", CARON),
+            'ì' => ("i", GRAVE),
+            'í' => ("i", ACUTE),
+            'î' => ("i", CIRCUMFLEX),
+            'ï' => ("i", DIAERESIS),
+            'ł' => ("l", STROKE),
+            'ñ' => ("n", TILDE),
+            'ń' => ("n", ACUTE),
+            'ò' => ("o", GRAVE),
+            'ó' => ("o", ACUTE),
+            'ô' => ("o", CIRCUMFLEX),
+            'õ' => ("o", TILDE),
+            'ö' => ("o", DIAERESIS),
+            'ø' => ("o", STROKE),
+            'œ' => ("oe", 0),
+            'ř' => ("r", CARON),
+            'ś' => ("s", ACUTE),
+            'š' => ("s", CARON),
+            'ş' => ("s", CEDILLA),
+            'ß' => ("ss", 0),
+            'þ' => ("th", 0),
+            'ù' => ("u", GRAVE),
+            'ú' => ("u", ACUTE),
+            'û' => ("u", CIRCUMFLEX),
+            'ü' => ("u", DIAERESIS),
+            'ů' => ("u", RING),
+            'ý' => ("y", ACUTE),
+            'ÿ' => ("y", DIAERESIS),
+            'ź' => ("z", ACUTE),
+            'ż' => ("z", DOT),
+            'ž' => ("z", CARON),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(collation: &Collation, words: &[&str]) -> Vec<String> {
+        let mut words: Vec<String> = words.iter().map(|w| w.to_string()).collect();
+        words.sort_by(|a, b| collation.compare(a, b));
+        words
+    }
+
+    #[test]
+    fn compares_by_key() {
+        let ci = Collation::CaseInsensitive;
+        assert!(ci.equal("Alice", "aLICE"));
+        assert_eq!(ci.key("Alice"), ci.key("ALICE"));
+        assert_eq!(sorted(&ci, &["b", "B", "a", "C"]), vec!["a", "b", "B", "C"]);
+        assert_eq!(
+            sorted(&Collation::Binary, &["b", "B", "a", "C"]),
+            vec!["B", "C", "a", "b"]
+        );
+
+        assert!(ci.equal("ΑΣ", "ασ"));
+        assert_eq!(ci.key("ΑΣ"), ci.key("ασ"));
+        assert_eq!(ci.key("ǅ"), ci.key("ǆ"));
+
+        // Keys order like the strings do
+        let words = [
+            "b", "B", "a", "C", "", "ab", "ΑΣ", "ασ", "ας", "İ", "i̇", "ǅ",
+        ];
+        for a in &words {
+            for b in &words {
+                assert_eq!(ci.key(a).cmp(&ci.key(b)), ci.compare(a, b), "{} {}", a, b);
+            }
+        }
+
+        assert!(Collation::CaseInsensitive.finds_equal(&Collation::Binary));
+        assert!(!Collation::Binary.finds_equal(&Collation::CaseInsensitive));
+    }
+
+    #[cfg(feature = "locale-collation")]
+    #[test]
+    fn sorts_like_the_language_does() {
+        let words = [
+            "zebra", "ängel", "Apa", "apa", "åsna", "öl", "año", "ano", "anz", "über",
+        ];
+        assert_eq!(
+            sorted(&Collation::locale("en"), &words),
+            vec!["ängel", "ano", "año", "anz", "apa", "Apa", "åsna", "öl", "über", "zebra"]
+        );
+        assert_eq!(
+            sorted(&Collation::locale("sv-SE"), &words),
+            vec!["ano", "año", "anz", "apa", "Apa", "über", "zebra", "åsna", "ängel", "öl"]
+        );
+        assert_eq!(
+            sorted(&Collation::locale("es"), &words),
+            vec!["ängel", "ano", "anz", "año", "apa", "Apa", "åsna", "öl", "über", "zebra"]
+        );
+        assert!(!Collation::locale("en").equal("apa", "Apa"));
+    }
+}
@@ -0,0 +1,112 @@
+//
+// Options for opening a database. There are getting to be enough knobs that handing them all to
+// open() stopped being an option, so instead you start from Database::options(), set the ones
+// you care about and open from there:
+//
+//   let mut db = GramDatabase::options()
+//       .durability(Durability::Buffered)
+//       .change_log(true)
+//       .open("graph.gram")?;
+//
+// Backends that don't have a use for a knob ignore it.
+//
+use crate::backend::Backend;
+use crate::{Collation, Database};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseConfig {
+    // How many query plans to keep around for re-use. Note that this is the only cache there is
+    // to size for now; the gram backend keeps the whole graph in memory
+    pub cache_size: usize,
+    pub durability: Durability,
+    // Open the database for reading only; queries that write fail with QueryError::ReadOnly
+    pub read_only: bool,
+    // Append writes to a change log next to the database file, rather than to the file itself;
+    // see GramBackend::open_with_log
+    pub change_log: bool,
+    // Threads the backend may use for work it can split up. The gram backend uses them to parse
+    // large files when the database is opened
+    pub threads: usize,
+    // See Database::set_query_limits
+    pub max_running_queries: usize,
+    pub max_queued_queries: usize,
+    // See Database::set_max_intermediate_rows
+    pub max_intermediate_rows: u64,
+    // How strings compare, see collation.rs
+    pub collation: Collation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    // A commit is synced to disk before the query that made it is done
+    Sync,
+    // Commits are handed to the OS, which writes them to disk when it sees fit. Much faster for
+    // lots of small writes, but a crash of the machine - not just of the process - can lose the
+    // last few commits.
+    Buffered,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            cache_size: 256,
+            durability: Durability::Sync,
+            read_only: false,
+            change_log: false,
+            threads: 1,
+            max_running_queries: usize::MAX,
+            max_queued_queries: usize::MAX,
+            max_intermediate_rows: u64::MAX,
+            collation: Collation::Binary,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn cache_size(mut self, plans: usize) -> Self {
+        self.cache_size = plans;
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn change_log(mut self, change_log: bool) -> Self {
+        self.change_log = change_log;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn max_queries(mut self, running: usize, queued: usize) -> Self {
+        self.max_running_queries = running;
+        self.max_queued_queries = queued;
+        self
+    }
+
+    pub fn max_intermediate_rows(mut self, rows: u64) -> Self {
+        self.max_intermediate_rows = rows;
+        self
+    }
+
+    pub fn collation(mut self, collation: Collation) -> Self {
+        self.collation = collation;
+        self
+    }
+}
+
+impl<T: Backend> Database<T> {
+    pub fn options() -> DatabaseConfig {
+        DatabaseConfig::default()
+    }
+}
@@ -0,0 +1,416 @@
+//
+// Converting between Rust values and Val, so parameters can be given as plain Rust values and
+// results read back into them, rather than matching on Val by hand:
+//
+//   let params = vec![("name".to_string(), "alice".into()), ("age".to_string(), 30.into())];
+//   let age: i64 = row.get_as("age")?;
+//
+// FromRow goes a step further and reads a whole row into a struct, see Database::query_as. It's
+// implemented for tuples, by column position, and from_row! implements it for structs whose
+// fields are named like the columns.
+//
+use crate::backend::Backend;
+use crate::{Database, Map, Node, QueryError, Rel, Result, RowView, Val};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+impl From<i64> for Val {
+    fn from(v: i64) -> Self {
+        Val::Int(v)
+    }
+}
+
+impl From<i32> for Val {
+    fn from(v: i32) -> Self {
+        Val::Int(v.into())
+    }
+}
+
+impl From<u32> for Val {
+    fn from(v: u32) -> Self {
+        Val::Int(v.into())
+    }
+}
+
+impl From<f64> for Val {
+    fn from(v: f64) -> Self {
+        Val::Float(v)
+    }
+}
+
+impl From<bool> for Val {
+    fn from(v: bool) -> Self {
+        Val::Bool(v)
+    }
+}
+
+impl From<&str> for Val {
+    fn from(v: &str) -> Self {
+        Val::String(v.into())
+    }
+}
+
+impl From<String> for Val {
+    fn from(v: String) -> Self {
+        Val::String(v.into())
+    }
+}
+
+impl From<Arc<str>> for Val {
+    fn from(v: Arc<str>) -> Self {
+        Val::String(v)
+    }
+}
+
+impl From<Node> for Val {
+    fn from(v: Node) -> Self {
+        Val::Node(v)
+    }
+}
+
+impl From<Rel> for Val {
+    fn from(v: Rel) -> Self {
+        Val::Rel(v)
+    }
+}
+
+// None is null
+impl<T: Into<Val>> From<Option<T>> for Val {
+    fn from(v: Option<T>) -> Self {
+        v.map_or(Val::Null, Into::into)
+    }
+}
+
+impl<T: Into<Val>> From<Vec<T>> for Val {
+    fn from(v: Vec<T>) -> Self {
+        Val::List(v.into_iter().map(Into::into).collect())
+    }
+}
+
+// Maps are kept in key order, so the same HashMap always makes the same Val
+impl<T: Into<Val>> From<HashMap<String, T>> for Val {
+    fn from(v: HashMap<String, T>) -> Self {
+        let mut map: Map = v.into_iter().map(|(k, v)| (k, v.into())).collect();
+        map.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Val::Map(Arc::new(map))
+    }
+}
+
+// A TypeError for a value that isn't what it was expected to be
+fn mismatch(expected: &str, v: &Val) -> crate::Error {
+    let got = match v {
+        Val::Null => "null",
+        Val::Int(_) => "an integer",
+        Val::Float(_) => "a float",
+        Val::String(_) => "a string",
+        Val::Bool(_) => "a boolean",
+        Val::Map(_) => "a map",
+        Val::List(_) => "a list",
+        Val::Node(_) => "a node",
+        Val::Rel(_) => "a relationship",
+    };
+    QueryError::TypeError {
+        message: format!("expected {}, got {}", expected, got),
+    }
+    .into()
+}
+
+impl TryFrom<Val> for i64 {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Int(i) => Ok(i),
+            v => Err(mismatch("an integer", &v)),
+        }
+    }
+}
+
+// Integers convert to floats too, since Cypher mixes them freely
+impl TryFrom<Val> for f64 {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Float(f) => Ok(f),
+            Val::Int(i) => Ok(i as f64),
+            v => Err(mismatch("a number", &v)),
+        }
+    }
+}
+
+impl TryFrom<Val> for bool {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Bool(b) => Ok(b),
+            v => Err(mismatch("a boolean", &v)),
+        }
+    }
+}
+
+impl TryFrom<Val> for String {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::String(s) => Ok(s.to_string()),
+            v => Err(mismatch("a string", &v)),
+        }
+    }
+}
+
+impl TryFrom<Val> for Arc<str> {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::String(s) => Ok(s),
+            v => Err(mismatch("a string", &v)),
+        }
+    }
+}
+
+impl TryFrom<Val> for Node {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Node(n) => Ok(n),
+            v => Err(mismatch("a node", &v)),
+        }
+    }
+}
+
+impl TryFrom<Val> for Rel {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Rel(r) => Ok(r),
+            v => Err(mismatch("a relationship", &v)),
+        }
+    }
+}
+
+// Null is None; anything else has to convert to T
+impl<T: TryFrom<Val, Error = crate::Error>> TryFrom<Val> for Option<T> {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Null => Ok(None),
+            v => T::try_from(v).map(Some),
+        }
+    }
+}
+
+impl<T: TryFrom<Val, Error = crate::Error>> TryFrom<Val> for Vec<T> {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::List(items) => items.iter().cloned().map(T::try_from).collect(),
+            v => Err(mismatch("a list", &v)),
+        }
+    }
+}
+
+impl<T: TryFrom<Val, Error = crate::Error>> TryFrom<Val> for HashMap<String, T> {
+    type Error = crate::Error;
+
+    fn try_from(v: Val) -> Result<Self> {
+        match v {
+            Val::Map(entries) => entries
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), T::try_from(v.clone())?)))
+                .collect(),
+            v => Err(mismatch("a map", &v)),
+        }
+    }
+}
+
+// A value that a result row can be read into, see Database::query_as
+pub trait FromRow: Sized {
+    fn from_row(row: &RowView) -> Result<Self>;
+}
+
+impl FromRow for RowView {
+    fn from_row(row: &RowView) -> Result<Self> {
+        Ok(row.clone())
+    }
+}
+
+impl FromRow for Vec<Val> {
+    fn from_row(row: &RowView) -> Result<Self> {
+        Ok(row.values().to_vec())
+    }
+}
+
+// Tuples take the columns in order, and need as many as they have elements
+macro_rules! tuple_from_row {
+    ($len:expr; $($t:ident $i:tt),+) => {
+        impl<$($t: TryFrom<Val, Error = crate::Error>),+> FromRow for ($($t,)+) {
+            fn from_row(row: &RowView) -> Result<Self> {
+                if row.values().len() != $len {
+                    bail!(QueryError::TypeError {
+                        message: format!(
+                            "expected {} columns, got {}",
+                            $len,
+                            row.values().len()
+                        )
+                    })
+                }
+                Ok(($(row.column_as::<$t>($i)?,)+))
+            }
+        }
+    };
+}
+
+tuple_from_row!(1; A 0);
+tuple_from_row!(2; A 0, B 1);
+tuple_from_row!(3; A 0, B 1, C 2);
+tuple_from_row!(4; A 0, B 1, C 2, D 3);
+tuple_from_row!(5; A 0, B 1, C 2, D 3, E 4);
+tuple_from_row!(6; A 0, B 1, C 2, D 3, E 4, F 5);
+
+// Implement FromRow for a struct, reading each of the listed fields from the column of the same
+// name; fields that are Options may have no column at all.
+//
+//   struct Person { name: String, age: Option<i64> }
+//   gqlite::from_row!(Person { name, age });
+//
+//   let people: Vec<Person> = db.query_as("MATCH (p:Person) RETURN p.name AS name, p.age AS age", &vec![])?;
+#[macro_export]
+macro_rules! from_row {
+    ($t:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::FromRow for $t {
+            fn from_row(row: &$crate::RowView) -> $crate::Result<Self> {
+                Ok($t {
+                    $($field: row.get_as(stringify!($field))?,)*
+                })
+            }
+        }
+    };
+}
+
+impl RowView {
+    // The value of the named column, converted to T; a missing column is null
+    pub fn get_as<T: TryFrom<Val, Error = crate::Error>>(&self, field: &str) -> Result<T> {
+        let v = self.get(field).cloned().unwrap_or(Val::Null);
+        T::try_from(v).map_err(|e| e.context(format!("column {}", field)))
+    }
+
+    // The value of the column at the given position, converted to T
+    pub fn column_as<T: TryFrom<Val, Error = crate::Error>>(&self, column: usize) -> Result<T> {
+        match self.values().get(column) {
+            Some(v) => T::try_from(v.clone()).map_err(|e| e.context(format!("column {}", column))),
+            None => bail!("there is no column {}", column),
+        }
+    }
+}
+
+impl<T: Backend> Database<T> {
+    // Run a query, reading each row of its result into an R; see FromRow
+    pub fn query_as<R: FromRow>(&mut self, query_str: &str, params: &Map) -> Result<Vec<R>> {
+        let mut cursor = self.new_cursor();
+        self.run_with_params(query_str, params, &mut cursor)?;
+        cursor
+            .rows()
+            .map(|row| row.and_then(|row| R::from_row(&row)))
+            .collect()
+    }
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use super::*;
+    use crate::gramdb::GramDatabase;
+    use crate::ErrorKind;
+
+    #[derive(Debug, PartialEq)]
+    struct Person {
+        name: String,
+        age: Option<i64>,
+        tags: Vec<String>,
+    }
+    from_row!(Person { name, age, tags });
+
+    #[test]
+    fn reads_results_into_rust_values() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        let params: Map = vec![
+            ("name".to_string(), "alice".into()),
+            ("age".to_string(), 30.into()),
+            ("tags".to_string(), vec!["a", "b"].into()),
+            ("none".to_string(), Option::<i64>::None.into()),
+        ];
+        db.query_as::<RowView>(
+            "CREATE ({name: $name, age: $age, tags: $tags, x: $none}), ({name: 'bob', tags: []})",
+            &params,
+        )?;
+
+        let people: Vec<Person> = db.query_as(
+            "MATCH (n) RETURN n.name AS name, n.age AS age, n.tags AS tags ORDER BY name",
+            &vec![],
+        )?;
+        assert_eq!(
+            people,
+            vec![
+                Person {
+                    name: "alice".into(),
+                    age: Some(30),
+                    tags: vec!["a".into(), "b".into()],
+                },
+                Person {
+                    name: "bob".into(),
+                    age: None,
+                    tags: vec![],
+                },
+            ]
+        );
+
+        let pairs: Vec<(String, f64)> = db.query_as(
+            "MATCH (n) WHERE n.name = 'alice' RETURN n.name, n.age",
+            &vec![],
+        )?;
+        assert_eq!(pairs, vec![("alice".to_string(), 30.0)]);
+
+        // Values that don't fit are TypeErrors, naming the column
+        let err = db
+            .query_as::<(i64,)>("RETURN 'x' AS x", &vec![])
+            .unwrap_err();
+        assert_eq!(crate::error::kind(&err), ErrorKind::TypeError);
+        assert_eq!(
+            format!("{:#}", err),
+            "column 0: expected an integer, got a string"
+        );
+        assert!(db.query_as::<(i64, i64)>("RETURN 1", &vec![]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn converts_values_both_ways() -> Result<()> {
+        let mut map = HashMap::new();
+        map.insert("b".to_string(), 2);
+        map.insert("a".to_string(), 1);
+        let v = Val::from(map.clone());
+        assert_eq!(
+            v,
+            Val::Map(Arc::new(vec![
+                ("a".to_string(), Val::Int(1)),
+                ("b".to_string(), Val::Int(2)),
+            ]))
+        );
+        assert_eq!(HashMap::<String, i64>::try_from(v)?, map);
+
+        let v = Val::from(vec![Some(1.5), None]);
+        assert_eq!(Vec::<Option<f64>>::try_from(v)?, vec![Some(1.5), None]);
+        assert!(bool::try_from(Val::from(true))?);
+        assert!(String::try_from(Val::Null).is_err());
+        assert!(Vec::<i64>::try_from(Val::from(vec!["a"])).is_err());
+        Ok(())
+    }
+}
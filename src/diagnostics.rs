@@ -0,0 +1,43 @@
+//
+// Diagnostics are messages about how queries are planned and executed, meant for whoever is
+// developing against gqlite rather than for end users. By default they go nowhere; set a sink
+// on the Database to receive them, or use StdoutDiagnostics to have them printed.
+//
+use crate::frontend::PlanDescription;
+use std::fmt::Debug;
+
+pub trait DiagnosticsSink: Debug {
+    // Called with the plan of each query once it's been planned
+    fn plan(&self, _query: &str, _plan: &PlanDescription) {}
+
+    // Called with things the planner thinks the user should know about the query, like that
+    // it's going to do something expensive
+    fn notification(&self, _query: &str, _notification: &Notification) {}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    // Stable identifier for the kind of notification, eg. "CartesianProduct"
+    pub code: &'static str,
+    pub message: String,
+}
+
+// Drops all diagnostics; the default
+#[derive(Debug, Default)]
+pub struct NoDiagnostics;
+
+impl DiagnosticsSink for NoDiagnostics {}
+
+// Prints all diagnostics to stdout
+#[derive(Debug, Default)]
+pub struct StdoutDiagnostics;
+
+impl DiagnosticsSink for StdoutDiagnostics {
+    fn plan(&self, _query: &str, plan: &PlanDescription) {
+        println!("plan: {}", plan);
+    }
+
+    fn notification(&self, _query: &str, notification: &Notification) {
+        println!("{}: {}", notification.code, notification.message);
+    }
+}
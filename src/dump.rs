@@ -0,0 +1,230 @@
+//
+// Writing the graph out as Cypher, for backups and for moving data to other tools: a script of
+// CREATE statements that recreates the graph in any database that speaks Cypher, including this
+// one, see Database::import_cypher.
+//
+// Each connected part of the graph is one statement, with made up identifiers - _0, _1 and so
+// on, after the node ids - tying rels to their endpoints:
+//
+//   CREATE (_0:Person {name: 'a'}),
+//     (_1:Person {name: 'b'}),
+//     (_0)-[:KNOWS {since: 2010}]->(_1);
+//   CREATE (_2:Car);
+//
+// The output is deterministic; nodes and rels come in id order, and labels and properties in
+// alphabetical order, so dumping the same graph twice gives the same script.
+//
+use crate::backend::Backend;
+use crate::{Database, Node, QueryError, Rel, Result, Val};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+impl<T: Backend> Database<T> {
+    // Write the whole graph to out as Cypher; the graph is read into memory to sort it out, so
+    // this needs room for a copy of it
+    pub fn dump(&mut self, mut out: impl Write) -> Result<()> {
+        let mut cursor = self.new_cursor();
+        let mut nodes = BTreeMap::new();
+        self.run("MATCH (n) RETURN n", &mut cursor)?;
+        while let Some(row) = cursor.next()? {
+            if let Val::Node(n) = &row.slots[0] {
+                nodes.insert(n.id, n.clone());
+            }
+        }
+        let mut rels = Vec::new();
+        self.run("MATCH ()-[r]->() RETURN r", &mut cursor)?;
+        while let Some(row) = cursor.next()? {
+            if let Val::Rel(r) = &row.slots[0] {
+                rels.push(r.clone());
+            }
+        }
+        rels.sort_by_key(|r| r.id);
+
+        // Group nodes and rels by the connected part of the graph they're in, keyed by the
+        // smallest node id in the part, so statements come out in node id order too
+        let mut parts = Parts::new(nodes.keys().copied());
+        for r in &rels {
+            parts.join(r.start, r.end);
+        }
+        let mut statements: BTreeMap<usize, (Vec<&Node>, Vec<&Rel>)> = BTreeMap::new();
+        for n in nodes.values() {
+            statements.entry(parts.find(n.id)).or_default().0.push(n);
+        }
+        for r in &rels {
+            statements.entry(parts.find(r.start)).or_default().1.push(r);
+        }
+
+        for (nodes, rels) in statements.values() {
+            let mut patterns = Vec::with_capacity(nodes.len() + rels.len());
+            for n in nodes {
+                patterns.push(node_pattern(n)?);
+            }
+            for r in rels {
+                patterns.push(rel_pattern(r)?);
+            }
+            writeln!(out, "CREATE {};", patterns.join(",\n  "))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+// Union-find over node ids, where each part is represented by its smallest id
+struct Parts {
+    parent: BTreeMap<usize, usize>,
+}
+
+impl Parts {
+    fn new(ids: impl Iterator<Item = usize>) -> Self {
+        Parts {
+            parent: ids.map(|id| (id, id)).collect(),
+        }
+    }
+
+    fn find(&mut self, id: usize) -> usize {
+        let mut root = id;
+        while let Some(&parent) = self.parent.get(&root) {
+            if parent == root {
+                break;
+            }
+            root = parent;
+        }
+        // Point everything on the way straight at the root, so the next find is quicker
+        let mut at = id;
+        while at != root {
+            at = self.parent.insert(at, root).unwrap_or(root);
+        }
+        root
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parent.insert(a.max(b), a.min(b));
+    }
+}
+
+fn node_pattern(n: &Node) -> Result<String> {
+    let mut out = format!("(_{}", n.id);
+    let mut labels: Vec<&String> = n.labels.iter().collect();
+    labels.sort();
+    for l in labels {
+        out.push(':');
+        out.push_str(&identifier(l));
+    }
+    if !n.props.is_empty() {
+        out.push(' ');
+        out.push_str(&props(&n.props)?);
+    }
+    out.push(')');
+    Ok(out)
+}
+
+fn rel_pattern(r: &Rel) -> Result<String> {
+    let mut out = format!("(_{})-[:{}", r.start, identifier(&r.rel_type));
+    if !r.props.is_empty() {
+        out.push(' ');
+        out.push_str(&props(&r.props)?);
+    }
+    out.push_str(&format!("]->(_{})", r.end));
+    Ok(out)
+}
+
+fn props(props: &[(String, Val)]) -> Result<String> {
+    let mut sorted: Vec<&(String, Val)> = props.iter().collect();
+    sorted.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut entries = Vec::with_capacity(sorted.len());
+    for (k, v) in sorted {
+        entries.push(format!("{}: {}", identifier(k), literal(v)?));
+    }
+    Ok(format!("{{{}}}", entries.join(", ")))
+}
+
+// Identifiers are only quoted if they need to be
+fn identifier(id: &str) -> String {
+    let mut chars = id.chars();
+    let plain = match chars.next() {
+        Some(c) => {
+            (c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if plain {
+        id.to_string()
+    } else {
+        format!("`{}`", id.replace('`', "``"))
+    }
+}
+
+// A value as a Cypher literal
+fn literal(v: &Val) -> Result<String> {
+    Ok(match v {
+        Val::Null => "null".to_string(),
+        Val::Int(v) => v.to_string(),
+        // Debug formatting always includes a decimal point or exponent, so this reads back as
+        // a float
+        Val::Float(v) if v.is_finite() => format!("{:?}", v),
+        Val::Bool(v) => v.to_string(),
+        Val::String(s) => {
+            let mut out = String::with_capacity(s.len() + 2);
+            out.push('\'');
+            for c in s.chars() {
+                match c {
+                    '\\' => out.push_str("\\\\"),
+                    '\'' => out.push_str("\\'"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    c => out.push(c),
+                }
+            }
+            out.push('\'');
+            out
+        }
+        Val::List(vs) => {
+            let items = vs.iter().map(literal).collect::<Result<Vec<_>>>()?;
+            format!("[{}]", items.join(", "))
+        }
+        Val::Map(entries) => props(entries)?,
+        _ => bail!(QueryError::TypeError {
+            message: format!("{:?} can't be written as a Cypher literal", v)
+        }),
+    })
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use crate::gramdb::GramDatabase;
+    use crate::Result;
+
+    fn dump(db: &mut GramDatabase) -> Result<String> {
+        let mut out = Vec::new();
+        db.dump(&mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn dumps_the_graph_as_cypher() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        db.import_cypher(
+            "CREATE (a:Person:Admin {name: 'it\\'s', score: 1.0}), (b:Person), (a)-[:KNOWS {since: 2010}]->(b);
+             CREATE (:`Odd Label` {`odd key`: true, x: -1});"
+                .as_bytes(),
+        )?;
+        let script = dump(&mut db)?;
+        assert_eq!(
+            script,
+            "CREATE (_0:Admin:Person {name: 'it\\'s', score: 1.0}),
+  (_1:Person),
+  (_0)-[:KNOWS {since: 2010}]->(_1);
+CREATE (_2:`Odd Label` {`odd key`: true, x: -1});
+"
+        );
+
+        // And back again
+        let mut copy = GramDatabase::in_memory()?;
+        assert_eq!(copy.import_cypher(script.as_bytes())?, 2);
+        assert_eq!(dump(&mut copy)?, script);
+        Ok(())
+    }
+}
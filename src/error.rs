@@ -0,0 +1,221 @@
+//
+// Errors in gqlite are anyhow::Errors, so they can pick up context on their way out. The ones a
+// caller may want to handle, rather than just report, carry a QueryError; kind() tells you what
+// class of failure any error is, wherever in the chain the cause sits.
+//
+use crate::frontend::Rule;
+use pest::error::{ErrorVariant, InputLocation, LineColLocation};
+use std::fmt::{self, Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryError {
+    // The query is not valid Cypher
+    SyntaxError { message: String, span: Span },
+    // The query is valid Cypher, but doesn't make sense; like two columns with the same name
+    SemanticError { message: String, span: Option<Span> },
+    // Running the query would break a constraint
+    ConstraintViolation { message: String },
+    // An operation was given values it can't work with, like subtracting a string from a number
+    TypeError { message: String },
+    // The query was stopped before it finished
+    Cancelled,
+    // The query writes, but the database was opened read-only
+    ReadOnly,
+    // The database is running as many queries as it's allowed to, and has as many queued up as
+    // it's allowed to; try again later
+    Overloaded,
+    // The query went past a limit on how much work a query may do, like
+    // DatabaseConfig::max_intermediate_rows
+    LimitExceeded { message: String },
+}
+
+impl QueryError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            QueryError::SyntaxError { span, .. } => Some(*span),
+            QueryError::SemanticError { span, .. } => *span,
+            _ => None,
+        }
+    }
+
+    pub(crate) fn semantic(message: String, at: pest::Span) -> Self {
+        QueryError::SemanticError {
+            message,
+            span: Some(Span::of(at)),
+        }
+    }
+
+    pub(crate) fn syntax(err: pest::error::Error<Rule>) -> Self {
+        let (start, end) = match err.location {
+            InputLocation::Pos(p) => (p, p),
+            InputLocation::Span(span) => span,
+        };
+        let (line, column) = match err.line_col {
+            LineColLocation::Pos(lc) => lc,
+            LineColLocation::Span(lc, _) => lc,
+        };
+        let message = match err.variant {
+            ErrorVariant::ParsingError { positives, .. } if !positives.is_empty() => {
+                let expected: Vec<String> = positives.iter().map(|r| format!("{:?}", r)).collect();
+                format!("invalid syntax, expected {}", expected.join(" or "))
+            }
+            ErrorVariant::ParsingError { .. } => "invalid syntax".to_string(),
+            ErrorVariant::CustomError { message } => message,
+        };
+        QueryError::SyntaxError {
+            message,
+            span: Span {
+                start,
+                end,
+                line,
+                column,
+            },
+        }
+    }
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::SyntaxError { message, span }
+            | QueryError::SemanticError {
+                message,
+                span: Some(span),
+            } => write!(
+                f,
+                "{} (line {}, column {})",
+                message, span.line, span.column
+            ),
+            QueryError::SemanticError { message, .. }
+            | QueryError::ConstraintViolation { message }
+            | QueryError::TypeError { message }
+            | QueryError::LimitExceeded { message } => write!(f, "{}", message),
+            QueryError::Cancelled => write!(f, "query was cancelled"),
+            QueryError::ReadOnly => write!(f, "the database is read-only"),
+            QueryError::Overloaded => write!(f, "too many queries are waiting to run"),
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+// Where in the query text an error is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    // Byte offsets into the query
+    pub start: usize,
+    pub end: usize,
+    // Where start is, counting from 1
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub(crate) fn of(span: pest::Span) -> Self {
+        let (line, column) = span.start_pos().line_col();
+        Span {
+            start: span.start(),
+            end: span.end(),
+            line,
+            column,
+        }
+    }
+
+    // The line of the query the span starts on, with carets under the span, like
+    //
+    //   MATCH (n) RETURN n.name AS x, n.age AS x
+    //                                 ^^^^^^^^^^
+    pub fn caret(&self, query: &str) -> String {
+        let line_start = query[..self.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = query[self.start..]
+            .find('\n')
+            .map_or(query.len(), |i| self.start + i);
+        let line = &query[line_start..line_end];
+        let indent = query[line_start..self.start].chars().count();
+        let width = query[self.start..self.end.min(line_end)].chars().count();
+        format!(
+            "{}\n{}{}",
+            line,
+            " ".repeat(indent),
+            "^".repeat(width.max(1))
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    SyntaxError,
+    SemanticError,
+    ConstraintViolation,
+    TypeError,
+    IoError,
+    // Stored data failed verification
+    Corruption,
+    Cancelled,
+    ReadOnly,
+    Overloaded,
+    LimitExceeded,
+    // Everything else; usually something that isn't supported yet, or a bug
+    Other,
+}
+
+pub fn kind(err: &anyhow::Error) -> ErrorKind {
+    for cause in err.chain() {
+        if let Some(e) = cause.downcast_ref::<QueryError>() {
+            return match e {
+                QueryError::SyntaxError { .. } => ErrorKind::SyntaxError,
+                QueryError::SemanticError { .. } => ErrorKind::SemanticError,
+                QueryError::ConstraintViolation { .. } => ErrorKind::ConstraintViolation,
+                QueryError::TypeError { .. } => ErrorKind::TypeError,
+                QueryError::Cancelled => ErrorKind::Cancelled,
+                QueryError::ReadOnly => ErrorKind::ReadOnly,
+                QueryError::Overloaded => ErrorKind::Overloaded,
+                QueryError::LimitExceeded { .. } => ErrorKind::LimitExceeded,
+            };
+        }
+        if cause.is::<std::io::Error>() {
+            return ErrorKind::IoError;
+        }
+        #[cfg(feature = "gram")]
+        {
+            if cause.is::<crate::backend::gram::CorruptionError>() {
+                return ErrorKind::Corruption;
+            }
+        }
+    }
+    ErrorKind::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendDesc, Tokens};
+    use crate::diagnostics::NoDiagnostics;
+    use crate::frontend::Frontend;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn reports_where_in_the_query_things_went_wrong() {
+        let frontend = Frontend {
+            tokens: Rc::new(RefCell::new(Tokens::new())),
+            backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        };
+
+        let query = "MATCH (n)\nRETURN n.name AS x, n.age AS x";
+        let err = frontend.plan(query).unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::SemanticError);
+        let span = err.downcast_ref::<QueryError>().unwrap().span().unwrap();
+        assert_eq!((span.line, span.column), (2, 21));
+        assert_eq!(
+            span.caret(query),
+            "RETURN n.name AS x, n.age AS x\n                    ^^^^^^^^^^"
+        );
+
+        let err = frontend.plan("MATCH (n) RETURN n.name AS").unwrap_err();
+        assert_eq!(kind(&err), ErrorKind::SyntaxError);
+        assert_eq!(kind(&anyhow!("no idea")), ErrorKind::Other);
+    }
+}
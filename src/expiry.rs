@@ -0,0 +1,80 @@
+//
+// Nodes that delete themselves once their time is up, for session stores and caches kept in the
+// graph. The label and property nodes expire by are registered with the backend:
+//
+//   db.expire_nodes("Session", "expiresAt")?;
+//   db.run("CREATE (:Session {token: 'abc', expiresAt: 1700000000000})", &mut cursor)?;
+//
+// and any :Session whose expiresAt, in milliseconds since the Unix epoch, is at or before now is
+// deleted, with its rels, by the next sweep. Each sweep deletes everything that expired in one
+// commit, so hooks see it as one change, and a failed sweep deletes nothing.
+//
+// There's no background thread to sweep with, since a Database isn't shared between threads;
+// either call sweep_expired every so often, or have the database sweep before each query it
+// runs, see set_sweep_on_read. Until they're swept, expired nodes are still there for queries to
+// find.
+//
+use crate::backend::Backend;
+use crate::metrics::QuerySummary;
+use crate::{Database, QueryError, Result};
+
+impl<T: Backend> Database<T> {
+    // Have nodes with the label deleted by sweeps once the time in the property has passed. The
+    // property is milliseconds since the Unix epoch; nodes where it's missing or not an integer
+    // never expire. Like indexes, this needs doing each time the database is opened.
+    pub fn expire_nodes(&mut self, label: &str, property: &str) -> Result<()> {
+        if self.read_only {
+            bail!(QueryError::ReadOnly)
+        }
+        self.backend.expire_nodes(label, property)
+    }
+
+    // Delete the nodes that have expired, and their rels; gives back how many there were
+    pub fn sweep_expired(&mut self) -> Result<QuerySummary> {
+        match now_millis() {
+            Some(now) => self.sweep_expired_at(now),
+            None => bail!("there is no clock to tell what has expired by, use sweep_expired_at"),
+        }
+    }
+
+    // Delete the nodes that expired at or before now, in milliseconds since the Unix epoch
+    pub fn sweep_expired_at(&mut self, now: i64) -> Result<QuerySummary> {
+        if self.read_only {
+            bail!(QueryError::ReadOnly)
+        }
+        // Queries may have the nodes in their rows, and deleting them would pull them out from
+        // under them
+        if !self.scheduler.borrow().is_idle() {
+            bail!("can't sweep for expired nodes while queries are running")
+        }
+        self.backend.sweep_expired(now)
+    }
+
+    // Sweep for expired nodes before running each query, so queries never see them; the sweep
+    // is skipped when other queries are still running, or there's no clock to go by.
+    pub fn set_sweep_on_read(&mut self, enabled: bool) {
+        self.sweep_on_read = enabled;
+    }
+
+    // The sweep for set_sweep_on_read
+    pub(crate) fn sweep_before_query(&mut self) -> Result<()> {
+        if !self.sweep_on_read || self.read_only || !self.scheduler.borrow().is_idle() {
+            return Ok(());
+        }
+        if let Some(now) = now_millis() {
+            self.backend.sweep_expired(now)?;
+        }
+        Ok(())
+    }
+}
+
+// On wasm32 there's no clock we can read without going through javascript
+pub(crate) fn now_millis() -> Option<i64> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_millis() as i64);
+    #[cfg(target_arch = "wasm32")]
+    return None;
+}
@@ -0,0 +1,174 @@
+//
+// Convert query results into Arrow RecordBatches, so they can be handed to dataframe libraries
+// and analytics pipelines as columns, rather than having users re-assemble them row by row.
+//
+use crate::backend::Backend;
+use crate::{Cursor, Result, Val};
+use ::arrow::array::{ArrayRef, BooleanArray, Float64Array, Int64Array, NullArray, StringArray};
+use ::arrow::datatypes::{DataType, Field, Schema};
+use ::arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use std::io::Write;
+use std::sync::Arc;
+
+// Drain the cursor into a single RecordBatch, one column per result field.
+//
+// Column types are inferred from the values: integers, floats, strings and booleans map to
+// their Arrow equivalents, a column mixing integers and floats becomes Float64 and a column
+// that is entirely null becomes Null. Anything else - maps, lists, nodes, rels or columns
+// mixing incompatible types - is written as Utf8, using the same formatting as the `g` CLI.
+pub fn to_record_batch<B: Backend>(cursor: &mut Cursor<B>) -> Result<RecordBatch> {
+    let fields = cursor.fields();
+    let mut columns: Vec<Vec<Val>> = vec![Vec::new(); fields.len()];
+    while let Some(row) = cursor.next()? {
+        for (column, val) in columns.iter_mut().zip(row.slots.iter()) {
+            column.push(val.clone());
+        }
+    }
+
+    let mut schema = Vec::with_capacity(fields.len());
+    let mut arrays = Vec::with_capacity(fields.len());
+    for (name, column) in fields.into_iter().zip(columns) {
+        let data_type = infer_type(&column);
+        schema.push(Field::new(name, data_type.clone(), true));
+        arrays.push(to_array(&data_type, column));
+    }
+
+    Ok(RecordBatch::try_new(Arc::new(Schema::new(schema)), arrays)?)
+}
+
+// Drain the cursor and write the result as a Parquet file, see to_record_batch for how
+// values are mapped to columns.
+pub fn write_parquet<B: Backend, W: Write + Send>(cursor: &mut Cursor<B>, out: W) -> Result<()> {
+    let batch = to_record_batch(cursor)?;
+    let mut writer = ArrowWriter::try_new(out, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+fn infer_type(column: &[Val]) -> DataType {
+    let mut inferred = DataType::Null;
+    for val in column {
+        let val_type = match val {
+            Val::Null => continue,
+            Val::Int(_) => DataType::Int64,
+            Val::Float(_) => DataType::Float64,
+            Val::Bool(_) => DataType::Boolean,
+            _ => DataType::Utf8,
+        };
+        inferred = match (inferred, val_type) {
+            (DataType::Null, t) => t,
+            (a, b) if a == b => a,
+            (DataType::Int64, DataType::Float64) | (DataType::Float64, DataType::Int64) => {
+                DataType::Float64
+            }
+            _ => DataType::Utf8,
+        }
+    }
+    inferred
+}
+
+fn to_array(data_type: &DataType, column: Vec<Val>) -> ArrayRef {
+    match data_type {
+        DataType::Int64 => Arc::new(
+            column
+                .into_iter()
+                .map(|v| match v {
+                    Val::Int(i) => Some(i),
+                    _ => None,
+                })
+                .collect::<Int64Array>(),
+        ),
+        DataType::Float64 => Arc::new(
+            column
+                .into_iter()
+                .map(|v| match v {
+                    Val::Float(f) => Some(f),
+                    Val::Int(i) => Some(i as f64),
+                    _ => None,
+                })
+                .collect::<Float64Array>(),
+        ),
+        DataType::Boolean => Arc::new(
+            column
+                .into_iter()
+                .map(|v| match v {
+                    Val::Bool(b) => Some(b),
+                    _ => None,
+                })
+                .collect::<BooleanArray>(),
+        ),
+        DataType::Utf8 => Arc::new(
+            column
+                .into_iter()
+                .map(|v| match v {
+                    Val::Null => None,
+                    Val::String(s) => Some(s.to_string()),
+                    v => Some(format!("{}", v)),
+                })
+                .collect::<StringArray>(),
+        ),
+        _ => Arc::new(NullArray::new(column.len())),
+    }
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use super::*;
+    use crate::gramdb::GramDatabase;
+    use ::arrow::array::Array;
+
+    #[test]
+    fn converts_results_to_columns() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        let mut cursor = db.new_cursor();
+        db.run(
+            "UNWIND [1, 2, 3] AS x RETURN x, 1.5 AS y, 'n' AS z",
+            &mut cursor,
+        )?;
+
+        let batch = to_record_batch(&mut cursor)?;
+
+        assert_eq!(batch.num_rows(), 3);
+        let schema = batch.schema();
+        let types: Vec<&DataType> = schema.fields().iter().map(|f| f.data_type()).collect();
+        assert_eq!(
+            types,
+            vec![&DataType::Int64, &DataType::Float64, &DataType::Utf8]
+        );
+        let z = batch
+            .column(2)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(z.value(2), "n");
+        Ok(())
+    }
+
+    #[test]
+    fn writes_parquet() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        let mut cursor = db.new_cursor();
+        db.run("UNWIND [1, 2, 3] AS x RETURN x", &mut cursor)?;
+
+        let mut out = Vec::new();
+        write_parquet(&mut cursor, &mut out)?;
+
+        assert_eq!(&out[..4], b"PAR1");
+        Ok(())
+    }
+
+    #[test]
+    fn widens_mixed_numbers_and_falls_back_to_strings() {
+        assert_eq!(
+            infer_type(&[Val::Int(1), Val::Null, Val::Float(0.5)]),
+            DataType::Float64
+        );
+        assert_eq!(
+            infer_type(&[Val::Int(1), Val::String("a".into())]),
+            DataType::Utf8
+        );
+        assert_eq!(infer_type(&[Val::Null]), DataType::Null);
+    }
+}
@@ -0,0 +1,150 @@
+//
+// Export query results as JSON graphs - arrays of nodes and edges - in the shapes Cytoscape.js
+// and D3 force layouts expect, for quickly visualizing a result in a web frontend.
+//
+use super::Subgraph;
+use crate::backend::Backend;
+use crate::{Cursor, Map, Node, Rel, Result, Val};
+use json::JsonValue;
+
+// Drain the cursor into a Cytoscape.js elements definition:
+//
+//   {"elements": {
+//     "nodes": [{"data": {"id": "n0", "labels": ["Person"], "properties": {..}}}],
+//     "edges": [{"data": {"id": "e0", "source": "n0", "target": "n1", "label": "KNOWS", "properties": {..}}}]
+//   }}
+//
+// Cytoscape wants string ids that are unique across nodes and edges, hence the prefixes. See
+// Subgraph for how duplicates and rels with endpoints missing from the result are handled.
+pub fn to_cytoscape_json<B: Backend>(cursor: &mut Cursor<B>) -> Result<JsonValue> {
+    let sg = Subgraph::collect(cursor)?;
+    let mut nodes = JsonValue::new_array();
+    for n in &sg.nodes {
+        let mut data = node_json(n);
+        data["id"] = format!("n{}", n.id).into();
+        nodes.push(object("data", data))?;
+    }
+    let mut edges = JsonValue::new_array();
+    for r in &sg.rels {
+        let mut data = JsonValue::new_object();
+        data["id"] = format!("e{}", r.id).into();
+        data["source"] = format!("n{}", r.start).into();
+        data["target"] = format!("n{}", r.end).into();
+        data["label"] = r.rel_type.as_str().into();
+        data["properties"] = map_json(&r.props);
+        edges.push(object("data", data))?;
+    }
+
+    let mut elements = JsonValue::new_object();
+    elements["nodes"] = nodes;
+    elements["edges"] = edges;
+    Ok(object("elements", elements))
+}
+
+// Drain the cursor into the nodes-and-links shape used by d3-force:
+//
+//   {"nodes": [{"id": 0, "labels": ["Person"], "properties": {..}}],
+//    "links": [{"id": 0, "source": 0, "target": 1, "type": "KNOWS", "properties": {..}}]}
+//
+// See Subgraph for how duplicates and rels with endpoints missing from the result are handled.
+pub fn to_d3_json<B: Backend>(cursor: &mut Cursor<B>) -> Result<JsonValue> {
+    let sg = Subgraph::collect(cursor)?;
+    let mut nodes = JsonValue::new_array();
+    for n in &sg.nodes {
+        nodes.push(node_json(n))?;
+    }
+    let mut links = JsonValue::new_array();
+    for r in &sg.rels {
+        links.push(rel_json(r))?;
+    }
+
+    let mut out = JsonValue::new_object();
+    out["nodes"] = nodes;
+    out["links"] = links;
+    Ok(out)
+}
+
+fn node_json(n: &Node) -> JsonValue {
+    let mut out = JsonValue::new_object();
+    out["id"] = n.id.into();
+    out["labels"] = n.labels.clone().into();
+    out["properties"] = map_json(&n.props);
+    out
+}
+
+fn rel_json(r: &Rel) -> JsonValue {
+    let mut out = JsonValue::new_object();
+    out["id"] = r.id.into();
+    out["source"] = r.start.into();
+    out["target"] = r.end.into();
+    out["type"] = r.rel_type.as_str().into();
+    out["properties"] = map_json(&r.props);
+    out
+}
+
+fn map_json(m: &Map) -> JsonValue {
+    let mut out = JsonValue::new_object();
+    for (k, v) in m {
+        out[k.as_str()] = val_json(v);
+    }
+    out
+}
+
+fn val_json(v: &Val) -> JsonValue {
+    match v {
+        Val::Null => JsonValue::Null,
+        Val::Int(i) => (*i).into(),
+        Val::Float(f) => (*f).into(),
+        Val::String(s) => s.as_ref().into(),
+        Val::Bool(b) => (*b).into(),
+        Val::Map(m) => map_json(m),
+        Val::List(vs) => JsonValue::Array(vs.iter().map(val_json).collect()),
+        Val::Node(n) => node_json(n),
+        Val::Rel(r) => rel_json(r),
+    }
+}
+
+fn object(key: &str, val: JsonValue) -> JsonValue {
+    let mut out = JsonValue::new_object();
+    out[key] = val;
+    out
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use super::*;
+    use crate::gramdb::GramDatabase;
+
+    #[test]
+    fn exports_cytoscape_and_d3_json() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        let mut cursor = db.new_cursor();
+        db.run(
+            "CREATE (a:Person {name: 'a'})-[:KNOWS {since: 2010}]->(b:Person {name: 'b'})",
+            &mut cursor,
+        )?;
+        while cursor.next()?.is_some() {}
+
+        db.run("MATCH (a)-[r]->(b) RETURN a, r, b", &mut cursor)?;
+        let cy = to_cytoscape_json(&mut cursor)?;
+        let nodes = &cy["elements"]["nodes"];
+        let edge = &cy["elements"]["edges"][0]["data"];
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0]["data"]["labels"][0], "Person");
+        assert_eq!(edge["label"], "KNOWS");
+        assert_eq!(edge["properties"]["since"], 2010);
+        assert_eq!(edge["source"], nodes[0]["data"]["id"]);
+
+        db.run("MATCH (a)-[r]->(b) RETURN a, r, b", &mut cursor)?;
+        let d3 = to_d3_json(&mut cursor)?;
+        assert_eq!(d3["nodes"].len(), 2);
+        assert_eq!(d3["nodes"][1]["properties"]["name"], "b");
+        assert_eq!(d3["links"][0]["target"], d3["nodes"][1]["id"]);
+
+        // Going either way, it's the same rel
+        db.run("MATCH (a)-[r]-(b) RETURN r", &mut cursor)?;
+        let d3 = to_d3_json(&mut cursor)?;
+        assert_eq!(d3["links"].len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//
+// Adapters that move query results out of a Cursor and into the formats other tools speak.
+// Each of these is behind its own feature flag, so you only pay for the dependencies you use.
+//
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "json")]
+pub mod graph_json;
+#[cfg(feature = "petgraph")]
+pub mod petgraph;
+
+#[cfg(any(feature = "json", feature = "petgraph"))]
+use crate::{backend::Backend, Cursor, Node, Rel, Result, Val};
+#[cfg(any(feature = "json", feature = "petgraph"))]
+use std::collections::{HashMap, HashSet};
+
+// The nodes and rels found in a query result, for the exporters that want to see the result
+// as a graph rather than as rows.
+//
+// Nodes and rels are de-duplicated on their ids. Rels whose endpoints were not themselves
+// returned get placeholder endpoints with just the id set; return the endpoints too if you need
+// their labels and properties.
+#[cfg(any(feature = "json", feature = "petgraph"))]
+#[derive(Debug, Default)]
+struct Subgraph {
+    nodes: Vec<Node>,
+    rels: Vec<Rel>,
+    // node id -> index into nodes
+    node_index: HashMap<usize, usize>,
+    rel_ids: HashSet<usize>,
+}
+
+#[cfg(any(feature = "json", feature = "petgraph"))]
+impl Subgraph {
+    // Drain the cursor, collecting every node and rel in the result, including those nested
+    // in lists and maps
+    fn collect<B: Backend>(cursor: &mut Cursor<B>) -> Result<Subgraph> {
+        let mut sg = Subgraph::default();
+        while let Some(row) = cursor.next()? {
+            for val in &row.slots {
+                sg.add_val(val);
+            }
+        }
+        Ok(sg)
+    }
+
+    fn add_val(&mut self, val: &Val) {
+        match val {
+            Val::Node(n) => {
+                let ix = self.node(n.id);
+                // Overwrite any placeholder we created when we saw a rel to this node
+                self.nodes[ix] = n.clone();
+            }
+            Val::Rel(r) => {
+                self.node(r.start);
+                self.node(r.end);
+                if self.rel_ids.insert(r.id) {
+                    self.rels.push(r.clone());
+                }
+            }
+            Val::List(vals) => {
+                for v in vals.iter() {
+                    self.add_val(v);
+                }
+            }
+            Val::Map(entries) => {
+                for (_, v) in entries.iter() {
+                    self.add_val(v);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn node(&mut self, id: usize) -> usize {
+        let nodes = &mut self.nodes;
+        *self.node_index.entry(id).or_insert_with(|| {
+            nodes.push(Node {
+                id,
+                labels: Vec::new(),
+                props: Vec::new(),
+            });
+            nodes.len() - 1
+        })
+    }
+}
@@ -0,0 +1,60 @@
+//
+// Materialize query results as a petgraph Graph, so the algorithms in the petgraph ecosystem
+// can run on subgraphs selected with Cypher.
+//
+use super::Subgraph;
+use crate::backend::Backend;
+use crate::{Cursor, Node, Rel, Result};
+use ::petgraph::graph::{DiGraph, NodeIndex};
+
+// Drain the cursor and build a directed graph out of every node and rel in the result, including
+// those nested in lists and maps. Node weights are the nodes themselves, labels and properties
+// included, and edge weights are the rels. See Subgraph for how duplicates and rels with
+// endpoints missing from the result are handled.
+pub fn to_petgraph<B: Backend>(cursor: &mut Cursor<B>) -> Result<DiGraph<Node, Rel>> {
+    let sg = Subgraph::collect(cursor)?;
+    let mut graph = DiGraph::with_capacity(sg.nodes.len(), sg.rels.len());
+    for n in sg.nodes {
+        graph.add_node(n);
+    }
+    for r in sg.rels {
+        let start = NodeIndex::new(sg.node_index[&r.start]);
+        let end = NodeIndex::new(sg.node_index[&r.end]);
+        graph.add_edge(start, end, r);
+    }
+    Ok(graph)
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use super::*;
+    use crate::gramdb::GramDatabase;
+
+    #[test]
+    fn builds_graph_from_results() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        let mut cursor = db.new_cursor();
+        db.run(
+            "CREATE (a:Person {name: 'a'})-[:KNOWS]->(b:Person {name: 'b'}), (a)-[:KNOWS]->(c:Person {name: 'c'})",
+            &mut cursor,
+        )?;
+        while cursor.next()?.is_some() {}
+        db.run(
+            "MATCH (a {name: 'a'})-[r:KNOWS]->(b) RETURN a, r",
+            &mut cursor,
+        )?;
+
+        let g = to_petgraph(&mut cursor)?;
+
+        assert_eq!(g.node_count(), 3);
+        assert_eq!(g.edge_count(), 2);
+        let a = g
+            .node_indices()
+            .find(|ix| g[*ix].labels == vec!["Person".to_string()])
+            .unwrap();
+        assert_eq!(g.neighbors(a).count(), 2);
+        // b and c were only reachable through rels, so they are placeholders
+        assert_eq!(g.node_weights().filter(|n| n.labels.is_empty()).count(), 2);
+        Ok(())
+    }
+}
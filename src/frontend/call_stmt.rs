@@ -0,0 +1,183 @@
+use super::{
+    identifier, plan_expr, types, views, LogicalPlan, Pair, PlanningContext, Result, Rule,
+};
+use crate::backend::Token;
+use crate::{QueryError, Slot, Type};
+
+// Plan a CALL, giving back the plan along with the columns it yields, named as the query named
+// them; those are the result of the query if the CALL is its last clause
+pub fn plan_call(
+    pc: &mut PlanningContext,
+    src: LogicalPlan,
+    call_stmt: Pair<Rule>,
+) -> Result<(LogicalPlan, Vec<(Token, Slot)>)> {
+    let mut parts = call_stmt.into_inner();
+
+    let name_part = parts.next().expect("CALL must name a procedure");
+    let name_str = name_part
+        .clone()
+        .into_inner()
+        .map(|id| identifier(&id).into_owned())
+        .collect::<Vec<String>>()
+        .join(".");
+    let name = pc.tokenize(&name_str);
+    if pc.views.contains_key(&name) {
+        return views::plan_view(pc, src, name, name_part.as_span(), parts);
+    }
+    let sig = match pc.backend_desc.procedure(name) {
+        Some(sig) => sig.clone(),
+        None => bail!(QueryError::semantic(
+            format!("There is no procedure with the name `{}`", name_str),
+            name_part.as_span()
+        )),
+    };
+
+    let mut args = Vec::new();
+    let mut yields = None;
+    for part in parts {
+        match part.as_rule() {
+            Rule::yield_clause => yields = Some(part),
+            _ => {
+                let span = part.as_span();
+                let arg = plan_expr(pc, part)?;
+                if let Some((_, expected)) = sig.args.get(args.len()) {
+                    if !types::compatible(expected, &types::type_of(pc, &arg)?) {
+                        bail!(QueryError::semantic(
+                            format!("`{}` expects a {:?} here", name_str, expected),
+                            span
+                        ))
+                    }
+                }
+                args.push(arg);
+            }
+        }
+    }
+    if args.len() != sig.args.len() {
+        bail!(QueryError::semantic(
+            format!(
+                "`{}` takes {} arguments, but was given {}",
+                name_str,
+                sig.args.len(),
+                args.len()
+            ),
+            name_part.as_span()
+        ))
+    }
+
+    let yielded = plan_yields(pc, &name_str, yields, &sig.outputs)?;
+    let mut outputs = Vec::with_capacity(yielded.len());
+    let mut columns = Vec::with_capacity(yielded.len());
+    for (output, alias, output_type) in yielded {
+        pc.declare_tok(alias);
+        pc.var_types.insert(alias, output_type);
+        let slot = pc.get_or_alloc_slot(alias);
+        outputs.push((output, slot));
+        columns.push((alias, slot));
+    }
+
+    Ok((
+        LogicalPlan::Call {
+            src: Box::new(src),
+            name,
+            args,
+            outputs,
+        },
+        columns,
+    ))
+}
+
+// What a CALL yields, as (output, the name the query gives it, type); without YIELD, every
+// output is yielded under its own name
+pub fn plan_yields(
+    pc: &mut PlanningContext,
+    name_str: &str,
+    yields: Option<Pair<Rule>>,
+    outputs: &[(Token, Type)],
+) -> Result<Vec<(Token, Token, Type)>> {
+    let mut yielded = Vec::new();
+    match yields {
+        Some(yields) => {
+            for item in yields.into_inner() {
+                let span = item.as_span();
+                let mut ids = item.into_inner();
+                let output = pc.tokenize(&identifier(&ids.next().expect("YIELD needs a name")));
+                let alias = ids.next().map(|alias| pc.tokenize(&identifier(&alias)));
+                match outputs.iter().find(|(tok, _)| *tok == output) {
+                    Some((_, output_type)) => {
+                        yielded.push((output, alias.unwrap_or(output), output_type.clone()))
+                    }
+                    None => bail!(QueryError::semantic(
+                        format!("`{}` has no output called `{}`", name_str, span.as_str()),
+                        span
+                    )),
+                }
+            }
+        }
+        None => {
+            for (output, output_type) in outputs {
+                yielded.push((*output, *output, output_type.clone()))
+            }
+        }
+    }
+    Ok(yielded)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{BackendDesc, ProcSignature, Tokens};
+    use crate::diagnostics::NoDiagnostics;
+    use crate::frontend::{Frontend, LogicalPlan};
+    use crate::{error, ErrorKind, Result, Type};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn frontend() -> Frontend {
+        let mut tokens = Tokens::new();
+        let mut backend_desc = BackendDesc::new(vec![]);
+        backend_desc.procedures.push(ProcSignature {
+            name: tokens.tokenize("db.labels"),
+            args: vec![],
+            outputs: vec![(tokens.tokenize("label"), Type::String)],
+            writes: false,
+        });
+        Frontend {
+            tokens: Rc::new(RefCell::new(tokens)),
+            backend_desc,
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        }
+    }
+
+    #[test]
+    fn plans_procedure_calls() -> Result<()> {
+        let frontend = frontend();
+        let tokens = Rc::clone(&frontend.tokens);
+        let columns = |plan: LogicalPlan| match plan {
+            LogicalPlan::ProduceResult { fields, .. } => fields
+                .iter()
+                .map(|(tok, _)| tokens.borrow().lookup(*tok).unwrap().to_string())
+                .collect::<Vec<String>>(),
+            other => panic!("expected a result, got {:?}", other),
+        };
+        assert_eq!(columns(frontend.plan("CALL db.labels()")?), vec!["label"]);
+        assert_eq!(
+            columns(frontend.plan("CALL db.labels() YIELD label AS l")?),
+            vec!["l"]
+        );
+        assert_eq!(
+            columns(frontend.plan("CALL db.labels() YIELD label RETURN label + '!' AS x")?),
+            vec!["x"]
+        );
+
+        for query in &[
+            "CALL db.nope()",
+            "CALL db.labels(1)",
+            "CALL db.labels() YIELD nope",
+            "CALL db.labels() RETURN label",
+        ] {
+            let err = frontend.plan(query).unwrap_err();
+            assert_eq!(error::kind(&err), ErrorKind::SemanticError, "{}", query);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+//
+// Completions for a query that's still being written, for tab-completion in a REPL or an editor:
+// what could go where the cursor is, given the query up to there.
+//
+//   MATCH (n:Per|           labels, like Person
+//   MATCH (n)-[:|           rel types
+//   MATCH (n) WHERE n.x = 1 RETURN |
+//                           variables in scope, functions and keywords
+//   CALL db.|               procedures and views
+//
+// Queries being written rarely parse, so like fingerprint.rs this works on the tokens of the
+// query text rather than a parse tree. That makes what's in scope a guess, but a good one: the
+// variables patterns, AS, and YIELD declare before the cursor, down to what the last WITH
+// carried over.
+//
+use super::fingerprint::{lex, Token as Lexeme};
+use super::Frontend;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completions {
+    // Byte offset of the start of the word being completed; a candidate replaces the query
+    // from here up to the cursor
+    pub start: usize,
+    pub candidates: Vec<Completion>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    // What to put in the query, quoted if it needs to be
+    pub text: String,
+    pub kind: CompletionKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionKind {
+    Keyword,
+    Variable,
+    Function,
+    Procedure,
+    Label,
+    RelType,
+}
+
+// Keywords are offered whole, so ORDER rather than ORDER BY doesn't need a second completion
+const KEYWORDS: &[&str] = &[
+    "AND",
+    "AS",
+    "ASC",
+    "CALL",
+    "CREATE",
+    "DESC",
+    "DISTINCT",
+    "FALSE",
+    "LIMIT",
+    "MATCH",
+    "NOT",
+    "NULL",
+    "NULLS FIRST",
+    "NULLS LAST",
+    "OPTIONAL MATCH",
+    "OR",
+    "ORDER BY",
+    "RETURN",
+    "SKIP",
+    "TRUE",
+    "UNWIND",
+    "WHERE",
+    "WITH",
+    "YIELD",
+];
+
+// The keywords a query can start with
+const CLAUSES: &[&str] = &[
+    "CALL",
+    "CREATE",
+    "MATCH",
+    "OPTIONAL MATCH",
+    "RETURN",
+    "UNWIND",
+    "WITH",
+];
+
+// The keywords that end the projections of a WITH, or the items of a YIELD
+const CLAUSE_WORDS: &[&str] = &[
+    "CALL", "CREATE", "LIMIT", "MATCH", "OPTIONAL", "ORDER", "RETURN", "SKIP", "UNWIND", "WHERE",
+    "WITH",
+];
+
+impl Frontend {
+    // What could be written at cursor_pos, a byte offset into partial_query; only the query up
+    // to the cursor is looked at. Labels and rel types come from the statistics in the
+    // backend description, so only ones the graph has are offered.
+    pub fn completions(&self, partial_query: &str, cursor_pos: usize) -> Completions {
+        let mut pos = cursor_pos.min(partial_query.len());
+        while !partial_query.is_char_boundary(pos) {
+            pos -= 1;
+        }
+        let prefix = &partial_query[..pos];
+        let word_len: usize = prefix
+            .chars()
+            .rev()
+            .take_while(|c| *c == '_' || c.is_alphanumeric())
+            .map(char::len_utf8)
+            .sum();
+        let start = pos - word_len;
+        let word = &prefix[start..];
+        let mut out = Completions {
+            start,
+            candidates: Vec::new(),
+        };
+        if in_string_or_comment(prefix) || word.starts_with(|c: char| c.is_ascii_digit()) {
+            return out;
+        }
+
+        // CALL completes whole procedure names, dots and all
+        let name_start = dotted_name_start(prefix, start);
+        if let Some(Lexeme::Word(w)) = lex(&prefix[..name_start]).last() {
+            if w.eq_ignore_ascii_case("CALL") {
+                let typed = &prefix[name_start..];
+                let t = self.tokens.borrow();
+                let procedures = self.backend_desc.procedures.iter().map(|p| p.name);
+                let views = self.views.keys().copied();
+                let names = procedures.chain(views).filter_map(|p| t.lookup(p));
+                out.start = name_start;
+                add(&mut out, CompletionKind::Procedure, names, typed);
+                return out;
+            }
+        }
+
+        let tokens = lex(&prefix[..start]);
+        let t = self.tokens.borrow();
+        match tokens.last() {
+            None => add(
+                &mut out,
+                CompletionKind::Keyword,
+                CLAUSES.iter().copied(),
+                word,
+            ),
+            // Labels of a node, or rel types of a rel; inside a map, a : is followed by a value
+            Some(Lexeme::Punct(":")) | Some(Lexeme::Punct("|")) => match open_bracket(&tokens) {
+                Some("[") => {
+                    if let Some(stats) = &self.backend_desc.statistics {
+                        let names = stats.rel_type_counts.keys().filter_map(|r| t.lookup(*r));
+                        add(&mut out, CompletionKind::RelType, names, word);
+                    }
+                }
+                Some("{") => self.expression_completions(&tokens, word, &mut out),
+                _ => {
+                    let mut labels: Vec<&str> = self
+                        .backend_desc
+                        .indexes
+                        .iter()
+                        .filter_map(|i| t.lookup(i.label))
+                        .collect();
+                    if let Some(stats) = &self.backend_desc.statistics {
+                        labels.extend(stats.label_counts.keys().filter_map(|l| t.lookup(*l)));
+                    }
+                    add(&mut out, CompletionKind::Label, labels.into_iter(), word);
+                }
+            },
+            // Property keys and parameters; there's no telling which there are
+            Some(Lexeme::Punct(".")) | Some(Lexeme::Param(_)) => (),
+            Some(_) => self.expression_completions(&tokens, word, &mut out),
+        }
+        out
+    }
+
+    // Variables in scope, then functions, then keywords
+    fn expression_completions(&self, tokens: &[Lexeme], word: &str, out: &mut Completions) {
+        let variables = in_scope(tokens);
+        add(
+            out,
+            CompletionKind::Variable,
+            variables.iter().map(String::as_str),
+            word,
+        );
+        let t = self.tokens.borrow();
+        let functions = self
+            .backend_desc
+            .functions
+            .iter()
+            .filter_map(|f| t.lookup(f.name));
+        add(out, CompletionKind::Function, functions, word);
+        add(out, CompletionKind::Keyword, KEYWORDS.iter().copied(), word);
+    }
+}
+
+// Add the names that start with what's been typed so far, sorted and without duplicates.
+// Keywords are matched without regard to case, names are case sensitive.
+fn add<'a>(
+    out: &mut Completions,
+    kind: CompletionKind,
+    names: impl Iterator<Item = &'a str>,
+    typed: &str,
+) {
+    let mut texts: Vec<String> = names
+        .filter(|name| match kind {
+            CompletionKind::Keyword => name
+                .get(..typed.len())
+                .is_some_and(|p| p.eq_ignore_ascii_case(typed)),
+            _ => name.starts_with(typed),
+        })
+        .map(|name| match kind {
+            CompletionKind::Keyword | CompletionKind::Procedure => name.to_string(),
+            _ => quote(name),
+        })
+        .collect();
+    texts.sort();
+    texts.dedup();
+    for text in texts {
+        if !out.candidates.iter().any(|c| c.text == text) {
+            out.candidates.push(Completion { text, kind });
+        }
+    }
+}
+
+// Names are only quoted if they need to be
+fn quote(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = match chars.next() {
+        Some(c) => {
+            (c.is_alphabetic() || c == '_') && chars.all(|c| c.is_alphanumeric() || c == '_')
+        }
+        None => false,
+    };
+    if plain {
+        name.to_string()
+    } else {
+        format!("`{}`", name.replace('`', "``"))
+    }
+}
+
+// Does the query end inside a string, a quoted name or a comment?
+fn in_string_or_comment(query: &str) -> bool {
+    let bytes = query.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if query[i..].starts_with("//") {
+            match query[i..].find('\n') {
+                Some(end) => i += end,
+                None => return true,
+            }
+        } else if query[i..].starts_with("/*") {
+            match query[i + 2..].find("*/") {
+                Some(end) => i += end + 4,
+                None => return true,
+            }
+        } else if c == b'\'' || c == b'"' || c == b'`' {
+            i += 1;
+            while i < bytes.len() && bytes[i] != c {
+                // Strings escape with backslashes, quoted names by doubling the backtick
+                if c != b'`' && bytes[i] == b'\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            if i >= bytes.len() {
+                return true;
+            }
+            if c == b'`' && bytes.get(i + 1) == Some(&b'`') {
+                i += 1;
+                continue;
+            }
+            i += 1;
+        } else {
+            i += 1;
+        }
+    }
+    false
+}
+
+// Where the dotted name that ends at end starts, like db.schema.visualization
+fn dotted_name_start(query: &str, end: usize) -> usize {
+    let mut start = end;
+    while query[..start].ends_with('.') {
+        let before = &query[..start - 1];
+        let word = before
+            .trim_end_matches(|c: char| c == '_' || c.is_alphanumeric())
+            .len();
+        if word == before.len() {
+            break;
+        }
+        start = word;
+    }
+    start
+}
+
+// The innermost bracket that's still open
+fn open_bracket<'a>(tokens: &[Lexeme<'a>]) -> Option<&'a str> {
+    let mut open = Vec::new();
+    for token in tokens {
+        match token {
+            Lexeme::Punct(p @ "(") | Lexeme::Punct(p @ "[") | Lexeme::Punct(p @ "{") => {
+                open.push(*p)
+            }
+            Lexeme::Punct(")") | Lexeme::Punct("]") | Lexeme::Punct("}") => {
+                open.pop();
+            }
+            _ => (),
+        }
+    }
+    open.pop()
+}
+
+fn is_keyword(token: Option<&Lexeme>, keyword: &str) -> bool {
+    matches!(token, Some(Lexeme::Word(w)) if w.eq_ignore_ascii_case(keyword))
+}
+
+fn is_any_keyword(token: Option<&Lexeme>) -> bool {
+    match token {
+        Some(Lexeme::Word(w)) => KEYWORDS
+            .iter()
+            .chain(CLAUSE_WORDS)
+            .any(|kw| kw.split(' ').next().unwrap().eq_ignore_ascii_case(w)),
+        _ => false,
+    }
+}
+
+// The name a word stands for, with any backtick quoting removed
+fn name_of(word: &str) -> String {
+    match word.strip_prefix('`') {
+        Some(quoted) => quoted
+            .strip_suffix('`')
+            .unwrap_or(quoted)
+            .replace("``", "`"),
+        None => word.to_string(),
+    }
+}
+
+// The variables declared in the tokens that are still in scope at the end of them, in the order
+// they were declared in
+fn in_scope(tokens: &[Lexeme]) -> Vec<String> {
+    let mut scope: Vec<String> = Vec::new();
+    let declare = |scope: &mut Vec<String>, name: String| {
+        if !scope.contains(&name) {
+            scope.push(name)
+        }
+    };
+    let mut i = 0;
+    while i < tokens.len() {
+        let next = |n: usize| tokens.get(i + n);
+        match &tokens[i] {
+            // WITH replaces what's in scope with what it projects, once it's done projecting
+            Lexeme::Word(w) if w.eq_ignore_ascii_case("WITH") => {
+                let (items, end) = items(tokens, i + 1);
+                if end < tokens.len() {
+                    if !items.iter().any(|item| item == "*") {
+                        scope.clear();
+                    }
+                    for item in items.into_iter().filter(|item| item != "*") {
+                        declare(&mut scope, item);
+                    }
+                    i = end;
+                    continue;
+                }
+            }
+            Lexeme::Word(w) if w.eq_ignore_ascii_case("YIELD") => {
+                let (items, end) = items(tokens, i + 1);
+                for item in items {
+                    declare(&mut scope, item);
+                }
+                i = end;
+                continue;
+            }
+            Lexeme::Word(w) if w.eq_ignore_ascii_case("AS") => {
+                if let Some(Lexeme::Word(alias)) = next(1) {
+                    declare(&mut scope, name_of(alias));
+                }
+            }
+            // (n), (n:Label) and (n {..}) in patterns, but not f(n)
+            Lexeme::Punct("(") => {
+                let prev = i.checked_sub(1).and_then(|p| tokens.get(p));
+                let in_pattern = !matches!(prev, Some(Lexeme::Word(_))) || is_any_keyword(prev);
+                if let (true, Some(Lexeme::Word(n))) = (in_pattern, next(1)) {
+                    if matches!(
+                        next(2),
+                        Some(Lexeme::Punct(":"))
+                            | Some(Lexeme::Punct(")"))
+                            | Some(Lexeme::Punct("{"))
+                    ) && !is_any_keyword(next(1))
+                    {
+                        declare(&mut scope, name_of(n));
+                    }
+                }
+            }
+            // -[r]-, -[r:TYPE]- and -[r {..}]-, but not list[0]
+            Lexeme::Punct("[") => {
+                let prev = i.checked_sub(1).and_then(|p| tokens.get(p));
+                let in_rel = matches!(prev, Some(Lexeme::Punct(p)) if p.ends_with('-'));
+                if let (true, Some(Lexeme::Word(r))) = (in_rel, next(1)) {
+                    if matches!(
+                        next(2),
+                        Some(Lexeme::Punct(":"))
+                            | Some(Lexeme::Punct("]"))
+                            | Some(Lexeme::Punct("{"))
+                    ) {
+                        declare(&mut scope, name_of(r));
+                    }
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    scope
+}
+
+// The names of the projections of a WITH, or the items of a YIELD, that start at tokens[from]:
+// the alias if there is one, or the variable if that's all the projection is. Also gives back
+// where they end, which is tokens.len() if the query does.
+fn items(tokens: &[Lexeme], from: usize) -> (Vec<String>, usize) {
+    let mut names = Vec::new();
+    let mut item_start = from;
+    let mut depth = 0;
+    let mut end = from;
+    let item_name = |item: &[Lexeme]| {
+        let item = match item.first() {
+            Some(first) if is_keyword(Some(first), "DISTINCT") => &item[1..],
+            _ => item,
+        };
+        match item {
+            [Lexeme::Punct("*")] => Some("*".to_string()),
+            [Lexeme::Word(w)] => Some(name_of(w)),
+            [.., Lexeme::Word(a), Lexeme::Word(w)] if a.eq_ignore_ascii_case("AS") => {
+                Some(name_of(w))
+            }
+            _ => None,
+        }
+    };
+    while end < tokens.len() {
+        match &tokens[end] {
+            Lexeme::Punct("(") | Lexeme::Punct("[") | Lexeme::Punct("{") => depth += 1,
+            Lexeme::Punct(")") | Lexeme::Punct("]") | Lexeme::Punct("}") => depth -= 1,
+            Lexeme::Punct(",") if depth == 0 => {
+                names.extend(item_name(&tokens[item_start..end]));
+                item_start = end + 1;
+            }
+            Lexeme::Word(w)
+                if depth == 0 && CLAUSE_WORDS.iter().any(|kw| kw.eq_ignore_ascii_case(w)) =>
+            {
+                break
+            }
+            _ => (),
+        }
+        end += 1;
+    }
+    names.extend(item_name(&tokens[item_start..end]));
+    (names, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BackendDesc, FuncSignature, FuncType, Statistics, Tokens};
+    use crate::diagnostics::NoDiagnostics;
+    use crate::Type;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn frontend() -> Frontend {
+        let mut tokens = Tokens::new();
+        let functions = vec![FuncSignature {
+            func_type: FuncType::Scalar,
+            name: tokens.tokenize("abs"),
+            returns: Type::Number,
+            args: vec![(tokens.tokenize("v"), Type::Number)],
+            optional: 0,
+        }];
+        let mut stats = Statistics::default();
+        for label in &["Person", "Place", "Two words"] {
+            stats.label_counts.insert(tokens.tokenize(label), 1);
+        }
+        stats.rel_type_counts.insert(tokens.tokenize("KNOWS"), 1);
+        let mut backend_desc = BackendDesc::new(functions);
+        backend_desc.statistics = Some(stats);
+        Frontend {
+            tokens: Rc::new(RefCell::new(tokens)),
+            backend_desc,
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        }
+    }
+
+    // The candidates at the end of the query
+    fn complete(f: &Frontend, query: &str) -> Vec<String> {
+        let completions = f.completions(query, query.len());
+        completions.candidates.into_iter().map(|c| c.text).collect()
+    }
+
+    #[test]
+    fn completes_labels_rel_types_and_keywords() {
+        let f = frontend();
+        assert_eq!(complete(&f, "MATCH (n:P"), vec!["Person", "Place"]);
+        assert_eq!(
+            complete(&f, "MATCH (n:"),
+            vec!["Person", "Place", "`Two words`"]
+        );
+        assert_eq!(complete(&f, "MATCH (n)-[:"), vec!["KNOWS"]);
+        assert_eq!(complete(&f, "MATCH (n) WHERE n:Pe"), vec!["Person"]);
+        assert_eq!(complete(&f, "op"), vec!["OPTIONAL MATCH"]);
+        assert_eq!(complete(&f, "MATCH (n) RETURN n ORD"), vec!["ORDER BY"]);
+
+        let completions = f.completions("MATCH (n:Pe) RETURN n", 11);
+        assert_eq!(completions.start, 9);
+        assert_eq!(
+            completions.candidates,
+            vec![Completion {
+                text: "Person".to_string(),
+                kind: CompletionKind::Label,
+            }]
+        );
+
+        // Nothing to offer in strings, or for property keys
+        assert!(complete(&f, "MATCH (n {name: 'P").is_empty());
+        assert!(complete(&f, "MATCH (n) RETURN n.").is_empty());
+    }
+
+    #[test]
+    fn completes_variables_in_scope() {
+        let f = frontend();
+        let variables = |query: &str| -> Vec<String> {
+            let completions = f.completions(query, query.len());
+            completions
+                .candidates
+                .into_iter()
+                .filter(|c| c.kind == CompletionKind::Variable)
+                .map(|c| c.text)
+                .collect()
+        };
+        assert_eq!(
+            variables("MATCH (a:Person)-[r:KNOWS]->(b), (c) RETURN "),
+            vec!["a", "b", "c", "r"]
+        );
+        assert_eq!(
+            variables("MATCH (a)-->(b) WITH a, b.name AS name WHERE "),
+            vec!["a", "name"]
+        );
+        assert_eq!(
+            variables("MATCH (a) WITH * UNWIND [1] AS x RETURN "),
+            vec!["a", "x"]
+        );
+        assert_eq!(variables("UNWIND range(1, 3) AS x RETURN abs(x"), vec!["x"]);
+        assert_eq!(variables("MATCH (apple), (b) RETURN a"), vec!["apple"]);
+
+        // Functions and keywords go after the variables
+        assert_eq!(
+            complete(&f, "MATCH (apple) RETURN a"),
+            vec!["apple", "abs", "AND", "AS", "ASC"]
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use super::{
     parse_pattern_graph, Dir, LogicalPlan, NodeSpec, Pair, PlanningContext, RelSpec, Result, Rule,
 };
+use crate::backend::Token;
+use crate::QueryError;
 
 pub fn plan_create(
     pc: &mut PlanningContext,
@@ -12,55 +14,64 @@ pub fn plan_create(
     let mut nodes = Vec::new();
     let mut rels = Vec::new();
     for id in pg.v_order {
+        let node = pg.v.remove(&id).ok_or(anyhow!("failed to parse pattern in query, please report this and include the query you are running"))?;
         if pc.is_declared(id) {
             // We already know about this node, it isn't meant to be created. ie
             // MATCH (n) CREATE (n)-[:NEWREL]->(newnode)
+            if !node.labels.is_empty()
+                || !node.label_alternatives.is_empty()
+                || !node.props.is_empty()
+                || node.prop_map.is_some()
+            {
+                bail!(already_bound(pc, id, "can't be given labels or properties"))
+            }
             continue;
         }
 
-        let node = pg.v.remove(&id).ok_or(anyhow!("failed to parse pattern in query, please report this and include the query you are running"))?;
         // Non-anonymous nodes declare new identifiers; we do this
         // here rather than in parse_pattern_graph so we can do the
         // is_declared check further up in this block.
         if !node.anonymous {
             pc.declare_tok(id);
         }
+        if !node.label_alternatives.is_empty() {
+            bail!("nodes are created with all the labels they're given, so CREATE can't have label alternatives like :A|B")
+        }
         nodes.push(NodeSpec {
             slot: pc.get_or_alloc_slot(id),
             labels: node.labels,
             props: node.props,
+            prop_map: node.prop_map,
         });
     }
 
+    // A pattern can be any number of hops long, each rel is created between whichever nodes
+    // it sits between in the pattern, new or already bound
     for rel in pg.e {
         if !rel.anonymous {
+            // Unlike nodes, a rel can't be referred to twice; it'd be a second rel
+            if pc.is_declared(rel.identifier) {
+                bail!(already_bound(pc, rel.identifier, "can't be created again"))
+            }
             pc.declare_tok(rel.identifier);
         }
-        match rel.dir {
-            Some(Dir::Out) => {
-                rels.push(RelSpec {
-                    slot: pc.get_or_alloc_slot(rel.identifier),
-                    rel_type: rel.rel_type.ok_or(anyhow!(
-                        "Relationship patterns in CREATE must have a type specified"
-                    ))?,
-                    start_node_slot: pc.get_or_alloc_slot(rel.left_node),
-                    end_node_slot: pc.get_or_alloc_slot(rel.right_node.unwrap()),
-                    props: rel.props,
-                });
-            }
-            Some(Dir::In) => {
-                rels.push(RelSpec {
-                    slot: pc.get_or_alloc_slot(rel.identifier),
-                    rel_type: rel.rel_type.ok_or(anyhow!(
-                        "Relationship patterns in CREATE must have a type specified"
-                    ))?,
-                    start_node_slot: pc.get_or_alloc_slot(rel.right_node.unwrap()),
-                    end_node_slot: pc.get_or_alloc_slot(rel.left_node),
-                    props: vec![],
-                });
-            }
+        let rel_type = rel.rel_type.ok_or(anyhow!(
+            "Relationship patterns in CREATE must have a type specified"
+        ))?;
+        let right_node = rel.right_node.expect("rels in patterns end in a node");
+        let (start, end) = match rel.dir {
+            Some(Dir::Out) => (rel.left_node, right_node),
+            Some(Dir::In) => (right_node, rel.left_node),
             None => bail!("relationships in CREATE clauses must have a direction"),
-        }
+        };
+        rels.push(RelSpec {
+            slot: pc.get_or_alloc_slot(rel.identifier),
+            rel_type,
+            start_node_slot: pc.get_or_alloc_slot(start),
+            end_node_slot: pc.get_or_alloc_slot(end),
+            props: rel.props,
+            prop_map: rel.prop_map,
+        });
     }
 
     Ok(LogicalPlan::Create {
@@ -70,11 +81,23 @@ pub fn plan_create(
     })
 }
 
+fn already_bound(pc: &PlanningContext, id: Token, problem: &str) -> QueryError {
+    let tokens = pc.tokens.borrow();
+    QueryError::SemanticError {
+        message: format!(
+            "`{}` is already bound, so it {}",
+            tokens.lookup(id).unwrap_or("?"),
+            problem
+        ),
+        span: None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::frontend::tests::plan;
     use crate::frontend::{Expr, LogicalPlan, MapEntryExpr, NodeSpec, RelSpec};
-    use crate::Error;
+    use crate::{error, Error, ErrorKind};
 
     #[test]
     fn plan_create() -> Result<(), Error> {
@@ -89,7 +112,8 @@ mod tests {
                 nodes: vec![NodeSpec {
                     slot: p.slot(id_n),
                     labels: vec![lbl_person],
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 }],
                 rels: vec![]
             }
@@ -109,7 +133,31 @@ mod tests {
                 nodes: vec![NodeSpec {
                     slot: p.slot(id_n),
                     labels: vec![],
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
+                }],
+                rels: vec![]
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_create_with_parameter_map() -> Result<(), Error> {
+        let mut p = plan("CREATE (n:User $props)")?;
+
+        let id_n = p.tokenize("n");
+        let lbl_user = p.tokenize("User");
+        let param_props = p.tokenize("props");
+        assert_eq!(
+            p.plan,
+            LogicalPlan::Create {
+                src: Box::new(LogicalPlan::Argument),
+                nodes: vec![NodeSpec {
+                    slot: p.slot(id_n),
+                    labels: vec![lbl_user],
+                    props: vec![],
+                    prop_map: Some(Expr::Param(param_props))
                 }],
                 rels: vec![]
             }
@@ -132,7 +180,8 @@ mod tests {
                 nodes: vec![NodeSpec {
                     slot: p.slot(id_n),
                     labels: vec![lbl_person, lbl_actor],
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 }],
                 rels: vec![]
             }
@@ -157,7 +206,8 @@ mod tests {
                     props: vec![MapEntryExpr {
                         key: key_name,
                         val: Expr::String("Bob".to_string()),
-                    }]
+                    }],
+                    prop_map: None
                 }],
                 rels: vec![]
             }
@@ -180,14 +230,16 @@ mod tests {
                 nodes: vec![NodeSpec {
                     slot: p.slot(id_n),
                     labels: vec![lbl_person],
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 }],
                 rels: vec![RelSpec {
                     slot: p.slot(id_r),
                     rel_type: rt_knows,
                     start_node_slot: p.slot(id_n),
                     end_node_slot: p.slot(id_n),
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 },]
             }
         );
@@ -209,17 +261,20 @@ mod tests {
                     NodeSpec {
                         slot: p.slot(id_a),
                         labels: vec![lbl_a],
-                        props: vec![]
+                        props: vec![],
+                        prop_map: None
                     },
                     NodeSpec {
                         slot: 1,
                         labels: vec![],
-                        props: vec![]
+                        props: vec![],
+                        prop_map: None
                     },
                     NodeSpec {
                         slot: 2,
                         labels: vec![],
-                        props: vec![]
+                        props: vec![],
+                        prop_map: None
                     }
                 ],
                 rels: vec![RelSpec {
@@ -227,7 +282,8 @@ mod tests {
                     rel_type: rt_knows,
                     start_node_slot: p.slot(id_a),
                     end_node_slot: 2,
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 },]
             }
         );
@@ -250,7 +306,8 @@ mod tests {
                 nodes: vec![NodeSpec {
                     slot: p.slot(id_n),
                     labels: vec![lbl_person],
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 }],
                 rels: vec![RelSpec {
                     slot: p.slot(id_r),
@@ -260,7 +317,8 @@ mod tests {
                     props: vec![MapEntryExpr {
                         key: k_since,
                         val: Expr::String("2012".to_string())
-                    },]
+                    },],
+                    prop_map: None
                 },]
             }
         );
@@ -289,7 +347,8 @@ mod tests {
                     NodeSpec {
                         slot: p.slot(id_o),
                         labels: vec![lbl_person],
-                        props: vec![]
+                        props: vec![],
+                        prop_map: None
                     }
                 ],
                 rels: vec![RelSpec {
@@ -297,7 +356,8 @@ mod tests {
                     rel_type: rt_knows,
                     start_node_slot: p.slot(id_n),
                     end_node_slot: p.slot(id_o),
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 },]
             }
         );
@@ -329,7 +389,8 @@ mod tests {
                         props: vec![MapEntryExpr {
                             key: key_id,
                             val: Expr::Int(0)
-                        }]
+                        }],
+                        prop_map: None
                     },
                     NodeSpec {
                         slot: p.slot(id_b),
@@ -337,7 +398,8 @@ mod tests {
                         props: vec![MapEntryExpr {
                             key: key_num,
                             val: Expr::Prop(Box::new(Expr::Slot(p.slot(id_a))), vec![key_id])
-                        }]
+                        }],
+                        prop_map: None
                     }
                 ],
                 rels: vec![]
@@ -368,7 +430,8 @@ mod tests {
                     NodeSpec {
                         slot: p.slot(id_o),
                         labels: vec![lbl_person],
-                        props: vec![]
+                        props: vec![],
+                        prop_map: None
                     }
                 ],
                 rels: vec![RelSpec {
@@ -376,10 +439,72 @@ mod tests {
                     rel_type: rt_knows,
                     start_node_slot: p.slot(id_o),
                     end_node_slot: p.slot(id_n),
-                    props: vec![]
+                    props: vec![],
+                    prop_map: None
                 },]
             }
         );
         Ok(())
     }
+
+    #[test]
+    fn plan_create_multi_hop_path() -> Result<(), Error> {
+        let mut p = plan("MATCH (b) CREATE (a)-[r:R1]->(b)<-[s:R2 {x: 1}]-(c)-[:R3]->(a)")?;
+
+        let (id_a, id_b, id_c) = (p.tokenize("a"), p.tokenize("b"), p.tokenize("c"));
+        let (id_r, id_s, key_x) = (p.tokenize("r"), p.tokenize("s"), p.tokenize("x"));
+        let (rt_r1, rt_r2, rt_r3) = (p.tokenize("R1"), p.tokenize("R2"), p.tokenize("R3"));
+        match &p.plan {
+            LogicalPlan::Create { nodes, rels, .. } => {
+                let node_slots: Vec<usize> = nodes.iter().map(|n| n.slot).collect();
+                assert_eq!(node_slots, vec![p.slot(id_a), p.slot(id_c)]);
+                assert_eq!(rels.len(), 3);
+                assert_eq!(
+                    rels[0],
+                    RelSpec {
+                        slot: p.slot(id_r),
+                        rel_type: rt_r1,
+                        start_node_slot: p.slot(id_a),
+                        end_node_slot: p.slot(id_b),
+                        props: vec![],
+                        prop_map: None
+                    }
+                );
+                assert_eq!(
+                    rels[1],
+                    RelSpec {
+                        slot: p.slot(id_s),
+                        rel_type: rt_r2,
+                        start_node_slot: p.slot(id_c),
+                        end_node_slot: p.slot(id_b),
+                        props: vec![MapEntryExpr {
+                            key: key_x,
+                            val: Expr::Int(1)
+                        }],
+                        prop_map: None
+                    }
+                );
+                assert_eq!(rels[2].rel_type, rt_r3);
+                assert_eq!(
+                    (rels[2].start_node_slot, rels[2].end_node_slot),
+                    (p.slot(id_c), p.slot(id_a))
+                );
+            }
+            other => panic!("expected a create, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn plan_create_refuses_to_redefine_bound_variables() {
+        for query in &[
+            "MATCH (n) CREATE (n:Person)",
+            "MATCH (n) CREATE (n {name: 'x'})-[:R]->()",
+            "MATCH ()-[r]->() CREATE ()-[r:R]->()",
+            "CREATE ()-[r:R]->()-[r:R]->()",
+        ] {
+            let err = plan(query).unwrap_err();
+            assert_eq!(error::kind(&err), ErrorKind::SemanticError, "{}", query);
+        }
+    }
 }
@@ -0,0 +1,325 @@
+// Plans written out in a canonical form, for users to pin the plans of their queries in tests;
+// see testing.rs. Unlike LogicalPlan::fmt_pretty, which is for debugging the planner, this only
+// changes when the plan does: tokens are written as the names they stand for, since what number
+// a name gets depends on what else the database has seen, and it's one operator per line, so
+// a changed plan shows up as a readable diff.
+//
+//   ProduceResult %1 AS name
+//     Project %0.name => %1
+//       Selection %0.age > $auto_0
+//         NodeScan %0 :Person
+//           Argument
+//
+// Slots are written %0, %1 and so on; the literals lifted out of the query are the parameters
+// named $auto_0, $auto_1 and so on, see literals.rs.
+use super::{
+    CountOf, Dir, Expr, IndexRange, LogicalPlan, MapEntryExpr, NodeSpec, Op, RelSpec, SortKey,
+};
+use crate::backend::{Token, Tokens};
+
+pub fn describe(plan: &LogicalPlan, t: &Tokens) -> String {
+    let mut out = String::new();
+    describe_operator(plan, t, 0, &mut out);
+    out
+}
+
+fn describe_operator(plan: &LogicalPlan, t: &Tokens, depth: usize, out: &mut String) {
+    for _ in 0..depth {
+        out.push_str("  ");
+    }
+    out.push_str(&operator(plan, t));
+    out.push('\n');
+    for child in plan.children() {
+        describe_operator(child, t, depth + 1, out);
+    }
+}
+
+// One operator of a plan, on one line without the plans it consumes
+pub fn operator(plan: &LogicalPlan, t: &Tokens) -> String {
+    let mut out = plan.name().to_string();
+    let args = match plan {
+        LogicalPlan::Argument => String::new(),
+        LogicalPlan::NodeScan { slot, labels, .. } => match labels {
+            Some(label) => format!("%{} :{}", slot, name(t, *label)),
+            None => format!("%{}", slot),
+        },
+        LogicalPlan::NodeIndexSeek {
+            slot,
+            label,
+            property,
+            range,
+            ..
+        } => format!(
+            "%{} :{}({}){}",
+            slot,
+            name(t, *label),
+            name(t, *property),
+            index_range(range, t)
+        ),
+        LogicalPlan::Expand {
+            src_slot,
+            rel_slot,
+            dst_slot,
+            rel_type,
+            dir,
+            predicate,
+            ..
+        } => {
+            let rel_type = rel_type.map_or(String::new(), |r| format!(":{}", name(t, r)));
+            let (left, right) = match dir {
+                Some(Dir::Out) => ("-", "->"),
+                Some(Dir::In) => ("<-", "-"),
+                None => ("-", "-"),
+            };
+            let mut s = format!(
+                "(%{}){}[%{}{}]{}(%{})",
+                src_slot, left, rel_slot, rel_type, right, dst_slot
+            );
+            if let Some(p) = predicate {
+                s.push_str(&format!(" WHERE {}", expr(p, t)));
+            }
+            s
+        }
+        LogicalPlan::Optional { slots, .. } => list(slots.iter().map(|s| format!("%{}", s))),
+        LogicalPlan::Selection { predicate, .. } => expr(predicate, t),
+        LogicalPlan::Create { nodes, rels, .. } => list(
+            nodes
+                .iter()
+                .map(|n| node_spec(n, t))
+                .chain(rels.iter().map(|r| rel_spec(r, t))),
+        ),
+        LogicalPlan::Aggregate {
+            grouping,
+            aggregations,
+            ..
+        } => {
+            let projections = |ps: &[(Expr, usize)]| {
+                list(
+                    ps.iter()
+                        .map(|(e, slot)| format!("{} => %{}", expr(e, t), slot)),
+                )
+            };
+            format!(
+                "grouping [{}] aggregations [{}]",
+                projections(grouping),
+                projections(aggregations)
+            )
+        }
+        LogicalPlan::CountStore { slot, count, .. } => {
+            let counted = match count {
+                CountOf::Nodes { label: Some(l) } => format!("(:{})", name(t, *l)),
+                CountOf::Nodes { label: None } => "()".to_string(),
+                CountOf::Rels { rel_type: Some(r) } => format!("()-[:{}]->()", name(t, *r)),
+                CountOf::Rels { rel_type: None } => "()-->()".to_string(),
+            };
+            format!("count{} => %{}", counted, slot)
+        }
+        LogicalPlan::Unwind {
+            list_expr, alias, ..
+        } => format!("{} => %{}", expr(list_expr, t), alias),
+        LogicalPlan::Call {
+            name: proc,
+            args,
+            outputs,
+            ..
+        } => format!(
+            "{}({}) YIELD {}",
+            name(t, *proc),
+            list(args.iter().map(|a| expr(a, t))),
+            list(
+                outputs
+                    .iter()
+                    .map(|(o, slot)| format!("{} => %{}", name(t, *o), slot))
+            )
+        ),
+        LogicalPlan::NestLoop { predicate, .. } => expr(predicate, t),
+        LogicalPlan::ConditionalApply { .. } | LogicalPlan::AntiConditionalApply { .. } => {
+            String::new()
+        }
+        LogicalPlan::Project { projections, .. } => list(
+            projections
+                .iter()
+                .map(|p| format!("{} => %{}", expr(&p.expr, t), p.dst)),
+        ),
+        LogicalPlan::Sort { sort_by, .. } => list(sort_by.iter().map(|k| sort_key(k, t))),
+        LogicalPlan::Limit { skip, limit, .. } => {
+            let mut s = Vec::new();
+            if let Some(skip) = skip {
+                s.push(format!("SKIP {}", expr(skip, t)));
+            }
+            if let Some(limit) = limit {
+                s.push(format!("LIMIT {}", expr(limit, t)));
+            }
+            s.join(" ")
+        }
+        LogicalPlan::ProduceResult { fields, .. } => list(
+            fields
+                .iter()
+                .map(|(field, slot)| format!("%{} AS {}", slot, name(t, *field))),
+        ),
+    };
+    if !args.is_empty() {
+        out.push(' ');
+        out.push_str(&args);
+    }
+    out
+}
+
+fn name(t: &Tokens, tok: Token) -> &str {
+    t.lookup(tok).unwrap_or("?")
+}
+
+fn list(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(", ")
+}
+
+fn index_range(range: &IndexRange, t: &Tokens) -> String {
+    let mut s = String::new();
+    if let Some(lower) = &range.lower {
+        let op = if lower.inclusive { ">=" } else { ">" };
+        s.push_str(&format!(" {} {}", op, expr(&lower.value, t)));
+    }
+    if let Some(upper) = &range.upper {
+        let op = if upper.inclusive { "<=" } else { "<" };
+        s.push_str(&format!(" {} {}", op, expr(&upper.value, t)));
+    }
+    s
+}
+
+fn node_spec(node: &NodeSpec, t: &Tokens) -> String {
+    let labels: String = node
+        .labels
+        .iter()
+        .map(|l| format!(":{}", name(t, *l)))
+        .collect();
+    format!(
+        "(%{}{}{}{})",
+        node.slot,
+        labels,
+        props(&node.props, t),
+        prop_map(&node.prop_map, t)
+    )
+}
+
+fn rel_spec(rel: &RelSpec, t: &Tokens) -> String {
+    format!(
+        "(%{})-[%{}:{}{}{}]->(%{})",
+        rel.start_node_slot,
+        rel.slot,
+        name(t, rel.rel_type),
+        props(&rel.props, t),
+        prop_map(&rel.prop_map, t),
+        rel.end_node_slot
+    )
+}
+
+fn prop_map(prop_map: &Option<Expr>, t: &Tokens) -> String {
+    match prop_map {
+        Some(e) => format!(" {}", expr(e, t)),
+        None => String::new(),
+    }
+}
+
+fn props(props: &[MapEntryExpr], t: &Tokens) -> String {
+    if props.is_empty() {
+        return String::new();
+    }
+    format!(" {}", map(props, t))
+}
+
+fn map(entries: &[MapEntryExpr], t: &Tokens) -> String {
+    format!(
+        "{{{}}}",
+        list(
+            entries
+                .iter()
+                .map(|e| format!("{}: {}", name(t, e.key), expr(&e.val, t)))
+        )
+    )
+}
+
+fn sort_key(key: &SortKey, t: &Tokens) -> String {
+    let mut s = expr(&key.expr, t);
+    if key.descending {
+        s.push_str(" DESC");
+    }
+    // Nulls go last ascending and first descending, unless the query said otherwise
+    if key.nulls_first != key.descending {
+        s.push_str(if key.nulls_first {
+            " NULLS FIRST"
+        } else {
+            " NULLS LAST"
+        });
+    }
+    s
+}
+
+// Expressions are written the way they'd be written in Cypher, with parentheses around anything
+// that's not a single term when it's part of a larger expression
+fn expr(e: &Expr, t: &Tokens) -> String {
+    match e {
+        Expr::And(terms) => list_with(terms, " AND ", t),
+        Expr::Or(terms) => list_with(terms, " OR ", t),
+        Expr::BinaryOp { left, right, op } => {
+            let op = match op {
+                Op::Eq => "=",
+                Op::NotEq => "<>",
+                Op::Gt => ">",
+                Op::Lt => "<",
+                Op::Gte => ">=",
+                Op::Lte => "<=",
+                Op::Div => "/",
+                Op::Mul => "*",
+                Op::Add => "+",
+                Op::Sub => "-",
+            };
+            format!("{} {} {}", term(left, t), op, term(right, t))
+        }
+        Expr::Null => "null".to_string(),
+        Expr::Bool(b) => b.to_string(),
+        Expr::Int(i) => i.to_string(),
+        Expr::Float(f) => format!("{:?}", f),
+        Expr::String(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+        Expr::Map(entries) => map(entries, t),
+        Expr::List(items) => format!("[{}]", list(items.iter().map(|i| expr(i, t)))),
+        Expr::Param(p) => {
+            // Lifted literals are named with their $ already
+            let p = name(t, *p);
+            match p.strip_prefix('$') {
+                Some(_) => p.to_string(),
+                None => format!("${}", p),
+            }
+        }
+        Expr::Prop(e, props) => {
+            let mut s = term(e, t);
+            for p in props {
+                s.push('.');
+                s.push_str(name(t, *p));
+            }
+            s
+        }
+        Expr::Subscript(e, key) => format!("{}[{}]", term(e, t), expr(key, t)),
+        Expr::Slot(s) => format!("%{}", s),
+        Expr::FuncCall { name: f, args } => {
+            format!("{}({})", name(t, *f), list(args.iter().map(|a| expr(a, t))))
+        }
+        Expr::HasLabel(s, l) => format!("%{}:{}", s, name(t, *l)),
+        // The planner plans these as ConditionalApply, so they don't make it into plans
+        Expr::PatternPredicate(_) => "<pattern>".to_string(),
+    }
+}
+
+fn term(e: &Expr, t: &Tokens) -> String {
+    match e {
+        Expr::And(_) | Expr::Or(_) | Expr::BinaryOp { .. } => format!("({})", expr(e, t)),
+        e => expr(e, t),
+    }
+}
+
+fn list_with(terms: &[Expr], sep: &str, t: &Tokens) -> String {
+    terms
+        .iter()
+        .map(|e| term(e, t))
+        .collect::<Vec<_>>()
+        .join(sep)
+}
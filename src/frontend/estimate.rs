@@ -0,0 +1,225 @@
+// How many rows each operator of a plan is expected to produce, going by the statistics the
+// backend keeps. Where the statistics don't say - how many rows pass a WHERE clause, how many
+// rels each node has of a type - this guesses, and the guesses are replaced by what profiled runs
+// of queries saw the same operators do, once there is any; see Database::cardinality_feedback.
+use super::describe;
+use super::{Expr, IndexRange, LogicalPlan, Op};
+use crate::backend::{IndexStats, Statistics, Tokens};
+
+// The share of rows a predicate term lets through, when there's nothing better to go by
+const EQUALITY_SELECTIVITY: f64 = 0.1;
+const RANGE_SELECTIVITY: f64 = 0.3;
+const OTHER_SELECTIVITY: f64 = 0.5;
+// Items per list UNWIND goes through, and rows per procedure call, when there's no telling
+const ROWS_PER_CALL: f64 = 10.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperatorEstimate {
+    // The operator, as LogicalPlan::name calls it
+    pub name: &'static str,
+    pub rows: f64,
+    // What this operator is known as in Statistics::feedback, if how many rows it produces is
+    // a guess that what it was seen doing can improve on
+    pub key: Option<String>,
+}
+
+// Estimates for each operator of the plan, root first and each operator before the plans it
+// consumes, in the order of LogicalPlan::children
+pub fn estimate(plan: &LogicalPlan, stats: &Statistics, t: &Tokens) -> Vec<OperatorEstimate> {
+    let mut out = Vec::new();
+    estimate_operator(plan, stats, t, 1.0, &mut out);
+    out
+}
+
+// Estimate plan and the plans it consumes into out, given how many rows its Argument gets; gives
+// back the rows plan is expected to produce
+fn estimate_operator(
+    plan: &LogicalPlan,
+    stats: &Statistics,
+    t: &Tokens,
+    argument_rows: f64,
+    out: &mut Vec<OperatorEstimate>,
+) -> f64 {
+    let index = out.len();
+    out.push(OperatorEstimate {
+        name: plan.name(),
+        rows: 0.0,
+        key: None,
+    });
+    let children = plan.children();
+    let src = |i: usize, argument_rows: f64, out: &mut Vec<OperatorEstimate>| {
+        estimate_operator(children[i], stats, t, argument_rows, out)
+    };
+
+    let nodes = stats.nodes as f64;
+    // Rows per src row of an operator that can learn from feedback, and the rows from src
+    let (per_src_row, src_rows) = match plan {
+        LogicalPlan::Argument => {
+            out[index].rows = argument_rows;
+            return argument_rows;
+        }
+        LogicalPlan::NodeScan { labels, .. } => {
+            let src_rows = src(0, argument_rows, out);
+            let scanned = match labels {
+                Some(l) => stats.nodes_with(*l) as f64,
+                None => nodes,
+            };
+            out[index].rows = src_rows * scanned;
+            return out[index].rows;
+        }
+        LogicalPlan::NodeIndexSeek {
+            label,
+            property,
+            range,
+            ..
+        } => {
+            let src_rows = src(0, argument_rows, out);
+            let guess = stats
+                .index(*label, *property)
+                .map_or(0.0, |index| seek_rows(index, range));
+            (guess, src_rows)
+        }
+        LogicalPlan::Expand {
+            rel_type,
+            dir,
+            predicate,
+            ..
+        } => {
+            let src_rows = src(0, argument_rows, out);
+            let rels = match rel_type {
+                Some(r) => stats.rels_with(*r) as f64,
+                None => stats.rels as f64,
+            };
+            // Each rel is there twice for an undirected expand, once from either end
+            let ends = if dir.is_some() { 1.0 } else { 2.0 };
+            let filter = predicate.as_ref().map_or(1.0, |p| selectivity(p, stats));
+            (ends * rels / nodes.max(1.0) * filter, src_rows)
+        }
+        LogicalPlan::Selection { predicate, .. } => {
+            (selectivity(predicate, stats), src(0, argument_rows, out))
+        }
+        LogicalPlan::Unwind { list_expr, .. } => {
+            let items = match list_expr {
+                Expr::List(items) => items.len() as f64,
+                _ => ROWS_PER_CALL,
+            };
+            (items, src(0, argument_rows, out))
+        }
+        LogicalPlan::Call { .. } => (ROWS_PER_CALL, src(0, argument_rows, out)),
+        LogicalPlan::ConditionalApply { .. } | LogicalPlan::AntiConditionalApply { .. } => {
+            let src_rows = src(0, argument_rows, out);
+            // The probe is run once per src row
+            src(1, src_rows, out);
+            (OTHER_SELECTIVITY, src_rows)
+        }
+        LogicalPlan::NestLoop { predicate, .. } => {
+            let outer = src(0, argument_rows, out);
+            let inner = src(1, outer, out);
+            out[index].rows = inner * selectivity(predicate, stats);
+            return out[index].rows;
+        }
+        LogicalPlan::Optional { .. } => {
+            out[index].rows = src(0, argument_rows, out).max(argument_rows);
+            return out[index].rows;
+        }
+        LogicalPlan::Aggregate { grouping, .. } => {
+            let src_rows = src(0, argument_rows, out);
+            // One row for all of them, or some share of them for each group
+            let groups = if grouping.is_empty() {
+                1.0
+            } else {
+                src_rows * OTHER_SELECTIVITY
+            };
+            out[index].rows = groups.min(src_rows.max(1.0));
+            return out[index].rows;
+        }
+        LogicalPlan::CountStore { .. }
+        | LogicalPlan::Create { .. }
+        | LogicalPlan::Project { .. }
+        | LogicalPlan::Sort { .. }
+        | LogicalPlan::Limit { .. }
+        | LogicalPlan::ProduceResult { .. } => {
+            out[index].rows = src(0, argument_rows, out);
+            return out[index].rows;
+        }
+    };
+
+    let key = key(plan, t);
+    let per_src_row = match stats.feedback.get(&key) {
+        Some(observed) if observed.runs > 0 => observed.rows_per_input(),
+        _ => per_src_row,
+    };
+    out[index].rows = src_rows * per_src_row;
+    out[index].key = Some(key);
+    out[index].rows
+}
+
+// What an operator is known as in Statistics::feedback: the operator as describe.rs writes it,
+// with its slots numbered in the order they appear, so the same operator in another query
+// has the same key
+pub fn key(plan: &LogicalPlan, t: &Tokens) -> String {
+    let line = describe::operator(plan, t);
+    let mut out = String::with_capacity(line.len());
+    let mut slots: Vec<&str> = Vec::new();
+    let mut rest = line.as_str();
+    while let Some(i) = rest.find('%') {
+        out.push_str(&rest[..=i]);
+        rest = &rest[i + 1..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let slot = &rest[..digits];
+        let n = match slots.iter().position(|s| *s == slot) {
+            Some(n) => n,
+            None => {
+                slots.push(slot);
+                slots.len() - 1
+            }
+        };
+        out.push_str(&n.to_string());
+        rest = &rest[digits..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// Nodes a seek in the index is expected to find per run
+pub fn seek_rows(index: &IndexStats, range: &IndexRange) -> f64 {
+    if is_point(range) {
+        return index.entries as f64 / index.distinct.max(1) as f64;
+    }
+    let bounds = range.lower.iter().count() + range.upper.iter().count();
+    index.entries as f64 * RANGE_SELECTIVITY.powi(bounds as i32)
+}
+
+// A range of one value, like from n.age = 30
+pub fn is_point(range: &IndexRange) -> bool {
+    match (&range.lower, &range.upper) {
+        (Some(lower), Some(upper)) => {
+            lower.inclusive && upper.inclusive && lower.value == upper.value
+        }
+        _ => false,
+    }
+}
+
+// The share of rows a predicate is expected to let through
+fn selectivity(predicate: &Expr, stats: &Statistics) -> f64 {
+    match predicate {
+        Expr::And(terms) => terms.iter().map(|t| selectivity(t, stats)).product(),
+        Expr::Or(terms) => {
+            1.0 - terms
+                .iter()
+                .map(|t| 1.0 - selectivity(t, stats))
+                .product::<f64>()
+        }
+        Expr::HasLabel(_, label) => stats.nodes_with(*label) as f64 / stats.nodes.max(1) as f64,
+        Expr::BinaryOp { op: Op::Eq, .. } => EQUALITY_SELECTIVITY,
+        Expr::BinaryOp {
+            op: Op::Gt | Op::Lt | Op::Gte | Op::Lte,
+            ..
+        } => RANGE_SELECTIVITY,
+        Expr::Bool(true) => 1.0,
+        Expr::Bool(false) | Expr::Null => 0.0,
+        _ => OTHER_SELECTIVITY,
+    }
+}
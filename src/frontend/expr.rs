@@ -2,10 +2,14 @@
 // to expressions.
 
 use crate::backend::{Token, Tokens};
-use crate::frontend::{PlanningContext, Result, Rule};
-use crate::Slot;
+use crate::frontend::{
+    identifier, parse_pattern, types, PatternGraph, PlanningContext, Result, Rule,
+};
+use crate::{QueryError, Slot, Val};
 use pest::iterators::Pair;
+use std::borrow::Cow;
 use std::collections::HashSet;
+use std::convert::TryFrom;
 use std::str::FromStr;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -13,6 +17,9 @@ pub enum Op {
     Eq,
     NotEq,
     Gt,
+    Lt,
+    Gte,
+    Lte,
     Div,
     Mul,
     Add,
@@ -27,6 +34,9 @@ impl FromStr for Op {
             "=" => Ok(Op::Eq),
             "<>" => Ok(Op::NotEq),
             ">" => Ok(Op::Gt),
+            "<" => Ok(Op::Lt),
+            ">=" => Ok(Op::Gte),
+            "<=" => Ok(Op::Lte),
             "/" => Ok(Op::Div),
             "*" => Ok(Op::Mul),
             "+" => Ok(Op::Add),
@@ -50,6 +60,7 @@ pub enum Expr {
     },
 
     // Literals
+    Null,
     Bool(bool),
     Int(i64),
     Float(f64),
@@ -59,8 +70,15 @@ pub enum Expr {
     Map(Vec<MapEntryExpr>),
     List(Vec<Expr>),
 
+    // A $parameter, bound to a value when the query is run. Literals in the query may also have
+    // been lifted out into parameters, see literals.rs
+    Param(Token),
+
     // Lookup a property by id
     Prop(Box<Self>, Vec<Token>),
+    // A property of a node, rel or map by the name the key evaluates to, or an item of a list
+    // by index; like n[key] or list[0]
+    Subscript(Box<Self>, Box<Self>),
     Slot(Slot),
     FuncCall {
         name: Token,
@@ -69,6 +87,11 @@ pub enum Expr {
 
     // True if the Node in the specified Slot has the specified Label
     HasLabel(Slot, Token),
+
+    // A pattern used as a predicate, eg. the (a)-->(b) in WHERE NOT (a)-->(b). These are not
+    // evaluated as expressions; the planner lifts them out of WHERE clauses and plans them as
+    // probes of the pattern.
+    PatternPredicate(Box<PatternGraph>),
 }
 
 impl Expr {
@@ -76,6 +99,9 @@ impl Expr {
     pub fn is_aggregating(&self, aggregating_funcs: &HashSet<Token>) -> bool {
         match self {
             Expr::Prop(c, _) => c.is_aggregating(aggregating_funcs),
+            Expr::Subscript(c, key) => {
+                c.is_aggregating(aggregating_funcs) || key.is_aggregating(aggregating_funcs)
+            }
             Expr::Slot(_) => false,
             Expr::Float(_) => false,
             Expr::Int(_) => false,
@@ -90,11 +116,70 @@ impl Expr {
             }
             Expr::And(terms) => terms.iter().any(|c| c.is_aggregating(aggregating_funcs)),
             Expr::Or(terms) => terms.iter().any(|c| c.is_aggregating(aggregating_funcs)),
-            Expr::Bool(_) => false,
+            Expr::Null | Expr::Bool(_) => false,
+            Expr::Param(_) => false,
             Expr::BinaryOp { left, right, op: _ } => {
                 left.is_aggregating(aggregating_funcs) | right.is_aggregating(aggregating_funcs)
             }
             Expr::HasLabel(_, _) => false,
+            Expr::PatternPredicate(_) => false,
+        }
+    }
+
+    // Add the slots this expression reads to out; false if it has a pattern predicate, whose
+    // slots aren't known until it's planned
+    pub fn collect_slots(&self, out: &mut Vec<Slot>) -> bool {
+        match self {
+            Expr::Slot(s) | Expr::HasLabel(s, _) => {
+                out.push(*s);
+                true
+            }
+            Expr::Prop(c, _) => c.collect_slots(out),
+            Expr::Subscript(c, key) => c.collect_slots(out) && key.collect_slots(out),
+            Expr::Map(children) => children.iter().all(|c| c.val.collect_slots(out)),
+            Expr::List(terms) | Expr::And(terms) | Expr::Or(terms) => {
+                terms.iter().all(|c| c.collect_slots(out))
+            }
+            Expr::FuncCall { args, .. } => args.iter().all(|c| c.collect_slots(out)),
+            Expr::BinaryOp { left, right, .. } => {
+                left.collect_slots(out) && right.collect_slots(out)
+            }
+            Expr::Null
+            | Expr::Bool(_)
+            | Expr::Int(_)
+            | Expr::Float(_)
+            | Expr::String(_)
+            | Expr::Param(_) => true,
+            Expr::PatternPredicate(_) => false,
+        }
+    }
+
+    // Call f with every slot this expression reads, so it can change them; see
+    // rewrite::compact_slots
+    pub fn slots_mut(&mut self, f: &mut dyn FnMut(&mut Slot)) {
+        match self {
+            Expr::Slot(s) | Expr::HasLabel(s, _) => f(s),
+            Expr::Prop(c, _) => c.slots_mut(f),
+            Expr::Subscript(c, key) => {
+                c.slots_mut(f);
+                key.slots_mut(f)
+            }
+            Expr::Map(children) => children.iter_mut().for_each(|c| c.val.slots_mut(f)),
+            Expr::List(terms) | Expr::And(terms) | Expr::Or(terms) => {
+                terms.iter_mut().for_each(|c| c.slots_mut(f))
+            }
+            Expr::FuncCall { args, .. } => args.iter_mut().for_each(|c| c.slots_mut(f)),
+            Expr::BinaryOp { left, right, .. } => {
+                left.slots_mut(f);
+                right.slots_mut(f)
+            }
+            Expr::Null
+            | Expr::Bool(_)
+            | Expr::Int(_)
+            | Expr::Float(_)
+            | Expr::String(_)
+            | Expr::Param(_)
+            | Expr::PatternPredicate(_) => (),
         }
     }
 
@@ -131,11 +216,13 @@ pub(super) fn plan_expr(pc: &mut PlanningContext, expression: Pair<Rule>) -> Res
             _ => bail!("({:?}): {}", inner.as_rule(), inner.as_str()),
         }
     }
-    if or_expressions.len() == 1 {
-        Ok(or_expressions.remove(0))
+    let expr = if or_expressions.len() == 1 {
+        or_expressions.remove(0)
     } else {
-        Ok(Expr::Or(or_expressions))
-    }
+        Expr::Or(or_expressions)
+    };
+    types::type_of(pc, &expr)?;
+    Ok(expr)
 }
 
 fn plan_add_sub(pc: &mut PlanningContext, item: Pair<Rule>) -> Result<Expr> {
@@ -199,49 +286,100 @@ fn plan_mul_div(pc: &mut PlanningContext, item: Pair<Rule>) -> Result<Expr> {
     }
 }
 
-fn plan_term(pc: &mut PlanningContext, term: Pair<Rule>) -> Result<Expr> {
+pub(super) fn plan_term(pc: &mut PlanningContext, term: Pair<Rule>) -> Result<Expr> {
+    if let Some(v) = literal_value(&term)? {
+        if let Val::Float(_) = v {
+            if !pc.backend_desc.types.float {
+                bail!("the backend does not support floating point numbers")
+            }
+        }
+        return Ok(match pc.literal_params.get(&term.as_span().start()) {
+            Some(param) => Expr::Param(*param),
+            None => match v {
+                Val::Int(v) => Expr::Int(v),
+                Val::Float(v) => Expr::Float(v),
+                Val::String(v) => Expr::String(v.to_string()),
+                Val::Bool(v) => Expr::Bool(v),
+                _ => unreachable!("only scalars are literal values"),
+            },
+        });
+    }
     match term.as_rule() {
-        Rule::string => {
-            let content = term
-                .into_inner()
-                .next()
-                .expect("Strings should always have an inner value")
-                .as_str();
-            return Ok(Expr::String(String::from(content)));
+        Rule::param => {
+            let name = pc.tokenize(&identifier(
+                &term.into_inner().next().expect("parameters have names"),
+            ));
+            // In a view, parameters are the arguments it was called with
+            if let Some(arg) = pc.view_args.get(&name) {
+                return Ok(arg.clone());
+            }
+            if !pc.parameters.contains(&name) {
+                pc.parameters.push(name);
+            }
+            return Ok(Expr::Param(name));
         }
         Rule::id => {
-            let tok = pc.tokenize(term.as_str());
-            return Ok(Expr::Slot(pc.get_or_alloc_slot(tok)));
+            return Ok(Expr::Slot(pc.slot_of(&term)?));
         }
         Rule::prop_lookup => {
             let mut prop_lookup = term.into_inner();
             let prop_lookup_expr = prop_lookup.next().unwrap();
             let base = match prop_lookup_expr.as_rule() {
-                Rule::id => {
-                    let tok = pc.tokenize(prop_lookup_expr.as_str());
-                    Expr::Slot(pc.get_or_alloc_slot(tok))
-                }
+                Rule::id => Expr::Slot(pc.slot_of(&prop_lookup_expr)?),
                 _ => unreachable!(),
             };
             let mut props = Vec::new();
             for p_inner in prop_lookup {
                 if let Rule::id = p_inner.as_rule() {
-                    props.push(pc.tokenize(p_inner.as_str()));
+                    props.push(pc.tokenize(&identifier(&p_inner)));
                 }
             }
             return Ok(Expr::Prop(Box::new(base), props));
         }
+        Rule::subscript => {
+            let mut parts = term.into_inner();
+            let mut out = plan_term(pc, parts.next().expect("subscripts have a base"))?;
+            for key in parts {
+                out = Expr::Subscript(Box::new(out), Box::new(plan_expr(pc, key)?));
+            }
+            Ok(out)
+        }
         Rule::func_call => {
+            let span = term.as_span();
             let mut func_call = term.into_inner();
             let func_name_item = func_call
                 .next()
                 .expect("All func_calls must start with an identifier");
-            let name = pc.tokenize(&func_name_item.as_str().to_lowercase());
+            let name_str = identifier(&func_name_item).to_lowercase();
+            let name = pc.tokenize(&name_str);
+            let (max, min) = match pc.backend_desc.functions.iter().find(|f| f.name == name) {
+                Some(sig) => (sig.args.len(), sig.args.len() - sig.optional),
+                None => bail!(QueryError::semantic(
+                    format!("There is no function with the name `{}`", name_str),
+                    span
+                )),
+            };
             // Parse args
             let mut args = Vec::new();
             for arg in func_call {
                 args.push(plan_expr(pc, arg)?);
             }
+            if args.len() < min || args.len() > max {
+                let takes = match (min, max) {
+                    (1, 1) => "1 argument".to_string(),
+                    (min, max) if min == max => format!("{} arguments", max),
+                    (min, max) => format!("{} to {} arguments", min, max),
+                };
+                bail!(QueryError::semantic(
+                    format!(
+                        "`{}` takes {}, but was given {}",
+                        name_str,
+                        takes,
+                        args.len()
+                    ),
+                    span
+                ))
+            }
             return Ok(Expr::FuncCall { name, args });
         }
         Rule::count_call => {
@@ -252,6 +390,9 @@ fn plan_term(pc: &mut PlanningContext, term: Pair<Rule>) -> Result<Expr> {
             });
         }
         Rule::list => {
+            if !pc.backend_desc.types.list {
+                bail!("the backend does not support lists")
+            }
             let mut items = Vec::new();
             let exprs = term.into_inner();
             for exp in exprs {
@@ -259,21 +400,28 @@ fn plan_term(pc: &mut PlanningContext, term: Pair<Rule>) -> Result<Expr> {
             }
             return Ok(Expr::List(items));
         }
-        Rule::map => return Ok(Expr::Map(parse_map_expression(pc, term)?)),
-        Rule::int => {
-            let v = term.as_str().parse::<i64>()?;
-            return Ok(Expr::Int(v));
-        }
-        Rule::float => {
-            let v = term.as_str().parse::<f64>()?;
-            return Ok(Expr::Float(v));
+        Rule::map => {
+            if !pc.backend_desc.types.map {
+                bail!("the backend does not support maps")
+            }
+            return Ok(Expr::Map(parse_map_expression(pc, term)?));
         }
-        Rule::science => {
-            let v = term.as_str().parse::<f64>()?;
-            return Ok(Expr::Float(v));
+        Rule::neg_expr => {
+            // Negated number literals are handled by literal_value, this is everything else
+            let negated = term
+                .into_inner()
+                .next()
+                .expect("unary minus must be followed by an expression");
+            return match plan_term(pc, negated)? {
+                Expr::Float(v) => Ok(Expr::Float(-v)),
+                expr => Ok(Expr::BinaryOp {
+                    left: Box::new(Expr::Int(0)),
+                    right: Box::new(expr),
+                    op: Op::Sub,
+                }),
+            };
         }
-        Rule::lit_true => return Ok(Expr::Bool(true)),
-        Rule::lit_false => return Ok(Expr::Bool(false)),
+        Rule::lit_null => return Ok(Expr::Null),
         Rule::binary_op => {
             let mut parts = term.into_inner();
             let left = parts.next().expect("binary operators must have a left arg");
@@ -296,10 +444,113 @@ fn plan_term(pc: &mut PlanningContext, term: Pair<Rule>) -> Result<Expr> {
             // this happens when there are parenthetises forcing "full" expressions down here
             return plan_expr(pc, term);
         }
+        Rule::not_expr => {
+            let negated = term
+                .into_inner()
+                .find(|p| p.as_rule() != Rule::not_kw)
+                .expect("NOT must be followed by an expression");
+            let name = pc.tokenize("not");
+            Ok(Expr::FuncCall {
+                name,
+                args: vec![plan_term(pc, negated)?],
+            })
+        }
+        Rule::pattern_predicate => {
+            let mut pg = PatternGraph::default();
+            parse_pattern(pc, &mut pg, term)?;
+            Ok(Expr::PatternPredicate(Box::new(pg)))
+        }
         _ => panic!("({:?}): {}", term.as_rule(), term.as_str()),
     }
 }
 
+// The value of a term if it is a literal number, string or boolean. Negative number literals
+// count as literals, both to save work at runtime and because the smallest integer can only be
+// written as a negative literal.
+pub(super) fn literal_value(term: &Pair<Rule>) -> Result<Option<Val>> {
+    Ok(Some(match term.as_rule() {
+        Rule::string => {
+            let content = term
+                .clone()
+                .into_inner()
+                .next()
+                .expect("Strings should always have an inner value")
+                .as_str();
+            Val::String(unescape(content).into())
+        }
+        Rule::int | Rule::hex_int => Val::Int(parse_int(term.as_str(), false)?),
+        Rule::float | Rule::science => Val::Float(parse_float(term.as_str())?),
+        Rule::lit_true => Val::Bool(true),
+        Rule::lit_false => Val::Bool(false),
+        Rule::neg_expr => {
+            let negated = term
+                .clone()
+                .into_inner()
+                .next()
+                .expect("unary minus must be followed by an expression");
+            match negated.as_rule() {
+                Rule::int | Rule::hex_int => Val::Int(parse_int(negated.as_str(), true)?),
+                Rule::float | Rule::science => Val::Float(-parse_float(negated.as_str())?),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    }))
+}
+
+// The contents of a string literal, with backslash escapes replaced by what they stand for
+fn unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return Cow::Borrowed(s);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        // The grammar only allows the escapes handled here, and quotes, slashes and backslashes
+        match chars.next() {
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    Cow::Owned(out)
+}
+
+// Integer literals are written without their sign, which is passed separately since the smallest
+// i64 has no positive counterpart
+fn parse_int(literal: &str, negative: bool) -> Result<i64> {
+    let (digits, radix) = match literal.get(..2) {
+        Some("0x") | Some("0X") => (&literal[2..], 16),
+        _ => (literal, 10),
+    };
+    let magnitude = u64::from_str_radix(digits, radix).ok().map(i128::from);
+    let v = magnitude.map(|m| if negative { -m } else { m });
+    match v.and_then(|v| i64::try_from(v).ok()) {
+        Some(v) => Ok(v),
+        None => bail!(
+            "integer literal {}{} does not fit in 64 bits",
+            if negative { "-" } else { "" },
+            literal
+        ),
+    }
+}
+
+fn parse_float(literal: &str) -> Result<f64> {
+    let v = literal.parse::<f64>()?;
+    if v.is_infinite() {
+        bail!("float literal {} is too large", literal)
+    }
+    Ok(v)
+}
+
 pub fn parse_map_expression(
     pc: &mut PlanningContext,
     map_expr: Pair<Rule>,
@@ -312,7 +563,7 @@ pub fn parse_map_expression(
                 let id_token = pair_iter
                     .next()
                     .expect("Map pair must contain an identifier");
-                let identifier = pc.tokenize(id_token.as_str());
+                let identifier = pc.tokenize(&identifier(&id_token));
 
                 let expr_token = pair_iter
                     .next()
@@ -333,6 +584,7 @@ pub fn parse_map_expression(
 mod tests {
     use super::*;
     use crate::backend::{BackendDesc, FuncSignature, FuncType, Token, Tokens};
+    use crate::diagnostics::NoDiagnostics;
     use crate::frontend::{Frontend, LogicalPlan};
     use crate::Type;
     use anyhow::Result;
@@ -357,11 +609,14 @@ mod tests {
             name: fn_count,
             returns: Type::Integer,
             args: vec![(tok_expr, Type::Any)],
+            optional: 0,
         }]);
 
         let frontend = Frontend {
             tokens: Rc::clone(&tokens),
             backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
         };
         let mut pc = PlanningContext::new(Rc::clone(&tokens), &backend_desc);
         let plan = frontend.plan_in_context(&format!("WITH {}", q), &mut pc);
@@ -373,7 +628,8 @@ mod tests {
         {
             return Ok(PlanArtifacts {
                 expr: projections[0].expr.clone(),
-                slots: pc.slots,
+                // Include what a WITH dropped, so tests can check the plan up to the WITH
+                slots: pc.dropped.into_iter().chain(pc.slots).collect(),
                 tokens: Rc::clone(&tokens),
             });
         } else {
@@ -384,6 +640,46 @@ mod tests {
     #[test]
     fn plan_some_numbers() -> Result<()> {
         assert_eq!(plan("-1e-9")?.expr, Expr::Float(-1e-9));
+        assert_eq!(plan("6.022e23")?.expr, Expr::Float(6.022e23));
+        assert_eq!(plan("0x1F")?.expr, Expr::Int(31));
+        assert_eq!(plan("-0x1f")?.expr, Expr::Int(-31));
+        assert_eq!(plan("- 7")?.expr, Expr::Int(-7));
+        assert_eq!(plan("-9223372036854775808")?.expr, Expr::Int(i64::MIN));
+        assert!(plan("9223372036854775808").is_err());
+        assert!(plan("0x10000000000000000").is_err());
+        assert!(plan("1e999").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn plan_string_escapes() -> Result<()> {
+        assert_eq!(
+            plan(r#"'it\'s a\\b\n"'"#)?.expr,
+            Expr::String("it's a\\b\n\"".to_string())
+        );
+        assert_eq!(
+            plan(r#""say \"hi\"""#)?.expr,
+            Expr::String("say \"hi\"".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_unary_minus() -> Result<()> {
+        let p = plan("-a = --1.5")?;
+        let id_a = p.tokens.borrow_mut().tokenize("a");
+        assert_eq!(
+            p.expr,
+            Expr::BinaryOp {
+                left: Box::new(Expr::BinaryOp {
+                    left: Box::new(Expr::Int(0)),
+                    right: Box::new(Expr::Slot(p.slots[&id_a])),
+                    op: Op::Sub
+                }),
+                right: Box::new(Expr::Float(1.5)),
+                op: Op::Eq
+            }
+        );
         Ok(())
     }
 
@@ -449,6 +745,25 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn plan_not() -> Result<()> {
+        let p = plan("NOT true = nothing")?;
+        let fn_not = p.tokens.borrow_mut().tokenize("not");
+        let id_nothing = p.tokens.borrow_mut().tokenize("nothing");
+        assert_eq!(
+            p.expr,
+            Expr::FuncCall {
+                name: fn_not,
+                args: vec![Expr::BinaryOp {
+                    left: Box::new(Expr::Bool(true)),
+                    right: Box::new(Expr::Slot(p.slots[&id_nothing])),
+                    op: Op::Eq
+                }]
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn plan_binary_operators() -> Result<()> {
         assert_eq!(
@@ -0,0 +1,283 @@
+//
+// Query normalization and fingerprinting. Two queries that differ only in their literal values,
+// whitespace or the case of their keywords normalize to the same text and fingerprint; this is
+// useful to group queries in monitoring, and it's the key for the plan cache.
+//
+// This works on the query text rather than on the parse tree, so that it can fingerprint any
+// query, including ones gqlite fails to parse.
+//
+use crate::Val;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NormalizedQuery {
+    // The query with literals replaced by parameters named $auto_0, $auto_1 and so on, keywords
+    // in upper case and whitespace normalized
+    pub text: String,
+    // The literals lifted out of the query, in order; the value of $auto_0 is at index 0
+    pub parameters: Vec<Val>,
+    // Stable hash of text; the same query gets the same fingerprint across processes and
+    // gqlite versions, unless normalization itself changes
+    pub fingerprint: u64,
+}
+
+pub fn fingerprint(query: &str) -> u64 {
+    normalize(query).fingerprint
+}
+
+pub fn normalize(query: &str) -> NormalizedQuery {
+    let tokens = lex(query);
+    let mut text = String::with_capacity(query.len());
+    let mut parameters = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && needs_space(&tokens[i - 1], token) {
+            text.push(' ');
+        }
+        match token {
+            Token::Word(w) => {
+                // Property keys and labels are case sensitive even if they look like keywords
+                let is_key = (i > 0
+                    && matches!(tokens[i - 1], Token::Punct(".") | Token::Punct(":")))
+                    || matches!(tokens.get(i + 1), Some(Token::Punct(":")));
+                match keyword(w) {
+                    Some(Keyword::Literal(v)) => {
+                        text.push_str(&format!("$auto_{}", parameters.len()));
+                        parameters.push(v);
+                    }
+                    Some(Keyword::Keyword(kw)) if !is_key => text.push_str(kw),
+                    _ => text.push_str(w),
+                }
+            }
+            Token::Literal(v) => {
+                text.push_str(&format!("$auto_{}", parameters.len()));
+                parameters.push(v.clone());
+            }
+            Token::Param(p) => text.push_str(p),
+            Token::Punct(p) => text.push_str(p),
+        }
+    }
+    let fingerprint = fnv1a(text.as_bytes());
+    NormalizedQuery {
+        text,
+        parameters,
+        fingerprint,
+    }
+}
+
+#[derive(Debug)]
+pub(super) enum Token<'a> {
+    // Identifiers and keywords
+    Word(&'a str),
+    // $param, kept as written
+    Param(&'a str),
+    Literal(Val),
+    Punct(&'a str),
+}
+
+enum Keyword {
+    Keyword(&'static str),
+    Literal(Val),
+}
+
+// Keywords are case insensitive, so they are normalized to upper case
+fn keyword(word: &str) -> Option<Keyword> {
+    const KEYWORDS: &[&str] = &[
+        "AND", "AS", "ASC", "BY", "CALL", "CREATE", "DESC", "DISTINCT", "LIMIT", "MATCH", "NOT",
+        "OPTIONAL", "OR", "ORDER", "RETURN", "SKIP", "UNWIND", "WHERE", "WITH", "XOR", "YIELD",
+    ];
+    let upper = word.to_ascii_uppercase();
+    match upper.as_str() {
+        "TRUE" => Some(Keyword::Literal(Val::Bool(true))),
+        "FALSE" => Some(Keyword::Literal(Val::Bool(false))),
+        "NULL" => Some(Keyword::Keyword("NULL")),
+        _ => KEYWORDS
+            .iter()
+            .find(|kw| **kw == upper)
+            .map(|kw| Keyword::Keyword(kw)),
+    }
+}
+
+// Whitespace is only kept where it separates words, and after commas
+fn needs_space(prev: &Token, next: &Token) -> bool {
+    let wordy = |t: &Token| !matches!(t, Token::Punct(_));
+    let is_keyword = |t: &Token| match t {
+        Token::Word(w) => matches!(keyword(w), Some(Keyword::Keyword(_))),
+        _ => false,
+    };
+    (wordy(prev) && wordy(next))
+        || is_keyword(prev)
+        || is_keyword(next)
+        || matches!(prev, Token::Punct(","))
+}
+
+pub(super) fn lex(query: &str) -> Vec<Token<'_>> {
+    let bytes = query.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if query[i..].starts_with("//") {
+            // Comments don't change what the query means, so they don't change the fingerprint
+            i = query[i..].find('\n').map_or(bytes.len(), |end| i + end);
+        } else if query[i..].starts_with("/*") {
+            i = query[i + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| i + end + 4);
+        } else if c == b'\'' || c == b'"' {
+            let mut s = String::new();
+            i += 1;
+            while i < bytes.len() && bytes[i] != c {
+                if bytes[i] == b'\\' && i + 1 < bytes.len() {
+                    i += 1;
+                }
+                let ch = query[i..].chars().next().unwrap();
+                s.push(ch);
+                i += ch.len_utf8();
+            }
+            i += 1;
+            tokens.push(Token::Literal(Val::String(s.into())));
+        } else if c == b'0' && matches!(bytes.get(i + 1), Some(b'x') | Some(b'X')) {
+            i += 2;
+            while i < bytes.len() && bytes[i].is_ascii_hexdigit() {
+                i += 1;
+            }
+            let v = i64::from_str_radix(&query[start + 2..i], 16);
+            tokens.push(Token::Literal(v.map(Val::Int).unwrap_or(Val::Null)));
+        } else if c.is_ascii_digit() {
+            let mut is_float = false;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+            if i < bytes.len() && bytes[i] == b'.' {
+                is_float = true;
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+                is_float = true;
+                i += 1;
+                if i < bytes.len() && (bytes[i] == b'-' || bytes[i] == b'+') {
+                    i += 1;
+                }
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+            }
+            let lit = &query[start..i];
+            tokens.push(Token::Literal(match (is_float, lit.parse::<i64>()) {
+                (false, Ok(v)) => Val::Int(v),
+                _ => lit.parse().map(Val::Float).unwrap_or(Val::Null),
+            }));
+        } else if c == b'$' || c == b'_' || c == b'`' || is_word_char(&query[i..], true) {
+            if c == b'`' {
+                // Quoted identifiers run to the closing backtick; doubled backticks are escapes
+                i += 1;
+                while i < bytes.len() && (bytes[i] != b'`' || bytes.get(i + 1) == Some(&b'`')) {
+                    i += if bytes[i] == b'`' { 2 } else { 1 };
+                }
+                i += 1;
+            } else {
+                i += query[i..].chars().next().unwrap().len_utf8();
+                while i < bytes.len() && is_word_char(&query[i..], false) {
+                    i += query[i..].chars().next().unwrap().len_utf8();
+                }
+            }
+            let word = &query[start..i.min(bytes.len())];
+            tokens.push(if c == b'$' {
+                Token::Param(word)
+            } else {
+                Token::Word(word)
+            });
+        } else if b"<>-=!+*/".contains(&c) {
+            // Operators and arrows, eg. <>, -->, <-
+            while i < bytes.len() && b"<>-=!+*/".contains(&bytes[i]) {
+                i += 1;
+            }
+            tokens.push(Token::Punct(&query[start..i]));
+        } else {
+            i += query[i..].chars().next().unwrap().len_utf8();
+            tokens.push(Token::Punct(&query[start..i]));
+        }
+    }
+    tokens
+}
+
+// Does rest start with a character that can be part of an identifier?
+fn is_word_char(rest: &str, first: bool) -> bool {
+    match rest.chars().next() {
+        Some(c) if first => c == '_' || c.is_alphabetic(),
+        Some(c) => c == '_' || c.is_alphanumeric(),
+        None => false,
+    }
+}
+
+// 64-bit FNV-1a; we don't use the std hasher since it's not guaranteed to be stable
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_literals_whitespace_and_keywords() {
+        let a = normalize("MATCH (n:Person {name: 'Bob'})\n  WHERE n.age > 30 RETURN n.name");
+        let b = normalize("match (n:Person{name:\"Alice\"}) where n.age>40 return n.name");
+
+        assert_eq!(
+            a.text,
+            "MATCH (n:Person{name:$auto_0}) WHERE n.age>$auto_1 RETURN n.name"
+        );
+        assert_eq!(a.text, b.text);
+        assert_eq!(a.fingerprint, b.fingerprint);
+        assert_eq!(a.parameters, vec![Val::String("Bob".into()), Val::Int(30)]);
+        assert_eq!(
+            b.parameters,
+            vec![Val::String("Alice".into()), Val::Int(40)]
+        );
+        assert_eq!(
+            normalize("RETURN 0x1F, 1e+3").parameters,
+            vec![Val::Int(31), Val::Float(1e3)]
+        );
+    }
+
+    #[test]
+    fn keeps_case_of_identifiers() {
+        assert_ne!(
+            fingerprint("MATCH (n) RETURN n.limit"),
+            fingerprint("MATCH (n) RETURN n.LIMIT")
+        );
+        assert_ne!(
+            fingerprint("MATCH (n:Person) RETURN n"),
+            fingerprint("MATCH (n:person) RETURN n")
+        );
+        assert_eq!(normalize("RETURN $x, TRUE").text, "RETURN $x, $auto_0");
+    }
+
+    #[test]
+    fn keeps_quoted_and_unicode_identifiers_whole() {
+        assert_eq!(
+            normalize("MATCH (`a ``b`:Größe) RETURN `a ``b`.straße").text,
+            "MATCH (`a ``b`:Größe) RETURN `a ``b`.straße"
+        );
+    }
+
+    #[test]
+    fn ignores_comments() {
+        assert_eq!(
+            normalize("MATCH (n) // all of them\n/* the name */ RETURN n.name").text,
+            "MATCH (n) RETURN n.name"
+        );
+        assert_eq!(normalize("RETURN 10 / 2").text, "RETURN $auto_0/$auto_1");
+    }
+}
@@ -0,0 +1,120 @@
+// Lifting literals out of queries and into parameters. A query planned with its literals lifted
+// out is planned once for every query that only differs from it in those literals: a plan for
+// MATCH (n {id: 1}) RETURN n runs MATCH (n {id: 2}) RETURN n just fine, with 2 bound to the
+// parameter that 1 was lifted into.
+//
+// Only numbers, strings and booleans are lifted. Lists and maps are left in place, since the
+// planner looks at what's in them, and null is left alone since it types as anything.
+use super::expr::literal_value;
+use super::{Pair, Result, Rule};
+use crate::{Type, Val};
+
+// A literal in a query
+#[derive(Debug, Clone, PartialEq)]
+pub struct Literal {
+    // Where in the query text the literal starts
+    pub start: usize,
+    pub value: Val,
+}
+
+// What a plan made with a literal lifted out depends on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Shape {
+    pub typ: Type,
+    // The literals are planned as the same parameter if they are equal, so a plan is only right
+    // for queries where the same literals are equal; this is the index of the first literal
+    // this one is equal to, if any
+    pub same_as: Option<usize>,
+}
+
+// Find the literals to lift out of a query, in the order they appear in it
+pub fn lift(query: Pair<Rule>) -> Result<Vec<Literal>> {
+    let mut out = Vec::new();
+    collect(query, &mut out)?;
+    Ok(out)
+}
+
+fn collect(pair: Pair<Rule>, out: &mut Vec<Literal>) -> Result<()> {
+    if let Some(value) = literal_value(&pair)? {
+        out.push(Literal {
+            start: pair.as_span().start(),
+            value,
+        });
+        return Ok(());
+    }
+    for inner in pair.into_inner() {
+        collect(inner, out)?;
+    }
+    Ok(())
+}
+
+// Two queries that normalize to the same text can share a plan if their literals have the same
+// shape
+pub fn shape(literals: &[Literal]) -> Vec<Shape> {
+    let mut out = Vec::with_capacity(literals.len());
+    for (i, lit) in literals.iter().enumerate() {
+        let same_as = literals[..i]
+            .iter()
+            .position(|prev| same_value(&prev.value, &lit.value));
+        out.push(Shape {
+            typ: type_of(&lit.value),
+            same_as,
+        })
+    }
+    out
+}
+
+// Like ==, except 0.0 and -0.0 are different literals, and NaN is the same literal as NaN
+fn same_value(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::Float(a), Val::Float(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+fn type_of(v: &Val) -> Type {
+    match v {
+        Val::Int(_) => Type::Integer,
+        Val::Float(_) => Type::Float,
+        Val::String(_) => Type::String,
+        Val::Bool(_) => Type::Boolean,
+        _ => Type::Any,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lift, shape, Shape};
+    use crate::frontend::parse;
+    use crate::{Result, Type, Val};
+
+    #[test]
+    fn lifts_literals_in_order() -> Result<()> {
+        let query = "MATCH (n {id: -1, name: 'x'}) WHERE n.x > 1.5 RETURN n, true, [1], null, -1";
+        let literals = lift(parse(query)?)?;
+        let values: Vec<Val> = literals.iter().map(|l| l.value.clone()).collect();
+        assert_eq!(
+            values,
+            vec![
+                Val::Int(-1),
+                Val::String("x".into()),
+                Val::Float(1.5),
+                Val::Bool(true),
+                Val::Int(1),
+                Val::Int(-1),
+            ]
+        );
+        assert_eq!(&query[literals[0].start..literals[0].start + 2], "-1");
+
+        let shapes = shape(&literals);
+        assert_eq!(
+            shapes[5],
+            Shape {
+                typ: Type::Integer,
+                same_as: Some(0)
+            }
+        );
+        assert!(shapes[..5].iter().all(|s| s.same_as.is_none()));
+        Ok(())
+    }
+}
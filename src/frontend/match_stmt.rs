@@ -1,23 +1,269 @@
-use super::{parse_pattern_graph, Dir, Expr, LogicalPlan, Pair, PlanningContext, Result, Rule};
+use super::{
+    parse_pattern_graph, types, Dir, Expr, LogicalPlan, Pair, PatternGraph, PlanningContext,
+    Result, Rule,
+};
 use crate::backend::Token;
-use crate::frontend::{Op, PatternNode};
+use crate::frontend::{MapEntryExpr, Op, PatternNode};
+use crate::Slot;
+use std::collections::HashMap;
 
 pub fn plan_match(
     pc: &mut PlanningContext,
     src: LogicalPlan,
     match_stmt: Pair<Rule>,
 ) -> Result<LogicalPlan> {
-    fn filter_expand(expand: LogicalPlan, slot: Token, labels: &[Token]) -> LogicalPlan {
-        let labels = labels
-            .iter()
-            .map(|&label| Expr::HasLabel(slot, label))
-            .collect::<Vec<_>>();
+    let mut plan = src;
+    let mut pg = parse_pattern_graph(pc, match_stmt)?;
+
+    if pg.optional {
+        return plan_optional_match(pc, plan, pg);
+    }
+
+    // Ok, now we have parsed the pattern into a full graph, time to start solving it
+    tracing::trace!(pattern_graph = ?pg, "built pattern graph");
+
+    plan = solve_pattern(pc, plan, &mut pg)?;
+
+    // Finally, add the pattern-wide predicate to filter the result of the pattern match
+    // see the note on PatternGraph about issues with this "late filter" approach
+    if let Some(pred) = pg.predicate.take() {
+        return plan_selection(pc, plan, pred);
+    }
+
+    Ok(plan)
+}
+
+// OPTIONAL MATCH keeps each incoming row even if the pattern doesn't match it, with the
+// identifiers the pattern introduces set to null. The WHERE of an OPTIONAL MATCH is part of the
+// pattern: a row that matches the pattern but not the WHERE is padded with nulls like one that
+// doesn't match at all, rather than filtered out. So the pattern and its predicate are solved
+// on their own, nested in an apply that feeds them one incoming row at a time, and the nulls
+// are filled in above all of that.
+fn plan_optional_match(
+    pc: &mut PlanningContext,
+    src: LogicalPlan,
+    mut pg: PatternGraph,
+) -> Result<LogicalPlan> {
+    // What the pattern binds, as opposed to what it refers to from earlier clauses
+    let introduced: Vec<Token> = pg
+        .v_order
+        .iter()
+        .copied()
+        .chain(pg.e.iter().map(|r| r.identifier))
+        .filter(|id| !pc.is_declared(*id))
+        .collect();
+
+    let mut inner = solve_pattern(pc, LogicalPlan::Argument, &mut pg)?;
+    if let Some(pred) = pg.predicate.take() {
+        inner = plan_selection(pc, inner, pred)?;
+    }
+    let slots = introduced
+        .iter()
+        .map(|id| pc.get_or_alloc_slot(*id))
+        .collect();
+    let optional = LogicalPlan::Optional {
+        src: Box::new(inner),
+        slots,
+    };
+
+    // The first clause of a query has a single incoming row, no need to apply
+    if src == LogicalPlan::Argument {
+        return Ok(optional);
+    }
+    Ok(LogicalPlan::NestLoop {
+        outer: Box::new(src),
+        inner: Box::new(optional),
+        predicate: Expr::Bool(true),
+    })
+}
+
+// Expand the plan such that every node and rel in the pattern graph is bound to a slot
+// in the output rows.
+//
+// A pattern with disconnected parts, like MATCH (a:User {id: 1}), (b:Product), is a cartesian
+// product of its parts. The parts are joined smallest first, going by a guess at how many rows
+// each gives, and the terms of the pattern predicate that only concern one part are applied
+// to that part before it's joined with the others. What's left of the predicate stays in
+// pg.predicate, for the caller to deal with.
+fn solve_pattern(
+    pc: &mut PlanningContext,
+    src: LogicalPlan,
+    pg: &mut PatternGraph,
+) -> Result<LogicalPlan> {
+    let mut rel_slots = Vec::new();
+    let parts = connected_parts(pg);
+    if parts.len() < 2 {
+        return solve_connected(pc, src, pg, &mut rel_slots);
+    }
+
+    // Which terms of the predicate can go with which part
+    let part_slots: Vec<Vec<Slot>> = parts
+        .iter()
+        .map(|part| {
+            let ids = part
+                .v_order
+                .iter()
+                .chain(part.e.iter().map(|r| &r.identifier));
+            ids.filter_map(|id| pc.slots.get(id).copied()).collect()
+        })
+        .collect();
+    let mut part_terms: Vec<Vec<Expr>> = vec![Vec::new(); parts.len()];
+    let mut rest = Vec::new();
+    let terms = match pg.predicate.take() {
+        Some(Expr::And(terms)) => terms,
+        Some(e) => vec![e],
+        None => Vec::new(),
+    };
+    for term in terms {
+        let mut used = Vec::new();
+        let part = if term.collect_slots(&mut used) {
+            let mut touched = part_slots
+                .iter()
+                .enumerate()
+                .filter(|(_, slots)| used.iter().any(|s| slots.contains(s)))
+                .map(|(i, _)| i);
+            match (touched.next(), touched.next()) {
+                (Some(i), None) => Some(i),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        match part {
+            Some(i) => part_terms[i].push(term),
+            None => rest.push(term),
+        }
+    }
+    pg.predicate = match rest.len() {
+        0 => None,
+        1 => rest.pop(),
+        _ => Some(Expr::And(rest)),
+    };
+
+    let mut parts: Vec<(f64, PatternGraph, Vec<Expr>)> = parts
+        .into_iter()
+        .zip(part_terms)
+        .map(|(part, terms)| (estimate_rows(pc, &part, terms.len()), part, terms))
+        .collect();
+    // Stable, so parts that look the same size are joined in the order they were written
+    parts.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut plan = src;
+    for (i, (_, mut part, terms)) in parts.into_iter().enumerate() {
+        let predicate = match terms.len() {
+            0 => None,
+            1 => terms.into_iter().next(),
+            _ => Some(Expr::And(terms)),
+        };
+        if i == 0 {
+            plan = solve_connected(pc, plan, &mut part, &mut rel_slots)?;
+            if let Some(predicate) = predicate {
+                plan = plan_selection(pc, plan, predicate)?;
+            }
+            continue;
+        }
+
+        pc.notify(
+            "CartesianProduct",
+            "This query builds a cartesian product between disconnected patterns, \
+             which may be slow; consider connecting them with a relationship"
+                .to_string(),
+        );
+        let mut inner = solve_connected(pc, LogicalPlan::Argument, &mut part, &mut rel_slots)?;
+        if let Some(predicate) = predicate {
+            inner = plan_selection(pc, inner, predicate)?;
+        }
+        plan = LogicalPlan::NestLoop {
+            outer: Box::new(plan),
+            inner: Box::new(inner),
+            predicate: Expr::Bool(true),
+        };
+    }
+    Ok(plan)
+}
+
+// Split a pattern into the parts that aren't connected to each other by any rel, eg.
+// MATCH (a)-->(b), (c) into (a)-->(b) and (c). Parts come in the order they appear in the
+// pattern, and without the pattern predicate.
+fn connected_parts(pg: &PatternGraph) -> Vec<PatternGraph> {
+    let mut part_of: HashMap<Token, usize> = HashMap::new();
+    let mut parts: Vec<PatternGraph> = Vec::new();
+    for id in &pg.v_order {
+        if part_of.contains_key(id) {
+            continue;
+        }
+        let part = parts.len();
+        part_of.insert(*id, part);
+        let mut reached = vec![*id];
+        while let Some(at) = reached.pop() {
+            for rel in &pg.e {
+                let right = rel.right_node.unwrap();
+                let other = if rel.left_node == at {
+                    right
+                } else if right == at {
+                    rel.left_node
+                } else {
+                    continue;
+                };
+                // Anything reached is in this part, so there's no harm in inserting it again
+                if part_of.insert(other, part).is_none() {
+                    reached.push(other);
+                }
+            }
+        }
+        parts.push(PatternGraph::default());
+    }
+    for id in &pg.v_order {
+        let part = &mut parts[part_of[id]];
+        part.v_order.push(*id);
+        part.v.insert(*id, pg.v[id].clone());
+    }
+    for rel in &pg.e {
+        parts[part_of[&rel.left_node]].e.push(rel.clone());
+    }
+    parts
+}
+
+// A guess at how many rows matching a part of a pattern gives, to order cartesian products by.
+// There are no statistics to go on, so this only knows that bound nodes, labels and predicates
+// narrow things down and that each rel multiplies the rows it expands from.
+fn estimate_rows(pc: &PlanningContext, part: &PatternGraph, predicates: usize) -> f64 {
+    let start = part
+        .v
+        .values()
+        .map(|n| {
+            if pc.is_declared(n.identifier) {
+                1.0
+            } else if !n.labels.is_empty() || !n.label_alternatives.is_empty() {
+                100.0
+            } else {
+                1000.0
+            }
+        })
+        .fold(f64::MAX, f64::min);
+    let props = part.v.values().map(|n| n.props.len()).sum::<usize>()
+        + part.e.iter().map(|r| r.props.len()).sum::<usize>()
+        + predicates;
+    start * 10f64.powi(part.e.len() as i32) * 0.1f64.powi(props as i32)
+}
+
+// Solve a pattern where every node is connected to every other by rels, see solve_pattern.
+// rel_slots are the rels the pattern has bound so far; the same rel can't be matched twice in
+// one pattern, so MATCH (a)--(b)--(c) doesn't walk back along the rel it came in on
+fn solve_connected(
+    pc: &mut PlanningContext,
+    src: LogicalPlan,
+    pg: &mut PatternGraph,
+    rel_slots: &mut Vec<Slot>,
+) -> Result<LogicalPlan> {
+    fn filter_expand(expand: LogicalPlan, slot: Token, node: &PatternNode) -> LogicalPlan {
+        let mut labels = label_predicates(slot, node, None);
         if labels.is_empty() {
             expand
         } else if labels.len() == 1 {
             LogicalPlan::Selection {
                 src: Box::new(expand),
-                predicate: labels.into_iter().next().unwrap(),
+                predicate: labels.pop().unwrap(),
             }
         } else {
             LogicalPlan::Selection {
@@ -28,33 +274,6 @@ pub fn plan_match(
     }
 
     let mut plan = src;
-    let mut pg = parse_pattern_graph(pc, match_stmt)?;
-
-    if pg.optional {
-        // We currently only handle a singular case here, OPTIONAL MATCH with a single unbound node:
-        if pg.e.len() > 0 {
-            bail!("gqlite does not yet support OPTIONAL MATCH entirely, sorry")
-        }
-
-        if pg.predicate.is_some() {
-            bail!("gqlite does not yet support OPTIONAL MATCH entirely, sorry")
-        }
-
-        let (_, node) = pg.v.iter().next().unwrap();
-
-        let node_slot = pc.get_or_alloc_slot(node.identifier);
-        return Ok(LogicalPlan::Optional {
-            src: Box::new(LogicalPlan::NodeScan {
-                src: Box::new(plan),
-                slot: node_slot,
-                labels: node.labels.first().cloned(),
-            }),
-            slots: vec![node_slot],
-        });
-    }
-
-    // Ok, now we have parsed the pattern into a full graph, time to start solving it
-    println!("built pg: {:?}", pg);
 
     // 1: Loop through all nodes in the pattern and..
     //    - Find any pre-existing bound nodes we could start from
@@ -81,9 +300,6 @@ pub fn plan_match(
 
         // Prefer a candidate with labels since that has higher selectivity
         if !candidate.labels.is_empty() {
-            if candidate.labels.len() > 1 {
-                panic!("Multiple label match not yet implemented")
-            }
             candidate_id = Some(id)
         }
     }
@@ -132,15 +348,18 @@ pub fn plan_match(
                 }
 
                 let dst = pc.get_or_alloc_slot(right_id);
+                let rel_slot = pc.get_or_alloc_slot(rel.identifier);
                 let expand = LogicalPlan::Expand {
                     src: Box::new(plan),
                     src_slot: pc.get_or_alloc_slot(left_id),
-                    rel_slot: pc.get_or_alloc_slot(rel.identifier),
+                    rel_slot,
                     dst_slot: dst,
                     rel_type: rel.rel_type,
                     dir: rel.dir,
+                    predicate: match_predicate(rel_slot, &rel.props, rel_slots),
                 };
-                plan = filter_expand(expand, dst, &right_node.labels);
+                rel_slots.push(rel_slot);
+                plan = filter_expand(expand, dst, right_node);
             } else if !left_solved && right_solved {
                 // Right is solved and left isn't, so we can expand to the left
                 let mut left_node = pg.v.get_mut(&left_id).unwrap();
@@ -155,42 +374,50 @@ pub fn plan_match(
                 }
 
                 let dst = pc.get_or_alloc_slot(left_id);
+                let rel_slot = pc.get_or_alloc_slot(rel.identifier);
                 let expand = LogicalPlan::Expand {
                     src: Box::new(plan),
                     src_slot: pc.get_or_alloc_slot(right_id),
-                    rel_slot: pc.get_or_alloc_slot(rel.identifier),
+                    rel_slot,
                     dst_slot: dst,
                     rel_type: rel.rel_type,
                     dir: rel.dir.map(Dir::reverse),
+                    predicate: match_predicate(rel_slot, &rel.props, rel_slots),
                 };
-                plan = filter_expand(expand, dst, &left_node.labels);
-            }
-        }
+                rel_slots.push(rel_slot);
+                plan = filter_expand(expand, dst, left_node);
+            } else if left_solved && right_solved {
+                // Both ends are already bound, eg. the (a)-->(b) in MATCH (a), (b) WHERE NOT (a)-->(b).
+                // We expand from the left into a scratch slot, and keep the rows where the
+                // node we reached is the one bound on the right.
+                rel.solved = true;
+                solved_any = true;
 
-        // If we didn't solve any rels, most likely we're just done.
-        // However, there is a chance we've got a disjoint pattern,
-        // eg. something like MATCH (a), (b) or MATCH (a), (b)-->(). So, go through the nodes
-        // and, if there are unsolved ones, this means there's a cartesian product we need to solve
-        if !solved_any {
-            for (_, v) in &mut pg.v {
-                if v.solved {
-                    continue;
+                if !rel.anonymous {
+                    pc.declare_tok(rel.identifier);
                 }
 
-                // Found an unsolved node; for now just go with the first one we find
-                found_unsolved = true;
-                v.solved = true;
-
-                let inner = Box::new(plan_match_node(pc, v, LogicalPlan::Argument)?);
-                plan = LogicalPlan::NestLoop {
-                    outer: Box::new(plan),
-                    inner,
-                    predicate: Expr::Bool(true),
+                let scratch = pc.new_anon_node();
+                let dst = pc.get_or_alloc_slot(scratch);
+                let rel_slot = pc.get_or_alloc_slot(rel.identifier);
+                let expand = LogicalPlan::Expand {
+                    src: Box::new(plan),
+                    src_slot: pc.get_or_alloc_slot(left_id),
+                    rel_slot,
+                    dst_slot: dst,
+                    rel_type: rel.rel_type,
+                    dir: rel.dir,
+                    predicate: match_predicate(rel_slot, &rel.props, rel_slots),
+                };
+                rel_slots.push(rel_slot);
+                plan = LogicalPlan::Selection {
+                    src: Box::new(expand),
+                    predicate: Expr::BinaryOp {
+                        left: Box::new(Expr::Slot(dst)),
+                        right: Box::new(Expr::Slot(pc.get_or_alloc_slot(right_id))),
+                        op: Op::Eq,
+                    },
                 };
-
-                // Just solve one and see if that's enough to expand the others
-                solved_any = true;
-                break;
             }
         }
 
@@ -198,66 +425,178 @@ pub fn plan_match(
             break;
         }
 
-        // Eg. we currently don't handle circular patterns (requiring JOINs) or patterns
-        // with multiple disjoint subgraphs.
+        // Eg. we currently don't handle circular patterns (requiring JOINs); disjoint parts
+        // of the pattern never get here, solve_pattern splits those up
         if !solved_any {
             panic!("Failed to solve pattern: {:?}", pg)
         }
     }
 
-    // Finally, add the pattern-wide predicate to filter the result of the pattern match
-    // see the note on PatternGraph about issues with this "late filter" approach
-    if let Some(pred) = pg.predicate {
-        return Ok(LogicalPlan::Selection {
-            src: Box::new(plan),
-            predicate: pred,
-        });
+    Ok(plan)
+}
+
+// Plan a filter over the rows from src. Pattern predicates among the top-level AND terms,
+// like the NOT (a)-->(b) in "WHERE a.name = 'x' AND NOT (a)-->(b)", are planned as probes of
+// the pattern; the remaining terms go into a regular Selection that is applied first.
+pub fn plan_selection(
+    pc: &mut PlanningContext,
+    src: LogicalPlan,
+    predicate: Expr,
+) -> Result<LogicalPlan> {
+    types::expect_boolean(pc, &predicate)?;
+    let terms = match predicate {
+        Expr::And(terms) => terms,
+        e => vec![e],
+    };
+
+    let tok_not = pc.tokenize("not");
+    let mut filters = Vec::new();
+    // Pattern predicates, in the order they appear, with whether they are negated
+    let mut probes = Vec::new();
+    for term in terms {
+        match term {
+            Expr::FuncCall { name, mut args }
+                if name == tok_not
+                    && args.len() == 1
+                    && matches!(args[0], Expr::PatternPredicate(_)) =>
+            {
+                if let Some(Expr::PatternPredicate(pg)) = args.pop() {
+                    probes.push((true, plan_probe(pc, *pg)?));
+                }
+            }
+            Expr::PatternPredicate(pg) => probes.push((false, plan_probe(pc, *pg)?)),
+            e => filters.push(e),
+        }
     }
 
+    let mut plan = src;
+    if !filters.is_empty() {
+        plan = LogicalPlan::Selection {
+            src: Box::new(plan),
+            predicate: if filters.len() == 1 {
+                filters.remove(0)
+            } else {
+                Expr::And(filters)
+            },
+        };
+    }
+    for (negated, probe) in probes {
+        plan = if negated {
+            LogicalPlan::AntiConditionalApply {
+                src: Box::new(plan),
+                probe: Box::new(probe),
+            }
+        } else {
+            LogicalPlan::ConditionalApply {
+                src: Box::new(plan),
+                probe: Box::new(probe),
+            }
+        };
+    }
     Ok(plan)
 }
 
+// Plan the pattern in a pattern predicate as a sub-plan that, given an argument row with the
+// identifiers bound outside the predicate, yields one row per match of the pattern.
+fn plan_probe(pc: &mut PlanningContext, mut pg: PatternGraph) -> Result<LogicalPlan> {
+    for node in pg.v.values() {
+        if !node.anonymous && !pc.is_declared(node.identifier) {
+            bail!(
+                "Variable `{}` not defined; pattern predicates can't introduce new variables",
+                pc.tokens.borrow().lookup(node.identifier).unwrap_or("?")
+            )
+        }
+    }
+    for rel in &pg.e {
+        if !rel.anonymous && !pc.is_declared(rel.identifier) {
+            bail!(
+                "Variable `{}` not defined; pattern predicates can't introduce new variables",
+                pc.tokens.borrow().lookup(rel.identifier).unwrap_or("?")
+            )
+        }
+    }
+    solve_pattern(pc, LogicalPlan::Argument, &mut pg)
+}
+
 fn plan_match_node(
     pc: &mut PlanningContext,
     v: &mut PatternNode,
     src: LogicalPlan,
 ) -> Result<LogicalPlan> {
-    if v.labels.len() > 1 {
-        bail!("Multiple label match not yet implemented")
-    }
-    // Getting all possible nodes..
+    // Getting all possible nodes; the backend finds the nodes with a label for us, so we scan by
+    // one of the labels the node must have, if it has any, and check the rest after
     let node_slot = pc.get_or_alloc_slot(v.identifier);
+    let scan_label = v.labels.first().cloned();
     let mut plan = LogicalPlan::NodeScan {
         src: Box::new(src),
         slot: node_slot,
-        labels: v.labels.first().cloned(),
+        labels: scan_label,
     };
 
-    if !v.props.is_empty() {
-        // Need to filter on props
-        let mut and_terms = Vec::new();
-        for e in &v.props {
-            and_terms.push(Expr::BinaryOp {
-                left: Box::new(Expr::Prop(Box::new(Expr::Slot(node_slot)), vec![e.key])),
-                right: Box::new(e.val.clone()),
-                op: Op::Eq,
-            })
-        }
-
-        let predicate = if and_terms.len() == 1 {
-            and_terms[0].clone()
-        } else {
-            Expr::And(and_terms)
-        };
+    let mut terms = label_predicates(node_slot, v, scan_label);
+    match match_predicate(node_slot, &v.props, &[]) {
+        Some(Expr::And(props)) => terms.extend(props),
+        Some(props) => terms.push(props),
+        None => (),
+    }
+    if !terms.is_empty() {
         plan = LogicalPlan::Selection {
             src: Box::new(plan),
-            predicate,
+            predicate: if terms.len() == 1 {
+                terms.remove(0)
+            } else {
+                Expr::And(terms)
+            },
         }
     }
 
     Ok(plan)
 }
 
+// Checks that the node in slot has the labels the pattern asks for; except for the scanned
+// label, which whatever found the node already checked
+fn label_predicates(slot: Slot, v: &PatternNode, scanned: Option<Token>) -> Vec<Expr> {
+    let mut terms: Vec<Expr> = v
+        .labels
+        .iter()
+        .filter(|&&label| Some(label) != scanned)
+        .map(|&label| Expr::HasLabel(slot, label))
+        .collect();
+    for any_of in &v.label_alternatives {
+        let any_of = any_of.iter().map(|&label| Expr::HasLabel(slot, label));
+        terms.push(Expr::Or(any_of.collect()));
+    }
+    terms
+}
+
+// Turn an inline property map, like the {name: 'David'} in (n {name: 'David'}), into a predicate
+// over the entity in the given slot. Returns None if there are no properties to filter on.
+// What a matched node or rel must satisfy: have the properties the pattern asks for and, for
+// rels, not be any of the rels the pattern already matched
+fn match_predicate(slot: Slot, props: &[MapEntryExpr], other_rels: &[Slot]) -> Option<Expr> {
+    let mut and_terms = Vec::with_capacity(props.len() + other_rels.len());
+    for e in props {
+        and_terms.push(Expr::BinaryOp {
+            left: Box::new(Expr::Prop(Box::new(Expr::Slot(slot)), vec![e.key])),
+            right: Box::new(e.val.clone()),
+            op: Op::Eq,
+        })
+    }
+    for other in other_rels {
+        and_terms.push(Expr::BinaryOp {
+            left: Box::new(Expr::Slot(slot)),
+            right: Box::new(Expr::Slot(*other)),
+            op: Op::NotEq,
+        })
+    }
+
+    match and_terms.len() {
+        0 => None,
+        1 => and_terms.pop(),
+        _ => Some(Expr::And(and_terms)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::frontend::tests::plan;
@@ -285,6 +624,7 @@ mod tests {
                 dst_slot: p.slot(id_o),
                 rel_type: None,
                 dir: Some(Dir::Out),
+                predicate: None,
             }
         );
         Ok(())
@@ -313,6 +653,7 @@ mod tests {
                     dst_slot: p.slot(id_n),
                     rel_type: Some(tpe_knows),
                     dir: Some(Dir::In),
+                    predicate: None,
                 }),
                 predicate: Expr::HasLabel(p.slot(id_n), lbl_person)
             }
@@ -376,12 +717,221 @@ mod tests {
                 rel_slot: 2,
                 dst_slot: p.slot(id_m),
                 rel_type: None,
-                dir: Some(Dir::Out)
+                dir: Some(Dir::Out),
+                predicate: None,
             }
         );
         Ok(())
     }
 
+    #[test]
+    fn plan_match_with_label_expressions() -> Result<(), Error> {
+        let mut p = plan("MATCH (n:Person:Admin)-->(m:Person|Bot)")?;
+        let id_n = p.tokenize("n");
+        let id_m = p.tokenize("m");
+        let id_anon_rel = p.tokenize("AnonRel#0");
+        let lbl_person = p.tokenize("Person");
+        let lbl_admin = p.tokenize("Admin");
+        let lbl_bot = p.tokenize("Bot");
+        // Labels are sorted by token, so this is the order they were first seen in
+        let (first, second) = (lbl_person.min(lbl_admin), lbl_person.max(lbl_admin));
+
+        assert_eq!(
+            p.plan,
+            LogicalPlan::Selection {
+                src: Box::new(LogicalPlan::Expand {
+                    // Scanning by one label, and checking the other
+                    src: Box::new(LogicalPlan::Selection {
+                        src: Box::new(LogicalPlan::NodeScan {
+                            src: Box::new(LogicalPlan::Argument),
+                            slot: p.slot(id_n),
+                            labels: Some(first),
+                        }),
+                        predicate: Expr::HasLabel(p.slot(id_n), second),
+                    }),
+                    src_slot: p.slot(id_n),
+                    rel_slot: p.slot(id_anon_rel),
+                    dst_slot: p.slot(id_m),
+                    rel_type: None,
+                    dir: Some(Dir::Out),
+                    predicate: None,
+                }),
+                predicate: Expr::Or(vec![
+                    Expr::HasLabel(p.slot(id_m), lbl_person),
+                    Expr::HasLabel(p.slot(id_m), lbl_bot),
+                ]),
+            }
+        );
+
+        assert!(plan("CREATE (n:Person|Bot)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn plan_match_with_inline_rel_predicate() -> Result<(), Error> {
+        let mut p = plan("MATCH (n:Person)-[r:RATED {stars: 5}]->(m)")?;
+        let lbl_person = p.tokenize("Person");
+        let tpe_rated = p.tokenize("RATED");
+        let key_stars = p.tokenize("stars");
+        let id_n = p.tokenize("n");
+        let id_r = p.tokenize("r");
+        let id_m = p.tokenize("m");
+
+        assert_eq!(
+            p.plan,
+            LogicalPlan::Expand {
+                src: Box::new(LogicalPlan::NodeScan {
+                    src: Box::new(LogicalPlan::Argument),
+                    slot: p.slot(id_n),
+                    labels: Some(lbl_person),
+                }),
+                src_slot: p.slot(id_n),
+                rel_slot: p.slot(id_r),
+                dst_slot: p.slot(id_m),
+                rel_type: Some(tpe_rated),
+                dir: Some(Dir::Out),
+                predicate: Some(Expr::BinaryOp {
+                    left: Box::new(Expr::Prop(
+                        Box::new(Expr::Slot(p.slot(id_r))),
+                        vec![key_stars]
+                    )),
+                    right: Box::new(Expr::Int(5)),
+                    op: Op::Eq
+                }),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_match_with_negated_pattern_predicate() -> Result<(), Error> {
+        let mut p = plan("MATCH (a:User) WHERE NOT (a)-[:BLOCKED]->(:User)")?;
+        let lbl_user = p.tokenize("User");
+        let tpe_blocked = p.tokenize("BLOCKED");
+        let id_a = p.tokenize("a");
+        let id_anon_rel = p.tokenize("AnonRel#0");
+        let id_anon_node = p.tokenize("AnonNode#0");
+
+        assert_eq!(
+            p.plan,
+            LogicalPlan::AntiConditionalApply {
+                src: Box::new(LogicalPlan::NodeScan {
+                    src: Box::new(LogicalPlan::Argument),
+                    slot: p.slot(id_a),
+                    labels: Some(lbl_user),
+                }),
+                probe: Box::new(LogicalPlan::Selection {
+                    src: Box::new(LogicalPlan::Expand {
+                        src: Box::new(LogicalPlan::Argument),
+                        src_slot: p.slot(id_a),
+                        rel_slot: p.slot(id_anon_rel),
+                        dst_slot: p.slot(id_anon_node),
+                        rel_type: Some(tpe_blocked),
+                        dir: Some(Dir::Out),
+                        predicate: None,
+                    }),
+                    predicate: Expr::HasLabel(p.slot(id_anon_node), lbl_user),
+                }),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_match_with_negated_pattern_predicate_between_bound_nodes() -> Result<(), Error> {
+        let mut p = plan("MATCH (a), (b) WHERE a.name = 'x' AND NOT (a)-[:BLOCKED]->(b)")?;
+        let tpe_blocked = p.tokenize("BLOCKED");
+        let key_name = p.tokenize("name");
+        let id_a = p.tokenize("a");
+        let id_b = p.tokenize("b");
+        let id_anon_rel = p.tokenize("AnonRel#0");
+        let id_anon_node = p.tokenize("AnonNode#0");
+
+        assert_eq!(
+            p.plan,
+            LogicalPlan::AntiConditionalApply {
+                // a.name = 'x' only concerns a, so it filters a before the product
+                src: Box::new(LogicalPlan::NestLoop {
+                    outer: Box::new(LogicalPlan::Selection {
+                        src: Box::new(LogicalPlan::NodeScan {
+                            src: Box::new(LogicalPlan::Argument),
+                            slot: p.slot(id_a),
+                            labels: None,
+                        }),
+                        predicate: Expr::BinaryOp {
+                            left: Box::new(Expr::Prop(
+                                Box::new(Expr::Slot(p.slot(id_a))),
+                                vec![key_name]
+                            )),
+                            right: Box::new(Expr::String("x".to_string())),
+                            op: Op::Eq
+                        },
+                    }),
+                    inner: Box::new(LogicalPlan::NodeScan {
+                        src: Box::new(LogicalPlan::Argument),
+                        slot: p.slot(id_b),
+                        labels: None,
+                    }),
+                    predicate: Expr::Bool(true),
+                }),
+                // Both ends are bound, so the probe expands from a and checks it reached b
+                probe: Box::new(LogicalPlan::Selection {
+                    src: Box::new(LogicalPlan::Expand {
+                        src: Box::new(LogicalPlan::Argument),
+                        src_slot: p.slot(id_a),
+                        rel_slot: p.slot(id_anon_rel),
+                        dst_slot: p.slot(id_anon_node),
+                        rel_type: Some(tpe_blocked),
+                        dir: Some(Dir::Out),
+                        predicate: None,
+                    }),
+                    predicate: Expr::BinaryOp {
+                        left: Box::new(Expr::Slot(p.slot(id_anon_node))),
+                        right: Box::new(Expr::Slot(p.slot(id_b))),
+                        op: Op::Eq
+                    },
+                }),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_match_with_pattern_predicate() -> Result<(), Error> {
+        let mut p = plan("MATCH (a:User) WHERE (a)-[:KNOWS]->()")?;
+        let lbl_user = p.tokenize("User");
+        let tpe_knows = p.tokenize("KNOWS");
+        let id_a = p.tokenize("a");
+        let id_anon_rel = p.tokenize("AnonRel#0");
+        let id_anon_node = p.tokenize("AnonNode#0");
+
+        assert_eq!(
+            p.plan,
+            LogicalPlan::ConditionalApply {
+                src: Box::new(LogicalPlan::NodeScan {
+                    src: Box::new(LogicalPlan::Argument),
+                    slot: p.slot(id_a),
+                    labels: Some(lbl_user),
+                }),
+                probe: Box::new(LogicalPlan::Expand {
+                    src: Box::new(LogicalPlan::Argument),
+                    src_slot: p.slot(id_a),
+                    rel_slot: p.slot(id_anon_rel),
+                    dst_slot: p.slot(id_anon_node),
+                    rel_type: Some(tpe_knows),
+                    dir: Some(Dir::Out),
+                    predicate: None,
+                }),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan_pattern_predicate_cannot_introduce_variables() {
+        assert!(plan("MATCH (a) WHERE NOT (a)-->(b)").is_err());
+    }
+
     #[test]
     fn plan_optional_match() -> Result<(), Error> {
         let mut p = plan("OPTIONAL MATCH (n) RETURN n")?;
@@ -411,6 +961,69 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn plan_optional_match_with_bound_identifiers() -> Result<(), Error> {
+        let mut p = plan("MATCH (a) OPTIONAL MATCH (a)-[r:KNOWS]->(b) WHERE b.admin")?;
+        let id_a = p.tokenize("a");
+        let id_r = p.tokenize("r");
+        let id_b = p.tokenize("b");
+        let tpe_knows = p.tokenize("KNOWS");
+        let key_admin = p.tokenize("admin");
+
+        // The WHERE is solved along with the pattern, so an a with no admin friends is kept,
+        // with r and b, but not a, set to null
+        assert_eq!(
+            p.plan,
+            LogicalPlan::NestLoop {
+                outer: Box::new(LogicalPlan::NodeScan {
+                    src: Box::new(LogicalPlan::Argument),
+                    slot: p.slot(id_a),
+                    labels: None,
+                }),
+                inner: Box::new(LogicalPlan::Optional {
+                    src: Box::new(LogicalPlan::Selection {
+                        src: Box::new(LogicalPlan::Expand {
+                            src: Box::new(LogicalPlan::Argument),
+                            src_slot: p.slot(id_a),
+                            rel_slot: p.slot(id_r),
+                            dst_slot: p.slot(id_b),
+                            rel_type: Some(tpe_knows),
+                            dir: Some(Dir::Out),
+                            predicate: None,
+                        }),
+                        predicate: Expr::Prop(Box::new(Expr::Slot(p.slot(id_b))), vec![key_admin]),
+                    }),
+                    slots: vec![p.slot(id_b), p.slot(id_r)],
+                }),
+                predicate: Expr::Bool(true),
+            }
+        );
+
+        // Nothing new is bound, so there's nothing to set to null; every a is kept either way
+        let mut p = plan("MATCH (a) OPTIONAL MATCH (a) WHERE a.admin")?;
+        let id_a = p.tokenize("a");
+        let key_admin = p.tokenize("admin");
+        assert_eq!(
+            p.plan,
+            LogicalPlan::NestLoop {
+                outer: Box::new(LogicalPlan::NodeScan {
+                    src: Box::new(LogicalPlan::Argument),
+                    slot: p.slot(id_a),
+                    labels: None,
+                }),
+                inner: Box::new(LogicalPlan::Optional {
+                    src: Box::new(LogicalPlan::Selection {
+                        src: Box::new(LogicalPlan::Argument),
+                        predicate: Expr::Prop(Box::new(Expr::Slot(p.slot(id_a))), vec![key_admin]),
+                    }),
+                    slots: vec![],
+                }),
+                predicate: Expr::Bool(true),
+            }
+        );
+        Ok(())
+    }
+
     #[test]
     fn plan_cartesian_product() -> Result<(), Error> {
         let mut p = plan("MATCH (a), (b) RETURN a, b")?;
@@ -454,4 +1067,57 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn plan_cartesian_product_smallest_part_first() -> Result<(), Error> {
+        let mut p = plan("MATCH (a), (b:Product {id: 1}) WHERE a.x = 'x' AND a.y = b.y")?;
+        let id_a = p.tokenize("a");
+        let id_b = p.tokenize("b");
+        let lbl_product = p.tokenize("Product");
+        let key_id = p.tokenize("id");
+        let key_x = p.tokenize("x");
+        let key_y = p.tokenize("y");
+        let prop = |slot, key| Box::new(Expr::Prop(Box::new(Expr::Slot(slot)), vec![key]));
+
+        // The one Product with id 1 is fewer rows than all nodes, so b goes first; a.x = 'x'
+        // filters a before the product, while a.y = b.y needs both and so comes after
+        assert_eq!(
+            p.plan,
+            LogicalPlan::Selection {
+                src: Box::new(LogicalPlan::NestLoop {
+                    outer: Box::new(LogicalPlan::Selection {
+                        src: Box::new(LogicalPlan::NodeScan {
+                            src: Box::new(LogicalPlan::Argument),
+                            slot: p.slot(id_b),
+                            labels: Some(lbl_product),
+                        }),
+                        predicate: Expr::BinaryOp {
+                            left: prop(p.slot(id_b), key_id),
+                            right: Box::new(Expr::Int(1)),
+                            op: Op::Eq
+                        },
+                    }),
+                    inner: Box::new(LogicalPlan::Selection {
+                        src: Box::new(LogicalPlan::NodeScan {
+                            src: Box::new(LogicalPlan::Argument),
+                            slot: p.slot(id_a),
+                            labels: None,
+                        }),
+                        predicate: Expr::BinaryOp {
+                            left: prop(p.slot(id_a), key_x),
+                            right: Box::new(Expr::String("x".to_string())),
+                            op: Op::Eq
+                        },
+                    }),
+                    predicate: Expr::Bool(true),
+                }),
+                predicate: Expr::BinaryOp {
+                    left: prop(p.slot(id_a), key_y),
+                    right: prop(p.slot(id_b), key_y),
+                    op: Op::Eq
+                },
+            }
+        );
+        Ok(())
+    }
 }
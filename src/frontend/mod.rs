@@ -5,24 +5,39 @@
 
 use pest::Parser;
 
-use crate::backend::{BackendDesc, Token, Tokens};
-use crate::Slot;
+use crate::backend::{BackendDesc, Statistics, Token, Tokens};
+use crate::diagnostics::{DiagnosticsSink, Notification};
+use crate::{QueryError, Slot, Type};
 use anyhow::Result;
 use pest::iterators::Pair;
+use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display, Formatter};
 use std::rc::Rc;
 
 mod expr;
 
+mod call_stmt;
+mod completions;
 mod create_stmt;
+mod describe;
+mod estimate;
+pub mod fingerprint;
+pub mod literals;
 mod match_stmt;
+mod rewrite;
+mod types;
+mod validate;
+mod views;
 mod with_stmt;
 
+pub use completions::{Completion, CompletionKind, Completions};
+pub use estimate::OperatorEstimate;
 use expr::plan_expr;
 pub use expr::{Expr, MapEntryExpr, Op};
+pub use views::View;
 
 #[derive(Parser)]
 #[grammar = "cypher.pest"]
@@ -32,53 +47,182 @@ pub struct CypherParser;
 pub struct Frontend {
     pub tokens: Rc<RefCell<Tokens>>,
     pub backend_desc: BackendDesc,
+    pub diagnostics: Box<dyn DiagnosticsSink>,
+    // Named queries that CALL can invoke, see define_view
+    pub views: Rc<HashMap<Token, View>>,
 }
 
 impl Frontend {
     pub fn plan(&self, query_str: &str) -> Result<LogicalPlan> {
-        self.plan_in_context(
+        let query = parse(query_str)?;
+        validate::validate(query.clone())?;
+        self.plan_query(
             query_str,
+            query,
             &mut PlanningContext::new(Rc::clone(&self.tokens), &self.backend_desc),
         )
     }
 
+    // Plan a query with its literals lifted out into parameters, so that the plan can be re-used
+    // for queries that only differ in those literals; see literals.rs
+    pub fn plan_parameterized(&self, query_str: &str) -> Result<ParameterizedPlan> {
+        let query = parse(query_str)?;
+        validate::validate(query.clone())?;
+        let literals = literals::lift(query.clone())?;
+
+        let mut pc = PlanningContext::new(Rc::clone(&self.tokens), &self.backend_desc);
+        let mut literal_params = Vec::with_capacity(literals.len());
+        for (i, shape) in literals::shape(&literals).into_iter().enumerate() {
+            let param = match shape.same_as {
+                Some(j) => literal_params[j],
+                None => pc.tokenize(&format!("$auto_{}", i)),
+            };
+            pc.literal_params.insert(literals[i].start, param);
+            pc.param_types.insert(param, shape.typ);
+            literal_params.push(param);
+        }
+
+        let plan = self.plan_query(query_str, query, &mut pc)?;
+        Ok(ParameterizedPlan {
+            plan,
+            literal_params,
+            parameters: pc.parameters,
+            names_literals: pc.names_literals,
+            schema_digest: self.backend_desc.schema_digest,
+        })
+    }
+
+    // The literals plan_parameterized would lift out of a query, in the order they appear in it
+    pub fn literals(&self, query_str: &str) -> Result<Vec<literals::Literal>> {
+        literals::lift(parse(query_str)?)
+    }
+
+    // Plan in a context the caller has set up, which may already have variables in scope; so
+    // unlike plan(), this does not check that variables are defined before they are used
     pub fn plan_in_context<'i, 'pc>(
         &self,
         query_str: &str,
         pc: &'i mut PlanningContext<'pc>,
     ) -> Result<LogicalPlan> {
-        let query = CypherParser::parse(Rule::query, &query_str)?
-            .next()
-            .unwrap(); // get and unwrap the `query` rule; never fails
+        self.plan_query(query_str, parse(query_str)?, pc)
+    }
 
-        let mut plan = LogicalPlan::Argument;
+    fn plan_query<'i, 'pc>(
+        &self,
+        query_str: &str,
+        query: Pair<Rule>,
+        pc: &'i mut PlanningContext<'pc>,
+    ) -> Result<LogicalPlan> {
+        let _plan_span = tracing::debug_span!("plan").entered();
 
-        for stmt in query.into_inner() {
-            match stmt.as_rule() {
-                Rule::match_stmt => {
-                    plan = match_stmt::plan_match(pc, plan, stmt)?;
-                }
-                Rule::unwind_stmt => {
-                    plan = plan_unwind(pc, plan, stmt)?;
-                }
-                Rule::create_stmt => {
-                    plan = create_stmt::plan_create(pc, plan, stmt)?;
-                }
-                Rule::return_stmt => {
-                    plan = with_stmt::plan_return(pc, plan, stmt)?;
-                }
-                Rule::with_stmt => {
-                    plan = with_stmt::plan_with(pc, plan, stmt)?;
-                }
-                Rule::EOI => (),
-                _ => unreachable!("Unknown statement: {:?}", stmt),
+        pc.views = Rc::clone(&self.views);
+        let plan = plan_statements(pc, query)?;
+        let plan = rewrite::rewrite(pc, plan);
+        check_operators(&plan, pc.backend_desc)?;
+
+        tracing::debug!(
+            plan = %plan.fmt_pretty(&"", &pc.tokens.borrow()),
+            "planned query"
+        );
+        for notification in pc.notifications.drain(..) {
+            self.diagnostics.notification(query_str, &notification);
+        }
+        self.diagnostics.plan(
+            query_str,
+            &PlanDescription {
+                plan: &plan,
+                tokens: &pc.tokens.borrow(),
+            },
+        );
+
+        Ok(plan)
+    }
+}
+
+// Plan the clauses of a query, one after the other, starting from an Argument
+fn plan_statements(pc: &mut PlanningContext, query: Pair<Rule>) -> Result<LogicalPlan> {
+    let mut plan = LogicalPlan::Argument;
+    // Columns yielded by a CALL; if the query ends with one, these are its result
+    let mut call_columns = None;
+
+    for stmt in query.into_inner() {
+        if stmt.as_rule() != Rule::EOI {
+            call_columns = None;
+        }
+        match stmt.as_rule() {
+            Rule::match_stmt => {
+                plan = match_stmt::plan_match(pc, plan, stmt)?;
+            }
+            Rule::unwind_stmt => {
+                plan = plan_unwind(pc, plan, stmt)?;
+            }
+            Rule::create_stmt => {
+                plan = create_stmt::plan_create(pc, plan, stmt)?;
+            }
+            Rule::return_stmt => {
+                plan = with_stmt::plan_return(pc, plan, stmt)?;
             }
+            Rule::with_stmt => {
+                plan = with_stmt::plan_with(pc, plan, stmt)?;
+            }
+            Rule::call_stmt => {
+                let (call, columns) = call_stmt::plan_call(pc, plan, stmt)?;
+                plan = call;
+                call_columns = Some(columns);
+            }
+            Rule::EOI => (),
+            _ => unreachable!("Unknown statement: {:?}", stmt),
         }
+    }
+
+    if let Some(fields) = call_columns {
+        plan = LogicalPlan::ProduceResult {
+            src: Box::new(plan),
+            fields,
+        };
+    }
+    Ok(plan)
+}
 
-        println!("plan: {}", &plan.fmt_pretty(&"", &pc.tokens.borrow()));
+// A plan for a query whose literals have been lifted out into parameters
+#[derive(Debug, Clone)]
+pub struct ParameterizedPlan {
+    pub plan: LogicalPlan,
+    // The parameter each literal of the query was lifted into, in the order the literals appear
+    // in the query; equal literals share a parameter
+    pub literal_params: Vec<Token>,
+    // Parameters the query itself refers to, which need to be given values to run it
+    pub parameters: Vec<Token>,
+    // Columns that aren't aliased are named after the query text, so if that text includes a
+    // lifted literal the plan is only right for the exact query it was planned for
+    pub names_literals: bool,
+    // The BackendDesc::schema_digest the plan was made for; it may seek indexes that are gone
+    // once the schema is something else
+    pub schema_digest: u64,
+}
 
-        Ok(plan)
+fn parse(query_str: &str) -> Result<Pair<'_, Rule>> {
+    let _parse_span = tracing::debug_span!("parse").entered();
+    let query = CypherParser::parse(Rule::query, query_str)
+        .map_err(QueryError::syntax)?
+        .next()
+        .unwrap(); // get and unwrap the `query` rule; never fails
+    Ok(query)
+}
+
+// Make sure the backend can run every operator in the plan, so we fail at planning time with
+// a useful message rather than somewhere inside the backend
+pub(crate) fn check_operators(plan: &LogicalPlan, bd: &BackendDesc) -> Result<()> {
+    if !bd.supports_operator(plan.name()) {
+        bail!(
+            "this query needs the {} operator, which the backend does not support",
+            plan.name()
+        )
+    }
+    for child in plan.children() {
+        check_operators(child, bd)?;
     }
+    Ok(())
 }
 
 // The ultimate output of the frontend is a logical plan. The logical plan is a tree of operators.
@@ -89,7 +233,7 @@ impl Frontend {
 // The pipeline has a single logical "row" - a vector of value slots - that's shared
 // by all operators; the various things the operators do refer to slots in the row,
 // like registers in a CPU.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum LogicalPlan {
     Argument,
     NodeScan {
@@ -97,6 +241,17 @@ pub enum LogicalPlan {
         slot: usize,
         labels: Option<Token>,
     },
+    // Find the nodes with a label whose value for a property is in a range, in an index the
+    // backend keeps on them, once per src row; see rewrite::seek_indexes. The predicates the range
+    // came from are still checked after, so backends may give nodes outside the range, as long as
+    // they don't miss any inside it.
+    NodeIndexSeek {
+        src: Box<Self>,
+        slot: Slot,
+        label: Token,
+        property: Token,
+        range: IndexRange,
+    },
     Expand {
         src: Box<Self>,
         src_slot: usize,
@@ -104,6 +259,9 @@ pub enum LogicalPlan {
         dst_slot: usize,
         rel_type: Option<Token>,
         dir: Option<Dir>,
+        // Optional filter evaluated for each candidate rel, with the rel already written to
+        // rel_slot; used for inline rel property maps like -[:RATED {stars: 5}]->
+        predicate: Option<Expr>,
     },
     // Produce source rows, unless source row is empty, in which case we produce one row with
     // the specified slots set to NULL
@@ -137,11 +295,27 @@ pub enum LogicalPlan {
         // Note that this may be empty, eg in the case of RETURN DISTINCT a.name.
         aggregations: Vec<(Expr, Slot)>,
     },
+    // Write the number of nodes with a label, or rels of a type, to slot, once per src row. The
+    // backend keeps count of these as the graph changes, so this is answered without scanning;
+    // see rewrite::count_from_store
+    CountStore {
+        src: Box<Self>,
+        slot: Slot,
+        count: CountOf,
+    },
     Unwind {
         src: Box<Self>,
         list_expr: Expr,
         alias: Slot,
     },
+    // For each src row, invoke a procedure and yield a row for each row it produces, with the
+    // named procedure outputs written to the given slots
+    Call {
+        src: Box<Self>,
+        name: Token,
+        args: Vec<Expr>,
+        outputs: Vec<(Token, Slot)>,
+    },
     // For each outer row, go through the inner and yield each row where the predicate matches.
     // This can be used as a general JOIN mechanism - though in most cases we'll want a more
     // specialized hash join. Still, this lets us do all kinds of joins as a broad fallback
@@ -150,6 +324,19 @@ pub enum LogicalPlan {
         inner: Box<Self>,
         predicate: Expr,
     },
+    // For each src row, run the probe plan with the src row as its argument, and yield the src
+    // row once if the probe produces at least one row. This is how we plan pattern predicates,
+    // like WHERE (a)-[:KNOWS]->(b); it's a semi-join, so many matches don't multiply the src row.
+    ConditionalApply {
+        src: Box<Self>,
+        probe: Box<Self>,
+    },
+    // Inverse of ConditionalApply: yield the src row only if the probe produces no rows. This is
+    // how we plan negated pattern predicates, like WHERE NOT (a)-[:BLOCKED]->(b).
+    AntiConditionalApply {
+        src: Box<Self>,
+        probe: Box<Self>,
+    },
 
     // Take the input and apply the specified projections
     Project {
@@ -158,7 +345,7 @@ pub enum LogicalPlan {
     },
     Sort {
         src: Box<Self>,
-        sort_by: Vec<Expr>,
+        sort_by: Vec<SortKey>,
     },
     Limit {
         src: Box<Self>,
@@ -173,6 +360,342 @@ pub enum LogicalPlan {
 }
 
 impl LogicalPlan {
+    // Names of all the operators, as returned by LogicalPlan::name
+    pub const OPERATORS: &'static [&'static str] = &[
+        "Argument",
+        "NodeScan",
+        "NodeIndexSeek",
+        "Expand",
+        "Optional",
+        "Selection",
+        "Create",
+        "Aggregate",
+        "CountStore",
+        "Unwind",
+        "Call",
+        "NestLoop",
+        "ConditionalApply",
+        "AntiConditionalApply",
+        "Project",
+        "Sort",
+        "Limit",
+        "ProduceResult",
+    ];
+
+    // Name of the operator at the root of this plan
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogicalPlan::Argument => "Argument",
+            LogicalPlan::NodeScan { .. } => "NodeScan",
+            LogicalPlan::NodeIndexSeek { .. } => "NodeIndexSeek",
+            LogicalPlan::Expand { .. } => "Expand",
+            LogicalPlan::Optional { .. } => "Optional",
+            LogicalPlan::Selection { .. } => "Selection",
+            LogicalPlan::Create { .. } => "Create",
+            LogicalPlan::Aggregate { .. } => "Aggregate",
+            LogicalPlan::CountStore { .. } => "CountStore",
+            LogicalPlan::Unwind { .. } => "Unwind",
+            LogicalPlan::Call { .. } => "Call",
+            LogicalPlan::NestLoop { .. } => "NestLoop",
+            LogicalPlan::ConditionalApply { .. } => "ConditionalApply",
+            LogicalPlan::AntiConditionalApply { .. } => "AntiConditionalApply",
+            LogicalPlan::Project { .. } => "Project",
+            LogicalPlan::Sort { .. } => "Sort",
+            LogicalPlan::Limit { .. } => "Limit",
+            LogicalPlan::ProduceResult { .. } => "ProduceResult",
+        }
+    }
+
+    // The plans this operator consumes
+    pub fn children(&self) -> Vec<&LogicalPlan> {
+        match self {
+            LogicalPlan::Argument => vec![],
+            LogicalPlan::NodeScan { src, .. }
+            | LogicalPlan::NodeIndexSeek { src, .. }
+            | LogicalPlan::Expand { src, .. }
+            | LogicalPlan::Optional { src, .. }
+            | LogicalPlan::Selection { src, .. }
+            | LogicalPlan::Create { src, .. }
+            | LogicalPlan::Aggregate { src, .. }
+            | LogicalPlan::CountStore { src, .. }
+            | LogicalPlan::Unwind { src, .. }
+            | LogicalPlan::Call { src, .. }
+            | LogicalPlan::Project { src, .. }
+            | LogicalPlan::Sort { src, .. }
+            | LogicalPlan::Limit { src, .. }
+            | LogicalPlan::ProduceResult { src, .. } => vec![src],
+            LogicalPlan::NestLoop { outer, inner, .. } => vec![outer, inner],
+            LogicalPlan::ConditionalApply { src, probe }
+            | LogicalPlan::AntiConditionalApply { src, probe } => vec![src, probe],
+        }
+    }
+
+    pub fn children_mut(&mut self) -> Vec<&mut LogicalPlan> {
+        match self {
+            LogicalPlan::Argument => vec![],
+            LogicalPlan::NodeScan { src, .. }
+            | LogicalPlan::NodeIndexSeek { src, .. }
+            | LogicalPlan::Expand { src, .. }
+            | LogicalPlan::Optional { src, .. }
+            | LogicalPlan::Selection { src, .. }
+            | LogicalPlan::Create { src, .. }
+            | LogicalPlan::Aggregate { src, .. }
+            | LogicalPlan::CountStore { src, .. }
+            | LogicalPlan::Unwind { src, .. }
+            | LogicalPlan::Call { src, .. }
+            | LogicalPlan::Project { src, .. }
+            | LogicalPlan::Sort { src, .. }
+            | LogicalPlan::Limit { src, .. }
+            | LogicalPlan::ProduceResult { src, .. } => vec![src],
+            LogicalPlan::NestLoop { outer, inner, .. } => vec![outer, inner],
+            LogicalPlan::ConditionalApply { src, probe }
+            | LogicalPlan::AntiConditionalApply { src, probe } => vec![src, probe],
+        }
+    }
+
+    // Call f with every slot this operator reads or writes, not counting the plans it consumes,
+    // so it can change them
+    pub fn slots_mut(&mut self, f: &mut dyn FnMut(&mut Slot)) {
+        let props = |props: &mut Vec<MapEntryExpr>, f: &mut dyn FnMut(&mut Slot)| {
+            props.iter_mut().for_each(|p| p.val.slots_mut(f))
+        };
+        match self {
+            LogicalPlan::Argument => (),
+            LogicalPlan::NodeScan { slot, .. } | LogicalPlan::CountStore { slot, .. } => f(slot),
+            LogicalPlan::NodeIndexSeek { slot, range, .. } => {
+                f(slot);
+                range.bounds_mut().for_each(|b| b.value.slots_mut(f))
+            }
+            LogicalPlan::Expand {
+                src_slot,
+                rel_slot,
+                dst_slot,
+                predicate,
+                ..
+            } => {
+                f(src_slot);
+                f(dst_slot);
+                f(rel_slot);
+                if let Some(p) = predicate {
+                    p.slots_mut(f)
+                }
+            }
+            LogicalPlan::Optional { slots, .. } => slots.iter_mut().for_each(f),
+            LogicalPlan::Selection { predicate, .. } | LogicalPlan::NestLoop { predicate, .. } => {
+                predicate.slots_mut(f)
+            }
+            LogicalPlan::Create { nodes, rels, .. } => {
+                for n in nodes {
+                    f(&mut n.slot);
+                    props(&mut n.props, f);
+                    if let Some(m) = &mut n.prop_map {
+                        m.slots_mut(f)
+                    }
+                }
+                for r in rels {
+                    f(&mut r.slot);
+                    f(&mut r.start_node_slot);
+                    f(&mut r.end_node_slot);
+                    props(&mut r.props, f);
+                    if let Some(m) = &mut r.prop_map {
+                        m.slots_mut(f)
+                    }
+                }
+            }
+            LogicalPlan::Aggregate {
+                grouping,
+                aggregations,
+                ..
+            } => {
+                for (e, slot) in grouping.iter_mut().chain(aggregations.iter_mut()) {
+                    e.slots_mut(f);
+                    f(slot);
+                }
+            }
+            LogicalPlan::Unwind {
+                list_expr, alias, ..
+            } => {
+                list_expr.slots_mut(f);
+                f(alias);
+            }
+            LogicalPlan::Call { args, outputs, .. } => {
+                args.iter_mut().for_each(|a| a.slots_mut(f));
+                outputs.iter_mut().for_each(|(_, slot)| f(slot));
+            }
+            LogicalPlan::ConditionalApply { .. } | LogicalPlan::AntiConditionalApply { .. } => (),
+            LogicalPlan::Project { projections, .. } => {
+                for p in projections {
+                    p.expr.slots_mut(f);
+                    f(&mut p.dst);
+                }
+            }
+            LogicalPlan::Sort { sort_by, .. } => {
+                sort_by.iter_mut().for_each(|k| k.expr.slots_mut(f))
+            }
+            LogicalPlan::Limit { skip, limit, .. } => skip
+                .iter_mut()
+                .chain(limit.iter_mut())
+                .for_each(|e| e.slots_mut(f)),
+            LogicalPlan::ProduceResult { fields, .. } => {
+                fields.iter_mut().for_each(|(_, slot)| f(slot))
+            }
+        }
+    }
+
+    // How many slots wide rows need to be to run this plan
+    pub fn row_width(&self) -> usize {
+        fn widen(plan: &mut LogicalPlan, width: &mut usize) {
+            plan.slots_mut(&mut |slot| *width = (*width).max(*slot + 1));
+            for c in plan.children_mut() {
+                widen(c, width)
+            }
+        }
+        let mut width = 0;
+        widen(&mut self.clone(), &mut width);
+        width
+    }
+
+    // This plan, with f applied to each of the plans this operator consumes
+    pub fn map_children(self, mut f: impl FnMut(Self) -> Self) -> Self {
+        let mut f = |src: Box<Self>| Box::new(f(*src));
+        match self {
+            LogicalPlan::Argument => LogicalPlan::Argument,
+            LogicalPlan::NodeScan { src, slot, labels } => LogicalPlan::NodeScan {
+                src: f(src),
+                slot,
+                labels,
+            },
+            LogicalPlan::NodeIndexSeek {
+                src,
+                slot,
+                label,
+                property,
+                range,
+            } => LogicalPlan::NodeIndexSeek {
+                src: f(src),
+                slot,
+                label,
+                property,
+                range,
+            },
+            LogicalPlan::Expand {
+                src,
+                src_slot,
+                rel_slot,
+                dst_slot,
+                rel_type,
+                dir,
+                predicate,
+            } => LogicalPlan::Expand {
+                src: f(src),
+                src_slot,
+                rel_slot,
+                dst_slot,
+                rel_type,
+                dir,
+                predicate,
+            },
+            LogicalPlan::Optional { src, slots } => LogicalPlan::Optional { src: f(src), slots },
+            LogicalPlan::Selection { src, predicate } => LogicalPlan::Selection {
+                src: f(src),
+                predicate,
+            },
+            LogicalPlan::Create { src, nodes, rels } => LogicalPlan::Create {
+                src: f(src),
+                nodes,
+                rels,
+            },
+            LogicalPlan::Aggregate {
+                src,
+                grouping,
+                aggregations,
+            } => LogicalPlan::Aggregate {
+                src: f(src),
+                grouping,
+                aggregations,
+            },
+            LogicalPlan::CountStore { src, slot, count } => LogicalPlan::CountStore {
+                src: f(src),
+                slot,
+                count,
+            },
+            LogicalPlan::Unwind {
+                src,
+                list_expr,
+                alias,
+            } => LogicalPlan::Unwind {
+                src: f(src),
+                list_expr,
+                alias,
+            },
+            LogicalPlan::Call {
+                src,
+                name,
+                args,
+                outputs,
+            } => LogicalPlan::Call {
+                src: f(src),
+                name,
+                args,
+                outputs,
+            },
+            LogicalPlan::NestLoop {
+                outer,
+                inner,
+                predicate,
+            } => LogicalPlan::NestLoop {
+                outer: f(outer),
+                inner: f(inner),
+                predicate,
+            },
+            LogicalPlan::ConditionalApply { src, probe } => LogicalPlan::ConditionalApply {
+                src: f(src),
+                probe: f(probe),
+            },
+            LogicalPlan::AntiConditionalApply { src, probe } => LogicalPlan::AntiConditionalApply {
+                src: f(src),
+                probe: f(probe),
+            },
+            LogicalPlan::Project { src, projections } => LogicalPlan::Project {
+                src: f(src),
+                projections,
+            },
+            LogicalPlan::Sort { src, sort_by } => LogicalPlan::Sort {
+                src: f(src),
+                sort_by,
+            },
+            LogicalPlan::Limit { src, skip, limit } => LogicalPlan::Limit {
+                src: f(src),
+                skip,
+                limit,
+            },
+            LogicalPlan::ProduceResult { src, fields } => LogicalPlan::ProduceResult {
+                src: f(src),
+                fields,
+            },
+        }
+    }
+
+    // Does running this plan change the graph?
+    pub fn writes(&self, bd: &BackendDesc) -> bool {
+        let writes = match self {
+            LogicalPlan::Create { .. } => true,
+            LogicalPlan::Call { name, .. } => matches!(bd.procedure(*name), Some(p) if p.writes),
+            _ => false,
+        };
+        writes || self.children().iter().any(|c| c.writes(bd))
+    }
+
+    // The plan in the canonical form users pin plans in, see describe.rs
+    pub fn describe(&self, t: &Tokens) -> String {
+        describe::describe(self, t)
+    }
+
+    // How many rows each operator is expected to produce, see estimate.rs
+    pub fn estimate(&self, stats: &Statistics, t: &Tokens) -> Vec<OperatorEstimate> {
+        estimate::estimate(self, stats, t)
+    }
+
     fn fmt_pretty(&self, ind: &str, t: &Tokens) -> String {
         match self {
             LogicalPlan::ProduceResult { src, fields } => {
@@ -229,6 +752,27 @@ impl LogicalPlan {
                     &lblstr
                 )
             }
+            LogicalPlan::NodeIndexSeek {
+                src,
+                slot,
+                label,
+                property,
+                range,
+            } => {
+                let next_indent = &format!("{}  ", ind);
+                format!(
+                    "NodeIndexSeek(\n{}src={}\n{}slot=Slot({})\n{}index=:{}({})\n{}range={:?})",
+                    ind,
+                    src.fmt_pretty(next_indent, t),
+                    ind,
+                    slot,
+                    ind,
+                    t.lookup(*label).unwrap_or("?"),
+                    t.lookup(*property).unwrap_or("?"),
+                    ind,
+                    range,
+                )
+            }
             LogicalPlan::Expand {
                 src,
                 src_slot,
@@ -236,9 +780,10 @@ impl LogicalPlan {
                 dst_slot,
                 rel_type,
                 dir,
+                predicate,
             } => {
                 let next_indent = &format!("{}  ", ind);
-                format!("Expand(\n{}src={}\n{}src_slot=Slot({})\n{}rel_slot=Slot({})\n{}dst_slot=Slot({}),\n{}rel_type={},\n{}dir={})",
+                format!("Expand(\n{}src={}\n{}src_slot=Slot({})\n{}rel_slot=Slot({})\n{}dst_slot=Slot({}),\n{}rel_type={},\n{}dir={},\n{}predicate={:?})",
                         ind, src.fmt_pretty(next_indent, t),
                         ind, src_slot,
                         ind, rel_slot,
@@ -247,7 +792,8 @@ impl LogicalPlan {
                             Some(tok) => t.lookup(*tok).unwrap_or("?"),
                             None => "<any>",
                         },
-                        ind, &format!("{:?}", dir))
+                        ind, &format!("{:?}", dir),
+                        ind, predicate)
             }
             LogicalPlan::Argument => format!("Argument()"),
             LogicalPlan::Create { src, nodes, rels } => {
@@ -310,27 +856,141 @@ impl LogicalPlan {
                     aggregations,
                 )
             }
+            LogicalPlan::CountStore { src, slot, count } => {
+                let next_indent = &format!("{}  ", ind);
+                let count = match count {
+                    CountOf::Nodes { label } => format!(
+                        "nodes:{}",
+                        label.map_or("<any>", |l| t.lookup(l).unwrap_or("?"))
+                    ),
+                    CountOf::Rels { rel_type } => format!(
+                        "rels:{}",
+                        rel_type.map_or("<any>", |r| t.lookup(r).unwrap_or("?"))
+                    ),
+                };
+                format!(
+                    "CountStore(\n{}src={}\n{}slot=Slot({})\n{}count={})",
+                    next_indent,
+                    src.fmt_pretty(next_indent, t),
+                    next_indent,
+                    slot,
+                    next_indent,
+                    count,
+                )
+            }
+            LogicalPlan::Call {
+                src,
+                name,
+                args,
+                outputs,
+            } => {
+                let next_indent = &format!("{}  ", ind);
+                let outputs: Vec<String> = outputs
+                    .iter()
+                    .map(|(tok, slot)| format!("{} => Slot({})", t.lookup(*tok).unwrap(), slot))
+                    .collect();
+                format!(
+                    "Call(
+{}src={}
+{}name={}
+{}args={:?}
+{}outputs=[{}])",
+                    next_indent,
+                    src.fmt_pretty(next_indent, t),
+                    next_indent,
+                    t.lookup(*name).unwrap(),
+                    next_indent,
+                    args,
+                    next_indent,
+                    outputs.join(", "),
+                )
+            }
+            LogicalPlan::ConditionalApply { src, probe } => {
+                let next_indent = &format!("{}  ", ind);
+                format!(
+                    "ConditionalApply(\n{}src={}\n{}probe={})",
+                    next_indent,
+                    src.fmt_pretty(next_indent, t),
+                    next_indent,
+                    probe.fmt_pretty(next_indent, t),
+                )
+            }
+            LogicalPlan::AntiConditionalApply { src, probe } => {
+                let next_indent = &format!("{}  ", ind);
+                format!(
+                    "AntiConditionalApply(\n{}src={}\n{}probe={})",
+                    next_indent,
+                    src.fmt_pretty(next_indent, t),
+                    next_indent,
+                    probe.fmt_pretty(next_indent, t),
+                )
+            }
             _ => format!("NoPretty({:?})", self),
         }
     }
 }
 
+// A logical plan along with what's needed to print it in a readable way
+pub struct PlanDescription<'a> {
+    pub plan: &'a LogicalPlan,
+    tokens: &'a Tokens,
+}
+
+impl<'a> Display for PlanDescription<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.plan.fmt_pretty("", self.tokens))
+    }
+}
+
 // Specification of a node to create
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct NodeSpec {
     pub slot: usize,
     pub labels: Vec<Token>,
     pub props: Vec<MapEntryExpr>,
+    // A map whose entries are properties as well, like the $props of CREATE (n $props)
+    pub prop_map: Option<Expr>,
 }
 
 // Specification of a rel to create
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct RelSpec {
     pub slot: usize,
     pub rel_type: Token,
     pub start_node_slot: usize,
     pub end_node_slot: usize,
     pub props: Vec<MapEntryExpr>,
+    // See NodeSpec::prop_map
+    pub prop_map: Option<Expr>,
+}
+
+// The values a NodeIndexSeek looks for; with neither bound, every node in the index
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct IndexRange {
+    pub lower: Option<RangeBound>,
+    pub upper: Option<RangeBound>,
+}
+
+impl IndexRange {
+    pub fn bounds_mut(&mut self) -> impl Iterator<Item = &mut RangeBound> {
+        self.lower.iter_mut().chain(self.upper.iter_mut())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RangeBound {
+    // Evaluated against the src row the seek is for
+    pub value: Expr,
+    pub inclusive: bool,
+}
+
+// What a CountStore counts
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum CountOf {
+    // Nodes with the given label, or all nodes
+    Nodes { label: Option<Token> },
+    // Rels of the given type, or all rels
+    Rels { rel_type: Option<Token> },
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -361,14 +1021,14 @@ impl RelType {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Predicate {
     And(Vec<Predicate>),
     Or(Vec<Predicate>),
     HasLabel(Token),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Projection {
     pub expr: Expr,
     pub alias: Token,
@@ -386,13 +1046,46 @@ impl Projection {
     }
 }
 
+// One of the things to ORDER BY. Rows that compare equal on every key keep the order they came
+// in, so the result only changes across runs if the input does.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SortKey {
+    pub expr: Expr,
+    pub descending: bool,
+    // Where nulls go, regardless of direction. Cypher treats null as larger than any other
+    // value, so this defaults to last for ascending and first for descending
+    pub nulls_first: bool,
+}
+
+impl SortKey {
+    pub fn new(expr: Expr, descending: bool, nulls_first: Option<bool>) -> Self {
+        SortKey {
+            expr,
+            descending,
+            nulls_first: nulls_first.unwrap_or(descending),
+        }
+    }
+
+    pub fn asc(expr: Expr) -> Self {
+        SortKey::new(expr, false, None)
+    }
+}
+
 #[derive(Debug)]
 pub struct PlanningContext<'i> {
     // Mapping of names used in the query string to slots in the row being processed
     slots: HashMap<Token, usize>,
+    // Slots are handed out from here, reusing ones no identifier is using anymore first
+    next_slot: usize,
+    free_slots: Vec<Slot>,
+    // Identifiers a WITH did not carry over, and the slot they had
+    dropped: HashMap<Token, Slot>,
     // Identifiers that the user has explictly declared. Eg in MATCH "(a)-->(b)" there are
     // three identifiers: a, b and an anonymous rel identifier. "a" and "b" are "named" here.
-    named_identifiers: HashSet<Token>,
+    // Kept in the order they were declared in, which is the order RETURN * lists them in.
+    named_identifiers: Vec<Token>,
+    // What we know about the types of identifiers, see types.rs
+    var_types: HashMap<Token, Type>,
 
     // TODO is there some nicer way to do this than Rc+RefCell?
     tokens: Rc<RefCell<Tokens>>,
@@ -405,20 +1098,57 @@ pub struct PlanningContext<'i> {
 
     anon_rel_seq: u32,
     anon_node_seq: u32,
+
+    // Things we've found while planning that the user should know about
+    notifications: Vec<Notification>,
+
+    // Literals that have been lifted out into parameters, by where they start in the query text,
+    // and the types of those parameters; see literals.rs
+    literal_params: HashMap<usize, Token>,
+    param_types: HashMap<Token, Type>,
+    // Set if an unaliased column is named after query text that includes a lifted literal
+    names_literals: bool,
+    // Parameters the query refers to, that need values when it runs
+    parameters: Vec<Token>,
+
+    // See views.rs: the views CALL can expand, the views being expanded right now, and what the
+    // parameters of the innermost one stand for
+    views: Rc<HashMap<Token, View>>,
+    expanding: Vec<Token>,
+    view_args: HashMap<Token, Expr>,
+    // Slots of the query a view is expanded into, which the view must leave alone
+    pinned: HashSet<Slot>,
 }
 
 impl<'i> PlanningContext<'i> {
     fn new(tokens: Rc<RefCell<Tokens>>, bd: &'i BackendDesc) -> Self {
         PlanningContext {
             slots: Default::default(),
+            next_slot: 0,
+            free_slots: Vec::new(),
+            dropped: HashMap::new(),
             named_identifiers: Default::default(),
+            var_types: Default::default(),
             tokens,
             backend_desc: bd,
             anon_rel_seq: 0,
             anon_node_seq: 0,
+            notifications: Vec::new(),
+            literal_params: HashMap::new(),
+            param_types: HashMap::new(),
+            names_literals: false,
+            parameters: Vec::new(),
+            views: Rc::new(HashMap::new()),
+            expanding: Vec::new(),
+            view_args: HashMap::new(),
+            pinned: HashSet::new(),
         }
     }
 
+    fn notify(&mut self, code: &'static str, message: String) {
+        self.notifications.push(Notification { code, message });
+    }
+
     // Note: See declare() if you are declaring a named identifier that should be subject to
     // operations that refer to "all named identifiers", like RETURN *
     fn tokenize(&mut self, contents: &str) -> Token {
@@ -428,7 +1158,9 @@ impl<'i> PlanningContext<'i> {
     // Declare a named identifier in the current scope if it isn't already;
     // the identifier becomes visible to operations like RETURN * and WITH *, et cetera.
     fn declare_tok(&mut self, tok: Token) {
-        self.named_identifiers.insert(tok);
+        if !self.named_identifiers.contains(&tok) {
+            self.named_identifiers.push(tok);
+        }
     }
 
     // Shorthand for tokenize + declare_tok
@@ -448,12 +1180,63 @@ impl<'i> PlanningContext<'i> {
     pub fn get_or_alloc_slot(&mut self, tok: Token) -> usize {
         match self.slots.get(&tok) {
             Some(slot) => *slot,
-            None => {
-                let slot = self.slots.len();
-                self.slots.insert(tok, slot);
-                slot
-            }
+            None => self.alloc_slot(tok),
+        }
+    }
+
+    // Give an identifier a slot of its own, even if it already had one
+    fn alloc_slot(&mut self, tok: Token) -> Slot {
+        let slot = self.free_slots.pop().unwrap_or_else(|| {
+            self.next_slot += 1;
+            self.next_slot - 1
+        });
+        self.slots.insert(tok, slot);
+        slot
+    }
+
+    // The slot of an identifier an expression refers to
+    fn slot_of(&mut self, id: &Pair<Rule>) -> Result<Slot> {
+        let name = identifier(id);
+        let tok = self.tokenize(&name);
+        if !self.slots.contains_key(&tok) && self.dropped.contains_key(&tok) {
+            bail!(QueryError::semantic(
+                format!(
+                    "Variable `{}` not defined, the WITH before this drops it",
+                    name
+                ),
+                id.as_span()
+            ))
+        }
+        Ok(self.get_or_alloc_slot(tok))
+    }
+
+    // Called once a WITH is planned: only what it projected stays in scope. The slots of
+    // everything else can be reused by what comes after it if reuse_slots is set, which is only
+    // safe if the WITH collects all its input rows before passing any on; otherwise operators
+    // before the WITH may still read those slots as they produce their next row, after later
+    // operators have written over them.
+    fn end_scope(&mut self, reuse_slots: bool) {
+        let in_scope = &self.named_identifiers;
+        let dropped: Vec<(Token, Slot)> = self
+            .slots
+            .iter()
+            .filter(|(tok, _)| !in_scope.contains(tok))
+            .map(|(tok, slot)| (*tok, *slot))
+            .collect();
+        for (tok, slot) in dropped {
+            self.slots.remove(&tok);
+            self.var_types.remove(&tok);
+            self.dropped.insert(tok, slot);
         }
+        if !reuse_slots {
+            return;
+        }
+        // Highest first, so the lowest free slot is handed out next
+        let live: HashSet<Slot> = self.slots.values().copied().collect();
+        self.free_slots = (0..self.next_slot)
+            .rev()
+            .filter(|s| !live.contains(s) && !self.pinned.contains(s))
+            .collect();
     }
 
     pub fn new_anon_rel(&mut self) -> Token {
@@ -478,12 +1261,13 @@ fn plan_unwind(
 
     let list_item = parts.next().expect("UNWIND must contain a list expression");
     let list_expr = plan_expr(pc, list_item)?;
-    let alias_token = pc.declare(
-        parts
-            .next()
-            .expect("UNWIND must contain an AS alias")
-            .as_str(),
-    );
+    let alias_item = parts.next().expect("UNWIND must contain an AS alias");
+    let alias_token = pc.declare(&identifier(&alias_item));
+    let item_type = match types::type_of(pc, &list_expr)? {
+        Type::List(t) => *t,
+        _ => Type::Any,
+    };
+    pc.var_types.insert(alias_token, item_type);
     let alias = pc.get_or_alloc_slot(alias_token);
 
     return Ok(LogicalPlan::Unwind {
@@ -493,11 +1277,17 @@ fn plan_unwind(
     });
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PatternNode {
     identifier: Token,
+    // Labels the node must have all of
     labels: Vec<Token>,
+    // Sets of labels the node must have at least one of each of, like [Person, Bot] for
+    // (n:Person|Bot)
+    label_alternatives: Vec<Vec<Token>>,
     props: Vec<MapEntryExpr>,
+    // Properties given as a parameter, only allowed in CREATE, see NodeSpec::prop_map
+    prop_map: Option<Expr>,
     // In the pattern, was this node assigned an identifier?
     // eg. in "MATCH (a)-->()", the second node is anonymous; it will have
     // been assigned an anonymous identifier
@@ -509,7 +1299,7 @@ impl PatternNode {
     fn merge(&mut self, _other: &PatternNode) {}
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct PatternRel {
     identifier: Token,
     rel_type: Option<Token>,
@@ -518,6 +1308,7 @@ pub struct PatternRel {
     // From the perspective of the left node, is this pattern inbound or outbound?
     dir: Option<Dir>,
     props: Vec<MapEntryExpr>,
+    prop_map: Option<Expr>,
     // In the pattern, was this node assigned an identifier?
     // eg. in "MATCH (a)-[r]->(b)-->(c)", the second rel is anonymous; it will have
     // been assigned an auto-generated identifier
@@ -525,7 +1316,7 @@ pub struct PatternRel {
     solved: bool,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub struct PatternGraph {
     v: HashMap<Token, PatternNode>,
     v_order: Vec<Token>,
@@ -556,7 +1347,9 @@ pub struct PatternGraph {
     // then filter. The difference is something like 6 orders of magnitude of comparisons made.
     //
     // Long story short: We want a way to "lift" predicates out of this filter when we plan MATCH,
-    // so that we filter stuff down as early as possible.
+    // so that we filter stuff down as early as possible. solve_pattern does this for terms that
+    // only concern one disconnected part of the pattern, like the two above; the rest is still
+    // applied at the end.
     predicate: Option<Expr>,
 }
 
@@ -585,34 +1378,7 @@ fn parse_pattern_graph(pc: &mut PlanningContext, patterns: Pair<Rule>) -> Result
     for part in patterns.into_inner() {
         match part.as_rule() {
             Rule::optional_clause => pg.optional = true,
-            Rule::pattern => {
-                let mut prior_node_id = None;
-                let mut prior_rel: Option<PatternRel> = None;
-                // For each node and rel segment of eg: (n:Message)-[:KNOWS]->()
-                for segment in part.into_inner() {
-                    match segment.as_rule() {
-                        Rule::node => {
-                            let prior_node = parse_pattern_node(pc, segment)?;
-                            prior_node_id = Some(prior_node.identifier);
-                            pg.merge_node(prior_node);
-                            if let Some(mut rel) = prior_rel {
-                                rel.right_node = prior_node_id;
-                                pg.merge_rel(rel);
-                                prior_rel = None
-                            }
-                        }
-                        Rule::rel => {
-                            prior_rel = Some(parse_pattern_rel(
-                                pc,
-                                prior_node_id.expect("pattern rel must be preceded by node"),
-                                segment,
-                            )?);
-                            prior_node_id = None
-                        }
-                        _ => unreachable!(),
-                    }
-                }
-            }
+            Rule::pattern => parse_pattern(pc, &mut pg, part)?,
             Rule::where_clause => {
                 pg.predicate = Some(plan_expr(
                     pc,
@@ -628,22 +1394,120 @@ fn parse_pattern_graph(pc: &mut PlanningContext, patterns: Pair<Rule>) -> Result
     Ok(pg)
 }
 
+// Add the nodes and rels of a single pattern, like (n:Message)-[:KNOWS]->(), to the pattern graph.
+// This handles both the pattern rule and the pattern_predicate rule, since they have the same parts.
+fn parse_pattern(
+    pc: &mut PlanningContext,
+    pg: &mut PatternGraph,
+    pattern: Pair<Rule>,
+) -> Result<()> {
+    let mut prior_node_id = None;
+    let mut prior_rel: Option<PatternRel> = None;
+    // For each node and rel segment of eg: (n:Message)-[:KNOWS]->()
+    for segment in pattern.into_inner() {
+        match segment.as_rule() {
+            Rule::node => {
+                let prior_node = parse_pattern_node(pc, segment)?;
+                prior_node_id = Some(prior_node.identifier);
+                pg.merge_node(prior_node);
+                if let Some(mut rel) = prior_rel {
+                    rel.right_node = prior_node_id;
+                    pg.merge_rel(rel);
+                    prior_rel = None
+                }
+            }
+            Rule::rel => {
+                prior_rel = Some(parse_pattern_rel(
+                    pc,
+                    prior_node_id.expect("pattern rel must be preceded by node"),
+                    segment,
+                )?);
+                prior_node_id = None
+            }
+            _ => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+// Tokenize a node or rel variable in a pattern; if it's already bound, it'd better be bound
+// to the right kind of thing
+fn pattern_variable(pc: &mut PlanningContext, id: &Pair<Rule>, t: Type) -> Result<Token> {
+    let tok = pc.tokenize(&identifier(id));
+    if let Some(existing) = pc.var_types.get(&tok) {
+        if pc.is_declared(tok) && !types::compatible(&t, existing) {
+            bail!(QueryError::TypeError {
+                message: format!(
+                    "Type mismatch: `{}` is used as a {:?} but was {:?}",
+                    identifier(id),
+                    t,
+                    existing
+                )
+            })
+        }
+    }
+    pc.var_types.insert(tok, t);
+    Ok(tok)
+}
+
+// If the expression is just a variable, like the `a` in RETURN a, gives the id of that variable
+fn bare_variable<'i>(expr: &Pair<'i, Rule>) -> Option<Pair<'i, Rule>> {
+    let mut expr = expr.clone();
+    loop {
+        match expr.as_rule() {
+            Rule::id => return Some(expr),
+            Rule::expr | Rule::and_expr | Rule::add_sub_expr | Rule::mult_div_expr => {
+                let mut inner = expr.into_inner();
+                match (inner.next(), inner.next()) {
+                    (Some(only), None) => expr = only,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+}
+
+// The name an id refers to, with any backtick quoting removed
+fn identifier<'i>(id: &Pair<'i, Rule>) -> Cow<'i, str> {
+    let inner = id
+        .clone()
+        .into_inner()
+        .next()
+        .expect("ids are quoted or unquoted");
+    match inner.as_rule() {
+        Rule::escaped_id => Cow::Owned(inner.as_str().replace("``", "`")),
+        _ => Cow::Borrowed(inner.as_str()),
+    }
+}
+
 // Figures out what step we need to find the specified node
 fn parse_pattern_node(pc: &mut PlanningContext, pattern_node: Pair<Rule>) -> Result<PatternNode> {
     let mut identifier = None;
     let mut labels = Vec::new();
+    let mut label_alternatives = Vec::new();
     let mut props = Vec::new();
+    let mut prop_map = None;
     for part in pattern_node.into_inner() {
         match part.as_rule() {
-            Rule::id => identifier = Some(pc.tokenize(part.as_str())),
+            Rule::id => identifier = Some(pattern_variable(pc, &part, Type::Node)?),
             Rule::label => {
-                for label in part.into_inner() {
-                    labels.push(pc.tokenize(label.as_str()));
+                let mut any_of: Vec<Token> = part
+                    .into_inner()
+                    .map(|label| pc.tokenize(&self::identifier(&label)))
+                    .collect();
+                any_of.sort_unstable();
+                any_of.dedup();
+                if any_of.len() == 1 {
+                    labels.extend(any_of);
+                } else {
+                    label_alternatives.push(any_of);
                 }
             }
             Rule::map => {
                 props = expr::parse_map_expression(pc, part)?;
             }
+            Rule::param => prop_map = Some(expr::plan_term(pc, part)?),
             _ => panic!("don't know how to handle: {}", part),
         }
     }
@@ -652,10 +1516,14 @@ fn parse_pattern_node(pc: &mut PlanningContext, pattern_node: Pair<Rule>) -> Res
     let id = identifier.unwrap_or_else(|| pc.new_anon_node());
     labels.sort_unstable();
     labels.dedup();
+    // (n:A:A|B) is just (n:A)
+    label_alternatives.retain(|any_of: &Vec<Token>| !any_of.iter().any(|l| labels.contains(l)));
     Ok(PatternNode {
         identifier: id,
         labels,
+        label_alternatives,
         props,
+        prop_map,
         anonymous,
         solved: false,
     })
@@ -670,20 +1538,26 @@ fn parse_pattern_rel(
     let mut rel_type = None;
     let mut dir = None;
     let mut props = Vec::new();
+    let mut prop_map = None;
+    let span = pattern_rel.as_span();
     for part in pattern_rel.into_inner() {
         match part.as_rule() {
-            Rule::id => identifier = Some(pc.tokenize(part.as_str())),
-            Rule::rel_type => rel_type = Some(pc.tokenize(part.as_str())),
+            Rule::id => identifier = Some(pattern_variable(pc, &part, Type::Relationship)?),
+            Rule::rel_type => {
+                let id = part.into_inner().next().expect("rel types are identifiers");
+                rel_type = Some(pc.tokenize(&self::identifier(&id)))
+            }
             Rule::left_arrow => dir = Some(Dir::In),
             Rule::right_arrow => {
                 if dir.is_some() {
-                    bail!("relationship can't be directed in both directions. If you want to find relationships in either direction, leave the arrows out")
+                    bail!(QueryError::semantic("relationship can't be directed in both directions. If you want to find relationships in either direction, leave the arrows out".to_string(), span))
                 }
                 dir = Some(Dir::Out)
             }
             Rule::map => {
                 props = expr::parse_map_expression(pc, part)?;
             }
+            Rule::param => prop_map = Some(expr::plan_term(pc, part)?),
             _ => unreachable!(),
         }
     }
@@ -696,6 +1570,7 @@ fn parse_pattern_rel(
         rel_type,
         dir,
         props,
+        prop_map,
         anonymous,
         solved: false,
     })
@@ -705,6 +1580,7 @@ fn parse_pattern_rel(
 pub(crate) mod tests {
     use super::*;
     use crate::backend::{BackendDesc, FuncSignature, FuncType, Token, Tokens};
+    use crate::diagnostics::NoDiagnostics;
     use crate::Type;
     use anyhow::Result;
     use std::cell::RefCell;
@@ -740,16 +1616,30 @@ pub(crate) mod tests {
         let tokens = Rc::new(RefCell::new(Tokens::new()));
         let tok_expr = tokens.borrow_mut().tokenize("expr");
         let fn_count = tokens.borrow_mut().tokenize("count");
-        let backend_desc = BackendDesc::new(vec![FuncSignature {
-            func_type: FuncType::Aggregating,
-            name: fn_count,
-            returns: Type::Integer,
-            args: vec![(tok_expr, Type::Any)],
-        }]);
+        let fn_opaque = tokens.borrow_mut().tokenize("opaque");
+        let backend_desc = BackendDesc::new(vec![
+            FuncSignature {
+                func_type: FuncType::Aggregating,
+                name: fn_count,
+                returns: Type::Integer,
+                args: vec![(tok_expr, Type::Any)],
+                optional: 0,
+            },
+            // A function the planner knows nothing about
+            FuncSignature {
+                func_type: FuncType::Scalar,
+                name: fn_opaque,
+                returns: Type::Any,
+                args: vec![],
+                optional: 0,
+            },
+        ]);
 
         let frontend = Frontend {
             tokens: Rc::clone(&tokens),
             backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
         };
         let mut pc = PlanningContext::new(Rc::clone(&tokens), &backend_desc);
         let plan = frontend.plan_in_context(q, &mut pc);
@@ -757,7 +1647,8 @@ pub(crate) mod tests {
         match plan {
             Ok(plan) => Ok(PlanArtifacts {
                 plan,
-                slots: pc.slots,
+                // Include what a WITH dropped, so tests can check the plan up to the WITH
+                slots: pc.dropped.into_iter().chain(pc.slots).collect(),
                 tokens: Rc::clone(&tokens),
             }),
             Err(e) => {
@@ -767,6 +1658,76 @@ pub(crate) mod tests {
         }
     }
 
+    #[derive(Debug, Default)]
+    struct RecordingDiagnostics {
+        plans: Rc<RefCell<Vec<String>>>,
+        notifications: Rc<RefCell<Vec<Notification>>>,
+    }
+
+    impl DiagnosticsSink for RecordingDiagnostics {
+        fn plan(&self, _query: &str, plan: &PlanDescription) {
+            self.plans.borrow_mut().push(plan.to_string());
+        }
+
+        fn notification(&self, _query: &str, notification: &Notification) {
+            self.notifications.borrow_mut().push(notification.clone());
+        }
+    }
+
+    #[test]
+    fn sends_plans_and_notifications_to_diagnostics() -> Result<()> {
+        let sink = RecordingDiagnostics::default();
+        let plans = Rc::clone(&sink.plans);
+        let notifications = Rc::clone(&sink.notifications);
+        let frontend = Frontend {
+            tokens: Rc::new(RefCell::new(Tokens::new())),
+            backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(sink),
+            views: Default::default(),
+        };
+
+        frontend.plan("MATCH (a), (b) RETURN a")?;
+
+        assert_eq!(plans.borrow().len(), 1);
+        assert!(plans.borrow()[0].starts_with("ProduceResult("));
+        let codes: Vec<&str> = notifications.borrow().iter().map(|n| n.code).collect();
+        assert_eq!(codes, vec!["CartesianProduct"]);
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_plan_what_the_backend_does_not_support() {
+        let mut backend_desc = BackendDesc::new(vec![]);
+        backend_desc.operators.remove("Sort");
+        backend_desc.types.float = false;
+        let frontend = Frontend {
+            tokens: Rc::new(RefCell::new(Tokens::new())),
+            backend_desc,
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        };
+
+        assert!(frontend.plan("MATCH (a) RETURN a").is_ok());
+        let err = frontend
+            .plan("MATCH (a) RETURN a ORDER BY a.name")
+            .unwrap_err();
+        assert!(err.to_string().contains("Sort"), "{}", err);
+        assert!(frontend.plan("RETURN 1.5").is_err());
+    }
+
+    #[test]
+    fn ignores_comments() -> Result<()> {
+        let with_comments = plan(
+            "// Everyone called Bob
+            MATCH (n) /* any node,
+               with any label */ WHERE n.name = 'Bob' // the name
+            RETURN n",
+        )?;
+        let without = plan("MATCH (n) WHERE n.name = 'Bob' RETURN n")?;
+        assert_eq!(with_comments.plan, without.plan);
+        Ok(())
+    }
+
     mod unwind {
         use crate::frontend::tests::plan;
         use crate::frontend::{Expr, LogicalPlan};
@@ -0,0 +1,539 @@
+// Rewrites of a complete plan, for things that are easier to spot once the whole plan is there
+// than while planning the clause they're in
+use super::estimate::{self, is_point};
+use super::{CountOf, Expr, IndexRange, LogicalPlan, Op, PlanningContext, RangeBound};
+use crate::backend::Token;
+use crate::Slot;
+use std::collections::{HashMap, HashSet};
+
+pub fn rewrite(pc: &mut PlanningContext, plan: LogicalPlan) -> LogicalPlan {
+    let plan = rewrite_operators(pc, plan);
+    // Only whole queries, that end in a RETURN or an update, tell what they need; plans that end
+    // in, say, a WITH are ones looked at a clause at a time, to see how that clause was planned
+    let plan = match plan {
+        LogicalPlan::ProduceResult { .. } | LogicalPlan::Create { .. } => {
+            prune_projections(plan, HashSet::new())
+        }
+        plan => plan,
+    };
+    compact_slots(pc, plan)
+}
+
+fn rewrite_operators(pc: &mut PlanningContext, plan: LogicalPlan) -> LogicalPlan {
+    // Top down, so the seek is picked with all the predicates above the scan in view
+    let plan = seek_indexes(pc, plan);
+    let plan = plan.map_children(|src| rewrite_operators(pc, src));
+    count_from_store(pc, plan)
+}
+
+// A node found through an index costs more than one found by scanning: it's looked up in the
+// index, and the predicates read the property again after
+const INDEX_ROW_COST: f64 = 2.0;
+
+// MATCH (n:Person) WHERE n.age > 30 finds the Person nodes in an index on their age, if the
+// backend keeps one and the statistics say that's cheaper than going through all Person nodes.
+// The predicates stay where they are and the seek only stands in for the scan under them, so
+// this looks down through anything in between that doesn't change what the node is, like the
+// Expand in MATCH (n:Person)-->(m) WHERE n.age > 30.
+fn seek_indexes(pc: &PlanningContext, plan: LogicalPlan) -> LogicalPlan {
+    if pc.backend_desc.indexes.is_empty() || !matches!(plan, LogicalPlan::Selection { .. }) {
+        return plan;
+    }
+    let mut terms = Vec::new();
+    // Slots written between the scan and the top selection, which the seek can't read
+    let mut written_above = Vec::new();
+    let mut at = &plan;
+    let (slot, scan_label) = loop {
+        match at {
+            LogicalPlan::Selection { src, predicate } => {
+                match predicate {
+                    Expr::And(ts) => terms.extend(ts),
+                    t => terms.push(t),
+                }
+                at = src
+            }
+            LogicalPlan::Expand {
+                src,
+                rel_slot,
+                dst_slot,
+                ..
+            } => {
+                written_above.push(*rel_slot);
+                written_above.push(*dst_slot);
+                at = src
+            }
+            LogicalPlan::NodeScan { slot, labels, .. } => break (*slot, *labels),
+            _ => return plan,
+        }
+    };
+    written_above.push(slot);
+
+    // Any label the node must have will do, and so will any property the predicates bound
+    let labels: Vec<Token> = scan_label
+        .into_iter()
+        .chain(terms.iter().filter_map(|t| match t {
+            Expr::HasLabel(s, l) if *s == slot => Some(*l),
+            _ => None,
+        }))
+        .collect();
+    let mut ranges: Vec<(Token, IndexRange)> = Vec::new();
+    for term in &terms {
+        if let Some((property, range)) = range_of(term, slot, &written_above) {
+            match ranges.iter_mut().find(|(p, _)| *p == property) {
+                // n.x > 1 AND n.x < 10 is one range; there's no telling which of two lower bounds
+                // is the tighter one until the query runs though, so the first one goes
+                Some((_, existing)) => {
+                    if existing.lower.is_none() {
+                        existing.lower = range.lower
+                    }
+                    if existing.upper.is_none() {
+                        existing.upper = range.upper
+                    }
+                }
+                None => ranges.push((property, range)),
+            }
+        }
+    }
+
+    let stats = pc.backend_desc.statistics.as_ref();
+    let mut best: Option<(f64, Token, Token, IndexRange)> = None;
+    for &label in &labels {
+        for (property, range) in &ranges {
+            let index = match pc.backend_desc.index(label, *property) {
+                Some(index) if index.ordered || is_point(range) => index,
+                _ => continue,
+            };
+            // An index collating otherwise than the predicates do can only stand in for the
+            // scan if it finds all the nodes they'd let through, see Collation::finds_equal
+            let collation = &pc.backend_desc.collation;
+            let collates = if is_point(range) {
+                index.collation.finds_equal(collation)
+            } else {
+                index.collation == *collation
+            };
+            if !collates {
+                continue;
+            }
+            // Without statistics, any index beats scanning; with them, what profiling the same
+            // seek found beats guessing from the index
+            let rows = match stats.and_then(|s| s.index(index.label, index.property)) {
+                Some(index_stats) => {
+                    let seek = LogicalPlan::NodeIndexSeek {
+                        src: Box::new(LogicalPlan::Argument),
+                        slot,
+                        label,
+                        property: *property,
+                        range: range.clone(),
+                    };
+                    let key = estimate::key(&seek, &pc.tokens.borrow());
+                    match stats.and_then(|s| s.feedback.get(&key)) {
+                        Some(observed) if observed.runs > 0 => observed.rows_per_input(),
+                        _ => estimate::seek_rows(index_stats, range),
+                    }
+                }
+                None => 0.0,
+            };
+            if best
+                .as_ref()
+                .is_none_or(|(best_rows, ..)| rows < *best_rows)
+            {
+                best = Some((rows, label, *property, range.clone()));
+            }
+        }
+    }
+    let (rows, label, property, range) = match best {
+        Some(best) => best,
+        None => return plan,
+    };
+    if let Some(stats) = stats {
+        let scanned = match scan_label {
+            Some(l) => stats.nodes_with(l),
+            None => stats.nodes,
+        };
+        if rows * INDEX_ROW_COST >= scanned as f64 {
+            return plan;
+        }
+    }
+
+    fn replace_scan(
+        plan: LogicalPlan,
+        seek: &mut Option<(Token, Token, IndexRange)>,
+    ) -> LogicalPlan {
+        match plan {
+            LogicalPlan::NodeScan { src, slot, labels } => {
+                let (label, property, range) = seek.take().expect("there's one scan to replace");
+                let seek = LogicalPlan::NodeIndexSeek {
+                    src,
+                    slot,
+                    label,
+                    property,
+                    range,
+                };
+                // Seeking by another label, the one scanned for still needs checking
+                match labels {
+                    Some(scanned) if scanned != label => LogicalPlan::Selection {
+                        src: Box::new(seek),
+                        predicate: Expr::HasLabel(slot, scanned),
+                    },
+                    _ => seek,
+                }
+            }
+            plan => plan.map_children(|src| replace_scan(src, seek)),
+        }
+    }
+    replace_scan(plan, &mut Some((label, property, range)))
+}
+
+// The range of values of a property of the node in slot that the predicate term needs, if it's a
+// comparison like n.age > 30 or $min <= n.age
+fn range_of(term: &Expr, slot: Slot, written_above: &[Slot]) -> Option<(Token, IndexRange)> {
+    let (left, right, op) = match term {
+        Expr::BinaryOp { left, right, op } => (&**left, &**right, op),
+        _ => return None,
+    };
+    let property_of = |e: &Expr| match e {
+        Expr::Prop(e, props) if props.len() == 1 && **e == Expr::Slot(slot) => Some(props[0]),
+        _ => None,
+    };
+    let (property, value, op) = match (property_of(left), property_of(right)) {
+        (Some(p), None) => (p, right, op.clone()),
+        // 30 < n.age is n.age > 30
+        (None, Some(p)) => (
+            p,
+            left,
+            match op {
+                Op::Gt => Op::Lt,
+                Op::Lt => Op::Gt,
+                Op::Gte => Op::Lte,
+                Op::Lte => Op::Gte,
+                op => op.clone(),
+            },
+        ),
+        _ => return None,
+    };
+    if !known_before(value, written_above) {
+        return None;
+    }
+    let bound = |inclusive| {
+        Some(RangeBound {
+            value: value.clone(),
+            inclusive,
+        })
+    };
+    let range = match op {
+        Op::Eq => IndexRange {
+            lower: bound(true),
+            upper: bound(true),
+        },
+        Op::Gt | Op::Gte => IndexRange {
+            lower: bound(op == Op::Gte),
+            upper: None,
+        },
+        Op::Lt | Op::Lte => IndexRange {
+            lower: None,
+            upper: bound(op == Op::Lte),
+        },
+        _ => return None,
+    };
+    Some((property, range))
+}
+
+// Can the seek work out the value of this expression, before it has found the node? It can't
+// read the slots written after it, and it's evaluated once per seek rather than once per node,
+// so nothing that might give a different value each time, like rand(), will do either.
+fn known_before(e: &Expr, written_above: &[Slot]) -> bool {
+    match e {
+        Expr::Param(_)
+        | Expr::Int(_)
+        | Expr::Float(_)
+        | Expr::String(_)
+        | Expr::Bool(_)
+        | Expr::Null => true,
+        Expr::Slot(s) => !written_above.contains(s),
+        Expr::Prop(e, _) => known_before(e, written_above),
+        Expr::BinaryOp { left, right, .. } => {
+            known_before(left, written_above) && known_before(right, written_above)
+        }
+        _ => false,
+    }
+}
+
+// MATCH (n:Person) RETURN count(n) is answered from the counts the backend keeps, rather than by
+// counting the Person nodes one by one; same for rels of a type, in MATCH ()-[r:KNOWS]->(). This
+// only applies when the count is all there is: anything else in the pattern or a WHERE would
+// need the nodes or rels themselves.
+fn count_from_store(pc: &mut PlanningContext, plan: LogicalPlan) -> LogicalPlan {
+    if !pc.backend_desc.supports_operator("CountStore") {
+        return plan;
+    }
+    let count_fn = pc.tokenize("count");
+    let (src, slot, counted) = match &plan {
+        LogicalPlan::Aggregate {
+            src,
+            grouping,
+            aggregations,
+        } if grouping.is_empty() && aggregations.len() == 1 => match &aggregations[0] {
+            (Expr::FuncCall { name, args }, slot) if *name == count_fn => match args.as_slice() {
+                // count(*)
+                [] => (src, *slot, None),
+                [Expr::Slot(counted)] => (src, *slot, Some(*counted)),
+                _ => return plan,
+            },
+            _ => return plan,
+        },
+        _ => return plan,
+    };
+    // Whatever is counted has to be a node or rel of the pattern, which are never null
+    let count = match &**src {
+        LogicalPlan::NodeScan {
+            src,
+            slot: node,
+            labels,
+        } if **src == LogicalPlan::Argument && counted.is_none_or(|c| c == *node) => {
+            CountOf::Nodes { label: *labels }
+        }
+        // Undirected, each rel would be counted from both ends
+        LogicalPlan::Expand {
+            src,
+            src_slot,
+            rel_slot,
+            dst_slot,
+            rel_type,
+            dir: Some(_),
+            predicate: None,
+        } if src_slot != dst_slot
+            && counted.is_none_or(|c| [*src_slot, *rel_slot, *dst_slot].contains(&c)) =>
+        {
+            match &**src {
+                LogicalPlan::NodeScan {
+                    src,
+                    slot,
+                    labels: None,
+                } if **src == LogicalPlan::Argument && slot == src_slot => CountOf::Rels {
+                    rel_type: *rel_type,
+                },
+                _ => return plan,
+            }
+        }
+        _ => return plan,
+    };
+    LogicalPlan::CountStore {
+        src: Box::new(LogicalPlan::Argument),
+        slot,
+        count,
+    }
+}
+
+// Drop projections nothing after them reads, like the b in WITH n.a AS a, n.b AS b RETURN a.
+// needed is the slots that operators above this plan read.
+fn prune_projections(plan: LogicalPlan, mut needed: HashSet<Slot>) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Project {
+            src,
+            mut projections,
+        } => {
+            // Projections write their slots one at a time, so keep any that a later one reads
+            let mut read = Vec::new();
+            for p in &projections {
+                p.expr.collect_slots(&mut read);
+            }
+            projections.retain(|p| needed.contains(&p.dst) || read.contains(&p.dst));
+            for p in &projections {
+                needed.remove(&p.dst);
+            }
+            for p in &mut projections {
+                p.expr.slots_mut(&mut |slot| {
+                    needed.insert(*slot);
+                });
+            }
+            let src = prune_projections(*src, needed);
+            if projections.is_empty() {
+                src
+            } else {
+                LogicalPlan::Project {
+                    src: Box::new(src),
+                    projections,
+                }
+            }
+        }
+        // Only the grouping keys and aggregates make it past an aggregation
+        LogicalPlan::Aggregate { .. } => {
+            let mut plan = plan;
+            let mut needed = HashSet::new();
+            plan.slots_mut(&mut |slot| {
+                needed.insert(*slot);
+            });
+            plan.map_children(|src| prune_projections(src, needed.clone()))
+        }
+        // The inner side of these reads the row the outer side produced, so whatever the inner
+        // side refers to is needed from the outer side as well
+        LogicalPlan::NestLoop {
+            outer,
+            inner,
+            mut predicate,
+        } => {
+            predicate.slots_mut(&mut |slot| {
+                needed.insert(*slot);
+            });
+            let inner = uses(prune_projections(*inner, needed.clone()), &mut needed);
+            LogicalPlan::NestLoop {
+                outer: Box::new(prune_projections(*outer, needed)),
+                inner: Box::new(inner),
+                predicate,
+            }
+        }
+        LogicalPlan::ConditionalApply { src, probe } => {
+            let probe = uses(prune_projections(*probe, needed.clone()), &mut needed);
+            LogicalPlan::ConditionalApply {
+                src: Box::new(prune_projections(*src, needed)),
+                probe: Box::new(probe),
+            }
+        }
+        LogicalPlan::AntiConditionalApply { src, probe } => {
+            let probe = uses(prune_projections(*probe, needed.clone()), &mut needed);
+            LogicalPlan::AntiConditionalApply {
+                src: Box::new(prune_projections(*src, needed)),
+                probe: Box::new(probe),
+            }
+        }
+        // Everything else may read any slot it refers to
+        mut plan => {
+            plan.slots_mut(&mut |slot| {
+                needed.insert(*slot);
+            });
+            plan.map_children(|src| prune_projections(src, needed.clone()))
+        }
+    }
+}
+
+// Add every slot plan refers to, anywhere in it, to slots
+fn uses(mut plan: LogicalPlan, slots: &mut HashSet<Slot>) -> LogicalPlan {
+    fn visit(plan: &mut LogicalPlan, slots: &mut HashSet<Slot>) {
+        plan.slots_mut(&mut |slot| {
+            slots.insert(*slot);
+        });
+        for c in plan.children_mut() {
+            visit(c, slots)
+        }
+    }
+    visit(&mut plan, slots);
+    plan
+}
+
+// Renumber the slots the plan uses so there are no gaps between them, keeping rows as narrow as
+// they can be; identifiers get slots as they are planned, and some of those slots end up unused,
+// like those of pruned projections. Slots are numbered in the order the plan first writes them.
+fn compact_slots(pc: &mut PlanningContext, mut plan: LogicalPlan) -> LogicalPlan {
+    fn renumber(plan: &mut LogicalPlan, numbering: &mut HashMap<Slot, Slot>) {
+        for c in plan.children_mut() {
+            renumber(c, numbering)
+        }
+        plan.slots_mut(&mut |slot| {
+            let next = numbering.len();
+            *slot = *numbering.entry(*slot).or_insert(next);
+        });
+    }
+    let mut numbering = HashMap::new();
+    renumber(&mut plan, &mut numbering);
+
+    // Keep identifiers pointing at their slots; the ones that aren't in the plan don't have one
+    // anymore
+    for slots in [&mut pc.slots, &mut pc.dropped] {
+        slots.retain(|_, slot| match numbering.get(slot) {
+            Some(new) => {
+                *slot = *new;
+                true
+            }
+            None => false,
+        });
+    }
+    plan
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Token;
+    use crate::frontend::tests::{plan, PlanArtifacts};
+    use crate::frontend::{CountOf, LogicalPlan};
+    use crate::Result;
+
+    fn count_store(plan: &LogicalPlan) -> Option<CountOf> {
+        match plan {
+            LogicalPlan::CountStore { count, .. } => Some(*count),
+            _ => plan.children().into_iter().find_map(count_store),
+        }
+    }
+
+    #[test]
+    fn plans_counts_from_the_count_store() -> Result<()> {
+        let mut p = plan("MATCH (n:Person) RETURN count(n)")?;
+        let person = p.tokenize("Person");
+        assert_eq!(
+            count_store(&p.plan),
+            Some(CountOf::Nodes {
+                label: Some(person)
+            })
+        );
+
+        let mut p = plan("MATCH ()-[r:KNOWS]->() RETURN count(*) AS c")?;
+        let knows = p.tokenize("KNOWS");
+        assert_eq!(
+            count_store(&p.plan),
+            Some(CountOf::Rels {
+                rel_type: Some(knows)
+            })
+        );
+
+        assert_eq!(
+            count_store(&plan("MATCH (n) RETURN count(*)")?.plan),
+            Some(CountOf::Nodes { label: None })
+        );
+        for q in &[
+            "MATCH (n:Person) WHERE n.age > 3 RETURN count(n)",
+            "MATCH (n:Person) RETURN n.name, count(n)",
+            "MATCH (n:Person) RETURN count(n.name)",
+            "MATCH (a)-[r]-(b) RETURN count(r)",
+            "MATCH (a:Person)-[r]->(b) RETURN count(r)",
+            "UNWIND [1, 2] AS x MATCH (n) RETURN count(n)",
+        ] {
+            assert_eq!(count_store(&plan(q)?.plan), None, "{}", q);
+        }
+        Ok(())
+    }
+
+    // Aliases of the projections anywhere in the plan
+    fn projected(p: &PlanArtifacts) -> Vec<String> {
+        fn visit(plan: &LogicalPlan, out: &mut Vec<Token>) {
+            if let LogicalPlan::Project { projections, .. } = plan {
+                out.extend(projections.iter().map(|p| p.alias));
+            }
+            plan.children().into_iter().for_each(|c| visit(c, out));
+        }
+        let mut aliases = Vec::new();
+        visit(&p.plan, &mut aliases);
+        let tokens = p.tokens.borrow();
+        let mut names: Vec<String> = aliases
+            .iter()
+            .map(|t| tokens.lookup(*t).unwrap().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn prunes_unread_projections_and_compacts_slots() -> Result<()> {
+        // Nothing reads b or the second n, so those go, and the row only needs n and a
+        let p = plan("MATCH (n) WITH n.a AS a, n.b AS b, n AS m RETURN a")?;
+        assert_eq!(projected(&p), vec!["a", "a"]);
+        assert_eq!(p.plan.row_width(), 2);
+
+        let p = plan("MATCH (n) WITH n.a AS a, n.b AS b WHERE b > 1 RETURN a")?;
+        assert_eq!(projected(&p), vec!["a", "a", "b"]);
+        assert_eq!(p.plan.row_width(), 3);
+
+        // What CREATE uses is read too
+        let p = plan("MATCH (n) WITH n.a AS a, n.b AS b CREATE (:A {a: a})")?;
+        assert_eq!(projected(&p), vec!["a"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+// Type inference for expressions. We mostly don't know what type a value will have until the
+// query runs, properties can be anything, but we often know enough to tell an expression is
+// nonsense, like adding a string to a node. Those are refused at plan time, so they don't get
+// to fail in confusing ways half way through executing a query.
+use super::{Expr, Op, PlanningContext, Result};
+use crate::{QueryError, Slot, Type};
+
+// Infer the type of an expression, failing if it's obviously ill-typed
+pub(super) fn type_of(pc: &PlanningContext, expr: &Expr) -> Result<Type> {
+    Ok(match expr {
+        Expr::Null => Type::Any,
+        Expr::Bool(_) => Type::Boolean,
+        Expr::Int(_) => Type::Integer,
+        Expr::Float(_) => Type::Float,
+        Expr::String(_) => Type::String,
+        Expr::Map(entries) => {
+            for e in entries {
+                type_of(pc, &e.val)?;
+            }
+            Type::Map
+        }
+        Expr::List(items) => {
+            let mut item_type = None;
+            for item in items {
+                let t = type_of(pc, item)?;
+                item_type = match item_type {
+                    None => Some(t),
+                    Some(prev) if prev == t => Some(prev),
+                    Some(_) => Some(Type::Any),
+                };
+            }
+            Type::List(Box::new(item_type.unwrap_or(Type::Any)))
+        }
+        Expr::Slot(slot) => slot_type(pc, *slot),
+        // We know the types of lifted literals, but not of parameters given by the user
+        Expr::Param(name) => pc.param_types.get(name).cloned().unwrap_or(Type::Any),
+        Expr::Prop(base, _) => {
+            let t = type_of(pc, base)?;
+            if !matches!(t, Type::Any | Type::Node | Type::Relationship | Type::Map) {
+                return mismatch("a node, relationship or map to look up properties on", &t);
+            }
+            // Properties can be anything
+            Type::Any
+        }
+        Expr::Subscript(base, key) => {
+            let key_type = type_of(pc, key)?;
+            match type_of(pc, base)? {
+                Type::List(item) => {
+                    if !compatible(&Type::Integer, &key_type) {
+                        return mismatch("an integer index", &key_type);
+                    }
+                    *item
+                }
+                Type::Node | Type::Relationship | Type::Map => {
+                    if !compatible(&Type::String, &key_type) {
+                        return mismatch("a string property key", &key_type);
+                    }
+                    Type::Any
+                }
+                Type::Any => Type::Any,
+                t => return mismatch("a node, relationship, map or list to subscript", &t),
+            }
+        }
+        Expr::And(terms) | Expr::Or(terms) => {
+            for term in terms {
+                let t = type_of(pc, term)?;
+                if !compatible(&Type::Boolean, &t) {
+                    return mismatch("a boolean", &t);
+                }
+            }
+            Type::Boolean
+        }
+        Expr::BinaryOp { left, right, op } => {
+            let l = type_of(pc, left)?;
+            let r = type_of(pc, right)?;
+            match op {
+                Op::Eq | Op::NotEq | Op::Gt | Op::Lt | Op::Gte | Op::Lte => Type::Boolean,
+                // + is also concatenation, of strings and lists
+                Op::Add => match (l, r) {
+                    (Type::Integer, Type::Integer) => Type::Integer,
+                    (Type::String, t) | (t, Type::String) if addable(&t) => Type::String,
+                    (Type::List(t), _) | (_, Type::List(t)) => Type::List(t),
+                    (l, r) if compatible(&Type::Number, &l) && compatible(&Type::Number, &r) => {
+                        arithmetic_result(&l, &r)
+                    }
+                    (l, r) => {
+                        let bad = if addable(&l) { r } else { l };
+                        return mismatch("a number, string or list to add", &bad);
+                    }
+                },
+                Op::Sub | Op::Mul | Op::Div => {
+                    for t in &[&l, &r] {
+                        if !compatible(&Type::Number, t) {
+                            return mismatch("a number", t);
+                        }
+                    }
+                    match op {
+                        Op::Div => Type::Number,
+                        _ => arithmetic_result(&l, &r),
+                    }
+                }
+            }
+        }
+        Expr::FuncCall { name, args } => {
+            let mut arg_types = Vec::with_capacity(args.len());
+            for arg in args {
+                arg_types.push(type_of(pc, arg)?);
+            }
+            match pc.backend_desc.functions.iter().find(|f| f.name == *name) {
+                Some(sig) => {
+                    // The arg count is checked when the call is parsed
+                    for ((_, expected), actual) in sig.args.iter().zip(&arg_types) {
+                        if !compatible(expected, actual) {
+                            return mismatch(&format!("{:?}", expected), actual);
+                        }
+                    }
+                    sig.returns.clone()
+                }
+                // Only NOT gets here, since it isn't spelled as a call
+                None => Type::Any,
+            }
+        }
+        Expr::HasLabel(slot, _) => {
+            let t = slot_type(pc, *slot);
+            if !compatible(&Type::Node, &t) {
+                return mismatch("a node", &t);
+            }
+            Type::Boolean
+        }
+        Expr::PatternPredicate(_) => Type::Boolean,
+    })
+}
+
+// The type of a variable, as far as we know it
+fn slot_type(pc: &PlanningContext, slot: Slot) -> Type {
+    pc.slots
+        .iter()
+        .find(|(_, s)| **s == slot)
+        .and_then(|(tok, _)| pc.var_types.get(tok))
+        .cloned()
+        .unwrap_or(Type::Any)
+}
+
+// Could a value of the actual type be used where the expected type is needed? Any goes both
+// ways, since it means we don't know until runtime
+pub(super) fn compatible(expected: &Type, actual: &Type) -> bool {
+    match (expected, actual) {
+        (Type::Any, _) | (_, Type::Any) => true,
+        (Type::Number, Type::Integer) | (Type::Number, Type::Float) => true,
+        (Type::Integer, Type::Number) | (Type::Float, Type::Number) => true,
+        (Type::List(e), Type::List(a)) => compatible(e, a),
+        (e, a) => e == a,
+    }
+}
+
+// Can this be added to a string?
+fn addable(t: &Type) -> bool {
+    matches!(
+        t,
+        Type::Any | Type::Number | Type::Integer | Type::Float | Type::String | Type::Boolean
+    )
+}
+
+fn arithmetic_result(l: &Type, r: &Type) -> Type {
+    match (l, r) {
+        (Type::Integer, Type::Integer) => Type::Integer,
+        (Type::Float, _) | (_, Type::Float) => Type::Float,
+        _ => Type::Number,
+    }
+}
+
+// WHERE and friends need something that is true or false
+pub(super) fn expect_boolean(pc: &PlanningContext, predicate: &Expr) -> Result<()> {
+    let t = type_of(pc, predicate)?;
+    if !compatible(&Type::Boolean, &t) {
+        return mismatch("a boolean predicate", &t);
+    }
+    Ok(())
+}
+
+fn mismatch<T>(expected: &str, actual: &Type) -> Result<T> {
+    bail!(QueryError::TypeError {
+        message: format!("Type mismatch: expected {} but was {:?}", expected, actual)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{BackendDesc, Tokens};
+    use crate::diagnostics::NoDiagnostics;
+    use crate::frontend::Frontend;
+    use crate::{error, ErrorKind};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn type_error(query: &str) -> bool {
+        let frontend = Frontend {
+            tokens: Rc::new(RefCell::new(Tokens::new())),
+            backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        };
+        match frontend.plan(query) {
+            Ok(_) => false,
+            Err(e) if error::kind(&e) == ErrorKind::TypeError => true,
+            Err(e) => panic!("expected a type error or no error, got {}", e),
+        }
+    }
+
+    #[test]
+    fn refuses_obviously_ill_typed_expressions() {
+        assert!(type_error("MATCH (n) WHERE n + 'x' RETURN n"));
+        assert!(type_error("MATCH (n)-[r]->() RETURN r - 1"));
+        assert!(type_error("WITH 'a' AS s RETURN s.name"));
+        assert!(type_error("WITH 1 AS n MATCH (n)-->(m) RETURN m"));
+        assert!(type_error("WITH 1 AS n MATCH (n:Person) RETURN n"));
+        assert!(type_error("MATCH (n) WHERE 1 AND n.x RETURN n"));
+        assert!(type_error("UNWIND [1, 2] AS x RETURN x.name"));
+
+        assert!(!type_error(
+            "MATCH (n) WHERE n.age > 2 RETURN n.name + '!', n.age - 1"
+        ));
+        assert!(!type_error(
+            "WITH 'a' + 1 AS s RETURN s + [1] AS l, -1.5 * 2"
+        ));
+        assert!(!type_error("MATCH (n) WITH n AS m RETURN m.name"));
+    }
+}
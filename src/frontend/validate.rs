@@ -0,0 +1,360 @@
+// Checks that run over the parsed query before it is planned, so mistakes in the query are
+// reported as such, pointing at where they are, rather than surfacing as odd plans or as failures
+// deep inside the planner.
+use super::{bare_variable, identifier, Pair, Result, Rule};
+use crate::QueryError;
+use std::collections::HashSet;
+
+// Variables that can be referred to at some point in the query
+type Scope = HashSet<String>;
+
+pub fn validate(query: Pair<Rule>) -> Result<()> {
+    check_pattern_predicates(query.clone())?;
+    let mut scope = Scope::new();
+    let mut stmts = query.into_inner().peekable();
+    while let Some(stmt) = stmts.next() {
+        match stmt.as_rule() {
+            Rule::match_stmt | Rule::create_stmt => {
+                let creating = stmt.as_rule() == Rule::create_stmt;
+                // Everything the patterns introduce is visible to the whole clause, including
+                // property maps in the pattern itself and the WHERE clause
+                for part in stmt.clone().into_inner() {
+                    if part.as_rule() == Rule::pattern {
+                        declare_pattern(&mut scope, part);
+                    }
+                }
+                for part in stmt.into_inner() {
+                    match part.as_rule() {
+                        Rule::pattern => check_pattern(&scope, part, creating)?,
+                        Rule::where_clause => check_expr(&scope, part)?,
+                        _ => (),
+                    }
+                }
+            }
+            Rule::unwind_stmt => {
+                let mut parts = stmt.into_inner();
+                check_expr(&scope, parts.next().expect("UNWIND must have a list"))?;
+                let alias = parts.next().expect("UNWIND must have an alias");
+                scope.insert(identifier(&alias).into_owned());
+            }
+            Rule::with_stmt | Rule::return_stmt => scope = check_projection(scope, stmt)?,
+            Rule::call_stmt => {
+                let span = stmt.as_span();
+                let mut yielded = false;
+                for part in stmt.into_inner() {
+                    match part.as_rule() {
+                        Rule::proc_name => (),
+                        Rule::yield_clause => {
+                            yielded = true;
+                            for item in part.into_inner() {
+                                let name = item.into_inner().last().expect("YIELD needs a name");
+                                scope.insert(identifier(&name).into_owned());
+                            }
+                        }
+                        _ => check_expr(&scope, part)?,
+                    }
+                }
+                // What a procedure outputs isn't known until planning, so it has to be spelled
+                // out if anything comes after the CALL
+                let last = matches!(stmts.peek().map(|s| s.as_rule()), None | Some(Rule::EOI));
+                if !yielded && !last {
+                    bail!(QueryError::semantic(
+                        "CALL inside a query needs YIELD to name the outputs it uses".to_string(),
+                        span
+                    ))
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// Check a WITH or RETURN, giving back the scope that follows it
+fn check_projection(before: Scope, stmt: Pair<Rule>) -> Result<Scope> {
+    let mut after = Scope::new();
+    for part in stmt.into_inner() {
+        match part.as_rule() {
+            Rule::projections => {
+                for projection in part.into_inner() {
+                    if projection.as_rule() == Rule::project_all {
+                        after.extend(before.iter().cloned());
+                        continue;
+                    }
+                    let mut items = projection.into_inner();
+                    let expr = items.next().expect("projections must have an expression");
+                    check_expr(&before, expr.clone())?;
+                    after.insert(match (items.next(), bare_variable(&expr)) {
+                        (Some(alias), _) | (None, Some(alias)) => identifier(&alias).into_owned(),
+                        (None, None) => expr.as_str().trim_end().to_string(),
+                    });
+                }
+            }
+            // WHERE and ORDER BY see both what was in scope before and what was projected
+            Rule::where_clause | Rule::order_clause => {
+                let both: Scope = before.union(&after).cloned().collect();
+                check_expr(&both, part)?
+            }
+            Rule::skip_clause | Rule::limit_clause => check_expr(&Scope::new(), part)?,
+            _ => (),
+        }
+    }
+    Ok(after)
+}
+
+fn declare_pattern(scope: &mut Scope, pattern: Pair<Rule>) {
+    for segment in pattern.into_inner() {
+        for part in segment.into_inner() {
+            if part.as_rule() == Rule::id {
+                scope.insert(identifier(&part).into_owned());
+            }
+        }
+    }
+}
+
+// Check the expressions in the property maps of a pattern
+fn check_pattern(scope: &Scope, pattern: Pair<Rule>, creating: bool) -> Result<()> {
+    for segment in pattern.into_inner() {
+        for part in segment.into_inner() {
+            match part.as_rule() {
+                Rule::map => check_expr(scope, part)?,
+                Rule::param if !creating => bail!(param_map_refused(&part)),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+// What a pattern with a parameter for its properties matches would depend on which keys the
+// parameter has, so those are only for creating things with
+fn param_map_refused(param: &Pair<Rule>) -> QueryError {
+    QueryError::semantic(
+        "Parameter maps can only be used in CREATE; match on their entries instead, like \
+         {name: $name}"
+            .to_string(),
+        param.as_span(),
+    )
+}
+
+fn check_expr(scope: &Scope, expr: Pair<Rule>) -> Result<()> {
+    match expr.as_rule() {
+        Rule::id => check_variable(scope, &expr),
+        Rule::prop_lookup => {
+            let base = expr
+                .into_inner()
+                .next()
+                .expect("lookups start with a variable");
+            check_variable(scope, &base)
+        }
+        Rule::param => Ok(()),
+        // Skip function names and map keys, only their arguments and values refer to variables
+        Rule::func_call | Rule::map_pair => {
+            for arg in expr.into_inner().skip(1) {
+                check_expr(scope, arg)?;
+            }
+            Ok(())
+        }
+        // Patterns in expressions can't introduce variables, so anything named has to exist
+        Rule::pattern_predicate => {
+            for segment in expr.into_inner() {
+                for part in segment.into_inner() {
+                    match part.as_rule() {
+                        Rule::id => check_variable(scope, &part)?,
+                        Rule::map => check_expr(scope, part)?,
+                        Rule::param => bail!(param_map_refused(&part)),
+                        _ => (),
+                    }
+                }
+            }
+            Ok(())
+        }
+        _ => {
+            for inner in expr.into_inner() {
+                check_expr(scope, inner)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Pattern predicates are planned as conditions rows have to meet, see plan_selection, so they
+// can only be the whole of a WHERE, or one of the conditions it ANDs together, maybe negated;
+// anywhere else they're refused here, rather than left for the backend to fail on
+fn check_pattern_predicates(pair: Pair<Rule>) -> Result<()> {
+    match pair.as_rule() {
+        Rule::where_clause => {
+            let expr = pair
+                .into_inner()
+                .next()
+                .expect("WHERE must have an expression");
+            // Parentheses around the whole of it make no difference
+            let top = innermost(expr);
+            let conjuncts: Vec<Pair<Rule>> = match top.as_rule() {
+                Rule::and_expr => top.into_inner().collect(),
+                _ => vec![top],
+            };
+            for conjunct in conjuncts {
+                if !is_condition(conjunct.clone()) {
+                    check_pattern_predicates(conjunct)?;
+                }
+            }
+            Ok(())
+        }
+        Rule::pattern_predicate => bail!(QueryError::semantic(
+            "Patterns can only be used as WHERE conditions, or ANDed together with others"
+                .to_string(),
+            pair.as_span()
+        )),
+        _ => {
+            for inner in pair.into_inner() {
+                check_pattern_predicates(inner)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Is this a pattern predicate, or the negation of one?
+fn is_condition(conjunct: Pair<Rule>) -> bool {
+    let term = innermost(conjunct);
+    match term.as_rule() {
+        Rule::pattern_predicate => true,
+        Rule::not_expr => term
+            .into_inner()
+            .find(|p| p.as_rule() != Rule::not_kw)
+            .is_some_and(|negated| innermost(negated).as_rule() == Rule::pattern_predicate),
+        _ => false,
+    }
+}
+
+// Skip past expressions that are just one other expression, like a term in parentheses
+fn innermost(mut pair: Pair<Rule>) -> Pair<Rule> {
+    loop {
+        match pair.as_rule() {
+            Rule::expr | Rule::and_expr | Rule::add_sub_expr | Rule::mult_div_expr => {
+                let mut inner = pair.clone().into_inner();
+                match (inner.next(), inner.next()) {
+                    (Some(only), None) => pair = only,
+                    _ => return pair,
+                }
+            }
+            _ => return pair,
+        }
+    }
+}
+
+fn check_variable(scope: &Scope, id: &Pair<Rule>) -> Result<()> {
+    let name = identifier(id);
+    if !scope.contains(name.as_ref()) {
+        bail!(QueryError::semantic(
+            format!("Variable `{}` not defined", name),
+            id.as_span()
+        ))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{BackendDesc, Tokens};
+    use crate::diagnostics::NoDiagnostics;
+    use crate::frontend::Frontend;
+    use crate::QueryError;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn undefined(query: &str) -> Option<(String, usize)> {
+        let frontend = Frontend {
+            tokens: Rc::new(RefCell::new(Tokens::new())),
+            backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        };
+        let err = frontend.plan(query).err()?;
+        match err.downcast_ref::<QueryError>() {
+            Some(QueryError::SemanticError {
+                message,
+                span: Some(span),
+            }) => Some((message.clone(), span.column)),
+            _ => panic!("expected a semantic error, got {}", err),
+        }
+    }
+
+    #[test]
+    fn reports_undefined_variables() {
+        assert_eq!(
+            undefined("MATCH (n) RETURN m"),
+            Some(("Variable `m` not defined".to_string(), 18))
+        );
+        assert_eq!(
+            undefined("MATCH (n) WHERE x.name = 'a' RETURN n"),
+            Some(("Variable `x` not defined".to_string(), 17))
+        );
+        assert!(undefined("MATCH (n) WITH n.name AS name RETURN n").is_some());
+        assert!(undefined("MATCH (n) WITH n AS m RETURN n").is_some());
+        assert!(undefined("MATCH (a) WHERE (a)-->(b) RETURN a").is_some());
+        assert!(undefined("UNWIND xs AS x RETURN x").is_some());
+
+        assert_eq!(
+            undefined("MATCH (n)-[r]->(m {name: n.name}) RETURN r, m"),
+            None
+        );
+        assert_eq!(
+            undefined("MATCH (n) RETURN n.name AS x ORDER BY n.age"),
+            None
+        );
+        assert_eq!(
+            undefined("MATCH (n) WITH * WHERE n.x = 1 RETURN count(*)"),
+            None
+        );
+        assert_eq!(
+            undefined("UNWIND [1] AS `x y` WITH `x y` RETURN `x y` + 1"),
+            None
+        );
+    }
+
+    #[test]
+    fn refuses_patterns_outside_where_conditions() {
+        let refused =
+            "Patterns can only be used as WHERE conditions, or ANDed together with others";
+        assert_eq!(
+            undefined("MATCH (a) WHERE (a)-->() OR a.name = 'b' RETURN a"),
+            Some((refused.to_string(), 17))
+        );
+        assert_eq!(
+            undefined("MATCH (a) RETURN (a)-->()"),
+            Some((refused.to_string(), 18))
+        );
+        assert!(undefined("MATCH (a) WHERE NOT NOT (a)-->() RETURN a").is_some());
+        assert!(undefined("MATCH (a) WITH a WHERE (a)-->() = true RETURN a").is_some());
+
+        assert_eq!(undefined("MATCH (a) WHERE (a)-->() RETURN a"), None);
+        assert_eq!(
+            undefined("MATCH (a) WHERE a.x = 1 AND NOT ((a)-->()) RETURN a"),
+            None
+        );
+        assert_eq!(
+            undefined("MATCH (a) WITH a WHERE ((a)-->() AND a.x = 1) RETURN a"),
+            None
+        );
+    }
+
+    #[test]
+    fn refuses_parameter_maps_outside_create() {
+        let refused = "Parameter maps can only be used in CREATE; match on their entries instead, \
+                       like {name: $name}";
+        assert_eq!(
+            undefined("MATCH (n:User $props) RETURN n"),
+            Some((refused.to_string(), 15))
+        );
+        assert_eq!(
+            undefined("MATCH (a)-[r $props]->(b) RETURN r"),
+            Some((refused.to_string(), 14))
+        );
+        assert!(undefined("MATCH (a) WHERE (a)-->({name: 1}) RETURN a").is_none());
+        assert!(undefined("MATCH (a) WHERE (a)-->($props) RETURN a").is_some());
+        assert_eq!(undefined("CREATE (n:User $props)"), None);
+        assert_eq!(undefined("MATCH (n:User {name: $name}) RETURN n"), None);
+    }
+}
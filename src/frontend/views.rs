@@ -0,0 +1,232 @@
+//
+// Views are named queries that other queries can CALL like procedures:
+//
+//   define_view("adults", "MATCH (n:Person) WHERE n.age >= $age RETURN n")
+//   MATCH (c:City) CALL adults(18) YIELD n RETURN c, n
+//
+// The $parameters of a view are its arguments, in the order they first appear in its query,
+// and the columns it returns are what it yields.
+//
+// Views aren't run as procedures; CALL expands them into the calling query when it's planned,
+// so they get planned and optimized along with the rest of it. The view is planned on its own
+// Argument, nested in an apply against the rows of the calling query, so it runs once per row
+// like a procedure would; an aggregating view aggregates per calling row, not over all of them.
+//
+use super::{
+    call_stmt, parse, plan_expr, plan_statements, validate, Expr, Frontend, LogicalPlan,
+    PlanningContext, Result, Rule,
+};
+use crate::backend::Token;
+use crate::{QueryError, Slot, Type};
+use pest::iterators::Pairs;
+use pest::Span;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
+pub struct View {
+    pub query: String,
+    // The arguments of the view; see the top of this file
+    pub params: Vec<Token>,
+}
+
+impl Frontend {
+    // Make a query callable by name from other queries, replacing any view by that name. The
+    // query is planned to check it, so this fails if it couldn't be run.
+    pub fn define_view(&mut self, name: &str, query_str: &str) -> Result<()> {
+        let name_tok = self.tokens.borrow_mut().tokenize(name);
+        if self.backend_desc.procedure(name_tok).is_some() {
+            bail!("there is already a procedure called `{}`", name)
+        }
+        let query = parse(query_str)?;
+        validate::validate(query.clone())?;
+        let last = query
+            .clone()
+            .into_inner()
+            .filter(|stmt| stmt.as_rule() != Rule::EOI)
+            .last();
+        if !matches!(last, Some(stmt) if stmt.as_rule() == Rule::return_stmt) {
+            bail!("view `{}` needs to end with RETURN", name)
+        }
+
+        let mut params = Vec::new();
+        for param in query.clone().into_inner().flatten() {
+            if param.as_rule() == Rule::param {
+                let id = param.into_inner().next().expect("parameters have names");
+                let tok = self.tokens.borrow_mut().tokenize(&super::identifier(&id));
+                if !params.contains(&tok) {
+                    params.push(tok);
+                }
+            }
+        }
+
+        let mut pc = PlanningContext::new(Rc::clone(&self.tokens), &self.backend_desc);
+        pc.views = Rc::clone(&self.views);
+        pc.expanding.push(name_tok);
+        plan_statements(&mut pc, query)?;
+
+        let view = View {
+            query: query_str.to_string(),
+            params,
+        };
+        Rc::make_mut(&mut self.views).insert(name_tok, view);
+        Ok(())
+    }
+}
+
+// The scope of the calling query, put aside while a view is planned
+struct Outer {
+    slots: HashMap<Token, Slot>,
+    dropped: HashMap<Token, Slot>,
+    named_identifiers: Vec<Token>,
+    var_types: HashMap<Token, Type>,
+    literal_params: HashMap<usize, Token>,
+    view_args: HashMap<Token, Expr>,
+    pinned: HashSet<Slot>,
+}
+
+// Plan CALL <view>(args) YIELD ..; parts are what follows the name in the CALL
+pub fn plan_view(
+    pc: &mut PlanningContext,
+    src: LogicalPlan,
+    name: Token,
+    name_span: Span,
+    parts: Pairs<Rule>,
+) -> Result<(LogicalPlan, Vec<(Token, Slot)>)> {
+    let view = pc.views[&name].clone();
+    let name_str = name_span.as_str();
+    if pc.expanding.contains(&name) {
+        bail!(QueryError::semantic(
+            format!("view `{}` ends up calling itself", name_str),
+            name_span
+        ))
+    }
+
+    let mut args = Vec::new();
+    let mut yields = None;
+    for part in parts {
+        match part.as_rule() {
+            Rule::yield_clause => yields = Some(part),
+            _ => args.push(plan_expr(pc, part)?),
+        }
+    }
+    if args.len() != view.params.len() {
+        bail!(QueryError::semantic(
+            format!(
+                "`{}` takes {} arguments, but was given {}",
+                name_str,
+                view.params.len(),
+                args.len()
+            ),
+            name_span
+        ))
+    }
+
+    // The view sees none of the variables of the query, only its own arguments. Those may refer
+    // to slots of the query, which is why the view mustn't reuse any of them.
+    let outer = Outer {
+        slots: std::mem::take(&mut pc.slots),
+        dropped: std::mem::take(&mut pc.dropped),
+        named_identifiers: std::mem::take(&mut pc.named_identifiers),
+        var_types: std::mem::take(&mut pc.var_types),
+        // These are by position in the query text, which means nothing in the view
+        literal_params: std::mem::take(&mut pc.literal_params),
+        view_args: std::mem::replace(
+            &mut pc.view_args,
+            view.params.iter().copied().zip(args).collect(),
+        ),
+        pinned: pc.pinned.clone(),
+    };
+    pc.pinned.extend(outer.slots.values().copied());
+    pc.expanding.push(name);
+    let body = parse(&view.query).and_then(|query| plan_statements(pc, query));
+    pc.expanding.pop();
+    pc.slots = outer.slots;
+    pc.dropped = outer.dropped;
+    pc.named_identifiers = outer.named_identifiers;
+    pc.var_types = outer.var_types;
+    pc.literal_params = outer.literal_params;
+    pc.view_args = outer.view_args;
+    pc.pinned = outer.pinned;
+    // Operators in the view may read their slots while later operators write theirs, so none of
+    // those can be handed out again
+    pc.free_slots.clear();
+
+    let (body, fields) = match body? {
+        LogicalPlan::ProduceResult { src, fields } => (*src, fields),
+        other => unreachable!("views end with RETURN, got {:?}", other),
+    };
+    let outputs: Vec<(Token, Type)> = fields.iter().map(|(tok, _)| (*tok, Type::Any)).collect();
+    let mut columns = Vec::with_capacity(fields.len());
+    for (output, alias, _) in call_stmt::plan_yields(pc, name_str, yields, &outputs)? {
+        let slot = fields.iter().find(|(tok, _)| *tok == output).unwrap().1;
+        pc.declare_tok(alias);
+        pc.var_types.remove(&alias);
+        pc.slots.insert(alias, slot);
+        columns.push((alias, slot));
+    }
+
+    Ok((
+        LogicalPlan::NestLoop {
+            outer: Box::new(src),
+            inner: Box::new(body),
+            predicate: Expr::Bool(true),
+        },
+        columns,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{BackendDesc, Tokens};
+    use crate::diagnostics::NoDiagnostics;
+    use crate::frontend::{Expr, Frontend, LogicalPlan, Op};
+    use crate::Result;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn expands_views_into_the_calling_query() -> Result<()> {
+        let mut frontend = Frontend {
+            tokens: Rc::new(RefCell::new(Tokens::new())),
+            backend_desc: BackendDesc::new(vec![]),
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
+        };
+        frontend.define_view("v", "MATCH (n) WHERE n.x = $x RETURN n AS m")?;
+        let plan = frontend.plan("MATCH (n) CALL v(n) YIELD m RETURN n, m")?;
+
+        // Down past the projections RETURN and the view's RETURN add
+        fn unproject(plan: LogicalPlan) -> LogicalPlan {
+            match plan {
+                LogicalPlan::ProduceResult { src, .. } | LogicalPlan::Project { src, .. } => {
+                    unproject(*src)
+                }
+                other => other,
+            }
+        }
+        let scanned = |plan: LogicalPlan| match plan {
+            LogicalPlan::NodeScan { slot, .. } => slot,
+            other => panic!("expected a scan, got {:?}", other),
+        };
+        let (outer, inner) = match unproject(plan) {
+            LogicalPlan::NestLoop { outer, inner, .. } => (outer, inner),
+            other => panic!("expected the view in a NestLoop, got {:?}", other),
+        };
+        let outer_n = scanned(*outer);
+        // The view's n is a different variable, with a slot of its own; its $x is the outer n
+        match unproject(*inner) {
+            LogicalPlan::Selection { src, predicate } => {
+                assert_ne!(scanned(*src), outer_n);
+                match predicate {
+                    Expr::BinaryOp {
+                        right, op: Op::Eq, ..
+                    } => assert_eq!(*right, Expr::Slot(outer_n)),
+                    other => panic!("expected an equality, got {:?}", other),
+                }
+            }
+            other => panic!("expected a selection, got {:?}", other),
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,10 @@
-use super::{plan_expr, Expr, LogicalPlan, Pair, PlanningContext, Projection, Result, Rule};
+use super::match_stmt::plan_selection;
+use super::{
+    bare_variable, identifier, plan_expr, types, Expr, LogicalPlan, Pair, PlanningContext,
+    Projection, Result, Rule, SortKey,
+};
+use crate::backend::Token;
+use crate::{QueryError, Type};
 use pest::iterators::Pairs;
 
 pub fn plan_with(
@@ -8,7 +14,12 @@ pub fn plan_with(
 ) -> Result<LogicalPlan> {
     let parts = stmt.into_inner();
     let projections: Projections = parse_projections(pc, parts)?;
-    return plan_parsed_with(pc, src, projections);
+    // Aggregating and sorting both consume all their input before producing any rows
+    let materializes =
+        projections.is_aggregating || projections.is_distinct || projections.sort.is_some();
+    let plan = plan_parsed_with(pc, src, projections)?;
+    pc.end_scope(materializes);
+    Ok(plan)
 }
 
 // This is shared between the RETURN and WITH plan functions
@@ -28,10 +39,7 @@ fn plan_parsed_with(
     };
 
     if let Some(e) = projections.selection {
-        plan = LogicalPlan::Selection {
-            src: Box::new(plan),
-            predicate: e,
-        };
+        plan = plan_selection(pc, plan, e)?;
     }
 
     // TODO: The plan nodes should somehow track metadata about what they promise wrt order
@@ -119,7 +127,7 @@ struct Projections {
     // Does the projection include explicit aggregating expressions, more than just "DISTINCT"?
     is_aggregating: bool,
     selection: Option<Expr>,
-    sort: Option<Vec<Expr>>,
+    sort: Option<Vec<SortKey>>,
     skip: Option<Expr>,
     limit: Option<Expr>,
 }
@@ -137,39 +145,56 @@ fn parse_projections(pc: &mut PlanningContext, parts: Pairs<Rule>) -> Result<Pro
             Rule::distinct_clause => {
                 is_distinct = true;
             }
-            // WITH a as b, count(c); aka normal explicit projection
+            // WITH a as b, count(c), or WITH *, count(c) AS n
             Rule::projections => {
                 // This projection clears out all named identifiers that existed previously;
                 // what we need here is scopes, but for now we're doing the bare minimum to pass
                 // the TCK..
-                pc.named_identifiers.clear();
+                let mut in_scope = std::mem::take(&mut pc.named_identifiers);
 
+                // The expressions are all planned before any alias is bound, since they see
+                // the identifiers from before this projection, even ones it re-binds
+                let mut planned = Vec::new();
                 for projection in part.into_inner() {
-                    let p = parse_projection(pc, projection)?;
+                    if projection.as_rule() == Rule::project_all {
+                        // * keeps everything in scope; openCypher lists those columns by name,
+                        // ahead of any projections that follow the *
+                        let tokens = pc.tokens.borrow();
+                        in_scope.sort_by(|a, b| tokens.lookup(*a).cmp(&tokens.lookup(*b)));
+                        drop(tokens);
+                        for id in &in_scope {
+                            let slot = pc.get_or_alloc_slot(*id);
+                            pc.declare_tok(*id);
+                            let id_type = types::type_of(pc, &Expr::Slot(slot))?;
+                            planned.push((Expr::Slot(slot), *id, id_type));
+                        }
+                        continue;
+                    }
+                    let span = projection.as_span();
+                    let (expr, alias, expr_type) = parse_projection(pc, projection)?;
+                    if planned.iter().any(|(_, existing, _)| *existing == alias) {
+                        let tokens = pc.tokens.borrow();
+                        let message = format!(
+                            "multiple result columns are called `{}`, use AS to give them different names",
+                            tokens.lookup(alias).unwrap_or("?")
+                        );
+                        bail!(QueryError::semantic(message, span))
+                    }
                     is_aggregating =
-                        is_aggregating || p.expr.is_aggregating(&pc.backend_desc.aggregates);
-                    projections.push(p);
+                        is_aggregating || expr.is_aggregating(&pc.backend_desc.aggregates);
+                    planned.push((expr, alias, expr_type));
                 }
-            }
-            // WITH *
-            Rule::project_all => {
-                for id in &pc.named_identifiers {
-                    projections.push(Projection {
-                        expr: Expr::Slot(*pc.slots.get(id).unwrap()),
-                        alias: *id,
-                        // TODO note that this adds a bunch of unecessary copying in all RETURN clauses and
-                        //      in cases where we use projections that just rename stuff (eg. WITH blah as
-                        //      x); we should consider making expr in Projection Optional, so it can be
-                        //      used for pure renaming, if benchmarking shows that's helpful.
-                        dst: *pc.slots.get(id).unwrap(),
-                    });
+
+                // An alias that names something else than it did before the projection gets a
+                // slot of its own, so writing it can't clobber what other projections read
+                for (expr, alias, expr_type) in planned {
+                    let dst = match pc.slots.get(&alias) {
+                        Some(slot) if expr == Expr::Slot(*slot) => *slot,
+                        _ => pc.alloc_slot(alias),
+                    };
+                    pc.var_types.insert(alias, expr_type);
+                    projections.push(Projection { expr, alias, dst });
                 }
-                let tokens = pc.tokens.borrow();
-                projections.sort_by(|a, b| {
-                    let a_str = tokens.lookup(a.alias);
-                    let b_str = tokens.lookup(b.alias);
-                    a_str.cmp(&b_str)
-                });
             }
             Rule::where_clause => {
                 let where_expr = part
@@ -179,34 +204,36 @@ fn parse_projections(pc: &mut PlanningContext, parts: Pairs<Rule>) -> Result<Pro
                 selection = Some(plan_expr(pc, where_expr)?);
             }
             Rule::skip_clause => {
-                let skip_expr = part
-                    .into_inner()
-                    .next()
-                    .ok_or(anyhow!("SKIP contained unexpected part"))?;
-                skip = Some(plan_expr(pc, skip_expr)?);
+                skip = Some(plan_row_count(pc, "SKIP", part)?);
             }
             Rule::limit_clause => {
-                let limit_expr = part
-                    .into_inner()
-                    .next()
-                    .ok_or(anyhow!("LIMIT contained unexpected part"))?;
-                limit = Some(plan_expr(pc, limit_expr)?);
+                limit = Some(plan_row_count(pc, "LIMIT", part)?);
             }
             Rule::order_clause => {
-                // Skip this for now; we don't support ORDER BY, but there are
-                // TCK specs that use ORDER BY where just ignoring it still passes the test
                 let mut out = Vec::new();
                 for sort_group in part.into_inner() {
-                    let sort_expr = sort_group
-                        .into_inner()
+                    let mut sort_parts = sort_group.into_inner();
+                    let sort_expr = sort_parts
                         .next()
                         .ok_or(anyhow!("SORT contained unexpected part"))?;
-                    let planned_expr = plan_expr(pc, sort_expr)?;
+                    let mut planned_expr = plan_expr(pc, sort_expr)?;
                     if is_aggregating || is_distinct {
-                        out.push(sort_expr_for_aggregation(&projections, planned_expr)?)
-                    } else {
-                        out.push(planned_expr);
+                        planned_expr = sort_expr_for_aggregation(&projections, planned_expr)?;
+                    }
+                    let mut descending = false;
+                    let mut nulls_first = None;
+                    for modifier in sort_parts {
+                        match modifier.as_rule() {
+                            Rule::sort_asc => descending = false,
+                            Rule::sort_desc => descending = true,
+                            Rule::nulls_order => {
+                                let placement = modifier.into_inner().next();
+                                nulls_first = placement.map(|p| p.as_rule() == Rule::nulls_first)
+                            }
+                            _ => bail!("unexpected part of ORDER BY: {:?}", modifier),
+                        }
                     }
+                    out.push(SortKey::new(planned_expr, descending, nulls_first));
                 }
                 sort = Some(out);
             }
@@ -224,6 +251,36 @@ fn parse_projections(pc: &mut PlanningContext, parts: Pairs<Rule>) -> Result<Pro
     })
 }
 
+// Plan the row count of a SKIP or LIMIT. It's evaluated once, before any rows are produced, so
+// it can't refer to variables; it's usually a $parameter, which we can only check is a
+// non-negative integer when the query runs
+fn plan_row_count(pc: &mut PlanningContext, clause: &str, part: Pair<Rule>) -> Result<Expr> {
+    let count_item = part
+        .into_inner()
+        .next()
+        .ok_or(anyhow!("{} contained unexpected part", clause))?;
+    let span = count_item.as_span();
+    let count = plan_expr(pc, count_item)?;
+    let mut slots = Vec::new();
+    if !count.collect_slots(&mut slots) || !slots.is_empty() {
+        bail!(QueryError::semantic(
+            format!(
+                "{} can't refer to variables, it needs to be known up front",
+                clause
+            ),
+            span
+        ))
+    }
+    let count_type = types::type_of(pc, &count)?;
+    if !types::compatible(&Type::Integer, &count_type) {
+        bail!(QueryError::semantic(
+            format!("{} expects an integer, got {:?}", clause, count_type),
+            span
+        ))
+    }
+    Ok(count)
+}
+
 // Sort expressions are a bit painful; they can't refer to stuff that was made out-of-scope
 // by the preceding WITH/RETURN projection, if the projection contains aggregation.
 fn sort_expr_for_aggregation(projections: &Vec<Projection>, e: Expr) -> Result<Expr> {
@@ -250,33 +307,48 @@ fn sort_expr_for_aggregation(projections: &Vec<Projection>, e: Expr) -> Result<E
     bail!("Can't sort by {:?}, because the planner can't tell if it is used in DISTINCT or aggregation, so is not sure if it is visible to the sorting step", e)
 }
 
-fn parse_projection(pc: &mut PlanningContext, projection: Pair<Rule>) -> Result<Projection> {
-    let default_alias = projection.as_str();
+// Plan a projection, giving back its expression, alias and the type of the expression; the
+// alias is declared, but not given a slot, see parse_projections
+fn parse_projection(
+    pc: &mut PlanningContext,
+    projection: Pair<Rule>,
+) -> Result<(Expr, Token, Type)> {
     let mut parts = projection.into_inner();
-    let expr = parts.next().map(|p| plan_expr(pc, p)).unwrap()?;
+    let expr_item = parts.next().unwrap();
+    let expr_span = expr_item.as_span();
+    // Unaliased projections are named by their text, except plain identifiers, which are named
+    // by what they refer to so `a b` can still be referred to as `a b` afterwards
+    let default_alias = match bare_variable(&expr_item) {
+        Some(id) => identifier(&id),
+        None => expr_item.as_str().trim_end().into(),
+    };
+    let expr = plan_expr(pc, expr_item)?;
+    let expr_type = types::type_of(pc, &expr)?;
     let alias = parts
         .next()
         .and_then(|p| match p.as_rule() {
-            Rule::id => Some(pc.declare(p.as_str())),
+            Rule::id => Some(pc.declare(&identifier(&p))),
             _ => None,
         })
-        .unwrap_or_else(|| pc.declare(default_alias.trim_end()));
-    Ok(Projection {
-        expr,
-        alias,
-        // TODO note that this adds a bunch of unecessary copying in all RETURN clauses and
-        //      in cases where we use projections that just rename stuff (eg. WITH blah as
-        //      x); we should consider making expr in Projection Optional, so it can be
-        //      used for pure renaming, if benchmarking shows that's helpful.
-        dst: pc.get_or_alloc_slot(alias),
-    })
+        .unwrap_or_else(|| {
+            let (start, end) = (expr_span.start(), expr_span.end());
+            if pc.literal_params.keys().any(|at| (start..end).contains(at)) {
+                pc.names_literals = true;
+            }
+            pc.declare(&default_alias)
+        });
+    // TODO note that this adds a bunch of unecessary copying in all RETURN clauses and
+    //      in cases where we use projections that just rename stuff (eg. WITH blah as
+    //      x); we should consider making expr in Projection Optional, so it can be
+    //      used for pure renaming, if benchmarking shows that's helpful.
+    Ok((expr, alias, expr_type))
 }
 
 #[cfg(test)]
 mod tests {
     use crate::frontend::tests::plan;
-    use crate::frontend::{Dir, Expr, LogicalPlan, Op, Projection};
-    use crate::Error;
+    use crate::frontend::{CountOf, Dir, Expr, LogicalPlan, Op, Projection, SortKey};
+    use crate::{error, Error, ErrorKind};
 
     #[test]
     fn plan_noop_with() -> Result<(), Error> {
@@ -342,7 +414,7 @@ mod tests {
                             dst: p.slot(id_p),
                         }],
                     }),
-                    sort_by: vec![Expr::Slot(p.slot(id_p))]
+                    sort_by: vec![SortKey::asc(Expr::Slot(p.slot(id_p)))]
                 }),
                 skip: Some(Expr::Int(1)),
                 limit: None,
@@ -351,6 +423,33 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn plan_with_order_direction_and_nulls() -> Result<(), Error> {
+        let mut p = plan(
+            "WITH 1 as a, 2 as b, 3 as c ORDER BY a DESC, b ASCENDING NULLS FIRST, c desc nulls last",
+        )?;
+
+        let (id_a, id_b, id_c) = (p.tokenize("a"), p.tokenize("b"), p.tokenize("c"));
+        let (slot_a, slot_b, slot_c) = (
+            Expr::Slot(p.slot(id_a)),
+            Expr::Slot(p.slot(id_b)),
+            Expr::Slot(p.slot(id_c)),
+        );
+        match &p.plan {
+            LogicalPlan::Sort { sort_by, .. } => assert_eq!(
+                sort_by,
+                &vec![
+                    SortKey::new(slot_a, true, None),
+                    SortKey::new(slot_b, false, Some(true)),
+                    SortKey::new(slot_c, true, Some(false)),
+                ]
+            ),
+            other => panic!("expected a sort, got {:?}", other),
+        }
+        assert!(plan("WITH 1 AS a ORDER BY a NULLS").is_err());
+        Ok(())
+    }
+
     // TODO technically the project is not needed here.. we're just renaming references..
     #[test]
     fn plan_with_limit() -> Result<(), Error> {
@@ -401,7 +500,7 @@ mod tests {
                         dst: p.slot(key_name),
                     }],
                 }),
-                sort_by: vec![Expr::Slot(p.slot(key_name))]
+                sort_by: vec![SortKey::asc(Expr::Slot(p.slot(key_name)))]
             }
         );
         Ok(())
@@ -446,7 +545,7 @@ mod tests {
                         }
                     ],
                 }),
-                sort_by: vec![Expr::Slot(p.slot(id_count_call))]
+                sort_by: vec![SortKey::asc(Expr::Slot(p.slot(id_count_call)))]
             }
         );
         Ok(())
@@ -485,7 +584,7 @@ mod tests {
                         dst: p.slot(key_name),
                     }],
                 }),
-                sort_by: vec![Expr::Slot(p.slot(key_name))]
+                sort_by: vec![SortKey::asc(Expr::Slot(p.slot(key_name)))]
             }
         );
         Ok(())
@@ -517,10 +616,10 @@ mod tests {
                         dst: p.slot(id_n),
                     }],
                 }),
-                sort_by: vec![Expr::Prop(
+                sort_by: vec![SortKey::asc(Expr::Prop(
                     Box::new(Expr::Slot(p.slot(id_n))),
                     vec![key_name]
-                )]
+                ))]
             }
         );
         Ok(())
@@ -586,6 +685,7 @@ mod tests {
                             dst_slot: p.slot(id_z),
                             rel_type: None,
                             dir: Some(Dir::Out),
+                            predicate: None,
                         }),
                         projections: vec![Projection {
                             expr: Expr::Slot(p.slot(id_a)),
@@ -605,6 +705,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn plan_with_drops_what_it_does_not_project() -> Result<(), Error> {
+        // z and the rel between a and z are gone after the WITH, so once the WITH has sorted
+        // all its rows b can have z's slot; the MATCH before a streaming WITH may still be
+        // reading it though
+        let mut p = plan("MATCH (a)-->(z) WITH a ORDER BY a MATCH (b) RETURN b")?;
+        let (id_b, id_z) = (p.tokenize("b"), p.tokenize("z"));
+        assert_eq!(p.slot(id_b), p.slot(id_z));
+        let mut p = plan("MATCH (a)-->(z) WITH a MATCH (b) RETURN b")?;
+        let (id_b, id_z) = (p.tokenize("b"), p.tokenize("z"));
+        assert_ne!(p.slot(id_b), p.slot(id_z));
+
+        let err = plan("MATCH (a)-->(z) WITH a RETURN z.name").unwrap_err();
+        assert_eq!(error::kind(&err), ErrorKind::SemanticError);
+        Ok(())
+    }
+
+    #[test]
+    fn plan_with_rebinding_aliases() -> Result<(), Error> {
+        // The swap has to read both a and b before writing either
+        let mut p = plan("MATCH (a), (b) WITH b AS a, a AS b")?;
+        let (id_a, id_b) = (p.tokenize("a"), p.tokenize("b"));
+        match &p.plan {
+            LogicalPlan::Project { projections, .. } => {
+                assert_eq!(projections[0].expr, Expr::Slot(1));
+                assert_eq!(projections[1].expr, Expr::Slot(0));
+                assert!(projections.iter().all(|p| p.dst > 1));
+            }
+            other => panic!("expected a projection, got {:?}", other),
+        }
+        assert_ne!(p.slot(id_a), p.slot(id_b));
+        Ok(())
+    }
+
     #[test]
     fn plan_return_star() -> Result<(), Error> {
         let mut p = plan("MATCH (n) RETURN *")?;
@@ -631,29 +765,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn plan_with_star_and_extra_projections() -> Result<(), Error> {
+        let mut p = plan("MATCH (z), (a) WITH *, z.name AS b RETURN *, 1 AS c")?;
+
+        let id_z = p.tokenize("z");
+        let id_a = p.tokenize("a");
+        let id_b = p.tokenize("b");
+        let id_c = p.tokenize("c");
+        let fields: Vec<_> = [id_a, id_b, id_z, id_c]
+            .iter()
+            .map(|id| (*id, p.slot(*id)))
+            .collect();
+        match &p.plan {
+            LogicalPlan::ProduceResult { fields: actual, .. } => assert_eq!(*actual, fields),
+            plan => panic!("expected a ProduceResult, got {:?}", plan),
+        }
+
+        assert!(plan("MATCH (a) WITH *, 1 AS a RETURN a").is_err());
+        assert!(plan("MATCH (a) RETURN a.x AS b, a.y AS b").is_err());
+        Ok(())
+    }
+
     #[test]
     fn plan_return_count() -> Result<(), Error> {
         let mut p = plan("MATCH (n) RETURN COUNT(*)")?;
         let alias = p.tokenize("COUNT(*)");
-        let fn_count = p.tokenize("count");
+        // Answered from the count store, see rewrite::count_from_store
         assert_eq!(
             p.plan,
             LogicalPlan::ProduceResult {
                 src: Box::new(LogicalPlan::Project {
-                    src: Box::new(LogicalPlan::Aggregate {
-                        src: Box::new(LogicalPlan::NodeScan {
-                            src: Box::new(LogicalPlan::Argument),
-                            slot: 0,
-                            labels: None,
-                        }),
-                        grouping: vec![],
-                        aggregations: vec![(
-                            Expr::FuncCall {
-                                name: fn_count,
-                                args: vec![]
-                            },
-                            p.slot(alias)
-                        )]
+                    src: Box::new(LogicalPlan::CountStore {
+                        src: Box::new(LogicalPlan::Argument),
+                        slot: p.slot(alias),
+                        count: CountOf::Nodes { label: None },
                     }),
                     projections: vec![Projection {
                         expr: Expr::Slot(p.slot(alias)),
@@ -706,27 +852,17 @@ mod tests {
         let mut p = plan("MATCH (n:Person) RETURN count(n)")?;
 
         let lbl_person = p.tokenize("Person");
-        let id_n = p.tokenize("n");
-        let fn_count = p.tokenize("count");
         let col_count_n = p.tokenize("count(n)");
         assert_eq!(
             p.plan,
             LogicalPlan::ProduceResult {
                 src: Box::new(LogicalPlan::Project {
-                    src: Box::new(LogicalPlan::Aggregate {
-                        src: Box::new(LogicalPlan::NodeScan {
-                            src: Box::new(LogicalPlan::Argument),
-                            slot: 0,
-                            labels: Some(lbl_person)
-                        }),
-                        grouping: vec![],
-                        aggregations: vec![(
-                            Expr::FuncCall {
-                                name: fn_count,
-                                args: vec![Expr::Slot(p.slot(id_n))]
-                            },
-                            p.slot(col_count_n)
-                        )]
+                    src: Box::new(LogicalPlan::CountStore {
+                        src: Box::new(LogicalPlan::Argument),
+                        slot: p.slot(col_count_n),
+                        count: CountOf::Nodes {
+                            label: Some(lbl_person)
+                        },
                     }),
                     projections: vec![Projection {
                         expr: Expr::Slot(p.slot(col_count_n)),
@@ -744,27 +880,15 @@ mod tests {
     fn plan_simple_count_no_label() -> Result<(), Error> {
         let mut p = plan("MATCH (n) RETURN count(n)")?;
 
-        let id_n = p.tokenize("n");
-        let fn_count = p.tokenize("count");
         let col_count_n = p.tokenize("count(n)");
         assert_eq!(
             p.plan,
             LogicalPlan::ProduceResult {
                 src: Box::new(LogicalPlan::Project {
-                    src: Box::new(LogicalPlan::Aggregate {
-                        src: Box::new(LogicalPlan::NodeScan {
-                            src: Box::new(LogicalPlan::Argument),
-                            slot: 0,
-                            labels: None
-                        }),
-                        grouping: vec![],
-                        aggregations: vec![(
-                            Expr::FuncCall {
-                                name: fn_count,
-                                args: vec![Expr::Slot(p.slot(id_n))]
-                            },
-                            p.slot(col_count_n)
-                        )]
+                    src: Box::new(LogicalPlan::CountStore {
+                        src: Box::new(LogicalPlan::Argument),
+                        slot: p.slot(col_count_n),
+                        count: CountOf::Nodes { label: None },
                     }),
                     projections: vec![Projection {
                         expr: Expr::Slot(p.slot(col_count_n)),
@@ -0,0 +1,261 @@
+//
+// Loading Cypher scripts, like the dumps neo4j-admin, cypher-shell and APOC export graphs as:
+// statements separated by semicolons, with cypher-shell commands like :begin and :commit on
+// lines of their own in between. This is how you bring a Neo4j dataset over.
+//
+// Note that only what gqlite supports can be imported; the constraint and cleanup statements
+// some exports start and end with are not, yet, so those need to be cut from the script.
+//
+use crate::backend::Backend;
+use crate::{Cursor, Database, Result};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Lines, Read};
+
+// How many statements are committed together, see Backend::begin_batch
+const BATCH_SIZE: usize = 1000;
+
+impl<T: Backend> Database<T> {
+    // Run every statement in the script, giving back how many there were. The script is read a
+    // statement at a time, so it doesn't need to fit in memory.
+    //
+    // Statements are committed in batches. If one fails, the import stops there with the
+    // statements before it committed, and the error tells the line the failed statement is on.
+    pub fn import_cypher(&mut self, script: impl Read) -> Result<usize> {
+        let mut cursor = self.new_cursor();
+        let mut imported = 0;
+        self.backend.begin_batch()?;
+        let result = self.import_statements(script, &mut cursor, &mut imported);
+        // Whatever was imported before a failure is kept, so the gram file agrees with the
+        // graph in memory, which isn't rolled back
+        self.backend.end_batch()?;
+        result.map(|_| imported)
+    }
+
+    fn import_statements(
+        &mut self,
+        script: impl Read,
+        cursor: &mut Cursor<T>,
+        imported: &mut usize,
+    ) -> Result<()> {
+        let mut batched = 0;
+        for stmt in Statements::new(script) {
+            let (line, stmt) = stmt?;
+            let ran = self.run(&stmt, cursor).and_then(|_| {
+                while cursor.next()?.is_some() {}
+                Ok(())
+            });
+            ran.map_err(|e| e.context(format!("failed to import the statement on line {}", line)))?;
+            *imported += 1;
+            batched += 1;
+            if batched == BATCH_SIZE {
+                self.backend.end_batch()?;
+                self.backend.begin_batch()?;
+                batched = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Splits a script into statements, each with the line it starts on
+struct Statements<R> {
+    lines: Lines<BufReader<R>>,
+    line: usize,
+    scan: Scan,
+    stmt: String,
+    stmt_line: usize,
+    ready: VecDeque<(usize, String)>,
+}
+
+// Where in the script we are; semicolons only end statements in Code
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scan {
+    Code,
+    // In a string or a quoted identifier, quoted with the given char
+    Quoted(char),
+    // Just after a backslash in a string
+    Escaped(char),
+    LineComment,
+    BlockComment,
+}
+
+impl<R: Read> Statements<R> {
+    fn new(script: R) -> Self {
+        Statements {
+            lines: BufReader::new(script).lines(),
+            line: 0,
+            scan: Scan::Code,
+            stmt: String::new(),
+            stmt_line: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn scan_line(&mut self, line: &str) {
+        // cypher-shell commands, which we have no use for
+        if self.scan == Scan::Code && self.stmt.trim().is_empty() && line.trim().starts_with(':') {
+            return;
+        }
+        let mut chars = line.chars().chain(Some('\n')).peekable();
+        while let Some(c) = chars.next() {
+            if self.stmt.trim().is_empty() {
+                self.stmt_line = self.line;
+            }
+            self.scan = match (self.scan, c) {
+                (Scan::Code, ';') => {
+                    self.end_statement();
+                    continue;
+                }
+                (Scan::Code, '\'') | (Scan::Code, '"') | (Scan::Code, '`') => Scan::Quoted(c),
+                (Scan::Code, '/') if chars.peek() == Some(&'/') => Scan::LineComment,
+                (Scan::Code, '/') if chars.peek() == Some(&'*') => {
+                    self.stmt.push(c);
+                    self.stmt.push(chars.next().unwrap());
+                    self.scan = Scan::BlockComment;
+                    continue;
+                }
+                // Backticks are escaped by doubling them, which works out by itself
+                (Scan::Quoted(q), '\\') if q != '`' => Scan::Escaped(q),
+                (Scan::Quoted(q), c) if c == q => Scan::Code,
+                (Scan::Escaped(q), _) => Scan::Quoted(q),
+                (Scan::LineComment, '\n') => Scan::Code,
+                (Scan::BlockComment, '*') if chars.peek() == Some(&'/') => {
+                    self.stmt.push(c);
+                    self.stmt.push(chars.next().unwrap());
+                    self.scan = Scan::Code;
+                    continue;
+                }
+                (scan, _) => scan,
+            };
+            self.stmt.push(c);
+        }
+    }
+
+    fn end_statement(&mut self) {
+        let stmt = std::mem::take(&mut self.stmt);
+        if !stmt.trim().is_empty() {
+            self.ready
+                .push_back((self.stmt_line, stmt.trim().to_string()));
+        }
+    }
+}
+
+impl<R: Read> Iterator for Statements<R> {
+    type Item = Result<(usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(stmt) = self.ready.pop_front() {
+                return Some(Ok(stmt));
+            }
+            match self.lines.next() {
+                Some(Ok(line)) => {
+                    self.line += 1;
+                    self.scan_line(&line);
+                }
+                Some(Err(e)) => return Some(Err(e.into())),
+                // The last statement doesn't need a semicolon
+                None => {
+                    self.end_statement();
+                    return self.ready.pop_front().map(Ok);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Statements;
+    use crate::Result;
+
+    #[test]
+    fn splits_scripts_into_statements() -> Result<()> {
+        let script = "
+:begin
+CREATE (:A {s: 'a;b', t: \"it\\\"s;\"}); CREATE (:`B;``C`)
+// a comment; with a semicolon
+;
+/* and
+   another; */ CREATE ();
+:commit
+";
+        let stmts = Statements::new(script.as_bytes()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            stmts,
+            vec![
+                (3, "CREATE (:A {s: 'a;b', t: \"it\\\"s;\"})".to_string()),
+                (
+                    3,
+                    "CREATE (:`B;``C`)\n// a comment; with a semicolon".to_string()
+                ),
+                (6, "/* and\n   another; */ CREATE ()".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "gram")]
+    #[test]
+    fn imports_cypher_dumps() -> Result<()> {
+        use crate::gramdb::GramDatabase;
+        use crate::{error, ErrorKind, Val};
+
+        let mut db = GramDatabase::in_memory()?;
+        let dump = "
+:begin
+CREATE (:Person:`UNIQUE IMPORT LABEL` {name:\"Alice\", `UNIQUE IMPORT ID`:0});
+CREATE (:Person:`UNIQUE IMPORT LABEL` {name:\"Bob\", `UNIQUE IMPORT ID`:1});
+:commit
+:begin
+MATCH (n1:`UNIQUE IMPORT LABEL`{`UNIQUE IMPORT ID`:0}), (n2:`UNIQUE IMPORT LABEL`{`UNIQUE IMPORT ID`:1}) CREATE (n1)-[r:KNOWS {since:2010}]->(n2);
+:commit
+";
+        assert_eq!(db.import_cypher(dump.as_bytes())?, 3);
+        let mut cursor = db.new_cursor();
+        db.run(
+            "MATCH (a:Person)-[r:KNOWS]->(b:Person) RETURN a.name, r.since, b.name",
+            &mut cursor,
+        )?;
+        assert_eq!(
+            cursor.next()?.unwrap().slots,
+            vec![
+                Val::String("Alice".into()),
+                Val::Int(2010),
+                Val::String("Bob".into())
+            ]
+        );
+
+        let err = db
+            .import_cypher("CREATE ();\n\nCREATE (a) CREATE (a:A);".as_bytes())
+            .unwrap_err();
+        assert!(format!("{}", err).contains("line 3"), "{}", err);
+        assert_ne!(error::kind(&err), ErrorKind::Other);
+        Ok(())
+    }
+
+    #[cfg(feature = "gram-file")]
+    #[test]
+    fn keeps_what_was_imported_before_a_failure() -> Result<()> {
+        use crate::gramdb::GramDatabase;
+        use crate::Val;
+        use std::io::{Seek, SeekFrom};
+
+        let mut file = tempfile::tempfile()?;
+        {
+            let mut db = GramDatabase::open(file.try_clone()?)?;
+            // The third statement overflows after creating its node, so its write is undone,
+            // and the fourth never runs
+            let script = "CREATE (:A); CREATE (:B);
+                CREATE (:C) WITH 1 AS x RETURN 9223372036854775807 + x;
+                CREATE (:D);";
+            assert!(db.import_cypher(script.as_bytes()).is_err());
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let mut db = GramDatabase::open(file)?;
+        let mut cursor = db.new_cursor();
+        db.run("MATCH (n) RETURN count(n)", &mut cursor)?;
+        assert_eq!(cursor.next()?.unwrap().slots, vec![Val::Int(2)]);
+        Ok(())
+    }
+}
@@ -5,28 +5,111 @@ extern crate pest_derive;
 extern crate anyhow;
 
 pub mod backend;
+pub mod collation;
+pub mod config;
+pub mod convert;
+pub mod diagnostics;
+pub mod dump;
+pub mod error;
+pub mod expiry;
+pub mod export;
 pub mod frontend;
+pub mod import;
+pub mod metrics;
+pub mod saved_plans;
+pub mod scheduler;
+pub mod session;
+pub mod statement;
+#[cfg(feature = "gram")]
+pub mod tck;
+pub mod testing;
 
 pub use anyhow::{Error, Result};
+pub use collation::{Collation, Collator};
+pub use config::{DatabaseConfig, Durability};
+pub use convert::FromRow;
+pub use error::{ErrorKind, QueryError};
+pub use session::Session;
+pub use statement::Statement;
 use std::fmt::{Debug, Display, Formatter};
 
-use backend::{Backend, BackendCursor};
+use backend::{Backend, BackendCursor, IndexHealth, Observed, Params};
 use core::fmt;
-use frontend::Frontend;
+use diagnostics::{DiagnosticsSink, NoDiagnostics};
+use frontend::fingerprint::fingerprint;
+use frontend::literals::{self, Shape};
+use frontend::{Completions, Frontend, LogicalPlan, OperatorEstimate, ParameterizedPlan};
+use metrics::{ExecutionStats, ExecutionSummary, Metrics, QuerySummary, Stopwatch};
+use scheduler::{QueryId, QueryInfo, QueryState, RunningQuery, Scheduler};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Index;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Database<T: Backend> {
     backend: T,
     frontend: Frontend,
+    metrics: Rc<RefCell<Metrics>>,
+    // Plans of recently run queries, see PlanKey
+    plan_cache: HashMap<PlanKey, CachedPlan>,
+    // How many plans we keep in the plan cache
+    plan_cache_size: usize,
+    // Shared with cursors, which let it know as their queries make progress
+    scheduler: Rc<RefCell<Scheduler>>,
+    read_only: bool,
+    // What operators were seen doing in profiled queries, by Statistics::feedback key; shared
+    // with cursors, which add to it as their queries finish
+    feedback: Rc<RefCell<HashMap<String, Observed>>>,
+    // See expiry.rs
+    sweep_on_read: bool,
+}
+
+// Plans are made with the literals of the query lifted out into parameters, so queries share a
+// plan if they normalize to the same text and their literals have the same shape
+type PlanKey = (u64, Vec<Shape>);
+
+#[derive(Debug)]
+struct CachedPlan {
+    // If columns are named after literals in the query text, the plan is only used for the
+    // exact query it was planned for
+    query: Option<String>,
+    plan: ParameterizedPlan,
 }
 
 impl<T: Backend> Database<T> {
     pub fn with_backend(backend: T) -> Result<Database<T>> {
+        Database::with_config(backend, &DatabaseConfig::default())
+    }
+
+    // A database on the given backend, with the parts of the config that aren't about how the
+    // backend was opened
+    pub fn with_config(mut backend: T, config: &DatabaseConfig) -> Result<Database<T>> {
+        backend.set_collation(&config.collation)?;
         let frontend = Frontend {
             tokens: backend.tokens(),
             backend_desc: backend.describe()?,
+            diagnostics: Box::new(NoDiagnostics),
+            views: Default::default(),
         };
-        Ok(Database { backend, frontend })
+        let mut scheduler = Scheduler::default();
+        scheduler.set_limits(config.max_running_queries, config.max_queued_queries);
+        backend.set_max_intermediate_rows(config.max_intermediate_rows);
+        Ok(Database {
+            backend,
+            frontend,
+            metrics: Default::default(),
+            plan_cache: HashMap::new(),
+            plan_cache_size: config.cache_size,
+            scheduler: Rc::new(RefCell::new(scheduler)),
+            read_only: config.read_only,
+            feedback: Default::default(),
+            sweep_on_read: false,
+        })
     }
 
     // TODO this is a side-effect, presumably, of me being bad at rust.
@@ -40,12 +123,506 @@ impl<T: Backend> Database<T> {
     //      follow!
     pub fn new_cursor(&mut self) -> Cursor<T> {
         let bc = self.backend.new_cursor();
-        Cursor { inner: bc }
+        Cursor {
+            inner: bc,
+            span: tracing::Span::none(),
+            metrics: Rc::clone(&self.metrics),
+            query: None,
+            scheduler: Rc::clone(&self.scheduler),
+            ticket: None,
+            pending: None,
+            profiled: false,
+            profile: None,
+            feedback: Rc::clone(&self.feedback),
+        }
     }
 
     pub fn run(&mut self, query_str: &str, cursor: &mut Cursor<T>) -> Result<()> {
-        let plan = self.frontend.plan(query_str)?;
-        self.backend.eval(plan, &mut cursor.inner)
+        self.run_with_params(query_str, &Vec::new(), cursor)
+    }
+
+    // Run a query that refers to $parameters, with the given values for them.
+    //
+    // If the database is already executing as many queries as it's allowed to, see
+    // set_query_limits, the query is queued rather than started; Cursor::state tells which, and
+    // admit starts a queued query once its turn has come.
+    pub fn run_with_params(
+        &mut self,
+        query_str: &str,
+        params: &Map,
+        cursor: &mut Cursor<T>,
+    ) -> Result<()> {
+        self.run_with_options(query_str, params, &RunOptions::default(), cursor)
+    }
+
+    // Run a query with $parameters like run_with_params, the way the options say; see RunOptions
+    pub fn run_with_options(
+        &mut self,
+        query_str: &str,
+        params: &Map,
+        options: &RunOptions,
+        cursor: &mut Cursor<T>,
+    ) -> Result<()> {
+        cursor.finish_query();
+        self.sweep_before_query()?;
+        // The query span lives in the cursor, since that's where the query is executed
+        cursor.span = tracing::debug_span!("query", query = query_str);
+        let span = cursor.span.clone();
+        let _enter = span.enter();
+
+        let planning_started = Stopwatch::start();
+        let (plan, params) = self.plan(query_str, params)?;
+        self.metrics
+            .borrow_mut()
+            .planning_time
+            .observe_duration(planning_started.elapsed());
+        self.submit(query_str, plan, params, options, cursor)
+    }
+
+    // Hand a planned query to the scheduler, to start executing it in the cursor as soon as
+    // there's room
+    fn submit(
+        &mut self,
+        query_str: &str,
+        plan: LogicalPlan,
+        params: Params,
+        options: &RunOptions,
+        cursor: &mut Cursor<T>,
+    ) -> Result<()> {
+        if self.read_only && plan.writes(&self.frontend.backend_desc) {
+            bail!(QueryError::ReadOnly)
+        }
+
+        let submitted = self.scheduler.borrow_mut().submit(query_str);
+        let id = match submitted {
+            Ok(id) => id,
+            Err(e) => {
+                self.metrics.borrow_mut().queries_rejected += 1;
+                return Err(e);
+            }
+        };
+        // The cursor may have been made by another database
+        cursor.scheduler = Rc::clone(&self.scheduler);
+        cursor.ticket = Some(id);
+        cursor.pending = Some((plan, params, *options));
+        if !self.admit(cursor)? {
+            self.metrics.borrow_mut().queries_queued += 1;
+        }
+        Ok(())
+    }
+
+    // Start the query queued in the cursor, if there's room for it now; tells if the query is
+    // executing, so false means it's still waiting, or that the cursor has no query at all
+    pub fn admit(&mut self, cursor: &mut Cursor<T>) -> Result<bool> {
+        let id = match cursor.ticket {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        if cursor.killed() {
+            return Err(cursor.cancel());
+        }
+        if !self.scheduler.borrow_mut().start(id) {
+            return Ok(false);
+        }
+        if let Some((plan, params, options)) = cursor.pending.take() {
+            cursor.profile = match self.backend.statistics() {
+                Some(stats) if cursor.profiled => Some(Profile {
+                    estimates: plan.estimate(
+                        &self.statistics_with_feedback(stats),
+                        &self.frontend.tokens.borrow(),
+                    ),
+                    complete: false,
+                }),
+                _ => None,
+            };
+            if let Err(e) = self.backend.eval(plan, params, &options, &mut cursor.inner) {
+                cursor.finish_query();
+                return Err(e);
+            }
+            self.scheduler
+                .borrow_mut()
+                .watch(id, cursor.inner.operator());
+            self.metrics.borrow_mut().queries_executed += 1;
+            cursor.query = Some(QueryStats {
+                rows: 0,
+                execution_time: Duration::default(),
+            });
+        }
+        Ok(true)
+    }
+
+    // Cap how many queries execute at once, and how many may wait in line for their turn; past
+    // that, queries fail with QueryError::Overloaded. There are no limits unless this is called.
+    pub fn set_query_limits(&mut self, max_running: usize, max_queued: usize) {
+        self.scheduler
+            .borrow_mut()
+            .set_limits(max_running, max_queued)
+    }
+
+    // Stop queries that go past the given number of rows in any one of their operators, with a
+    // QueryError::LimitExceeded naming the operator; so a query that accidentally matches a
+    // cartesian product fails, rather than runs for as long as it takes to produce it. Rows an
+    // operator produces count towards the limit for as long as the query runs, so the inner side
+    // of a NestLoop is held to it over all of its runs. There's no limit unless this is called;
+    // backends that can't count rows per operator ignore it.
+    //
+    // TODO: Once there are var-length expands, a limit on how deep they go belongs next to this.
+    pub fn set_max_intermediate_rows(&mut self, rows: u64) {
+        self.backend.set_max_intermediate_rows(rows)
+    }
+
+    // The queries that are executing or queued, in the order they were run
+    pub fn queries(&self) -> Vec<QueryInfo> {
+        self.scheduler.borrow().queries()
+    }
+
+    // The queries that are executing, with how long they've been at it and where they're at,
+    // in the order they were run; for finding the ones to kill
+    pub fn running_queries(&self) -> Vec<RunningQuery> {
+        self.scheduler.borrow().running()
+    }
+
+    // Stop a query, queued or executing, making room for the next one in line. Its cursor fails
+    // with QueryError::Cancelled the next time it's used, and what the query wrote is thrown away
+    // like it is for queries that fail; for the gram backend that means it's never written to the
+    // file or seen by hooks, though it stays in the graph in memory. Since queries only execute
+    // while their cursors are pulled, the query is stopped between rows, not in the middle of
+    // producing one. Tells if there was such a query; there isn't once its result is exhausted.
+    pub fn kill(&mut self, id: QueryId) -> bool {
+        let killed = self.scheduler.borrow_mut().kill(id);
+        if killed {
+            self.metrics.borrow_mut().queries_cancelled += 1;
+        }
+        killed
+    }
+
+    // Send query plans and planner notifications to the given sink; see diagnostics::StdoutDiagnostics
+    // if you just want them printed
+    pub fn set_diagnostics(&mut self, sink: impl DiagnosticsSink + 'static) {
+        self.frontend.diagnostics = Box::new(sink);
+    }
+
+    // Have the hook called with what each commit changed, right after it commits - for keeping
+    // caches, search indexes or other views of the graph in step with it. A query commits once
+    // its results are exhausted or its cursor is re-used; in a batch the hook is called once, when
+    // the batch ends. Writes of queries that fail are never seen by the hook.
+    pub fn on_change(&mut self, hook: impl FnMut(&[Change]) + 'static) -> Result<()> {
+        self.backend.on_change(Box::new(hook))
+    }
+
+    // Run a query and hand its result to the visitor, see RowVisitor; gives back how many rows the
+    // visitor saw. Fails rather than waits if the query would be queued behind other queries.
+    pub fn run_with_visitor(
+        &mut self,
+        query_str: &str,
+        params: &Map,
+        visitor: &mut dyn RowVisitor,
+    ) -> Result<u64> {
+        let mut cursor = self.new_cursor();
+        self.run_with_params(query_str, params, &mut cursor)?;
+        cursor.visit(visitor)
+    }
+
+    // Run a query to completion without building its rows, for writes and existence checks where
+    // only how many rows there were matters; see Cursor::skip_rest. Like run_with_visitor, fails
+    // rather than waits if the query would be queued behind other queries.
+    pub fn execute(&mut self, query_str: &str, params: &Map) -> Result<ExecutionSummary> {
+        let mut cursor = self.new_cursor();
+        self.run_with_params(query_str, params, &mut cursor)?;
+        let rows = cursor.skip_rest()?;
+        Ok(ExecutionSummary {
+            rows,
+            summary: cursor.summary(),
+        })
+    }
+
+    // Add a random graph to the database, to try queries and indexes out on without hunting for
+    // a dataset; see the gen.graph procedure for the topologies and config. Gives back how many
+    // nodes and rels were created.
+    pub fn generate_graph(
+        &mut self,
+        topology: &str,
+        nodes: usize,
+        edges_per_node: usize,
+        config: Map,
+    ) -> Result<(i64, i64)> {
+        let params = vec![
+            ("topology".to_string(), Val::String(topology.into())),
+            ("nodes".to_string(), Val::Int(nodes as i64)),
+            ("edgesPerNode".to_string(), Val::Int(edges_per_node as i64)),
+            ("config".to_string(), Val::Map(Arc::new(config))),
+        ];
+        let mut cursor = self.new_cursor();
+        self.run_with_params(
+            "CALL gen.graph($topology, $nodes, $edgesPerNode, $config)",
+            &params,
+            &mut cursor,
+        )?;
+        match cursor.next()? {
+            Some(Row { slots }) => match slots.as_slice() {
+                [Val::Int(nodes), Val::Int(rels)] => Ok((*nodes, *rels)),
+                other => bail!(
+                    "expected node and rel counts from gen.graph, got {:?}",
+                    other
+                ),
+            },
+            None => bail!("expected node and rel counts from gen.graph, got no rows"),
+        }
+    }
+
+    // Take a snapshot of part of the graph for the algo procedures to run on, under the given
+    // name; see the graph.project procedure for the config. Gives back how many nodes and rels
+    // the projection has.
+    pub fn project_graph(&mut self, name: &str, config: Map) -> Result<(i64, i64)> {
+        let params = vec![
+            ("name".to_string(), Val::String(name.into())),
+            ("config".to_string(), Val::Map(Arc::new(config))),
+        ];
+        let mut cursor = self.new_cursor();
+        self.run_with_params("CALL graph.project($name, $config)", &params, &mut cursor)?;
+        match cursor.next()? {
+            Some(Row { slots }) => match slots.as_slice() {
+                [Val::Int(nodes), Val::Int(rels)] => Ok((*nodes, *rels)),
+                other => bail!(
+                    "expected node and rel counts from graph.project, got {:?}",
+                    other
+                ),
+            },
+            None => bail!("expected node and rel counts from graph.project, got no rows"),
+        }
+    }
+
+    // Keep an index on a property of the nodes with a label, so queries looking for a value or a
+    // range of values of it, like MATCH (n:Person) WHERE n.age > 30, can find the nodes in the
+    // index rather than go through every Person. The gram backend keeps indexes in memory only,
+    // so they need creating again each time the database is opened.
+    //
+    // The index is built in full before the planner hears of it, so no query is planned to seek
+    // it before it's done; queries that are already running carry on without it.
+    pub fn create_index(&mut self, label: &str, property: &str) -> Result<()> {
+        self.backend.create_index(label, property, None)?;
+        self.describe_backend()
+    }
+
+    // Keep an index like create_index does, with its strings in another collation than the
+    // database compares strings by. The planner only has queries seek it when that finds all the
+    // nodes they're after: a case-insensitive index can find the nodes for n.name = 'Bob' in a
+    // database that compares strings by code point, since the predicate is checked again after,
+    // but can't find the ones for n.name > 'Bob'. See collation.rs.
+    pub fn create_index_with_collation(
+        &mut self,
+        label: &str,
+        property: &str,
+        collation: Collation,
+    ) -> Result<()> {
+        self.backend
+            .create_index(label, property, Some(&collation))?;
+        self.describe_backend()
+    }
+
+    // Stop keeping an index, see create_index. Queries planned from then on scan rather than
+    // seek; queries that were already running when it was dropped finish using it.
+    pub fn drop_index(&mut self, label: &str, property: &str) -> Result<()> {
+        self.backend.drop_index(label, property)?;
+        self.describe_backend()
+    }
+
+    // Build an index again from the nodes there are, for an index that's gone stale, see
+    // index_health; there's no need to dump and load the graph, or to drop the index and have
+    // queries scan while it's created again. The new index is built next to the old one, and
+    // replaces it once it's done, so queries seek the old one until then, and queries already
+    // seeking it finish with it.
+    pub fn rebuild_index(&mut self, label: &str, property: &str) -> Result<()> {
+        self.backend.rebuild_index(label, property)
+    }
+
+    // How each index is holding up: how many nodes it has next to how many it should, and when
+    // it was last built. This goes through the nodes of each index's label to count them, so
+    // it's not something to call per query.
+    //
+    // The gram backend builds its indexes from the graph as it was recovered from the file and
+    // log, and keeps them up to date as nodes are created and deleted, so a stale index there is
+    // a bug, though one rebuild_index recovers from.
+    pub fn index_health(&self) -> Vec<IndexHealth> {
+        self.backend.index_health()
+    }
+
+    // Compare strings by the collation from here on, see collation.rs; usually it's picked when
+    // the database is opened, see DatabaseConfig::collation. Indexes that collate like the
+    // database are built again in the new collation.
+    pub fn set_collation(&mut self, collation: Collation) -> Result<()> {
+        self.backend.set_collation(&collation)?;
+        self.describe_backend()
+    }
+
+    // Have the planner plan by the indexes and constraints the backend has now. Plans made for
+    // others aren't used after this, see ParameterizedPlan::schema_digest.
+    fn describe_backend(&mut self) -> Result<()> {
+        self.frontend.backend_desc = self.backend.describe()?;
+        Ok(())
+    }
+
+    // Make a query callable from other queries with CALL name(..), its $parameters being the
+    // arguments, in the order they first appear in it. See frontend/views.rs.
+    pub fn define_view(&mut self, name: &str, query: &str) -> Result<()> {
+        self.frontend.define_view(name, query)?;
+        // Plans of queries calling a view by this name have the old one built into them
+        self.plan_cache.clear();
+        Ok(())
+    }
+
+    // What could be written at cursor_pos in a query that's being written, for tab-completion;
+    // see frontend/completions.rs. Labels and rel types are the ones the graph has now.
+    pub fn completions(&mut self, partial_query: &str, cursor_pos: usize) -> Completions {
+        self.frontend.backend_desc.statistics = self
+            .backend
+            .statistics()
+            .map(|stats| self.statistics_with_feedback(stats));
+        self.frontend.completions(partial_query, cursor_pos)
+    }
+
+    // Plan a query, or find it in the plan cache, along with the values of its parameters
+    fn plan(&mut self, query_str: &str, user_params: &Map) -> Result<(LogicalPlan, Params)> {
+        let (planned, literals) = self.plan_cached(query_str)?;
+        let params = self.bind(&planned, &literals, user_params)?;
+        Ok((planned.plan, params))
+    }
+
+    // Plan a query, or find it in the plan cache, along with the values of the literals that
+    // were lifted out of it
+    fn plan_cached(&mut self, query_str: &str) -> Result<(ParameterizedPlan, Vec<Val>)> {
+        let literals = self.frontend.literals(query_str)?;
+        let key = (fingerprint(query_str), literals::shape(&literals));
+        let cached = self
+            .plan_cache
+            .get(&key)
+            .filter(|cached| match &cached.query {
+                Some(query) => query == query_str,
+                None => true,
+            })
+            // Plans made before an index was created or dropped may seek the wrong indexes; the
+            // query is planned again, and the new plan takes their place
+            .filter(|cached| cached.plan.schema_digest == self.frontend.backend_desc.schema_digest);
+
+        let planned = match cached {
+            Some(cached) => {
+                self.metrics.borrow_mut().plan_cache_hits += 1;
+                cached.plan.clone()
+            }
+            None => {
+                // Plans are picked by how much data there is when they're made, and stay in the
+                // cache after; they give the same results either way, but may not be the fastest
+                // once the graph has grown or shrunk a lot
+                self.frontend.backend_desc.statistics = self
+                    .backend
+                    .statistics()
+                    .map(|stats| self.statistics_with_feedback(stats));
+                let planned = self.frontend.plan_parameterized(query_str)?;
+                if self.plan_cache.len() >= self.plan_cache_size {
+                    // No clever eviction policy yet, just start over
+                    self.plan_cache.clear();
+                }
+                self.plan_cache.insert(
+                    key,
+                    CachedPlan {
+                        query: planned.names_literals.then(|| query_str.to_string()),
+                        plan: planned.clone(),
+                    },
+                );
+                planned
+            }
+        };
+        Ok((planned, literals.into_iter().map(|l| l.value).collect()))
+    }
+
+    // Values for all the parameters of a planned query: those the user gave, and the literals
+    fn bind(
+        &self,
+        planned: &ParameterizedPlan,
+        literals: &[Val],
+        user_params: &Map,
+    ) -> Result<Params> {
+        let mut params = Params::new();
+        {
+            let mut tokens = self.frontend.tokens.borrow_mut();
+            for (name, v) in user_params {
+                params.insert(tokens.tokenize(name), v.clone());
+            }
+            for param in &planned.parameters {
+                if !params.contains_key(param) {
+                    bail!(QueryError::SemanticError {
+                        message: format!(
+                            "Expected a value for parameter ${}",
+                            tokens.lookup(*param).unwrap_or("?")
+                        ),
+                        span: None,
+                    })
+                }
+            }
+        }
+        for (param, literal) in planned.literal_params.iter().zip(literals) {
+            params.insert(*param, literal.clone());
+        }
+        Ok(params)
+    }
+
+    // A snapshot of the metrics this database has collected since it was opened
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.borrow().clone()
+    }
+
+    // Where the planner has been wrong about how many rows an operator produces: for each
+    // operator seen in queries profiled with Cursor::with_stats, what it did next to what the
+    // planner expected, the worst misestimate first. Operators the planner estimates from
+    // statistics it keeps exact, like how many nodes have a label, aren't included; for the rest,
+    // plans made from now on are estimated by what they were seen doing rather than by guesses.
+    // A query's feedback is recorded once its result is exhausted and it's done with, that is
+    // its cursor is reset, re-used or dropped; queries stopped early don't count.
+    pub fn cardinality_feedback(&self) -> Vec<(String, Observed)> {
+        let mut feedback: Vec<(String, Observed)> = self
+            .feedback
+            .borrow()
+            .iter()
+            .map(|(key, observed)| (key.clone(), observed.clone()))
+            .collect();
+        feedback.sort_by(|(a_key, a), (b_key, b)| {
+            b.misestimate()
+                .total_cmp(&a.misestimate())
+                .then_with(|| a_key.cmp(b_key))
+        });
+        feedback
+    }
+
+    fn statistics_with_feedback(&self, mut stats: backend::Statistics) -> backend::Statistics {
+        stats.feedback = self.feedback.borrow().clone();
+        stats
+    }
+}
+
+// How to run a query, see Database::run_with_options. The defaults are what Database::run does.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RunOptions {
+    // Seed for rand() and anything else in the query that picks at random, so running the query
+    // again with the same seed picks the same; without one, each run picks differently
+    pub seed: Option<u64>,
+    // Give the same result, in the same order, every time the query is run on the same graph,
+    // for test suites and pipelines that compare results across runs: rand() is seeded with 0
+    // unless there's a seed, and the labels and properties of nodes and rels come sorted by name
+    // rather than in whatever order the backend keeps them. Scans and aggregations already go
+    // in an order that only depends on the graph.
+    pub deterministic: bool,
+}
+
+impl RunOptions {
+    // The seed the query is run with
+    pub fn seed(&self) -> u64 {
+        match self.seed {
+            Some(seed) => seed,
+            None if self.deterministic => 0,
+            // The std hasher is seeded at random, wasm32 aside, which makes for a seed that
+            // doesn't need the rand crate
+            None => RandomState::new().build_hasher().finish(),
+        }
     }
 }
 
@@ -58,7 +635,8 @@ impl<T: Backend> Database<T> {
 //
 // Eg. this is efficient, but very unergonomic. I think the solution - for now, at least? -
 // is to have a "sugared" version where you can get an iterator out of a cursor, so if you are
-// ok to pay a small performance penalty you get back the regular Rust iteration API.
+// ok to pay a small performance penalty you get back the regular Rust iteration API. That's
+// Cursor::rows.
 //
 // In fact, the sugared version should probably be the default thing you interact with, with an
 // option to drop down to a non-allocating core API if you like.
@@ -72,6 +650,42 @@ impl<T: Backend> Database<T> {
 #[derive(Debug)]
 pub struct Cursor<B: Backend> {
     inner: B::Cursor,
+    span: tracing::Span,
+    metrics: Rc<RefCell<Metrics>>,
+    // Stats for the query this cursor is running, if any, reported to metrics when it's done
+    query: Option<QueryStats>,
+    scheduler: Rc<RefCell<Scheduler>>,
+    // The cursors query, as the scheduler knows it, until the query is done
+    ticket: Option<QueryId>,
+    // The plan of a query that is queued, for Database::admit to hand to the backend
+    pending: Option<(LogicalPlan, Params, RunOptions)>,
+    // Set by with_stats; profiled queries have their estimates checked against what they did
+    profiled: bool,
+    profile: Option<Profile>,
+    feedback: Rc<RefCell<HashMap<String, Observed>>>,
+}
+
+// What the planner expected of the operators of a profiled query, see Database::cardinality_feedback
+#[derive(Debug)]
+struct Profile {
+    // Root first, like ExecutionStats::operators
+    estimates: Vec<OperatorEstimate>,
+    // Set once the result is exhausted, until what the query did is recorded as feedback
+    complete: bool,
+}
+
+#[derive(Debug)]
+struct QueryStats {
+    rows: u64,
+    execution_time: Duration,
+}
+
+impl QueryStats {
+    fn report(self, metrics: &RefCell<Metrics>) {
+        let mut m = metrics.borrow_mut();
+        m.rows_produced += self.rows;
+        m.execution_time.observe_duration(self.execution_time);
+    }
 }
 
 impl<B: Backend> Cursor<B> {
@@ -79,8 +693,263 @@ impl<B: Backend> Cursor<B> {
         self.inner.fields()
     }
 
+    // Pre-allocate room for this many slots per row, so the first queries run with this cursor
+    // don't need to grow it. Once a cursor has been used, running more queries with it re-uses its
+    // buffers rather than allocating new ones.
+    pub fn with_capacity(mut self, slots: usize) -> Self {
+        self.inner.reserve(slots);
+        self
+    }
+
+    // Measure what each operator of the queries run with this cursor costs, see stats. This
+    // makes queries slower, so it's for tests and for looking into queries that are slow.
+    pub fn with_stats(mut self) -> Self {
+        self.inner.collect_stats();
+        self.profiled = true;
+        self
+    }
+
+    // What the query this cursor was last given cost, per operator, if the cursor was made
+    // with_stats and the backend can measure its operators. The stats are complete once the
+    // result is exhausted, and are kept until the next query is run with the cursor.
+    pub fn stats(&self) -> Option<ExecutionStats> {
+        let mut stats = self.inner.stats()?;
+        if let Some(estimates) = self.estimates_for(&stats) {
+            for (op, estimate) in stats.operators.iter_mut().zip(estimates) {
+                op.estimated_rows = Some(estimate.rows);
+            }
+        }
+        Some(stats)
+    }
+
+    // The estimates of the query, if they line up with the operators the backend measured
+    fn estimates_for(&self, stats: &ExecutionStats) -> Option<&[OperatorEstimate]> {
+        let estimates = &self.profile.as_ref()?.estimates;
+        let aligned = estimates.len() == stats.operators.len()
+            && estimates
+                .iter()
+                .zip(&stats.operators)
+                .all(|(estimate, op)| estimate.name == op.name);
+        aligned.then_some(estimates.as_slice())
+    }
+
+    // Add what the operators of the finished query did to the feedback the planner goes by
+    fn record_feedback(&mut self) {
+        match &mut self.profile {
+            Some(profile) if profile.complete => profile.complete = false,
+            _ => return,
+        }
+        let stats = match self.inner.stats() {
+            Some(stats) => stats,
+            None => return,
+        };
+        let estimates = match self.estimates_for(&stats) {
+            Some(estimates) => estimates,
+            None => return,
+        };
+        let mut feedback = self.feedback.borrow_mut();
+        for (i, (op, estimate)) in stats.operators.iter().zip(estimates).enumerate() {
+            let key = match &estimate.key {
+                Some(key) => key,
+                None => continue,
+            };
+            // Operators come before their sources, so the first operator with this one as its
+            // parent is the source it's estimated against
+            let rows_in = stats
+                .operators
+                .iter()
+                .find(|src| src.parent == Some(i))
+                .map_or(0, |src| src.rows);
+            let observed = feedback.entry(key.clone()).or_default();
+            observed.runs += 1;
+            observed.rows_in += rows_in;
+            observed.rows_out += op.rows;
+            observed.estimated_rows += estimate.rows;
+        }
+    }
+
+    // What the query this cursor was last given wrote to the graph, if the backend keeps count;
+    // complete once the result is exhausted
+    pub fn summary(&self) -> Option<QuerySummary> {
+        self.inner.summary()
+    }
+
+    // Let go of the current result, if any, while keeping the cursors buffers for the next query.
+    // You don't need to call this before re-using the cursor in Database::run, it's for when you
+    // want to release a result early.
+    pub fn reset(&mut self) -> Result<()> {
+        self.finish_query();
+        self.inner.reset()
+    }
+
+    // What the query this cursor was last given is up to, if it's not done yet
+    pub fn state(&self) -> Option<QueryState> {
+        self.ticket.and_then(|id| self.scheduler.borrow().state(id))
+    }
+
     pub fn next(&mut self) -> Result<Option<&Row>> {
-        self.inner.next()
+        if self.killed() {
+            return Err(self.cancel());
+        }
+        if self.pending.is_some() {
+            bail!("the query is queued behind other queries, see Database::admit")
+        }
+        let _enter = self.span.enter();
+        let started = Stopwatch::start();
+        let result = self.inner.next();
+        if let Some(q) = &mut self.query {
+            q.execution_time += started.elapsed();
+            match result {
+                Ok(Some(_)) => q.rows += 1,
+                _ => self.query.take().unwrap().report(&self.metrics),
+            }
+        }
+        if let (Ok(None), Some(profile)) = (&result, &mut self.profile) {
+            profile.complete = true;
+        }
+        if let Some(id) = self.ticket {
+            match result {
+                Ok(Some(_)) => self.scheduler.borrow_mut().streaming(id),
+                _ => {
+                    self.scheduler.borrow_mut().finish(id);
+                    self.ticket = None;
+                }
+            }
+        }
+        result
+    }
+
+    // Hand the rest of the current result to the visitor, without copying values out of the
+    // cursor; gives back how many rows the visitor saw. The result is let go of once the visitor
+    // is done, even if it stopped early.
+    pub fn visit(&mut self, visitor: &mut dyn RowVisitor) -> Result<u64> {
+        if self.killed() {
+            return Err(self.cancel());
+        }
+        if self.pending.is_some() {
+            bail!("the query is queued behind other queries, see Database::admit")
+        }
+        let span = self.span.clone();
+        let _enter = span.enter();
+        visitor.fields(&self.inner.fields())?;
+        let started = Stopwatch::start();
+        let mut rows = 0;
+        let mut stopped = false;
+        let result = loop {
+            match self.inner.visit_next(visitor) {
+                Ok(Some(more)) => {
+                    rows += 1;
+                    if !more {
+                        stopped = true;
+                        break Ok(rows);
+                    }
+                }
+                Ok(None) => {
+                    if let Some(profile) = &mut self.profile {
+                        profile.complete = true;
+                    }
+                    break Ok(rows);
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        if let Some(q) = &mut self.query {
+            q.execution_time += started.elapsed();
+            q.rows += rows;
+        }
+        if stopped {
+            self.reset()?;
+        } else {
+            self.finish_query();
+        }
+        result
+    }
+
+    // Run through the rest of the current result without the backend building its rows; gives
+    // back how many rows there were
+    pub fn skip_rest(&mut self) -> Result<u64> {
+        if self.killed() {
+            return Err(self.cancel());
+        }
+        if self.pending.is_some() {
+            bail!("the query is queued behind other queries, see Database::admit")
+        }
+        let span = self.span.clone();
+        let _enter = span.enter();
+        let started = Stopwatch::start();
+        let mut rows = 0;
+        let result = loop {
+            match self.inner.skip_next() {
+                Ok(true) => rows += 1,
+                Ok(false) => {
+                    if let Some(profile) = &mut self.profile {
+                        profile.complete = true;
+                    }
+                    break Ok(rows);
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        if let Some(q) = &mut self.query {
+            q.execution_time += started.elapsed();
+            q.rows += rows;
+        }
+        self.finish_query();
+        result
+    }
+
+    // Iterate over the rest of the current result. Each row is copied out of the cursor, so it
+    // can be kept and used with the usual iterator combinators; use next() to avoid that
+    pub fn rows(&mut self) -> Rows<'_, B> {
+        Rows {
+            fields: self.fields().into(),
+            cursor: self,
+            done: false,
+        }
+    }
+
+    // Was the query of this cursor killed? The scheduler forgets about queries once they're
+    // killed, while the cursor holds on to its ticket until the query is done
+    fn killed(&self) -> bool {
+        match self.ticket {
+            Some(id) => self.scheduler.borrow().state(id).is_none(),
+            None => false,
+        }
+    }
+
+    // Let go of the query of a killed cursor, giving back the error to fail with
+    fn cancel(&mut self) -> Error {
+        // A query killed while it was queued never made it to the backend, which may still have
+        // the result of the query before it, done and to be kept, in the cursor
+        let executing = self.pending.is_none();
+        self.ticket = None;
+        self.finish_query();
+        let released = if executing {
+            self.inner.cancel()
+        } else {
+            self.inner.reset()
+        };
+        match released {
+            Ok(()) => QueryError::Cancelled.into(),
+            Err(e) => e,
+        }
+    }
+
+    fn finish_query(&mut self) {
+        self.record_feedback();
+        if let Some(q) = self.query.take() {
+            q.report(&self.metrics);
+        }
+        if let Some(id) = self.ticket.take() {
+            self.scheduler.borrow_mut().finish(id);
+        }
+        self.pending = None;
+    }
+}
+
+impl<B: Backend> Drop for Cursor<B> {
+    fn drop(&mut self) {
+        self.finish_query()
     }
 }
 
@@ -89,6 +958,139 @@ pub struct Row {
     pub slots: Vec<Val>,
 }
 
+// See Cursor::rows
+pub struct Rows<'c, B: Backend> {
+    cursor: &'c mut Cursor<B>,
+    fields: Rc<[String]>,
+    // Set once the result is exhausted or has failed, so we don't keep pulling from the cursor
+    done: bool,
+}
+
+impl<'c, B: Backend> Iterator for Rows<'c, B> {
+    type Item = Result<RowView>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.cursor.next() {
+            Ok(Some(row)) => Some(Ok(RowView {
+                fields: Rc::clone(&self.fields),
+                values: row.slots.clone(),
+            })),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// A row of a result that owns its values, as yielded by Cursor::rows
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowView {
+    fields: Rc<[String]>,
+    values: Vec<Val>,
+}
+
+impl RowView {
+    // The column names of the result this row is from
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn values(&self) -> &[Val] {
+        &self.values
+    }
+
+    // The value of the named column
+    pub fn get(&self, field: &str) -> Option<&Val> {
+        let i = self.fields.iter().position(|f| f == field)?;
+        self.values.get(i)
+    }
+
+    pub fn into_values(self) -> Vec<Val> {
+        self.values
+    }
+}
+
+impl Index<usize> for RowView {
+    type Output = Val;
+
+    fn index(&self, i: usize) -> &Val {
+        &self.values[i]
+    }
+}
+
+// Receives a result row by row without the values being copied out of the cursor, see
+// Database::run_with_visitor. Good for counting, writing results straight to a socket or
+// aggregating them somewhere else, where building owned rows is wasted work.
+pub trait RowVisitor {
+    // Called once, before the first row, with the column names of the result
+    fn fields(&mut self, _fields: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    // Called for each row; the values are only borrowed for the duration of the call. Return
+    // false to stop before the rest of the result.
+    fn row(&mut self, row: &dyn RowRef) -> Result<bool>;
+}
+
+// The values of a result row, borrowed from the cursor, as handed to a RowVisitor
+pub trait RowRef {
+    fn columns(&self) -> usize;
+
+    fn get(&self, column: usize) -> ValRef<'_>;
+}
+
+impl RowRef for Row {
+    fn columns(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn get(&self, column: usize) -> ValRef<'_> {
+        ValRef::from(&self.slots[column])
+    }
+}
+
+// A borrowed view of a Val. Nodes and relationships are given by id only, since building up
+// their labels and properties is exactly the cost visitors are there to avoid; return the
+// properties you need as columns of their own, or use Cursor::next if you want whole entities.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValRef<'a> {
+    Null,
+    Int(i64),
+    Float(f64),
+    String(&'a str),
+    Bool(bool),
+
+    Map(&'a Map),
+    List(&'a [Val]),
+
+    Node(usize),
+    Rel(usize),
+}
+
+impl<'a> From<&'a Val> for ValRef<'a> {
+    fn from(v: &'a Val) -> Self {
+        match v {
+            Val::Null => ValRef::Null,
+            Val::Int(v) => ValRef::Int(*v),
+            Val::Float(v) => ValRef::Float(*v),
+            Val::String(v) => ValRef::String(v),
+            Val::Bool(v) => ValRef::Bool(*v),
+            Val::Map(v) => ValRef::Map(v),
+            Val::List(v) => ValRef::List(v),
+            Val::Node(v) => ValRef::Node(v.id),
+            Val::Rel(v) => ValRef::Rel(v.id),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     pub id: usize,
@@ -99,6 +1101,9 @@ pub struct Node {
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Rel {
+    // Unique among the rels of the graph, the way Node::id is among its nodes; so two rels
+    // between the same nodes, with the same type and properties, can still be told apart
+    pub id: usize,
     pub start: usize,
     pub end: usize,
     // TODO not forcing massive amounts of variable-length data copying..
@@ -106,16 +1111,51 @@ pub struct Rel {
     pub props: Map,
 }
 
+// Something a commit did to the graph, see Database::on_change. CREATE is the only way for
+// queries to change the graph so far, so this is mostly about new nodes and rels, with the labels
+// and properties they were created with; nodes are deleted when they expire, see expiry.rs, and
+// take their rels with them. Deleted nodes and rels are as they were right before.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    NodeCreated(Node),
+    RelCreated(Rel),
+    NodeDeleted(Node),
+    RelDeleted(Rel),
+}
+
+impl Node {
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.iter().any(|l| l == label)
+    }
+
+    pub fn property(&self, key: &str) -> Option<&Val> {
+        property(&self.props, key)
+    }
+}
+
+impl Rel {
+    pub fn property(&self, key: &str) -> Option<&Val> {
+        property(&self.props, key)
+    }
+}
+
+fn property<'a>(props: &'a Map, key: &str) -> Option<&'a Val> {
+    props.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+// Strings, lists and maps are reference counted, so that copying values between rows - which
+// queries do a lot of - doesn't copy their contents. Use Arc::make_mut or build a new value if
+// you need to modify one.
 #[derive(Debug, Clone, PartialEq)]
 pub enum Val {
     Null,
     Int(i64),
     Float(f64),
-    String(String),
+    String(Arc<str>),
     Bool(bool),
 
-    Map(Map),
-    List(Vec<Val>),
+    Map(Arc<Map>),
+    List(Arc<[Val]>),
 
     Node(Node),
     Rel(Rel),
@@ -149,7 +1189,7 @@ pub type Map = Vec<(String, Val)>;
 pub type Slot = usize;
 
 // openCypher 9 enumeration of types
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     // This is not a documented part of the openCypher type system, but.. well I'm not sure how
     // else we represent the arguments to a function like count(..).
@@ -178,15 +1218,2389 @@ pub enum Type {
 pub mod gramdb {
     use super::{Cursor, Database, Result};
     use crate::backend::gram;
-    use std::fs::File;
+    #[cfg(feature = "gram-file")]
+    use crate::DatabaseConfig;
+    #[cfg(feature = "gram-file")]
+    use std::fs::{File, OpenOptions};
+    #[cfg(feature = "gram-file")]
+    use std::path::Path;
 
     pub type GramDatabase = Database<gram::GramBackend>;
     pub type GramCursor = Cursor<gram::GramBackend>;
 
     impl GramDatabase {
+        // A database that only lives in memory, see GramBackend::in_memory
+        pub fn in_memory() -> Result<GramDatabase> {
+            Database::with_backend(gram::GramBackend::in_memory()?)
+        }
+
+        // An in-memory database, starting out with the graph described by the given gram
+        pub fn from_gram(gram: &str) -> Result<GramDatabase> {
+            Database::with_backend(gram::GramBackend::from_gram(gram)?)
+        }
+
+        #[cfg(feature = "gram-file")]
         pub fn open(file: File) -> Result<GramDatabase> {
             let backend = gram::GramBackend::open(file)?;
             Database::with_backend(backend)
         }
+
+        // Open a gram file, writing changes to an append-only log rather than to the gram file
+        // itself; see GramBackend::open_with_log
+        #[cfg(feature = "gram-file")]
+        pub fn open_with_log(file: File, log: File) -> Result<GramDatabase> {
+            let backend = gram::GramBackend::open_with_log(file, log)?;
+            Database::with_backend(backend)
+        }
+
+        // Fold all changes back into the gram file, see GramBackend::compact. If the database
+        // was opened by path with a change log, the new gram file is written in the background:
+        // queries keep running on this database while it is, and other processes reading the
+        // gram file keep seeing the old one, until finish_compaction puts the new one in place.
+        // The overflow file isn't compacted; the space of the values deleted from it stays taken.
+        #[cfg(feature = "gram-file")]
+        pub fn compact(&mut self) -> Result<()> {
+            if self.read_only {
+                bail!(crate::QueryError::ReadOnly)
+            }
+            self.backend.compact()
+        }
+
+        // Wait for the compaction compact() started, if it's still being written, and switch
+        // over to the new gram file; this also happens when the database is closed
+        #[cfg(feature = "gram-file")]
+        pub fn finish_compaction(&mut self) -> Result<()> {
+            self.backend.finish_compaction()
+        }
+    }
+
+    #[cfg(feature = "gram-file")]
+    impl DatabaseConfig {
+        // Open the gram file at the given path, creating it if it doesn't exist and the database
+        // isn't read-only. The change log, if asked for, lives next to it, with .log tacked on
+        // to the name, and so does the file large values overflow to, with .overflow; that one is
+        // created when the first value large enough to go in it is written.
+        pub fn open(&self, path: impl AsRef<Path>) -> Result<GramDatabase> {
+            let path = path.as_ref();
+            let file = self.open_file(path)?;
+            let log_path = gram::sibling_path(path, ".log");
+            // A read-only database has no use for a log that was never written
+            let log = if self.change_log && (log_path.exists() || !self.read_only) {
+                Some(self.open_file(&log_path)?)
+            } else {
+                None
+            };
+            let overflow_path = gram::sibling_path(path, ".overflow");
+            let overflow = if overflow_path.exists() {
+                Some(gram::OverflowFile::Open(self.open_file(&overflow_path)?))
+            } else if !self.read_only {
+                Some(gram::OverflowFile::At(overflow_path))
+            } else {
+                None
+            };
+            let backend = gram::GramBackend::load(
+                file,
+                Some(path.to_path_buf()),
+                log,
+                Some(log_path),
+                overflow,
+                self.durability,
+                self.threads,
+            )?;
+            Database::with_config(backend, self)
+        }
+
+        fn open_file(&self, path: &Path) -> Result<File> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!self.read_only)
+                .create(!self.read_only)
+                .open(path)
+                .map_err(|e| anyhow!(e).context(format!("failed to open {}", path.display())))?;
+            Ok(file)
+        }
+    }
+
+    #[cfg(all(test, feature = "gram-file"))]
+    mod tests {
+        use super::*;
+        use crate::backend::Observed;
+        use crate::metrics::QuerySummary;
+        use crate::{Change, Collation, Error, Map, RowView, RunOptions, Val};
+        use std::cell::RefCell;
+        use std::io::{Read, Seek, SeekFrom, Write};
+        use std::rc::Rc;
+
+        #[test]
+        fn created_data_survives_reopening_the_file() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            {
+                let mut db = GramDatabase::open(file.try_clone()?)?;
+                let mut cursor = db.new_cursor();
+                db.run(
+                    "CREATE (a:Person {name: 'a', age: 42, score: 1.5})-[:KNOWS {since: 2010}]->(b:Person {name: 'b'})",
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+            }
+
+            file.seek(SeekFrom::Start(0))?;
+            let mut db = GramDatabase::open(file)?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "MATCH (a:Person)-[r:KNOWS]->(b) RETURN a.name, a.age, a.score, r.since, b.name",
+                &mut cursor,
+            )?;
+            let row = cursor.next()?.expect("the created pattern should be there");
+            assert_eq!(
+                row.slots,
+                vec![
+                    Val::String("a".into()),
+                    Val::Int(42),
+                    Val::Float(1.5),
+                    Val::Int(2010),
+                    Val::String("b".into()),
+                ]
+            );
+            assert!(cursor.next()?.is_none());
+            Ok(())
+        }
+
+        #[test]
+        fn property_types_survive_reopening_the_file() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            let query = "MATCH (n:`we:ird label`)-[r:`and 'this'`]->() \
+                         RETURN n.f, n.i, n.s, n.b, n.t, n.xs, n.empty, n.nan, n.inf, r.w";
+            let mut before = Vec::new();
+            {
+                let mut db = GramDatabase::open(file.try_clone()?)?;
+                let mut cursor = db.new_cursor();
+                let params = vec![
+                    ("nan".to_string(), Val::Float(f64::NAN)),
+                    ("inf".to_string(), Val::Float(f64::INFINITY)),
+                ];
+                db.run_with_params(
+                    "CREATE (:`we:ird label` {f: 2.0, i: 2, s: '2', b: true, t: 'true', \
+                       xs: [1, 2.5, 'a', false, null], empty: [], nan: $nan, inf: $inf}) \
+                       -[:`and 'this'` {w: -0.5}]->()",
+                    &params,
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+                db.run(query, &mut cursor)?;
+                before.extend(cursor.next()?.unwrap().slots.iter().cloned());
+                // Through the change log, and then through a compacted gram file
+                db.compact()?;
+            }
+
+            file.seek(SeekFrom::Start(0))?;
+            let mut db = GramDatabase::open(file)?;
+            let mut cursor = db.new_cursor();
+            db.run(query, &mut cursor)?;
+            let after = &cursor
+                .next()?
+                .expect("the node should still be there")
+                .slots;
+            assert_eq!(after[..7], before[..7]);
+            assert_eq!(after[9], before[9]);
+            assert_eq!(after[0], Val::Float(2.0));
+            assert!(matches!(after[7], Val::Float(f) if f.is_nan()));
+            assert_eq!(after[8], Val::Float(f64::INFINITY));
+            Ok(())
+        }
+
+        #[test]
+        fn detects_corrupt_records() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            {
+                let mut db = GramDatabase::open(file.try_clone()?)?;
+                let mut cursor = db.new_cursor();
+                db.run("CREATE (a:Person {name: 'abc'})", &mut cursor)?;
+                while cursor.next()?.is_some() {}
+            }
+
+            let mut gram = String::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_string(&mut gram)?;
+            let record_start = gram.find("//#crc32").unwrap();
+            file.seek(SeekFrom::Start(gram.find("abc").unwrap() as u64))?;
+            file.write_all(b"abd")?;
+
+            file.seek(SeekFrom::Start(0))?;
+            let err = GramDatabase::open(file).unwrap_err();
+            let corruption = err
+                .downcast_ref::<gram::CorruptionError>()
+                .expect("should be a corruption error");
+            assert_eq!(corruption.offset, record_start);
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::Corruption);
+            Ok(())
+        }
+
+        #[test]
+        fn loads_strings_that_look_like_record_headers() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            file.write_all(b"(a:Note {text: 'see //#crc32 0 5'})")?;
+            file.seek(SeekFrom::Start(0))?;
+            {
+                let mut db = GramDatabase::open(file.try_clone()?)?;
+                let mut cursor = db.new_cursor();
+                db.run(
+                    "CREATE (:Note {text: 'line\n//#crc32 deadbeef 3\nabc'})",
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+            }
+
+            file.seek(SeekFrom::Start(0))?;
+            let mut db = GramDatabase::open(file)?;
+            let mut cursor = db.new_cursor();
+            db.run("MATCH (n:Note) RETURN n.text ORDER BY n.text", &mut cursor)?;
+            let row = cursor.next()?.unwrap();
+            assert_eq!(
+                row.slots[0],
+                Val::String("line\n//#crc32 deadbeef 3\nabc".into())
+            );
+            let row = cursor.next()?.unwrap();
+            assert_eq!(row.slots[0], Val::String("see //#crc32 0 5".into()));
+            Ok(())
+        }
+
+        #[test]
+        fn counts_queries_and_rows() -> Result<()> {
+            let mut db = GramDatabase::open(tempfile::tempfile()?)?;
+            let mut cursor = db.new_cursor();
+            db.run("UNWIND [1, 2, 3] AS x RETURN x", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            // Not exhausted, only counted once the cursor is re-used
+            db.run("UNWIND [1, 2, 3] AS x RETURN x", &mut cursor)?;
+            cursor.next()?;
+            assert_eq!(db.metrics().rows_produced, 3);
+            db.run("RETURN 1", &mut cursor)?;
+
+            let metrics = db.metrics();
+            assert_eq!(metrics.queries_executed, 3);
+            assert_eq!(metrics.rows_produced, 4);
+            assert_eq!(metrics.planning_time.count, 3);
+            assert_eq!(metrics.execution_time.count, 2);
+            assert_eq!(metrics.plan_cache_hits, 1);
+            Ok(())
+        }
+
+        #[test]
+        fn measures_what_each_operator_costs() -> Result<()> {
+            let mut db = GramDatabase::from_gram("(:A {x: 1})-[:R]->(:B) (:A {x: 2}) (:B) (:B)")?;
+            let mut cursor = db.new_cursor();
+            db.run("MATCH (a:A)-[:R]->(b) RETURN a.x", &mut cursor)?;
+            assert_eq!(cursor.stats(), None);
+
+            let mut cursor = db.new_cursor().with_stats();
+            db.run("MATCH (a:A)-[:R]->(b) RETURN a.x", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            let stats = cursor.stats().unwrap();
+            let op = |name: &str| {
+                let found = stats.operators.iter().find(|op| op.name == name);
+                found
+                    .unwrap_or_else(|| panic!("no {} in {:?}", name, stats))
+                    .clone()
+            };
+            assert_eq!(stats.operators[0].parent, None);
+            // The scan looks at every node, and keeps the two A's
+            assert_eq!((op("NodeScan").rows, op("NodeScan").db_hits), (2, 5));
+            // Only the first A has a rel to look at
+            assert_eq!((op("Expand").rows, op("Expand").db_hits), (1, 1));
+            assert_eq!(op("Project").db_hits, 1);
+            assert_eq!(stats.db_hits(), 7);
+
+            // Stats are of the last query only
+            db.run("RETURN 1", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(cursor.stats().unwrap().db_hits(), 0);
+            Ok(())
+        }
+
+        #[test]
+        fn feeds_profiled_row_counts_back_to_the_planner() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "UNWIND range(1, 100) AS i CREATE (:Person {kind: 'a', flag: i = 1})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.run("MATCH (n:Person) WHERE n.flag RETURN n", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            // Nothing's profiled yet
+            assert_eq!(db.cardinality_feedback(), vec![]);
+
+            let query = "MATCH (n:Person) WHERE n.flag = true RETURN n";
+            let selection = |cursor: &Cursor<_>| {
+                let stats = cursor.stats().unwrap();
+                let found = stats.operators.iter().find(|op| op.name == "Selection");
+                found.unwrap().clone()
+            };
+            let mut cursor = db.new_cursor().with_stats();
+            db.run(query, &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            // One in ten is the guess for an equality
+            let op = selection(&cursor);
+            assert_eq!((op.rows, op.estimated_rows), (1, Some(10.0)));
+            assert_eq!(op.misestimate(), Some(5.5));
+
+            // What the query did is recorded once the cursor moves on
+            db.run(query, &mut cursor)?;
+            let feedback = db.cardinality_feedback();
+            assert_eq!(
+                feedback[0],
+                (
+                    "Selection %0.flag = $auto_0".to_string(),
+                    Observed {
+                        runs: 1,
+                        rows_in: 100,
+                        rows_out: 1,
+                        estimated_rows: 10.0,
+                    }
+                )
+            );
+            while cursor.next()?.is_some() {}
+            assert_eq!(selection(&cursor).estimated_rows, Some(1.0));
+
+            // The planner picks plans by it too: an index on a property most nodes have the same
+            // value for looks worth seeking, until it's seen finding most nodes
+            db.create_index("Person", "kind")?;
+            db.run(
+                "CREATE (:Person {kind: 'b'}), (:Person {kind: 'c'})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            let operators = |db: &mut GramDatabase, query: &str| -> Result<Vec<&'static str>> {
+                let mut cursor = db.new_cursor().with_stats();
+                db.run(query, &mut cursor)?;
+                while cursor.next()?.is_some() {}
+                let stats = cursor.stats().unwrap();
+                Ok(stats.operators.iter().map(|op| op.name).collect())
+            };
+            let ops = operators(&mut db, "MATCH (n:Person) WHERE n.kind = 'a' RETURN n")?;
+            assert!(ops.contains(&"NodeIndexSeek"), "{:?}", ops);
+            let ops = operators(&mut db, "MATCH (n:Person) WHERE n.kind = 'a' RETURN n.kind")?;
+            assert!(ops.contains(&"NodeScan"), "{:?}", ops);
+
+            // Queries stopped early don't count
+            let runs = |db: &GramDatabase| {
+                let feedback = db.cardinality_feedback();
+                let found = feedback
+                    .iter()
+                    .find(|(key, _)| key.starts_with("Selection"));
+                found.unwrap().1.runs
+            };
+            assert_eq!(runs(&db), 2);
+            let mut cursor = db.new_cursor().with_stats();
+            db.run(query, &mut cursor)?;
+            cursor.next()?;
+            cursor.reset()?;
+            assert_eq!(runs(&db), 2);
+            Ok(())
+        }
+
+        #[test]
+        fn reuses_plans_only_for_the_same_query() -> Result<()> {
+            let mut db = GramDatabase::open(tempfile::tempfile()?)?;
+            let mut cursor = db.new_cursor();
+            for (query, expected) in &[("RETURN 1", 1), ("RETURN 1", 1), ("RETURN 2", 2)] {
+                db.run(query, &mut cursor)?;
+                assert_eq!(cursor.next()?.unwrap().slots[0], Val::Int(*expected));
+            }
+            assert_eq!(db.metrics().plan_cache_hits, 1);
+            Ok(())
+        }
+
+        #[test]
+        fn shares_plans_between_queries_that_differ_in_literals() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE ({id: 1, name: 'a'}), ({id: 2, name: 'b'})",
+                &mut cursor,
+            )?;
+            cursor.next()?;
+            for (id, name) in &[(1, "a"), (2, "b"), (3, "")] {
+                let query = format!("MATCH (n {{id: {}}}) RETURN n.name AS name", id);
+                db.run(&query, &mut cursor)?;
+                let expected = Some(Val::String((*name).into())).filter(|_| *id != 3);
+                assert_eq!(cursor.next()?.map(|row| row.slots[0].clone()), expected);
+            }
+            assert_eq!(db.metrics().plan_cache_hits, 2);
+
+            // Different types, or literals that were equal no longer being so, need new plans
+            db.run("MATCH (n {id: '1'}) RETURN n.name AS name", &mut cursor)?;
+            assert!(cursor.next()?.is_none());
+            db.run("RETURN 1 AS a, 1 AS b", &mut cursor)?;
+            db.run("RETURN 1 AS a, 2 AS b", &mut cursor)?;
+            assert_eq!(
+                cursor.next()?.unwrap().slots,
+                vec![Val::Int(1), Val::Int(2)]
+            );
+            assert_eq!(db.metrics().plan_cache_hits, 2);
+            Ok(())
+        }
+
+        #[test]
+        fn with_only_carries_over_what_it_projects() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "WITH 1 AS a, 2 AS b, 3 AS c WITH b AS a, a AS b UNWIND [4] AS d RETURN *",
+                &mut cursor,
+            )?;
+            assert_eq!(
+                cursor.next()?.unwrap().slots,
+                vec![Val::Int(2), Val::Int(1), Val::Int(4)]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn returns_nodes_and_rels_with_their_data() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (a:Person {name: 'a'}), (a)-[:KNOWS]->(a), (a)-[:KNOWS]->(a)",
+                &mut cursor,
+            )?;
+            cursor.next()?;
+
+            db.run("MATCH (n)-[r]->() RETURN n, r", &mut cursor)?;
+            let mut rel_ids = Vec::new();
+            while let Some(row) = cursor.next()? {
+                match (&row.slots[0], &row.slots[1]) {
+                    (Val::Node(n), Val::Rel(r)) => {
+                        assert!(n.has_label("Person"));
+                        assert_eq!(n.property("name"), Some(&Val::String("a".into())));
+                        assert_eq!(r.property("since"), None);
+                        rel_ids.push(r.id);
+                    }
+                    other => panic!("expected a node and a rel, got {:?}", other),
+                }
+            }
+            // Two otherwise identical rels
+            assert_eq!(rel_ids.len(), 2);
+            assert_ne!(rel_ids[0], rel_ids[1]);
+            Ok(())
+        }
+
+        #[test]
+        fn iterates_over_rows() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run("UNWIND [1, 2, 3] AS x RETURN x, x * 10 AS y", &mut cursor)?;
+            let ys = cursor
+                .rows()
+                .map(|row| row.map(|row| row.get("y").cloned()))
+                .collect::<Result<Vec<_>>>()?;
+            assert_eq!(
+                ys,
+                vec![Some(Val::Int(10)), Some(Val::Int(20)), Some(Val::Int(30))]
+            );
+
+            db.run("UNWIND [1, 2] AS x RETURN x", &mut cursor)?;
+            let mut seen = Vec::new();
+            for row in cursor.rows() {
+                let row = row?;
+                assert_eq!(row.fields(), &["x".to_string()]);
+                seen.push(row[0].clone());
+            }
+            assert_eq!(seen, vec![Val::Int(1), Val::Int(2)]);
+            assert!(cursor.rows().next().is_none());
+            Ok(())
+        }
+
+        #[test]
+        fn generates_graphs() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            assert_eq!(db.generate_graph("barabasi", 100, 3, vec![])?, (100, 294));
+            assert_eq!(count(&mut db, "MATCH (n:Node) RETURN count(n)")?, 100);
+            assert_eq!(
+                count(&mut db, "MATCH ()-[r:LINK]->() RETURN count(r)")?,
+                294
+            );
+            // Nodes only ever link to nodes before them
+            let query = "MATCH (a)-[:LINK]->(b) WHERE a.id > b.id RETURN count(*)";
+            assert_eq!(count(&mut db, query)?, 294);
+
+            let config = "{labels: ['Person', 'User'], relType: 'KNOWS', seed: 7}";
+            let gen = format!("CALL gen.graph('random', 50, 2, {})", config);
+            let mut cursor = db.new_cursor();
+            db.run(&gen, &mut cursor)?;
+            assert_eq!(
+                cursor.next()?.map(|r| r.slots.clone()),
+                Some(vec![Val::Int(50), Val::Int(100)])
+            );
+            assert_eq!(count(&mut db, "MATCH (n:Person:User) RETURN count(n)")?, 50);
+            let query = "MATCH (a)-[:KNOWS]->(b) WHERE a.id <> b.id RETURN count(*)";
+            assert_eq!(count(&mut db, query)?, 100);
+
+            // The same seed gives the same graph
+            let edges = |db: &mut GramDatabase| -> Result<Vec<Val>> {
+                let mut cursor = db.new_cursor();
+                db.run(
+                    "MATCH (a:Person)-[:KNOWS]->(b) RETURN a.id * 1000 + b.id AS e ORDER BY e",
+                    &mut cursor,
+                )?;
+                let mut out = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    out.push(row.slots[0].clone());
+                }
+                Ok(out)
+            };
+            let mut other = GramDatabase::in_memory()?;
+            let mut cursor = other.new_cursor();
+            other.run(&gen, &mut cursor)?;
+            cursor.next()?;
+            assert_eq!(edges(&mut db)?, edges(&mut other)?);
+
+            assert!(db.generate_graph("lattice", 10, 1, vec![]).is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn runs_algorithms_on_projections() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(a:Page {name: 'a'})-[:LINK]->(b:Page {name: 'b'})-[:LINK]->(c:Page {name: 'c'})
+                 (c)-[:LINK]->(a) (a)-[:CITES]->(c) (a)-[:LINK]->(x:Ad {name: 'x'})",
+            )?;
+            let config = vec![
+                (
+                    "labels".to_string(),
+                    Val::List(vec![Val::String("Page".into())].into()),
+                ),
+                (
+                    "relTypes".to_string(),
+                    Val::List(vec![Val::String("LINK".into())].into()),
+                ),
+            ];
+            assert_eq!(db.project_graph("pages", config)?, (3, 3));
+            // The projection doesn't see what's written after it's taken
+            let mut cursor = db.new_cursor();
+            db.run(
+                "MATCH (a {name: 'a'}) CREATE (a)-[:LINK]->(:Page {name: 'd'})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+
+            let ranks = |db: &mut GramDatabase, graph: &str| -> Result<Vec<(String, f64)>> {
+                let mut cursor = db.new_cursor();
+                let query = format!(
+                    "CALL algo.pageRank('{}', {{}}) YIELD node, score RETURN node.name, score",
+                    graph
+                );
+                db.run(&query, &mut cursor)?;
+                let mut out = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    match row.slots.as_slice() {
+                        [Val::String(name), Val::Float(score)] => {
+                            out.push((name.to_string(), *score))
+                        }
+                        other => bail!("expected a name and score, got {:?}", other),
+                    }
+                }
+                Ok(out)
+            };
+            // Walks go round the cycle, so every page is as likely as the others
+            let pages = ranks(&mut db, "pages")?;
+            assert_eq!(pages.len(), 3);
+            for (_, score) in &pages {
+                assert!((score - 1.0 / 3.0).abs() < 1e-9, "{:?}", pages);
+            }
+
+            // Walks from a follow the heavier rel more often
+            let mut db = GramDatabase::from_gram(
+                "(a {name: 'a'})-[:R {w: 3}]->(b {name: 'b'}) (a)-[:R {w: 1}]->(c {name: 'c'})",
+            )?;
+            let mut cursor = db.new_cursor();
+            db.run("CALL graph.project('weighted', {weight: 'w'})", &mut cursor)?;
+            assert_eq!(
+                cursor.next()?.map(|r| r.slots.clone()),
+                Some(vec![Val::Int(3), Val::Int(2)])
+            );
+            let weighted = ranks(&mut db, "weighted")?;
+            let score = |name: &str| weighted.iter().find(|(n, _)| n == name).unwrap().1;
+            assert!(score("b") > score("c"), "{:?}", weighted);
+            assert!(score("c") > score("a"), "{:?}", weighted);
+
+            assert!(ranks(&mut db, "nope").is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn visits_rows_without_copying_them_out() -> Result<()> {
+            use crate::{RowRef, RowVisitor, ValRef};
+
+            #[derive(Default)]
+            struct Collect {
+                fields: Vec<String>,
+                seen: Vec<String>,
+                stop_after: Option<usize>,
+            }
+
+            impl RowVisitor for Collect {
+                fn fields(&mut self, fields: &[String]) -> Result<()> {
+                    self.fields = fields.to_vec();
+                    Ok(())
+                }
+
+                fn row(&mut self, row: &dyn RowRef) -> Result<bool> {
+                    let mut out = Vec::new();
+                    for i in 0..row.columns() {
+                        out.push(match row.get(i) {
+                            ValRef::String(s) => s.to_string(),
+                            ValRef::Node(id) => format!("node {}", id),
+                            ValRef::Rel(_) => "rel".to_string(),
+                            ValRef::List(vs) => format!("{} items", vs.len()),
+                            v => format!("{:?}", v),
+                        });
+                    }
+                    self.seen.push(out.join(", "));
+                    Ok(self.stop_after != Some(self.seen.len()))
+                }
+            }
+
+            let mut db = GramDatabase::from_gram(
+                "(a:Person {name: 'a'})-[:KNOWS]->(b:Person {name: 'b'})-[:KNOWS]->(a)",
+            )?;
+            let mut v = Collect::default();
+            let rows = db.run_with_visitor(
+                "MATCH (n:Person)-[r]->(m) RETURN n.name AS name, m, r, [n, m] AS both ORDER BY name",
+                &Vec::new(),
+                &mut v,
+            )?;
+            assert_eq!(rows, 2);
+            assert_eq!(v.fields, vec!["name", "m", "r", "both"]);
+            assert_eq!(
+                v.seen,
+                vec!["a, node 1, rel, 2 items", "b, node 0, rel, 2 items"]
+            );
+
+            let mut v = Collect {
+                stop_after: Some(2),
+                ..Default::default()
+            };
+            let params = vec![("n".to_string(), Val::Int(10))];
+            let rows =
+                db.run_with_visitor("UNWIND [1, 2, 3, $n] AS x RETURN x", &params, &mut v)?;
+            assert_eq!(rows, 2);
+            assert_eq!(v.seen, vec!["Int(1)", "Int(2)"]);
+            assert_eq!(db.metrics().rows_produced, 4);
+            Ok(())
+        }
+
+        #[test]
+        fn unwinds_ranges_without_building_them() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            // Each row of a range counts towards the limit, but the range itself doesn't need
+            // to fit anywhere
+            db.set_max_intermediate_rows(2_000_000);
+            let query = "UNWIND range(1, 1000000) AS i RETURN count(i)";
+            assert_eq!(count(&mut db, query)?, 1_000_000);
+            let rows =
+                |db: &mut GramDatabase, query: &str, params: &crate::Map| -> Result<Vec<Val>> {
+                    let mut cursor = db.new_cursor();
+                    db.run_with_params(query, params, &mut cursor)?;
+                    let mut out = Vec::new();
+                    while let Some(row) = cursor.next()? {
+                        out.push(row.slots[0].clone());
+                    }
+                    Ok(out)
+                };
+            let ints = |is: &[i64]| -> Vec<Val> { is.iter().map(|i| Val::Int(*i)).collect() };
+            let query = "UNWIND range(10, 1, -3) AS i RETURN i";
+            assert_eq!(rows(&mut db, query, &vec![])?, ints(&[10, 7, 4, 1]));
+            // Outside of UNWIND, range() is a list like any other
+            assert_eq!(
+                rows(&mut db, "RETURN range(0, 2)", &vec![])?,
+                vec![Val::List(ints(&[0, 1, 2]).into())]
+            );
+
+            assert_eq!(rows(&mut db, "UNWIND null AS x RETURN x", &vec![])?, vec![]);
+            assert_eq!(rows(&mut db, "UNWIND [] AS x RETURN x", &vec![])?, vec![]);
+            let params = vec![("l".to_string(), Val::List(ints(&[1, 2]).into()))];
+            assert_eq!(
+                rows(&mut db, "UNWIND $l AS x RETURN x", &params)?,
+                ints(&[1, 2])
+            );
+
+            assert!(count(&mut db, "UNWIND range(1, 10, 0) AS i RETURN count(i)").is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn seeks_indexes_for_ranges() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "UNWIND range(0, 99) AS i CREATE (:Person {age: i})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.run(
+                "CREATE (:Person {age: 'unknown'}), (:Person {age: 41.5}), (:Person), \
+                 (:Robot {age: 50}), (:Rare:Person {age: 50})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+
+            let queries = [
+                ("MATCH (n:Person) WHERE n.age > 90 RETURN count(n)", 9),
+                ("MATCH (n:Person) WHERE n.age >= 90 RETURN count(n)", 10),
+                ("MATCH (n:Person) WHERE 3 > n.age RETURN count(n)", 4),
+                (
+                    "MATCH (n:Person) WHERE n.age > 40 AND n.age <= 42 RETURN count(n)",
+                    3,
+                ),
+                ("MATCH (n:Person) WHERE n.age = 50 RETURN count(n)", 2),
+                (
+                    "MATCH (n:Person) WHERE n.age > 41.2 AND n.age < 42 RETURN count(n)",
+                    1,
+                ),
+                // Strings sort before numbers, so 'unknown' is both above 'a' and below 3
+                ("MATCH (n:Person) WHERE n.age > 'a' RETURN count(n)", 103),
+                ("MATCH (n:Person) WHERE n.age < 0 RETURN count(n)", 1),
+                ("MATCH (n:Rare:Person) WHERE n.age > 1 RETURN count(n)", 1),
+                ("MATCH (n:Person:Rare) WHERE n.age > 1 RETURN count(n)", 1),
+            ];
+            let scanned: Vec<i64> = queries
+                .iter()
+                .map(|(q, _)| count(&mut db, q))
+                .collect::<Result<_>>()?;
+            db.create_index("Person", "age")?;
+            let seeked: Vec<i64> = queries
+                .iter()
+                .map(|(q, _)| count(&mut db, q))
+                .collect::<Result<_>>()?;
+            assert_eq!(scanned, seeked);
+            let expected: Vec<i64> = queries.iter().map(|(_, n)| *n).collect();
+            assert_eq!(seeked, expected);
+
+            let operators = |db: &mut GramDatabase, query: &str| -> Result<Vec<&'static str>> {
+                let mut cursor = db.new_cursor().with_stats();
+                db.run(query, &mut cursor)?;
+                while cursor.next()?.is_some() {}
+                let stats = cursor.stats().unwrap();
+                Ok(stats.operators.iter().map(|op| op.name).collect())
+            };
+            let ops = operators(&mut db, "MATCH (n:Person) WHERE n.age > 97 RETURN n.age")?;
+            assert!(ops.contains(&"NodeIndexSeek"), "{:?}", ops);
+            assert!(!ops.contains(&"NodeScan"), "{:?}", ops);
+            // Nodes created later are in the index too
+            db.run("CREATE (:Person {age: 1000})", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(count(&mut db, queries[0].0)?, 10);
+
+            // Without a comparison to narrow it down, there's nothing to seek
+            let ops = operators(&mut db, "MATCH (n:Person) WHERE n.age <> 1 RETURN n")?;
+            assert!(ops.contains(&"NodeScan"), "{:?}", ops);
+            Ok(())
+        }
+
+        #[test]
+        fn compares_strings_by_collation() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            db.set_collation(Collation::CaseInsensitive)?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (:Person {name: 'alice'}), (:Person {name: 'Bob'}), \
+                 (:Person {name: 'ALICE'}), (:Person {name: 'carol'})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            let names = |db: &mut GramDatabase, query: &str| -> Result<Vec<String>> {
+                let rows: Vec<(String,)> = db.query_as(query, &vec![])?;
+                Ok(rows.into_iter().map(|(name,)| name).collect())
+            };
+
+            assert_eq!(
+                names(
+                    &mut db,
+                    "MATCH (n:Person) WHERE n.name = 'Alice' RETURN n.name"
+                )?,
+                vec!["alice", "ALICE"]
+            );
+            assert_eq!(
+                names(&mut db, "MATCH (n:Person) RETURN n.name ORDER BY n.name")?,
+                vec!["alice", "ALICE", "Bob", "carol"]
+            );
+            assert_eq!(
+                names(&mut db, "MATCH (n:Person) WHERE n.name > 'b' RETURN n.name")?,
+                vec!["Bob", "carol"]
+            );
+            assert_eq!(
+                names(&mut db, "MATCH (n:Person) RETURN DISTINCT n.name")?.len(),
+                3
+            );
+            assert_eq!(
+                names(&mut db, "UNWIND ['ΑΣ', 'ασ'] AS s RETURN DISTINCT s")?.len(),
+                1
+            );
+            assert_eq!(
+                names(&mut db, "MATCH (n:Person) RETURN max(n.name)")?,
+                vec!["carol"]
+            );
+
+            // Indexes find the same nodes
+            db.run(
+                "UNWIND range(1, 100) AS i CREATE (:Person {name: 'zed'})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.create_index("Person", "name")?;
+            let operators = |db: &mut GramDatabase, query: &str| -> Result<Vec<&'static str>> {
+                let mut cursor = db.new_cursor().with_stats();
+                db.run(query, &mut cursor)?;
+                while cursor.next()?.is_some() {}
+                Ok(cursor
+                    .stats()
+                    .unwrap()
+                    .operators
+                    .iter()
+                    .map(|op| op.name)
+                    .collect())
+            };
+            let query = "MATCH (n:Person) WHERE n.name = 'Alice' RETURN n.name";
+            assert!(operators(&mut db, query)?.contains(&"NodeIndexSeek"));
+            assert_eq!(names(&mut db, query)?, vec!["alice", "ALICE"]);
+            assert_eq!(
+                count(
+                    &mut db,
+                    "MATCH (n:Person) WHERE n.name < 'C' RETURN count(n)"
+                )?,
+                3
+            );
+
+            // An index of its own collation is only sought when it finds what the predicates do
+            let mut db = GramDatabase::in_memory()?;
+            db.run(
+                "UNWIND range(1, 100) AS i CREATE (:Person {name: i})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.run(
+                "CREATE (:Person {name: 'zed'}), (:Person {name: 'Zed'})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.create_index_with_collation("Person", "name", Collation::CaseInsensitive)?;
+            let query = "MATCH (n:Person) WHERE n.name = 'Zed' RETURN count(n)";
+            assert!(operators(&mut db, query)?.contains(&"NodeIndexSeek"));
+            assert_eq!(count(&mut db, query)?, 1);
+            let query = "MATCH (n:Person) WHERE n.name < 'zed' RETURN count(n)";
+            assert!(operators(&mut db, query)?.contains(&"NodeScan"));
+            assert_eq!(count(&mut db, query)?, 1);
+            assert!(db
+                .create_index_with_collation("Person", "name", Collation::Binary)
+                .is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn runs_queries_reproducibly() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            let mut run = |db: &mut GramDatabase, query: &str, options: RunOptions| {
+                db.run_with_options(query, &vec![], &options, &mut cursor)?;
+                let mut rows = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    rows.push(row.slots.clone());
+                }
+                Ok::<_, Error>(rows)
+            };
+            let seeded = |seed| RunOptions {
+                seed: Some(seed),
+                ..Default::default()
+            };
+            let query = "UNWIND range(1, 3) AS i RETURN rand() AS r";
+            let first = run(&mut db, query, seeded(7))?;
+            assert_eq!(run(&mut db, query, seeded(7))?, first);
+            assert_ne!(run(&mut db, query, seeded(8))?, first);
+            assert_ne!(run(&mut db, query, RunOptions::default())?, first);
+            for row in &first {
+                assert!(matches!(row[0], Val::Float(r) if (0.0..1.0).contains(&r)));
+            }
+            assert_ne!(first[0], first[1]);
+
+            // Deterministic runs are seeded too, and sort what the graph keeps unordered
+            let deterministic = RunOptions {
+                deterministic: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                run(&mut db, query, deterministic)?,
+                run(&mut db, query, seeded(0))?
+            );
+            run(
+                &mut db,
+                "CREATE (:B:C:A {b: 1, c: 2, a: 3})-[:R {y: 1, z: 2, x: 3}]->()",
+                deterministic,
+            )?;
+            let rows = run(&mut db, "MATCH (n:A)-[r]->() RETURN n, r", deterministic)?;
+            match rows[0].as_slice() {
+                [Val::Node(n), Val::Rel(r)] => {
+                    assert_eq!(n.labels, vec!["A", "B", "C"]);
+                    let keys =
+                        |props: &Map| props.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+                    assert_eq!(keys(&n.props), vec!["a", "b", "c"]);
+                    assert_eq!(keys(&r.props), vec!["x", "y", "z"]);
+                }
+                other => panic!("expected a node and a rel, got {:?}", other),
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn completes_what_the_graph_and_backend_have() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let complete = |db: &mut GramDatabase, query: &str| -> Vec<String> {
+                let completions = db.completions(query, query.len());
+                completions.candidates.into_iter().map(|c| c.text).collect()
+            };
+            assert!(complete(&mut db, "MATCH (n:").is_empty());
+
+            let mut cursor = db.new_cursor();
+            db.run("CREATE (:Person)-[:KNOWS]->(:Place)", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(complete(&mut db, "MATCH (n:P"), vec!["Person", "Place"]);
+            assert_eq!(complete(&mut db, "MATCH (n)-[:K"), vec!["KNOWS"]);
+            assert_eq!(
+                complete(&mut db, "MATCH (n) RETURN ra"),
+                vec!["rand", "range"]
+            );
+            assert_eq!(
+                complete(&mut db, "CALL g"),
+                vec!["gen.graph", "graph.project"]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn drops_indexes_under_running_queries() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "UNWIND range(1, 100) AS i CREATE (:Person {age: i})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.create_index("Person", "age")?;
+            let query = "MATCH (n:Person) WHERE n.age > 90 RETURN n.age";
+            let stmt =
+                db.prepare("MATCH (n:Person) WHERE n.age = $age CREATE (n)-[:SEEN]->(:Log)")?;
+
+            // A query that started out seeking the index finishes with it
+            let mut running = db.new_cursor().with_stats();
+            db.run(query, &mut running)?;
+            assert!(running.next()?.is_some());
+            db.drop_index("Person", "age")?;
+            let mut rows = 1;
+            while running.next()?.is_some() {
+                rows += 1;
+            }
+            assert_eq!(rows, 10);
+            let ops: Vec<&str> = running
+                .stats()
+                .unwrap()
+                .operators
+                .iter()
+                .map(|op| op.name)
+                .collect();
+            assert!(ops.contains(&"NodeIndexSeek"), "{:?}", ops);
+
+            // Queries planned from then on don't seek it, including ones planned before
+            let plan = crate::testing::plan_of(&mut db, query)?;
+            assert!(plan.contains("NodeScan"), "{}", plan);
+            let ages = (1..=3).map(|age| vec![("age".to_string(), Val::Int(age))]);
+            assert_eq!(stmt.execute_batch(&mut db, ages)?, 3);
+            assert_eq!(count(&mut db, "MATCH (:Log) RETURN count(*)")?, 3);
+            assert!(db.drop_index("Person", "age").is_err());
+
+            db.create_index("Person", "age")?;
+            let plan = crate::testing::plan_of(&mut db, query)?;
+            assert!(plan.contains("NodeIndexSeek"), "{}", plan);
+            Ok(())
+        }
+
+        #[test]
+        fn rebuilds_indexes_under_running_queries() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "UNWIND range(1, 100) AS i CREATE (:Person {age: i}), (:Person)",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            db.create_index("Person", "age")?;
+            let health = db.index_health();
+            assert_eq!(health.len(), 1);
+            assert_eq!(
+                (health[0].label.as_str(), health[0].property.as_str()),
+                ("Person", "age")
+            );
+            assert_eq!((health[0].entries, health[0].expected), (100, 100));
+            assert!(!health[0].is_stale());
+            assert!(health[0].built_at.is_some());
+            assert_eq!(health[0].rebuilds, 0);
+
+            // A query seeking the index while it's rebuilt finishes with the old one
+            let query = "MATCH (n:Person) WHERE n.age > 90 RETURN n.age";
+            db.run(query, &mut cursor)?;
+            assert!(cursor.next()?.is_some());
+            db.rebuild_index("Person", "age")?;
+            let mut rows = 1;
+            while cursor.next()?.is_some() {
+                rows += 1;
+            }
+            assert_eq!(rows, 10);
+
+            // The new one is sought from then on, and kept up to date like the old one was
+            let plan = crate::testing::plan_of(&mut db, query)?;
+            assert!(plan.contains("NodeIndexSeek"), "{}", plan);
+            db.run("CREATE (:Person {age: 101})", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(
+                count(&mut db, "MATCH (n:Person) WHERE n.age > 90 RETURN count(n)")?,
+                11
+            );
+            let health = db.index_health();
+            assert_eq!((health[0].entries, health[0].expected), (101, 101));
+            assert_eq!(health[0].rebuilds, 1);
+
+            assert!(db.rebuild_index("Person", "name").is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn reads_annotated_gram_values() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "({i: -5, f: -0.5, n: 1::float, s: 1::string, b: 'true'::bool, \
+                  nan: 'NaN'::float, xs: [1, [2.0], 'a', null], id: -5x, nil: null})",
+            )?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "MATCH (n) RETURN n.i, n.f, n.n, n.s, n.b, n.xs, n.id, n.nil, n.nan",
+                &mut cursor,
+            )?;
+            let row = cursor.next()?.unwrap();
+            assert_eq!(
+                row.slots[..8],
+                [
+                    Val::Int(-5),
+                    Val::Float(-0.5),
+                    Val::Float(1.0),
+                    Val::String("1".into()),
+                    Val::Bool(true),
+                    Val::List(
+                        vec![
+                            Val::Int(1),
+                            Val::List(vec![Val::Float(2.0)].into()),
+                            Val::String("a".into()),
+                            Val::Null,
+                        ]
+                        .into()
+                    ),
+                    Val::String("-5x".into()),
+                    Val::Null,
+                ]
+            );
+            assert!(matches!(row.slots[8], Val::Float(f) if f.is_nan()));
+
+            let err = GramDatabase::from_gram("({x: 'a'::int})").unwrap_err();
+            assert_eq!(err.to_string(), "'a' is not a valid int");
+            Ok(())
+        }
+
+        #[test]
+        fn looks_up_properties_by_computed_names() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(:User {name: \"alice\", email: \"a@example.com\"}) (:User {name: \"bob\"})",
+            )?;
+            let rows =
+                |db: &mut GramDatabase, query: &str, params: &crate::Map| -> Result<Vec<Val>> {
+                    let mut cursor = db.new_cursor();
+                    db.run_with_params(query, params, &mut cursor)?;
+                    let mut out = Vec::new();
+                    while let Some(row) = cursor.next()? {
+                        out.push(row.slots[0].clone());
+                    }
+                    Ok(out)
+                };
+            let strs =
+                |ss: &[&str]| -> Vec<Val> { ss.iter().map(|s| Val::String((*s).into())).collect() };
+            let query = "MATCH (u:User) RETURN u['name'] ORDER BY u.name";
+            assert_eq!(rows(&mut db, query, &vec![])?, strs(&["alice", "bob"]));
+
+            let params = vec![("key".to_string(), Val::String("email".into()))];
+            let query = "MATCH (u:User) WHERE u[$key] = 'a@example.com' RETURN u.name";
+            assert_eq!(rows(&mut db, query, &params)?, strs(&["alice"]));
+            let query = "MATCH (u:User) WITH u, 'email' AS k RETURN u[k] ORDER BY u.name";
+            assert_eq!(
+                rows(&mut db, query, &vec![])?,
+                vec![Val::String("a@example.com".into()), Val::Null]
+            );
+
+            let query = "MATCH (u:User) RETURN keys(u) ORDER BY u.name";
+            assert_eq!(
+                rows(&mut db, query, &vec![])?,
+                vec![
+                    Val::List(strs(&["email", "name"]).into()),
+                    Val::List(strs(&["name"]).into())
+                ]
+            );
+            assert_eq!(
+                rows(&mut db, "RETURN keys({b: 1, a: 2})", &vec![])?,
+                vec![Val::List(strs(&["b", "a"]).into())]
+            );
+
+            assert_eq!(
+                rows(&mut db, "RETURN [1, 2, 3][-1]", &vec![])?,
+                vec![Val::Int(3)]
+            );
+            assert_eq!(
+                rows(&mut db, "RETURN [1, 2, 3][5]", &vec![])?,
+                vec![Val::Null]
+            );
+            assert_eq!(
+                rows(&mut db, "RETURN {a: 1}[null]", &vec![])?,
+                vec![Val::Null]
+            );
+            assert!(rows(&mut db, "RETURN [1, 2, 3]['a']", &vec![]).is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn stops_queries_that_produce_too_many_rows() -> Result<()> {
+            let mut db = GramDatabase::from_gram("({x: 1}) ({x: 1}) ({x: 1}) ({x: 1}) ({x: 1})")?;
+            db.set_max_intermediate_rows(10);
+            assert_eq!(count(&mut db, "MATCH (a) RETURN count(a.x)")?, 5);
+
+            let err = count(&mut db, "MATCH (a), (b) RETURN count(*)").unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::LimitExceeded);
+            assert!(format!("{}", err).contains("NodeScan"), "{}", err);
+
+            // The limit can be set when opening, too
+            let config = GramDatabase::options().max_intermediate_rows(4);
+            let mut db = Database::with_config(
+                gram::GramBackend::from_gram("({x: 1}) ({x: 1}) ({x: 1}) ({x: 1}) ({x: 1})")?,
+                &config,
+            )?;
+            assert!(count(&mut db, "MATCH (a) RETURN count(a.x)").is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn queues_queries_beyond_the_limit() -> Result<()> {
+            use crate::scheduler::QueryState;
+
+            let mut db = GramDatabase::in_memory()?;
+            db.set_query_limits(1, 1);
+            let mut a = db.new_cursor();
+            let mut b = db.new_cursor();
+            let mut c = db.new_cursor();
+            db.run("UNWIND [1, 2] AS x RETURN x", &mut a)?;
+            db.run("RETURN 3", &mut b)?;
+            assert_eq!(a.state(), Some(QueryState::Running));
+            assert_eq!(b.state(), Some(QueryState::Queued));
+            assert!(b.next().is_err());
+            let err = db.run("RETURN 4", &mut c).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::Overloaded);
+
+            a.next()?;
+            assert_eq!(a.state(), Some(QueryState::Streaming));
+            assert!(!db.admit(&mut b)?);
+            while a.next()?.is_some() {}
+            assert_eq!(a.state(), None);
+            assert!(db.admit(&mut b)?);
+            assert_eq!(b.next()?.unwrap().slots, vec![Val::Int(3)]);
+
+            let metrics = db.metrics();
+            assert_eq!(metrics.queries_queued, 1);
+            assert_eq!(metrics.queries_rejected, 1);
+            let queries = db.queries();
+            assert_eq!(queries.len(), 1);
+            assert_eq!(queries[0].query, "RETURN 3");
+            assert_eq!(queries[0].state, QueryState::Streaming);
+            Ok(())
+        }
+
+        #[test]
+        fn kills_queries() -> Result<()> {
+            let mut db = GramDatabase::from_gram("(:Person) (:Person) (:Person)")?;
+            db.set_query_limits(1, 1);
+            let commits = Rc::new(RefCell::new(0));
+            let seen = Rc::clone(&commits);
+            db.on_change(move |_| *seen.borrow_mut() += 1)?;
+            let mut a = db.new_cursor();
+            let mut b = db.new_cursor();
+            db.run("MATCH (p:Person) CREATE (:Copy) RETURN p", &mut a)?;
+            db.run("MATCH (p:Person) RETURN count(p)", &mut b)?;
+            a.next()?;
+
+            let running = db.running_queries();
+            assert_eq!(running.len(), 1);
+            assert_eq!(running[0].query, "MATCH (p:Person) CREATE (:Copy) RETURN p");
+            assert_eq!(running[0].operator, Some("NodeScan"));
+
+            // Killing the executing query makes room for the queued one, and hooks never see what
+            // the killed one wrote
+            assert!(db.kill(running[0].id));
+            assert!(!db.kill(running[0].id));
+            let err = a.next().unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::Cancelled);
+            assert!(db.admit(&mut b)?);
+            assert_eq!(b.next()?.unwrap().slots, vec![Val::Int(3)]);
+            while b.next()?.is_some() {}
+            assert_eq!(*commits.borrow(), 0);
+
+            // Queued queries can be killed before they start
+            db.run("MATCH (p:Person) RETURN p", &mut a)?;
+            db.run("MATCH (p:Person) RETURN p", &mut b)?;
+            let queued = db.queries()[1].id;
+            assert!(db.running_queries().iter().all(|q| q.id != queued));
+            assert!(db.kill(queued));
+            let err = db.admit(&mut b).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::Cancelled);
+            assert_eq!(db.metrics().queries_cancelled, 2);
+            Ok(())
+        }
+
+        #[test]
+        fn opens_with_options() -> Result<()> {
+            use crate::Durability;
+
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("graph.gram");
+            {
+                let mut db = GramDatabase::options()
+                    .change_log(true)
+                    .durability(Durability::Buffered)
+                    .cache_size(1)
+                    .open(&path)?;
+                let mut cursor = db.new_cursor();
+                db.run("CREATE (:Person {name: 'a'})", &mut cursor)?;
+                while cursor.next()?.is_some() {}
+            }
+            assert_eq!(std::fs::metadata(&path)?.len(), 0);
+            assert!(std::fs::metadata(dir.path().join("graph.gram.log"))?.len() > 0);
+
+            let mut db = GramDatabase::options()
+                .change_log(true)
+                .read_only(true)
+                .open(&path)?;
+            assert_eq!(count(&mut db, "MATCH (n:Person) RETURN count(n)")?, 1);
+            let mut cursor = db.new_cursor();
+            let err = db.run("CREATE ()", &mut cursor).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::ReadOnly);
+            let err = db.generate_graph("random", 10, 1, vec![]).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::ReadOnly);
+            let err = db.compact().unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::ReadOnly);
+
+            let missing = GramDatabase::options()
+                .read_only(true)
+                .open(dir.path().join("nope.gram"));
+            assert_eq!(
+                crate::error::kind(&missing.unwrap_err()),
+                crate::ErrorKind::IoError
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn calls_views() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(a:Person {name: 'a', age: 10})-[:KNOWS]->(b:Person {name: 'b', age: 20})
+                 (a)-[:KNOWS]->(c:Person {name: 'c', age: 30})-[:KNOWS]->(b)",
+            )?;
+            let rows = |db: &mut GramDatabase, query: &str| -> Result<Vec<String>> {
+                let mut cursor = db.new_cursor();
+                db.run(query, &mut cursor)?;
+                let mut out = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    let vals: Vec<String> = row.slots.iter().map(|v| v.to_string()).collect();
+                    out.push(vals.join(" "));
+                }
+                out.sort();
+                Ok(out)
+            };
+
+            db.define_view(
+                "older_than",
+                "MATCH (n:Person) WHERE n.age > $age RETURN n.name AS name, n",
+            )?;
+            assert_eq!(
+                rows(&mut db, "CALL older_than(15) YIELD name RETURN name")?,
+                vec!["b", "c"]
+            );
+            // Once per row, with the arguments of that row; the names of the view and the query
+            // don't mix
+            assert_eq!(
+                rows(
+                    &mut db,
+                    "MATCH (n:Person) CALL older_than(n.age) YIELD name AS older RETURN n.name, older"
+                )?,
+                vec!["a b", "a c", "b c"]
+            );
+
+            db.define_view(
+                "friends",
+                "MATCH (p:Person {name: $name})-[:KNOWS]->(f) RETURN count(f) AS friends",
+            )?;
+            assert_eq!(
+                rows(
+                    &mut db,
+                    "MATCH (p:Person)-[:KNOWS]->() WITH DISTINCT p CALL friends(p.name) YIELD friends RETURN p.name, friends"
+                )?,
+                vec!["a 2", "c 1"]
+            );
+
+            // Redefining a view changes the queries calling it, even ones already planned
+            db.define_view(
+                "older_than",
+                "MATCH (n:Person) WHERE n.age > ($age + 10) RETURN n.name AS name, n",
+            )?;
+            assert_eq!(
+                rows(&mut db, "CALL older_than(15) YIELD name RETURN name")?,
+                vec!["c"]
+            );
+
+            db.define_view("loop", "RETURN 1 AS x")?;
+            db.define_view("loop2", "CALL loop() YIELD x RETURN x")?;
+            assert!(db
+                .define_view("loop", "CALL loop2() YIELD x RETURN x")
+                .is_err());
+            assert!(db.define_view("nope", "MATCH (n) WITH n").is_err());
+            assert!(db
+                .define_view("db.schema.visualization", "RETURN 1 AS x")
+                .is_err());
+            let mut cursor = db.new_cursor();
+            let err = db.run("CALL older_than()", &mut cursor).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::SemanticError);
+            Ok(())
+        }
+
+        #[test]
+        fn binds_parameters() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            let params = vec![("x".to_string(), Val::Int(2))];
+            db.run_with_params("RETURN $x + 1 AS y", &params, &mut cursor)?;
+            assert_eq!(cursor.next()?.unwrap().slots, vec![Val::Int(3)]);
+
+            let err = db.run("RETURN $x + 1 AS y", &mut cursor).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::SemanticError);
+            Ok(())
+        }
+
+        #[test]
+        fn pages_with_parameters() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            let query = "UNWIND [1, 2, 3, 4, 5] AS x RETURN x SKIP $offset LIMIT $n";
+            let mut page = |offset: Val, n: Val| -> Result<Vec<Val>> {
+                let params = vec![("offset".to_string(), offset), ("n".to_string(), n)];
+                db.run_with_params(query, &params, &mut cursor)?;
+                let mut rows = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    rows.push(row.slots[0].clone());
+                }
+                Ok(rows)
+            };
+            assert_eq!(
+                page(Val::Int(1), Val::Int(2))?,
+                vec![Val::Int(2), Val::Int(3)]
+            );
+            assert_eq!(page(Val::Int(4), Val::Int(2))?, vec![Val::Int(5)]);
+            assert_eq!(page(Val::Int(0), Val::Int(0))?, vec![]);
+
+            let err = page(Val::Int(-1), Val::Int(2)).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::SemanticError);
+            let err = page(Val::Int(0), Val::Int(-1)).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::SemanticError);
+            let err = page(Val::Int(0), Val::String("2".into())).unwrap_err();
+            assert_eq!(crate::error::kind(&err), crate::ErrorKind::TypeError);
+
+            // These are refused before the query runs
+            for query in &[
+                "MATCH (n) RETURN n LIMIT n.count",
+                "RETURN 1 LIMIT 1.5",
+                "RETURN 1 SKIP 'a'",
+            ] {
+                let err = db.run(query, &mut cursor).unwrap_err();
+                assert_eq!(
+                    crate::error::kind(&err),
+                    crate::ErrorKind::SemanticError,
+                    "{}",
+                    query
+                );
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn summarizes_what_queries_wrote() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (a:Person:User {name: 'a', age: null})-[:KNOWS {since: 2001}]->(b)",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(
+                cursor.summary(),
+                Some(QuerySummary {
+                    nodes_created: 2,
+                    relationships_created: 1,
+                    properties_set: 2,
+                    labels_added: 2,
+                    ..Default::default()
+                })
+            );
+
+            db.run("MATCH (n) RETURN n", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert!(!cursor.summary().unwrap().contains_updates());
+            Ok(())
+        }
+
+        #[test]
+        fn executes_queries_without_building_rows() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let created =
+                db.execute("UNWIND range(1, 3) AS i CREATE (:Person {id: i})", &vec![])?;
+            assert_eq!(created.rows, 0);
+            assert_eq!(created.summary.unwrap().nodes_created, 3);
+
+            let params = vec![("id".to_string(), Val::Int(2))];
+            let found = db.execute("MATCH (n:Person) WHERE n.id = $id RETURN n", &params)?;
+            assert_eq!(found.rows, 1);
+            assert!(!found.summary.unwrap().contains_updates());
+            assert_eq!(db.execute("MATCH (n) RETURN n.id", &vec![])?.rows, 3);
+            assert_eq!(db.metrics().rows_produced, 4);
+            Ok(())
+        }
+
+        #[test]
+        fn tells_hooks_what_each_commit_changed() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let commits = Rc::new(RefCell::new(Vec::new()));
+            let seen = Rc::clone(&commits);
+            db.on_change(move |changes| seen.borrow_mut().push(changes.to_vec()))?;
+
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (a:Person {name: 'Alice'})-[:KNOWS]->(b)",
+                &mut cursor,
+            )?;
+            // Nothing is committed until the result is exhausted
+            assert!(commits.borrow().is_empty());
+            assert!(cursor.next()?.is_none());
+            assert_eq!(commits.borrow().len(), 1);
+            match commits.borrow()[0].as_slice() {
+                [Change::NodeCreated(a), Change::NodeCreated(b), Change::RelCreated(r)] => {
+                    assert_eq!(a.labels, vec!["Person".to_string()]);
+                    assert_eq!(
+                        a.props,
+                        vec![("name".to_string(), Val::String("Alice".into()))]
+                    );
+                    assert_eq!((r.start, r.end), (a.id, b.id));
+                    assert_eq!(r.rel_type, "KNOWS");
+                }
+                other => panic!("expected two nodes and a rel, got {:?}", other),
+            }
+
+            // Nor do queries that fail
+            db.run(
+                "CREATE (:C) WITH 1 AS x RETURN 9223372036854775807 + x",
+                &mut cursor,
+            )?;
+            assert!(cursor.next().is_err());
+
+            assert_eq!(commits.borrow().len(), 1);
+
+            // A batch is handed over in one go, when it ends
+            db.import_cypher("CREATE (:A); CREATE (:B);".as_bytes())?;
+            assert_eq!(commits.borrow().len(), 2);
+            assert_eq!(commits.borrow()[1].len(), 2);
+            Ok(())
+        }
+
+        #[test]
+        fn sweeps_expired_nodes() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            db.expire_nodes("Session", "expiresAt")?;
+            db.create_index("Session", "token")?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (u:User)-[:HAS]->(a:Session {token: 'a', expiresAt: 1000}), \
+                 (a)-[:NEXT]->(:Session {token: 'b', expiresAt: 2000}), (a)-[:SAME]->(a), \
+                 (:Session {token: 'c'}), (:Session {token: 'd', expiresAt: 'never'}), \
+                 (:Other {expiresAt: 0})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            let commits = Rc::new(RefCell::new(Vec::new()));
+            let seen = Rc::clone(&commits);
+            db.on_change(move |changes| seen.borrow_mut().push(changes.to_vec()))?;
+
+            // Queries that are still running have the nodes in their rows
+            db.run("MATCH (s:Session) RETURN s", &mut cursor)?;
+            assert!(db.sweep_expired_at(1000).is_err());
+            while cursor.next()?.is_some() {}
+
+            let summary = db.sweep_expired_at(1000)?;
+            assert_eq!(
+                (summary.nodes_deleted, summary.relationships_deleted),
+                (1, 3)
+            );
+            assert_eq!(count(&mut db, "MATCH (s:Session) RETURN count(s)")?, 3);
+            assert_eq!(count(&mut db, "MATCH ()-->() RETURN count(*)")?, 0);
+            // Nor does the index find it any more
+            let found =
+                db.query_as::<RowView>("MATCH (s:Session {token: 'a'}) RETURN s", &vec![])?;
+            assert!(found.is_empty());
+            assert_eq!(
+                count(&mut db, "MATCH (s:Session {token: 'b'}) RETURN count(s)")?,
+                1
+            );
+            assert_eq!(count(&mut db, "MATCH (n) RETURN count(n)")?, 5);
+            assert_eq!(commits.borrow().len(), 1);
+            // The rels go first, then the node they were attached to
+            match commits.borrow()[0].as_slice() {
+                [rels @ .., Change::NodeDeleted(a)] if rels.len() == 3 => {
+                    assert!(rels.iter().all(|r| matches!(r, Change::RelDeleted(_))));
+                    assert_eq!(a.labels, vec!["Session".to_string()]);
+                }
+                other => panic!("expected three rels and a node, got {:?}", other),
+            }
+
+            // Nothing more has expired as of then
+            assert!(!db.sweep_expired_at(1000)?.contains_updates());
+
+            // 2000 milliseconds into 1970 is long gone
+            db.set_sweep_on_read(true);
+            assert_eq!(count(&mut db, "MATCH (s:Session) RETURN count(s)")?, 2);
+            assert_eq!(count(&mut db, "MATCH (n:Other) RETURN count(n)")?, 1);
+            Ok(())
+        }
+
+        #[test]
+        fn keeps_expired_nodes_when_the_sweep_fails() -> Result<()> {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("graph.gram");
+            std::fs::write(
+                &path,
+                "(a:Session {expiresAt: 1})-[:NEXT]->(b:Session {expiresAt: 1}) (:Session)",
+            )?;
+
+            // A file opened for reading only fails the write
+            let mut db = GramDatabase::open(File::open(&path)?)?;
+            db.expire_nodes("Session", "expiresAt")?;
+            assert!(db.sweep_expired_at(1).is_err());
+            assert_eq!(count(&mut db, "MATCH (s:Session) RETURN count(s)")?, 3);
+            assert_eq!(count(&mut db, "MATCH ()-->() RETURN count(*)")?, 1);
+
+            let mut db =
+                GramDatabase::open(OpenOptions::new().read(true).write(true).open(&path)?)?;
+            db.expire_nodes("Session", "expiresAt")?;
+            let commits = Rc::new(RefCell::new(Vec::new()));
+            let seen = Rc::clone(&commits);
+            db.on_change(move |changes| seen.borrow_mut().push(changes.to_vec()))?;
+            let summary = db.sweep_expired_at(1)?;
+            assert_eq!(
+                (summary.nodes_deleted, summary.relationships_deleted),
+                (2, 1)
+            );
+            // The rel between them is only deleted the once
+            let rels = commits.borrow()[0]
+                .iter()
+                .filter(|c| matches!(c, Change::RelDeleted(_)))
+                .count();
+            assert_eq!(rels, 1);
+            assert_eq!(count(&mut db, "MATCH (s:Session) RETURN count(s)")?, 1);
+            Ok(())
+        }
+
+        #[test]
+        fn replays_deletions_from_the_change_log() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            let log = tempfile::tempfile()?;
+            {
+                let mut db = GramDatabase::open_with_log(file.try_clone()?, log.try_clone()?)?;
+                db.expire_nodes("Session", "expiresAt")?;
+                let mut cursor = db.new_cursor();
+                db.run(
+                    "CREATE (:User)-[:HAS]->(:Session {expiresAt: 1}), (:Session {expiresAt: 5})",
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+                assert_eq!(db.sweep_expired_at(1)?.nodes_deleted, 1);
+            }
+
+            let mut db = GramDatabase::open_with_log(file.try_clone()?, log.try_clone()?)?;
+            assert_eq!(count(&mut db, "MATCH (s:Session) RETURN count(s)")?, 1);
+            assert_eq!(count(&mut db, "MATCH ()-->() RETURN count(*)")?, 0);
+            db.compact()?;
+
+            file.seek(SeekFrom::Start(0))?;
+            let mut compacted = String::new();
+            file.read_to_string(&mut compacted)?;
+            assert!(!compacted.contains("expiresAt: 1"), "{}", compacted);
+            file.seek(SeekFrom::Start(0))?;
+            let mut db = GramDatabase::open(file)?;
+            assert_eq!(count(&mut db, "MATCH (n) RETURN count(n)")?, 2);
+            Ok(())
+        }
+
+        #[test]
+        fn reuses_the_ids_of_deleted_nodes_and_rels() -> Result<()> {
+            // The id of the node, or rel, in the one row the query gives back
+            fn id(db: &mut GramDatabase, query: &str) -> Result<usize> {
+                let mut cursor = db.new_cursor();
+                db.run(query, &mut cursor)?;
+                let id = match &cursor.next()?.expect("a row").slots[0] {
+                    Val::Node(n) => n.id,
+                    Val::Rel(r) => r.id,
+                    v => bail!("expected a node or rel, got {:?}", v),
+                };
+                assert!(cursor.next()?.is_none());
+                Ok(id)
+            }
+
+            let file = tempfile::tempfile()?;
+            let log = tempfile::tempfile()?;
+            let mut db = GramDatabase::open_with_log(file.try_clone()?, log.try_clone()?)?;
+            db.expire_nodes("Session", "expiresAt")?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (:User)-[:HAS]->(:Session {expiresAt: 1}), (:Session {expiresAt: 5})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            let node = id(&mut db, "MATCH (s:Session {expiresAt: 1}) RETURN s")?;
+            let rel = id(&mut db, "MATCH ()-[r]->() RETURN r")?;
+            assert_eq!(db.sweep_expired_at(1)?.nodes_deleted, 1);
+
+            let created = id(&mut db, "CREATE (s:Session {expiresAt: 9}) RETURN s")?;
+            assert_eq!(created, node);
+            let created = id(
+                &mut db,
+                "MATCH (u:User), (s:Session {expiresAt: 9}) CREATE (u)-[r:HAS]->(s) RETURN r",
+            )?;
+            assert_eq!(created, rel);
+            assert_eq!(count(&mut db, "MATCH (n) RETURN count(n)")?, 3);
+            assert_eq!(count(&mut db, "MATCH ()-->() RETURN count(*)")?, 1);
+
+            // A node that takes the id of one that's deleted isn't scanned by the query that
+            // created it, any more than one that's added at the end
+            assert_eq!(db.sweep_expired_at(9)?.nodes_deleted, 2);
+            db.run("MATCH (n) CREATE (:Copy)", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(count(&mut db, "MATCH (n:Copy) RETURN count(n)")?, 1);
+
+            // Ids aren't kept in the log, nodes are numbered in the order they are replayed, but
+            // the nodes that are deleted once it is leave their ids free all the same: four
+            // sessions were deleted, so four new nodes fit in alongside the User and the Copy
+            drop(cursor);
+            drop(db);
+            let mut db = GramDatabase::open_with_log(file, log)?;
+            let mut cursor = db.new_cursor();
+            db.run("UNWIND range(1, 4) AS i CREATE (:Session)", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            db.run("MATCH (n) RETURN n", &mut cursor)?;
+            let mut ids = vec![];
+            while let Some(row) = cursor.next()? {
+                match &row.slots[0] {
+                    Val::Node(n) => ids.push(n.id),
+                    v => bail!("expected a node, got {:?}", v),
+                }
+            }
+            ids.sort_unstable();
+            assert_eq!(ids, (0..6).collect::<Vec<_>>());
+            Ok(())
+        }
+
+        #[test]
+        fn reused_cursor_only_has_the_columns_of_the_current_query() -> Result<()> {
+            let mut db = GramDatabase::open(tempfile::tempfile()?)?;
+            let mut cursor = db.new_cursor().with_capacity(4);
+            db.run("RETURN 1, 2, 3", &mut cursor)?;
+            assert_eq!(cursor.next()?.unwrap().slots.len(), 3);
+            db.run("RETURN 1", &mut cursor)?;
+            assert_eq!(cursor.next()?.unwrap().slots, vec![Val::Int(1)]);
+
+            cursor.reset()?;
+            assert!(cursor.next().is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn creates_the_overflow_file_when_a_value_needs_it() -> Result<()> {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("graph.gram");
+            let overflow = dir.path().join("graph.gram.overflow");
+            std::fs::write(&path, "(a:Doc {title: 'a'})")?;
+            {
+                let mut db = GramDatabase::options().read_only(true).open(&path)?;
+                assert_eq!(count(&mut db, "MATCH (n:Doc) RETURN count(n)")?, 1);
+            }
+            assert!(!overflow.exists());
+
+            let mut db = GramDatabase::options().open(&path)?;
+            assert_eq!(count(&mut db, "MATCH (n:Doc) RETURN count(n)")?, 1);
+            let mut cursor = db.new_cursor();
+            db.run("CREATE (:Doc {title: 'b'})", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            assert!(!overflow.exists());
+
+            let doc = "x".repeat(5000);
+            db.run(
+                &format!("CREATE (:Doc {{title: 'c', body: '{}'}})", doc),
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+            assert_eq!(std::fs::metadata(&overflow)?.len(), 5000);
+            db.run("MATCH (n:Doc {title: 'c'}) RETURN n.body", &mut cursor)?;
+            assert_eq!(cursor.next()?.unwrap().slots[0], Val::String(doc.into()));
+            Ok(())
+        }
+
+        #[test]
+        fn keeps_large_values_in_an_overflow_file() -> Result<()> {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("graph.gram");
+            let overflow = dir.path().join("graph.gram.overflow");
+            let doc = "x".repeat(5000);
+            // A gram file from before there was an overflow file, with the value inline
+            std::fs::write(&path, format!("(a:Doc {{body: '{}'}})", doc))?;
+            {
+                let mut db = GramDatabase::options().open(&path)?;
+                let mut cursor = db.new_cursor();
+                db.run(
+                    &format!("CREATE (:Doc {{body: '{}', title: 'b'}})", doc),
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+                assert_eq!(std::fs::metadata(&overflow)?.len(), 5000);
+
+                db.compact()?;
+                assert!(std::fs::metadata(&path)?.len() < 200);
+                assert_eq!(std::fs::metadata(&overflow)?.len(), 10000);
+            }
+
+            let mut db = GramDatabase::options().open(&path)?;
+            let mut cursor = db.new_cursor();
+            db.run("MATCH (n:Doc) RETURN n.body, n", &mut cursor)?;
+            for _ in 0..2 {
+                let row = cursor.next()?.unwrap();
+                assert_eq!(row.slots[0], Val::String(doc.as_str().into()));
+                match &row.slots[1] {
+                    Val::Node(n) => {
+                        assert!(n.props.contains(&("body".into(), row.slots[0].clone())))
+                    }
+                    v => panic!("expected a node, got {:?}", v),
+                }
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn writes_repeated_strings_once() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            let gram = |file: &mut File| -> Result<String> {
+                let mut gram = String::new();
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_string(&mut gram)?;
+                Ok(gram)
+            };
+            {
+                let mut db = GramDatabase::open(file.try_clone()?)?;
+                for name in &["a", "b", "c", "d"] {
+                    let query = format!(
+                        "CREATE (:Person {{name: '{}', country: 'Sweden', bio: 'it\\'s'}})",
+                        name
+                    );
+                    let mut cursor = db.new_cursor();
+                    db.run(&query, &mut cursor)?;
+                    while cursor.next()?.is_some() {}
+                }
+                // Written in full the first time, as an entry the second, and referred to after
+                assert_eq!(gram(&mut file)?.matches("'Sweden'").count(), 2);
+                assert_eq!(gram(&mut file)?.matches("@0").count(), 4);
+
+                db.compact()?;
+                assert_eq!(gram(&mut file)?.matches("'Sweden'").count(), 1);
+            }
+
+            file.seek(SeekFrom::Start(0))?;
+            let mut db = GramDatabase::open(file)?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "MATCH (n:Person {country: 'Sweden'}) RETURN count(n), min(n.bio)",
+                &mut cursor,
+            )?;
+            assert_eq!(
+                cursor.next()?.unwrap().slots,
+                vec![Val::Int(4), Val::String("it's".into())]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn supports_quoted_and_unicode_identifiers() -> Result<()> {
+            let file = tempfile::tempfile()?;
+            {
+                let mut db = GramDatabase::open(file.try_clone()?)?;
+                let mut cursor = db.new_cursor();
+                db.run(
+                    "CREATE (:`Label With Space` {`weird name`: 1, größe: 2})-[:`ÄR ``MED```]->(:Größe)",
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+            }
+            let mut db = GramDatabase::open(file)?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "MATCH (`a b`:`Label With Space`)-[:`ÄR ``MED```]->(:Größe) RETURN `a b`.`weird name` + `a b`.größe",
+                &mut cursor,
+            )?;
+            assert_eq!(cursor.next()?.unwrap().slots, vec![Val::Int(3)]);
+            Ok(())
+        }
+
+        fn count(db: &mut GramDatabase, query: &str) -> Result<i64> {
+            let mut cursor = db.new_cursor();
+            db.run(query, &mut cursor)?;
+            match cursor.next()? {
+                Some(row) => match row.slots[0] {
+                    Val::Int(n) => Ok(n),
+                    ref v => bail!("expected a count, got {:?}", v),
+                },
+                None => bail!("expected a count, got no rows"),
+            }
+        }
+
+        #[test]
+        fn runs_queries_against_an_in_memory_graph() -> Result<()> {
+            let mut db = GramDatabase::from_gram("(a:Person {name: 'a'})-[:KNOWS]->(b:Person)")?;
+            let mut cursor = db.new_cursor();
+            db.run("CREATE (:Person {name: 'c'})", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            // Nothing to write back to
+            db.compact()?;
+
+            assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 3);
+            assert_eq!(
+                count(&mut db, "MATCH (a)-[:KNOWS]->(b) RETURN count(a)")?,
+                1
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn counts_nodes_and_rels_without_scanning() -> Result<()> {
+            // A node's labels can be spread out over the gram file
+            let mut db = GramDatabase::from_gram(
+                "(a:Person)-[:KNOWS]->(b:Person) (a:Admin) (a:Person)-[:KNOWS]->(a) (b)-[:OWNS]->(:Car)",
+            )?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (:Person:Admin)-[:KNOWS]->(:Person), ()",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+
+            assert_eq!(count(&mut db, "MATCH (n:Person) RETURN count(n)")?, 4);
+            assert_eq!(count(&mut db, "MATCH (n:Admin) RETURN count(*)")?, 2);
+            assert_eq!(count(&mut db, "MATCH (n:Nope) RETURN count(n)")?, 0);
+            assert_eq!(count(&mut db, "MATCH (n) RETURN count(n)")?, 6);
+            assert_eq!(count(&mut db, "MATCH ()-[r:KNOWS]->() RETURN count(r)")?, 3);
+            assert_eq!(count(&mut db, "MATCH (a)<-[r]-(b) RETURN count(b)")?, 4);
+            Ok(())
+        }
+
+        #[test]
+        fn matches_label_expressions() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(:Person:Admin) (:Person) (:Bot) (:Person:Bot) (:Car) (:Admin)",
+            )?;
+            assert_eq!(count(&mut db, "MATCH (n:Person:Admin) RETURN count(*)")?, 1);
+            assert_eq!(count(&mut db, "MATCH (n:Admin:Person) RETURN count(*)")?, 1);
+            assert_eq!(count(&mut db, "MATCH (n:Person|Bot) RETURN count(*)")?, 4);
+            assert_eq!(
+                count(&mut db, "MATCH (n:Bot|Admin:Person) RETURN count(*)")?,
+                2
+            );
+
+            let mut cursor = db.new_cursor();
+            db.run("OPTIONAL MATCH (n:Car:Bot) RETURN n", &mut cursor)?;
+            assert_eq!(cursor.next()?.unwrap().slots, vec![Val::Null]);
+            Ok(())
+        }
+
+        #[test]
+        fn evaluates_nested_function_calls() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let vals = db.query_as::<Vec<Val>>(
+                "RETURN NOT NOT true, NOT abs(1) = 1, abs(abs(-2)), keys({a: abs(-1)})",
+                &vec![],
+            )?;
+            assert_eq!(
+                vals,
+                vec![vec![
+                    Val::Bool(true),
+                    Val::Bool(false),
+                    Val::Int(2),
+                    Val::from(vec!["a"])
+                ]]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn refuses_unknown_functions_and_wrong_arg_counts() -> Result<()> {
+            let mut db = GramDatabase::in_memory()?;
+            let mut cursor = db.new_cursor();
+            for (query, message, column) in &[
+                (
+                    "RETURN foo(1)",
+                    "There is no function with the name `foo`",
+                    8,
+                ),
+                (
+                    "RETURN size(range(1, 3))",
+                    "There is no function with the name `size`",
+                    8,
+                ),
+                (
+                    "RETURN abs(1, 2)",
+                    "`abs` takes 1 argument, but was given 2",
+                    8,
+                ),
+                (
+                    "RETURN rand(1)",
+                    "`rand` takes 0 arguments, but was given 1",
+                    8,
+                ),
+                (
+                    "RETURN 1 + range(1)",
+                    "`range` takes 2 to 3 arguments, but was given 1",
+                    12,
+                ),
+            ] {
+                let err = db.run(query, &mut cursor).unwrap_err();
+                match err.downcast_ref::<crate::QueryError>() {
+                    Some(crate::QueryError::SemanticError {
+                        message: m,
+                        span: Some(span),
+                    }) => assert_eq!((m.as_str(), span.column), (*message, *column)),
+                    _ => panic!("expected a semantic error for {}, got {}", query, err),
+                }
+            }
+            let vals = db.query_as::<Vec<Val>>("RETURN range(1, 5, 2)", &vec![])?;
+            assert_eq!(vals, vec![vec![Val::from(vec![1, 3, 5])]]);
+            Ok(())
+        }
+
+        #[test]
+        fn evaluates_or_with_nulls() -> Result<()> {
+            let mut db =
+                GramDatabase::from_gram("({name: 'a'}) ({name: 'b'}) ({name: 'c', x: true})")?;
+            let names = db.query_as::<(String,)>(
+                "MATCH (n) WHERE n.missing OR n.name = 'a' OR n.x RETURN n.name ORDER BY n.name",
+                &vec![],
+            )?;
+            assert_eq!(names, vec![("a".to_string(),), ("c".to_string(),)]);
+
+            // True wins over null, and null over false; same the other way around for AND
+            let logic = db.query_as::<Vec<Val>>(
+                "RETURN null OR true, null OR false, null AND false, null AND true",
+                &vec![],
+            )?;
+            assert_eq!(
+                logic,
+                vec![vec![
+                    Val::Bool(true),
+                    Val::Null,
+                    Val::Bool(false),
+                    Val::Null
+                ]]
+            );
+            let err = db.query_as::<RowView>("MATCH (n) WHERE n.name OR true RETURN n", &vec![]);
+            assert!(err.is_err());
+            Ok(())
+        }
+
+        #[test]
+        fn joins_disconnected_patterns() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(:User {id: 1}) (:User {id: 2}) (:User {id: 3}) (:Product {id: 1}) (:Product {id: 2})",
+            )?;
+            assert_eq!(
+                count(&mut db, "MATCH (a:User), (b:Product) RETURN count(*)")?,
+                6
+            );
+            assert_eq!(
+                count(
+                    &mut db,
+                    "MATCH (a:User), (b:Product {id: 2}) WHERE a.id > 1 AND a.id = b.id RETURN count(*)"
+                )?,
+                1
+            );
+            assert_eq!(count(&mut db, "MATCH (a), (b), (c) RETURN count(*)")?, 125);
+            Ok(())
+        }
+
+        #[test]
+        fn keeps_rows_an_optional_match_where_rules_out() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(a:A {id: 1})-[:R]->(:B {x: 1}) (a)-[:R]->(:B {x: 2}) (:A {id: 2})-[:R]->(:B {x: 1}) (:A {id: 3})",
+            )?;
+            // Every A is kept; only the B's the WHERE allows are, with null for the rest
+            assert_eq!(
+                count(
+                    &mut db,
+                    "MATCH (a:A) OPTIONAL MATCH (a)-->(b) WHERE b.x > 1 RETURN count(*)"
+                )?,
+                3
+            );
+            assert_eq!(
+                count(
+                    &mut db,
+                    "MATCH (a:A) OPTIONAL MATCH (a)-->(b) WHERE b.x > 1 RETURN count(b)"
+                )?,
+                1
+            );
+            assert_eq!(
+                count(
+                    &mut db,
+                    "MATCH (a:A) OPTIONAL MATCH (a)-->(b) WHERE b.x = 2 \
+                     WITH a, count(b.x) AS n WHERE n = 0 RETURN count(*)"
+                )?,
+                2
+            );
+            assert_eq!(
+                count(
+                    &mut db,
+                    "OPTIONAL MATCH (n:A) WHERE n.id = 4 RETURN count(*)"
+                )?,
+                1
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn orders_missing_properties_and_ties_predictably() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(:P {name: 'a', age: 2}) (:P {name: 'b'}) (:P {name: 'c', age: 1}) (:P {name: 'd', age: 2}) (:P {name: 'e'})",
+            )?;
+            let mut names = |query: &str| -> Result<Vec<Val>> {
+                let mut cursor = db.new_cursor();
+                db.run(query, &mut cursor)?;
+                let mut out = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    out.push(row.slots[0].clone());
+                }
+                Ok(out)
+            };
+            let s = |names: &[&str]| -> Vec<Val> {
+                names.iter().map(|n| Val::String((*n).into())).collect()
+            };
+
+            // Nulls are larger than anything else, and ties keep the order the rows came in
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age")?,
+                s(&["c", "a", "d", "b", "e"])
+            );
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age DESC")?,
+                s(&["b", "e", "a", "d", "c"])
+            );
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age NULLS FIRST")?,
+                s(&["b", "e", "c", "a", "d"])
+            );
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age DESC NULLS LAST, n.name DESC")?,
+                s(&["d", "a", "c", "e", "b"])
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn sorts_only_as_much_as_the_limit_needs() -> Result<()> {
+            let gram: Vec<String> = (0..200)
+                .map(|i| format!("(:P {{name: {}, age: {}}})", i, i % 7))
+                .collect();
+            let mut db = GramDatabase::from_gram(&gram.join(" "))?;
+            let mut names = |query: &str| -> Result<Vec<Val>> {
+                let mut cursor = db.new_cursor();
+                db.run(query, &mut cursor)?;
+                let mut out = Vec::new();
+                while let Some(row) = cursor.next()? {
+                    out.push(row.slots[0].clone());
+                }
+                Ok(out)
+            };
+            // Ties keep the order the rows came in, even across the rows the sort drops as it goes
+            let mut expected: Vec<i64> = (0..200).collect();
+            expected.sort_by_key(|i| i % 7);
+            let expected = |skip: usize, limit: usize| -> Vec<Val> {
+                expected[skip..skip + limit]
+                    .iter()
+                    .map(|i| Val::Int(*i))
+                    .collect()
+            };
+
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age SKIP 40 LIMIT 3")?,
+                expected(40, 3)
+            );
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age LIMIT 100")?,
+                expected(0, 100)
+            );
+            assert_eq!(
+                names("MATCH (n:P) RETURN n.name ORDER BY n.age SKIP 5 LIMIT 0")?,
+                vec![]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn matches_each_rel_once_regardless_of_direction() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(a {name: 'a'})-[:R]->(b {name: 'b'}) (b)-[:R]->(a) (a)-[:R]->(a) (b)-[:R]->(c {name: 'c'})",
+            )?;
+            assert_eq!(
+                count(&mut db, "MATCH (a {name: 'a'})--(x) RETURN count(x)")?,
+                3
+            );
+            assert_eq!(
+                count(&mut db, "MATCH (a {name: 'a'})-->(x) RETURN count(x)")?,
+                2
+            );
+            assert_eq!(
+                count(&mut db, "MATCH (a {name: 'a'})<--(x) RETURN count(x)")?,
+                2
+            );
+            // Four rels, each matched once from either end, except the loop, which has one
+            assert_eq!(count(&mut db, "MATCH ()-[r]-() RETURN count(r)")?, 7);
+            assert_eq!(count(&mut db, "MATCH (n)-[r]-(n) RETURN count(r)")?, 1);
+            // The second hop can't go back along the rel the first one took
+            assert_eq!(
+                count(&mut db, "MATCH (c {name: 'c'})--(b)--(x) RETURN count(x)")?,
+                2
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn describes_the_shape_of_the_graph() -> Result<()> {
+            let mut db = GramDatabase::from_gram(
+                "(a:Person)-[:KNOWS]->(b:Person:Admin)-[:OWNS]->(:Car) (a)-[:KNOWS]->(:Person) ()-[:SEES]->(b)",
+            )?;
+            let mut cursor = db.new_cursor();
+            db.run("CALL db.schema.visualization()", &mut cursor)?;
+            assert_eq!(cursor.fields(), vec!["nodes", "relationships"]);
+            let row = cursor.next()?.unwrap().slots.clone();
+            assert!(cursor.next()?.is_none());
+            let (nodes, rels) = match (&row[0], &row[1]) {
+                (Val::List(nodes), Val::List(rels)) => (nodes.clone(), rels.clone()),
+                other => panic!("expected two lists, got {:?}", other),
+            };
+            let labels: Vec<&str> = nodes
+                .iter()
+                .map(|n| match n {
+                    Val::Node(n) => n.labels[0].as_str(),
+                    other => panic!("expected a node, got {:?}", other),
+                })
+                .collect();
+            assert_eq!(labels, vec!["Admin", "Car", "Person"]);
+            let shapes: Vec<(&str, &str, &str)> = rels
+                .iter()
+                .map(|r| match r {
+                    Val::Rel(r) => (labels[r.start], r.rel_type.as_str(), labels[r.end]),
+                    other => panic!("expected a rel, got {:?}", other),
+                })
+                .collect();
+            assert_eq!(
+                shapes,
+                vec![
+                    ("Admin", "OWNS", "Car"),
+                    ("Person", "KNOWS", "Admin"),
+                    ("Person", "KNOWS", "Person"),
+                    ("Person", "OWNS", "Car"),
+                ]
+            );
+
+            db.run(
+                "CALL db.schema.visualization() YIELD nodes AS ns UNWIND ns AS n RETURN n.name",
+                &mut cursor,
+            )?;
+            let mut names = Vec::new();
+            while let Some(row) = cursor.next()? {
+                names.push(row.slots[0].clone());
+            }
+            assert_eq!(
+                names,
+                vec![
+                    Val::String("Admin".into()),
+                    Val::String("Car".into()),
+                    Val::String("Person".into())
+                ]
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn replays_and_compacts_the_change_log() -> Result<()> {
+            let mut file = tempfile::tempfile()?;
+            let log = tempfile::tempfile()?;
+            {
+                let mut db = GramDatabase::open_with_log(file.try_clone()?, log.try_clone()?)?;
+                let mut cursor = db.new_cursor();
+                db.run(
+                    "CREATE (a:Person {name: 'a'})-[:KNOWS]->(b:Person {name: 'b'})",
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+            }
+            assert_eq!(file.metadata()?.len(), 0);
+            assert!(log.metadata()?.len() > 0);
+
+            let mut db = GramDatabase::open_with_log(file.try_clone()?, log.try_clone()?)?;
+            assert_eq!(
+                count(&mut db, "MATCH (a)-[:KNOWS]->(b) RETURN count(a)")?,
+                1
+            );
+            db.compact()?;
+            assert!(file.metadata()?.len() > 0);
+            assert_eq!(log.metadata()?.len(), 0);
+
+            file.seek(SeekFrom::Start(0))?;
+            let mut db = GramDatabase::open(file)?;
+            assert_eq!(
+                count(&mut db, "MATCH (a)-[:KNOWS]->(b) RETURN count(a)")?,
+                1
+            );
+            assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 2);
+            Ok(())
+        }
+
+        #[test]
+        fn compacts_by_replacing_the_file() -> Result<()> {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("graph.gram");
+            let log_path = dir.path().join("graph.gram.log");
+            let open = || GramDatabase::options().change_log(true).open(&path);
+            let mut db = open()?;
+            let mut cursor = db.new_cursor();
+            db.run(
+                "CREATE (a:Person {name: 'a'})-[:KNOWS]->(b:Person {name: 'b'})",
+                &mut cursor,
+            )?;
+            while cursor.next()?.is_some() {}
+
+            // Someone reading the gram file while it's compacted keeps seeing the old one
+            let mut reader = File::open(&path)?;
+            db.compact()?;
+            db.finish_compaction()?;
+            let mut old = String::new();
+            reader.read_to_string(&mut old)?;
+            assert_eq!(old, "");
+            assert!(std::fs::metadata(&path)?.len() > 0);
+
+            // Writes after compaction go to the log, as before, also once re-opened
+            drop(db);
+            let mut db = open()?;
+            let mut cursor = db.new_cursor();
+            db.run("CREATE (:Person {name: 'c'})", &mut cursor)?;
+            while cursor.next()?.is_some() {}
+            drop(db);
+            let mut db = open()?;
+            assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 3);
+
+            // Crashing after the new file is in place, but before the log is cleared, leaves the
+            // log that was folded in; that mustn't be replayed again
+            let log = std::fs::read(&log_path)?;
+            db.compact()?;
+            drop(db);
+            std::fs::write(&log_path, &log)?;
+            let mut db = open()?;
+            assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 3);
+            assert_eq!(
+                count(&mut db, "MATCH (a)-[:KNOWS]->(b) RETURN count(a)")?,
+                1
+            );
+            Ok(())
+        }
+
+        #[test]
+        fn compacts_while_queries_go_on() -> Result<()> {
+            let dir = tempfile::tempdir()?;
+            let path = dir.path().join("graph.gram");
+            let log_path = dir.path().join("graph.gram.log");
+            let open = |path: &Path| GramDatabase::options().change_log(true).open(path);
+            let mut db = open(&path)?;
+            let mut cursor = db.new_cursor();
+            // The same country over and over, so the log has dictionary entries
+            for name in ["a", "b", "c"] {
+                db.run(
+                    &format!("CREATE (:Person {{name: '{}', country: 'Sweden'}})", name),
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+            }
+
+            db.compact()?;
+            // Reads and writes go on while the new file is written, and the old one stays
+            // as it was until the new one is put in place
+            assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 3);
+            for name in ["d", "e"] {
+                db.run(
+                    &format!("CREATE (:Person {{name: '{}', country: 'Sweden'}})", name),
+                    &mut cursor,
+                )?;
+                while cursor.next()?.is_some() {}
+            }
+            assert_eq!(std::fs::metadata(&path)?.len(), 0);
+            let before = (std::fs::read(&path)?, std::fs::read(&log_path)?);
+            db.finish_compaction()?;
+            assert!(std::fs::metadata(&path)?.len() > 0);
+            let after = (std::fs::read(&path)?, std::fs::read(&log_path)?);
+            // The new log has only what was written during the compaction
+            assert!(after.1.len() < before.1.len());
+            drop(db);
+
+            // Whether it was before, during or after the switch to the new files that the
+            // database went away, it comes back with everything in it
+            let crashed = dir.path().join("crashed.gram");
+            let crashed_log = dir.path().join("crashed.gram.log");
+            for (gram, log) in [
+                (&before.0, &before.1),
+                (&after.0, &before.1),
+                (&after.0, &after.1),
+            ] {
+                std::fs::write(&crashed, gram)?;
+                std::fs::write(&crashed_log, log)?;
+                let mut db = open(&crashed)?;
+                assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 5);
+                assert_eq!(
+                    count(
+                        &mut db,
+                        "MATCH (a:Person {country: 'Sweden'}) RETURN count(a)"
+                    )?,
+                    5
+                );
+            }
+            let mut db = open(&path)?;
+            assert_eq!(count(&mut db, "MATCH (a:Person) RETURN count(a)")?, 5);
+            Ok(())
+        }
     }
 }
@@ -1,9 +1,9 @@
 fn main() -> anyhow::Result<()> {
-    #[cfg(all(feature = "cli", feature = "gram"))]
+    #[cfg(all(feature = "cli", feature = "gram-file"))]
     {
         use clap::{App, AppSettings};
         use gqlite::gramdb::GramDatabase;
-        use std::fs::OpenOptions;
+        use gqlite::QueryError;
 
         let matches = App::new("g")
             .version("0.0")
@@ -12,21 +12,38 @@ fn main() -> anyhow::Result<()> {
             .args_from_usage(
                 "-f, --file=[FILE] @graph.gram 'Sets the gram file to use'
             -h, --help 'Print help information'
+            -v, --verbose 'Print query plans and planner notifications'
             <QUERY> 'Query to execute'",
             )
             .get_matches();
 
+        // Set RUST_LOG=debug to see the query plan, or RUST_LOG=trace for per-operator row counts
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+            .with_writer(std::io::stderr)
+            .init();
+
         let query_str = matches.value_of("QUERY").unwrap();
         let path = matches.value_of("file").unwrap_or("graph.gram");
-        let file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(path)?;
-
-        let mut db = GramDatabase::open(file)?;
+        let mut db = GramDatabase::options().open(path)?;
+        if matches.is_present("verbose") {
+            db.set_diagnostics(gqlite::diagnostics::StdoutDiagnostics);
+        }
         let mut cursor = db.new_cursor();
-        db.run(query_str, &mut cursor)?;
+        if let Err(err) = db.run(query_str, &mut cursor) {
+            // Point out where in the query the problem is, if we know
+            if let Some(span) = err.downcast_ref::<QueryError>().and_then(|e| e.span()) {
+                eprintln!("{}", span.caret(query_str));
+            }
+            return Err(err);
+        }
+
+        let fields = cursor.fields();
+        if !fields.is_empty() {
+            println!("----");
+            println!("{}", fields.join(", "));
+            println!("----");
+        }
 
         while let Some(row) = cursor.next()? {
             let mut first = true;
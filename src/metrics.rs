@@ -0,0 +1,206 @@
+//
+// Counters and histograms the database maintains about the queries it runs, for embedders to
+// export to whatever monitoring system they use. The shapes here mirror Prometheus counters and
+// histograms, so exporting to Prometheus is a matter of copying the numbers over.
+//
+use crate::backend::misestimate;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    // Queries that were successfully planned and handed to the backend
+    pub queries_executed: u64,
+    // Queries that had to wait for others to finish before they could start, see Scheduler
+    pub queries_queued: u64,
+    // Queries turned away because the scheduler queue was full
+    pub queries_rejected: u64,
+    // Queries killed before they were done, see Database::kill
+    pub queries_cancelled: u64,
+    // Rows returned to users through cursors
+    pub rows_produced: u64,
+    // Queries whose plan was found in the plan cache
+    pub plan_cache_hits: u64,
+    // Time spent parsing and planning each query, in seconds
+    pub planning_time: Histogram,
+    // Time spent executing each query, in seconds. This counts time spent inside the backend
+    // producing rows, not time the user spends with the cursor between rows. A query counts as
+    // done when its results are exhausted, or its cursor is dropped or re-used.
+    pub execution_time: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            queries_executed: 0,
+            queries_queued: 0,
+            queries_rejected: 0,
+            queries_cancelled: 0,
+            rows_produced: 0,
+            plan_cache_hits: 0,
+            planning_time: Histogram::new(DEFAULT_TIME_BUCKETS),
+            execution_time: Histogram::new(DEFAULT_TIME_BUCKETS),
+        }
+    }
+}
+
+// Bucket upper bounds in seconds, from 10 microseconds to 10 seconds
+const DEFAULT_TIME_BUCKETS: &[f64] = &[
+    0.000_01, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    // (upper bound, number of observations less than or equal to the upper bound), ascending.
+    // Like in Prometheus, the counts are cumulative, and observations above the largest bound
+    // are only included in count.
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Histogram {
+        Histogram {
+            buckets: bounds.iter().map(|b| (*b, 0)).collect(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, v: f64) {
+        for (bound, count) in self.buckets.iter_mut() {
+            if v <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+
+    pub fn observe_duration(&mut self, d: Duration) {
+        self.observe(d.as_secs_f64())
+    }
+}
+
+// What one query cost to run, operator by operator, for cursors that collect it; see
+// Cursor::with_stats. Unlike Metrics, this is about a single query, so applications can assert
+// on what a query costs in their own tests, or log the expensive ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionStats {
+    // The operators of the query plan, root first; each operator comes before the operators it
+    // pulls rows from
+    pub operators: Vec<OperatorStats>,
+}
+
+impl ExecutionStats {
+    // Total db hits of the query, see OperatorStats::db_hits
+    pub fn db_hits(&self) -> u64 {
+        self.operators.iter().map(|op| op.db_hits).sum()
+    }
+
+    // Total time the query spent executing
+    pub fn time(&self) -> Duration {
+        self.operators.iter().map(|op| op.time).sum()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OperatorStats {
+    // The operator, as it's called in query plans; like NodeScan or Expand
+    pub name: &'static str,
+    // The operator this one produces rows for, by index in ExecutionStats::operators; None for
+    // the root of the plan
+    pub parent: Option<usize>,
+    // Rows this operator produced; for operators that are re-run, like the inner side of a
+    // NestLoop, the total over all runs
+    pub rows: u64,
+    // How many times this operator went to the graph: looked at a node or relationship, read a
+    // property, checked a label or wrote something. This is the measure of query cost that
+    // doesn't vary with how busy the machine is, so it's the one to assert on in tests.
+    pub db_hits: u64,
+    // Time spent in this operator, not counting the operators it pulls rows from. Zero on wasm32,
+    // see Stopwatch.
+    pub time: Duration,
+    // Rows the planner expected this operator to produce, if the backend keeps the statistics to
+    // tell; see Database::cardinality_feedback
+    pub estimated_rows: Option<f64>,
+}
+
+impl OperatorStats {
+    // How far off the planner was about the rows of this operator, see backend::misestimate
+    pub fn misestimate(&self) -> Option<f64> {
+        self.estimated_rows
+            .map(|estimated| misestimate(estimated, self.rows as f64))
+    }
+}
+
+// What a query wrote to the graph, counted the way drivers report it; see Cursor::summary. The
+// counts are complete once the result is exhausted. CREATE is the only way for a query to write
+// yet; what's deleted is deleted by sweeps for expired nodes, see Database::sweep_expired.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QuerySummary {
+    pub nodes_created: u64,
+    pub relationships_created: u64,
+    // Properties created nodes and rels were given, not counting those that were null
+    pub properties_set: u64,
+    pub labels_added: u64,
+    pub nodes_deleted: u64,
+    pub relationships_deleted: u64,
+}
+
+impl QuerySummary {
+    pub fn contains_updates(&self) -> bool {
+        *self != QuerySummary::default()
+    }
+}
+
+// What running a query to completion with Database::execute came to: how many rows it returned,
+// without the rows themselves, and what it wrote if the backend keeps count
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ExecutionSummary {
+    pub rows: u64,
+    pub summary: Option<QuerySummary>,
+}
+
+// Measures time for the histograms above. On wasm32 there is no clock we can read without
+// going through javascript - Instant::now() panics there - so all timings are zero.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    started: Instant,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Stopwatch {
+        Stopwatch {
+            #[cfg(not(target_arch = "wasm32"))]
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.started.elapsed();
+        #[cfg(target_arch = "wasm32")]
+        return Duration::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut h = Histogram::new(&[1.0, 2.0]);
+        h.observe(0.5);
+        h.observe(1.5);
+        h.observe(3.0);
+
+        assert_eq!(h.buckets, vec![(1.0, 1), (2.0, 2)]);
+        assert_eq!(h.count, 3);
+        assert_eq!(h.sum, 5.0);
+    }
+}
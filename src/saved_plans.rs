@@ -0,0 +1,954 @@
+//
+// Saving the plan cache to a file and loading it back, so a service that runs a fixed set of
+// queries can plan them once, ship the plans along, and skip planning when it starts up.
+//
+// Plans refer to labels, property keys, variable names and so on by token, and tokens are only
+// meaningful to the process that handed them out. So the file has the names of the tokens its
+// plans use, and loading tokenizes those names again and renumbers the tokens in the plans.
+//
+// The format is a compact binary one, meant to be read back by the same version of gqlite:
+//
+//   magic, format version
+//   token count, token names
+//   plan count, plans: cache key, query text if the plan is only for that exact query, plan
+//
+// Numbers are LEB128 varints, signed ones zigzag encoded first; strings are a length and UTF-8.
+//
+use crate::backend::{Backend, Token};
+use crate::frontend::literals::Shape;
+use crate::frontend::{
+    check_operators, CountOf, Dir, Expr, IndexRange, LogicalPlan, MapEntryExpr, NodeSpec, Op,
+    ParameterizedPlan, Projection, RangeBound, RelSpec, SortKey,
+};
+use crate::{CachedPlan, Database, PlanKey, Result, Type};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8] = b"gqlite-plans";
+// Bump this whenever the encoding of plans changes, including when operators or expressions
+// change shape
+const VERSION: u64 = 3;
+
+impl<T: Backend> Database<T> {
+    // Write every plan in the plan cache to out
+    pub fn save_plans(&self, mut out: impl Write) -> Result<()> {
+        let mut body = Encoder::default();
+        // Sorted, so saving the same cache twice gives the same file
+        let mut keys: Vec<&PlanKey> = self.plan_cache.keys().collect();
+        keys.sort_by_key(|(fingerprint, shapes)| (*fingerprint, format!("{:?}", shapes)));
+        body.uint(keys.len() as u64);
+        for key in keys {
+            let cached = &self.plan_cache[key];
+            body.uint(key.0);
+            body.uint(key.1.len() as u64);
+            for shape in &key.1 {
+                body.typ(&shape.typ);
+                body.opt_uint(shape.same_as.map(|i| i as u64));
+            }
+            match &cached.query {
+                Some(query) => {
+                    body.bool(true);
+                    body.str(query);
+                }
+                None => body.bool(false),
+            }
+            body.parameterized_plan(&cached.plan)?;
+        }
+
+        let mut head = Encoder::default();
+        head.out.extend_from_slice(MAGIC);
+        head.uint(VERSION);
+        head.uint(body.tokens.len() as u64);
+        let tokens = self.frontend.tokens.borrow();
+        for tok in &body.tokens {
+            match tokens.lookup(*tok) {
+                Some(name) => head.str(name),
+                None => bail!("plan refers to token {}, which has no name", tok),
+            }
+        }
+        out.write_all(&head.out)?;
+        out.write_all(&body.out)?;
+        out.flush()?;
+        Ok(())
+    }
+
+    // Add the plans save_plans wrote to the plan cache, giving back how many there were. Plans
+    // the cache has no room for, that need operators this backend doesn't have, or that were made
+    // for other indexes and constraints than the database has now, are skipped; so create the
+    // indexes the plans were made with before loading them.
+    pub fn load_plans(&mut self, mut input: impl Read) -> Result<usize> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        let mut d = Decoder {
+            input: &bytes,
+            pos: 0,
+            tokens: Vec::new(),
+        };
+        if d.bytes(MAGIC.len())? != MAGIC {
+            bail!("this is not a gqlite plan file")
+        }
+        let version = d.uint()?;
+        if version != VERSION {
+            bail!(
+                "plan file is format version {}, but this version of gqlite reads version {}",
+                version,
+                VERSION
+            )
+        }
+        let token_count = d.uint()?;
+        {
+            let mut tokens = self.frontend.tokens.borrow_mut();
+            for _ in 0..token_count {
+                let name = d.str()?;
+                d.tokens.push(tokens.tokenize(&name));
+            }
+        }
+
+        let mut loaded = 0;
+        let plan_count = d.uint()?;
+        for _ in 0..plan_count {
+            let fingerprint = d.uint()?;
+            let shapes = d.list(|d| {
+                Ok(Shape {
+                    typ: d.typ()?,
+                    same_as: d.opt_uint()?.map(|i| i as usize),
+                })
+            })?;
+            let query = if d.bool()? { Some(d.str()?) } else { None };
+            let plan = d.parameterized_plan()?;
+            if self.plan_cache.len() >= self.plan_cache_size
+                || plan.schema_digest != self.frontend.backend_desc.schema_digest
+                || check_operators(&plan.plan, &self.frontend.backend_desc).is_err()
+            {
+                continue;
+            }
+            self.plan_cache
+                .insert((fingerprint, shapes), CachedPlan { query, plan });
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    out: Vec<u8>,
+    // Tokens in the order they were first written, which is what they're numbered by in the file
+    tokens: Vec<Token>,
+    token_index: HashMap<Token, u64>,
+}
+
+impl Encoder {
+    fn uint(&mut self, mut v: u64) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.out.push(byte);
+                return;
+            }
+            self.out.push(byte | 0x80);
+        }
+    }
+
+    fn int(&mut self, v: i64) {
+        self.uint(((v << 1) ^ (v >> 63)) as u64)
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.out.push(v as u8)
+    }
+
+    fn str(&mut self, v: &str) {
+        self.uint(v.len() as u64);
+        self.out.extend_from_slice(v.as_bytes());
+    }
+
+    fn opt_uint(&mut self, v: Option<u64>) {
+        // 0 is None, so everything else is one up
+        self.uint(v.map_or(0, |v| v + 1))
+    }
+
+    fn token(&mut self, tok: Token) {
+        let next = self.tokens.len() as u64;
+        let index = *self.token_index.entry(tok).or_insert(next);
+        if index == next {
+            self.tokens.push(tok);
+        }
+        self.uint(index)
+    }
+
+    fn opt_token(&mut self, tok: Option<Token>) {
+        match tok {
+            Some(tok) => {
+                self.bool(true);
+                self.token(tok)
+            }
+            None => self.bool(false),
+        }
+    }
+
+    fn tokens(&mut self, toks: &[Token]) {
+        self.uint(toks.len() as u64);
+        toks.iter().for_each(|t| self.token(*t));
+    }
+
+    fn slot(&mut self, slot: usize) {
+        self.uint(slot as u64)
+    }
+
+    fn slots(&mut self, slots: &[usize]) {
+        self.uint(slots.len() as u64);
+        slots.iter().for_each(|s| self.slot(*s));
+    }
+
+    fn named_slots(&mut self, named: &[(Token, usize)]) {
+        self.uint(named.len() as u64);
+        for (tok, slot) in named {
+            self.token(*tok);
+            self.slot(*slot);
+        }
+    }
+
+    fn typ(&mut self, typ: &Type) {
+        let tag = match typ {
+            Type::Any => 0,
+            Type::Number => 1,
+            Type::Integer => 2,
+            Type::Float => 3,
+            Type::String => 4,
+            Type::Boolean => 5,
+            Type::Node => 6,
+            Type::Relationship => 7,
+            Type::Path => 8,
+            Type::List(_) => 9,
+            Type::Map => 10,
+        };
+        self.out.push(tag);
+        if let Type::List(item) = typ {
+            self.typ(item)
+        }
+    }
+
+    fn parameterized_plan(&mut self, p: &ParameterizedPlan) -> Result<()> {
+        self.plan(&p.plan)?;
+        self.tokens(&p.literal_params);
+        self.tokens(&p.parameters);
+        self.bool(p.names_literals);
+        self.uint(p.schema_digest);
+        Ok(())
+    }
+
+    fn plan(&mut self, plan: &LogicalPlan) -> Result<()> {
+        match plan {
+            LogicalPlan::Argument => self.out.push(0),
+            LogicalPlan::NodeScan { src, slot, labels } => {
+                self.out.push(1);
+                self.plan(src)?;
+                self.slot(*slot);
+                self.opt_token(*labels);
+            }
+            LogicalPlan::Expand {
+                src,
+                src_slot,
+                rel_slot,
+                dst_slot,
+                rel_type,
+                dir,
+                predicate,
+            } => {
+                self.out.push(2);
+                self.plan(src)?;
+                self.slot(*src_slot);
+                self.slot(*rel_slot);
+                self.slot(*dst_slot);
+                self.opt_token(*rel_type);
+                self.out.push(match dir {
+                    None => 0,
+                    Some(Dir::Out) => 1,
+                    Some(Dir::In) => 2,
+                });
+                self.opt_expr(predicate.as_ref())?;
+            }
+            LogicalPlan::Optional { src, slots } => {
+                self.out.push(3);
+                self.plan(src)?;
+                self.slots(slots);
+            }
+            LogicalPlan::Selection { src, predicate } => {
+                self.out.push(4);
+                self.plan(src)?;
+                self.expr(predicate)?;
+            }
+            LogicalPlan::Create { src, nodes, rels } => {
+                self.out.push(5);
+                self.plan(src)?;
+                self.uint(nodes.len() as u64);
+                for n in nodes {
+                    self.slot(n.slot);
+                    self.tokens(&n.labels);
+                    self.map(&n.props)?;
+                    self.opt_expr(n.prop_map.as_ref())?;
+                }
+                self.uint(rels.len() as u64);
+                for r in rels {
+                    self.slot(r.slot);
+                    self.token(r.rel_type);
+                    self.slot(r.start_node_slot);
+                    self.slot(r.end_node_slot);
+                    self.map(&r.props)?;
+                    self.opt_expr(r.prop_map.as_ref())?;
+                }
+            }
+            LogicalPlan::Aggregate {
+                src,
+                grouping,
+                aggregations,
+            } => {
+                self.out.push(6);
+                self.plan(src)?;
+                for exprs in &[grouping, aggregations] {
+                    self.uint(exprs.len() as u64);
+                    for (e, slot) in exprs.iter() {
+                        self.expr(e)?;
+                        self.slot(*slot);
+                    }
+                }
+            }
+            LogicalPlan::CountStore { src, slot, count } => {
+                self.out.push(7);
+                self.plan(src)?;
+                self.slot(*slot);
+                match count {
+                    CountOf::Nodes { label } => {
+                        self.bool(false);
+                        self.opt_token(*label);
+                    }
+                    CountOf::Rels { rel_type } => {
+                        self.bool(true);
+                        self.opt_token(*rel_type);
+                    }
+                }
+            }
+            LogicalPlan::Unwind {
+                src,
+                list_expr,
+                alias,
+            } => {
+                self.out.push(8);
+                self.plan(src)?;
+                self.expr(list_expr)?;
+                self.slot(*alias);
+            }
+            LogicalPlan::Call {
+                src,
+                name,
+                args,
+                outputs,
+            } => {
+                self.out.push(9);
+                self.plan(src)?;
+                self.token(*name);
+                self.exprs(args)?;
+                self.named_slots(outputs);
+            }
+            LogicalPlan::NestLoop {
+                outer,
+                inner,
+                predicate,
+            } => {
+                self.out.push(10);
+                self.plan(outer)?;
+                self.plan(inner)?;
+                self.expr(predicate)?;
+            }
+            LogicalPlan::ConditionalApply { src, probe } => {
+                self.out.push(11);
+                self.plan(src)?;
+                self.plan(probe)?;
+            }
+            LogicalPlan::AntiConditionalApply { src, probe } => {
+                self.out.push(12);
+                self.plan(src)?;
+                self.plan(probe)?;
+            }
+            LogicalPlan::Project { src, projections } => {
+                self.out.push(13);
+                self.plan(src)?;
+                self.uint(projections.len() as u64);
+                for p in projections {
+                    self.expr(&p.expr)?;
+                    self.token(p.alias);
+                    self.slot(p.dst);
+                }
+            }
+            LogicalPlan::Sort { src, sort_by } => {
+                self.out.push(14);
+                self.plan(src)?;
+                self.uint(sort_by.len() as u64);
+                for k in sort_by {
+                    self.expr(&k.expr)?;
+                    self.bool(k.descending);
+                    self.bool(k.nulls_first);
+                }
+            }
+            LogicalPlan::Limit { src, skip, limit } => {
+                self.out.push(15);
+                self.plan(src)?;
+                self.opt_expr(skip.as_ref())?;
+                self.opt_expr(limit.as_ref())?;
+            }
+            LogicalPlan::ProduceResult { src, fields } => {
+                self.out.push(16);
+                self.plan(src)?;
+                self.named_slots(fields);
+            }
+            LogicalPlan::NodeIndexSeek {
+                src,
+                slot,
+                label,
+                property,
+                range,
+            } => {
+                self.out.push(17);
+                self.plan(src)?;
+                self.slot(*slot);
+                self.token(*label);
+                self.token(*property);
+                for bound in [&range.lower, &range.upper] {
+                    self.opt_expr(bound.as_ref().map(|b| &b.value))?;
+                    self.bool(bound.as_ref().is_some_and(|b| b.inclusive));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<()> {
+        match e {
+            Expr::And(terms) => {
+                self.out.push(0);
+                self.exprs(terms)?;
+            }
+            Expr::Or(terms) => {
+                self.out.push(1);
+                self.exprs(terms)?;
+            }
+            Expr::BinaryOp { left, right, op } => {
+                self.out.push(2);
+                self.expr(left)?;
+                self.expr(right)?;
+                self.out.push(match op {
+                    Op::Eq => 0,
+                    Op::NotEq => 1,
+                    Op::Gt => 2,
+                    Op::Div => 3,
+                    Op::Mul => 4,
+                    Op::Add => 5,
+                    Op::Sub => 6,
+                    Op::Lt => 7,
+                    Op::Gte => 8,
+                    Op::Lte => 9,
+                });
+            }
+            Expr::Null => self.out.push(3),
+            Expr::Bool(v) => {
+                self.out.push(4);
+                self.bool(*v);
+            }
+            Expr::Int(v) => {
+                self.out.push(5);
+                self.int(*v);
+            }
+            Expr::Float(v) => {
+                self.out.push(6);
+                self.out.extend_from_slice(&v.to_le_bytes());
+            }
+            Expr::String(v) => {
+                self.out.push(7);
+                self.str(v);
+            }
+            Expr::Map(entries) => {
+                self.out.push(8);
+                self.map(entries)?;
+            }
+            Expr::List(items) => {
+                self.out.push(9);
+                self.exprs(items)?;
+            }
+            Expr::Param(tok) => {
+                self.out.push(10);
+                self.token(*tok);
+            }
+            Expr::Prop(entity, keys) => {
+                self.out.push(11);
+                self.expr(entity)?;
+                self.tokens(keys);
+            }
+            Expr::Slot(slot) => {
+                self.out.push(12);
+                self.slot(*slot);
+            }
+            Expr::FuncCall { name, args } => {
+                self.out.push(13);
+                self.token(*name);
+                self.exprs(args)?;
+            }
+            Expr::HasLabel(slot, label) => {
+                self.out.push(14);
+                self.slot(*slot);
+                self.token(*label);
+            }
+            Expr::Subscript(base, key) => {
+                self.out.push(15);
+                self.expr(base)?;
+                self.expr(key)?;
+            }
+            // These are planned as probes, so they're never left in a finished plan
+            Expr::PatternPredicate(_) => bail!("can't save a plan with a pattern predicate in it"),
+        }
+        Ok(())
+    }
+
+    fn opt_expr(&mut self, e: Option<&Expr>) -> Result<()> {
+        match e {
+            Some(e) => {
+                self.bool(true);
+                self.expr(e)
+            }
+            None => {
+                self.bool(false);
+                Ok(())
+            }
+        }
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) -> Result<()> {
+        self.uint(exprs.len() as u64);
+        for e in exprs {
+            self.expr(e)?;
+        }
+        Ok(())
+    }
+
+    fn map(&mut self, entries: &[MapEntryExpr]) -> Result<()> {
+        self.uint(entries.len() as u64);
+        for e in entries {
+            self.token(e.key);
+            self.expr(&e.val)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    // The tokens in this process that the tokens in the file are, by their number in the file
+    tokens: Vec<Token>,
+}
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.input.len() - self.pos < n {
+            bail!("plan file ends unexpectedly at byte {}", self.input.len())
+        }
+        self.pos += n;
+        Ok(&self.input[self.pos - n..self.pos])
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn uint(&mut self) -> Result<u64> {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift > 63 {
+                bail!("malformed number in plan file at byte {}", self.pos)
+            }
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
+
+    fn int(&mut self) -> Result<i64> {
+        let v = self.uint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        Ok(self.byte()? != 0)
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.uint()? as usize;
+        Ok(String::from_utf8(self.bytes(len)?.to_vec())?)
+    }
+
+    fn opt_uint(&mut self) -> Result<Option<u64>> {
+        Ok(self.uint()?.checked_sub(1))
+    }
+
+    fn token(&mut self) -> Result<Token> {
+        let index = self.uint()? as usize;
+        match self.tokens.get(index) {
+            Some(tok) => Ok(*tok),
+            None => bail!(
+                "plan file refers to token {}, which it has no name for",
+                index
+            ),
+        }
+    }
+
+    fn opt_token(&mut self) -> Result<Option<Token>> {
+        Ok(if self.bool()? {
+            Some(self.token()?)
+        } else {
+            None
+        })
+    }
+
+    fn tokens(&mut self) -> Result<Vec<Token>> {
+        self.list(|d| d.token())
+    }
+
+    fn slot(&mut self) -> Result<usize> {
+        Ok(self.uint()? as usize)
+    }
+
+    fn named_slots(&mut self) -> Result<Vec<(Token, usize)>> {
+        self.list(|d| Ok((d.token()?, d.slot()?)))
+    }
+
+    fn list<V>(&mut self, mut item: impl FnMut(&mut Self) -> Result<V>) -> Result<Vec<V>> {
+        let len = self.uint()? as usize;
+        // Not trusting the length to be sane before there's data to back it up
+        let mut out = Vec::with_capacity(len.min(self.input.len() - self.pos));
+        for _ in 0..len {
+            out.push(item(self)?);
+        }
+        Ok(out)
+    }
+
+    fn typ(&mut self) -> Result<Type> {
+        Ok(match self.byte()? {
+            0 => Type::Any,
+            1 => Type::Number,
+            2 => Type::Integer,
+            3 => Type::Float,
+            4 => Type::String,
+            5 => Type::Boolean,
+            6 => Type::Node,
+            7 => Type::Relationship,
+            8 => Type::Path,
+            9 => Type::List(Box::new(self.typ()?)),
+            10 => Type::Map,
+            tag => bail!("unknown type {} in plan file at byte {}", tag, self.pos),
+        })
+    }
+
+    fn parameterized_plan(&mut self) -> Result<ParameterizedPlan> {
+        Ok(ParameterizedPlan {
+            plan: self.plan()?,
+            literal_params: self.tokens()?,
+            parameters: self.tokens()?,
+            names_literals: self.bool()?,
+            schema_digest: self.uint()?,
+        })
+    }
+
+    fn src(&mut self) -> Result<Box<LogicalPlan>> {
+        Ok(Box::new(self.plan()?))
+    }
+
+    fn plan(&mut self) -> Result<LogicalPlan> {
+        Ok(match self.byte()? {
+            0 => LogicalPlan::Argument,
+            1 => LogicalPlan::NodeScan {
+                src: self.src()?,
+                slot: self.slot()?,
+                labels: self.opt_token()?,
+            },
+            2 => LogicalPlan::Expand {
+                src: self.src()?,
+                src_slot: self.slot()?,
+                rel_slot: self.slot()?,
+                dst_slot: self.slot()?,
+                rel_type: self.opt_token()?,
+                dir: match self.byte()? {
+                    0 => None,
+                    1 => Some(Dir::Out),
+                    2 => Some(Dir::In),
+                    tag => bail!(
+                        "unknown direction {} in plan file at byte {}",
+                        tag,
+                        self.pos
+                    ),
+                },
+                predicate: self.opt_expr()?,
+            },
+            3 => LogicalPlan::Optional {
+                src: self.src()?,
+                slots: self.list(|d| d.slot())?,
+            },
+            4 => LogicalPlan::Selection {
+                src: self.src()?,
+                predicate: self.expr()?,
+            },
+            5 => LogicalPlan::Create {
+                src: self.src()?,
+                nodes: self.list(|d| {
+                    Ok(NodeSpec {
+                        slot: d.slot()?,
+                        labels: d.tokens()?,
+                        props: d.map()?,
+                        prop_map: d.opt_expr()?,
+                    })
+                })?,
+                rels: self.list(|d| {
+                    Ok(RelSpec {
+                        slot: d.slot()?,
+                        rel_type: d.token()?,
+                        start_node_slot: d.slot()?,
+                        end_node_slot: d.slot()?,
+                        props: d.map()?,
+                        prop_map: d.opt_expr()?,
+                    })
+                })?,
+            },
+            6 => LogicalPlan::Aggregate {
+                src: self.src()?,
+                grouping: self.list(|d| Ok((d.expr()?, d.slot()?)))?,
+                aggregations: self.list(|d| Ok((d.expr()?, d.slot()?)))?,
+            },
+            7 => LogicalPlan::CountStore {
+                src: self.src()?,
+                slot: self.slot()?,
+                count: if self.bool()? {
+                    CountOf::Rels {
+                        rel_type: self.opt_token()?,
+                    }
+                } else {
+                    CountOf::Nodes {
+                        label: self.opt_token()?,
+                    }
+                },
+            },
+            8 => LogicalPlan::Unwind {
+                src: self.src()?,
+                list_expr: self.expr()?,
+                alias: self.slot()?,
+            },
+            9 => LogicalPlan::Call {
+                src: self.src()?,
+                name: self.token()?,
+                args: self.exprs()?,
+                outputs: self.named_slots()?,
+            },
+            10 => LogicalPlan::NestLoop {
+                outer: self.src()?,
+                inner: self.src()?,
+                predicate: self.expr()?,
+            },
+            11 => LogicalPlan::ConditionalApply {
+                src: self.src()?,
+                probe: self.src()?,
+            },
+            12 => LogicalPlan::AntiConditionalApply {
+                src: self.src()?,
+                probe: self.src()?,
+            },
+            13 => LogicalPlan::Project {
+                src: self.src()?,
+                projections: self.list(|d| {
+                    Ok(Projection {
+                        expr: d.expr()?,
+                        alias: d.token()?,
+                        dst: d.slot()?,
+                    })
+                })?,
+            },
+            14 => LogicalPlan::Sort {
+                src: self.src()?,
+                sort_by: self.list(|d| {
+                    Ok(SortKey {
+                        expr: d.expr()?,
+                        descending: d.bool()?,
+                        nulls_first: d.bool()?,
+                    })
+                })?,
+            },
+            15 => LogicalPlan::Limit {
+                src: self.src()?,
+                skip: self.opt_expr()?,
+                limit: self.opt_expr()?,
+            },
+            16 => LogicalPlan::ProduceResult {
+                src: self.src()?,
+                fields: self.named_slots()?,
+            },
+            17 => LogicalPlan::NodeIndexSeek {
+                src: self.src()?,
+                slot: self.slot()?,
+                label: self.token()?,
+                property: self.token()?,
+                range: IndexRange {
+                    lower: self.range_bound()?,
+                    upper: self.range_bound()?,
+                },
+            },
+            tag => bail!("unknown operator {} in plan file at byte {}", tag, self.pos),
+        })
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        Ok(match self.byte()? {
+            0 => Expr::And(self.exprs()?),
+            1 => Expr::Or(self.exprs()?),
+            2 => Expr::BinaryOp {
+                left: Box::new(self.expr()?),
+                right: Box::new(self.expr()?),
+                op: match self.byte()? {
+                    0 => Op::Eq,
+                    1 => Op::NotEq,
+                    2 => Op::Gt,
+                    3 => Op::Div,
+                    4 => Op::Mul,
+                    5 => Op::Add,
+                    6 => Op::Sub,
+                    7 => Op::Lt,
+                    8 => Op::Gte,
+                    9 => Op::Lte,
+                    tag => bail!("unknown operator {} in plan file at byte {}", tag, self.pos),
+                },
+            },
+            3 => Expr::Null,
+            4 => Expr::Bool(self.bool()?),
+            5 => Expr::Int(self.int()?),
+            6 => {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(self.bytes(8)?);
+                Expr::Float(f64::from_le_bytes(bytes))
+            }
+            7 => Expr::String(self.str()?),
+            8 => Expr::Map(self.map()?),
+            9 => Expr::List(self.exprs()?),
+            10 => Expr::Param(self.token()?),
+            11 => Expr::Prop(Box::new(self.expr()?), self.tokens()?),
+            12 => Expr::Slot(self.slot()?),
+            13 => Expr::FuncCall {
+                name: self.token()?,
+                args: self.exprs()?,
+            },
+            14 => Expr::HasLabel(self.slot()?, self.token()?),
+            15 => Expr::Subscript(Box::new(self.expr()?), Box::new(self.expr()?)),
+            tag => bail!(
+                "unknown expression {} in plan file at byte {}",
+                tag,
+                self.pos
+            ),
+        })
+    }
+
+    fn opt_expr(&mut self) -> Result<Option<Expr>> {
+        Ok(if self.bool()? {
+            Some(self.expr()?)
+        } else {
+            None
+        })
+    }
+
+    fn range_bound(&mut self) -> Result<Option<RangeBound>> {
+        let value = self.opt_expr()?;
+        let inclusive = self.bool()?;
+        Ok(value.map(|value| RangeBound { value, inclusive }))
+    }
+
+    fn exprs(&mut self) -> Result<Vec<Expr>> {
+        self.list(|d| d.expr())
+    }
+
+    fn map(&mut self) -> Result<Vec<MapEntryExpr>> {
+        self.list(|d| {
+            Ok(MapEntryExpr {
+                key: d.token()?,
+                val: d.expr()?,
+            })
+        })
+    }
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use crate::gramdb::GramDatabase;
+    use crate::{Result, Val};
+
+    fn rows(db: &mut GramDatabase, query: &str) -> Result<Vec<Vec<Val>>> {
+        let mut cursor = db.new_cursor();
+        db.run(query, &mut cursor)?;
+        let mut out = Vec::new();
+        while let Some(row) = cursor.next()? {
+            out.push(row.slots.clone());
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn saves_and_loads_plans() -> Result<()> {
+        let queries = [
+            "MATCH (n:Person {name: 'a'})-[r:KNOWS]->(m) WHERE m.age > 1.5 RETURN m.name, r",
+            "MATCH (n:Person) RETURN n.name AS name ORDER BY name DESC LIMIT 10",
+            "UNWIND [1, 2, null] AS x WITH x WHERE x <> 2 RETURN x, {x: x, y: ['y', -3]}",
+            "MATCH (n:Person), (m:Person|Bot) WHERE NOT (n)-->(m) RETURN n.name, m.name",
+            "MATCH (n:Person) RETURN count(n)",
+        ];
+        let gram = "(a:Person {name: 'a'})-[:KNOWS {since: 1}]->(b:Person {name: 'b', age: 2})";
+
+        let mut db = GramDatabase::from_gram(gram)?;
+        let mut expected = Vec::new();
+        for q in &queries {
+            expected.push(rows(&mut db, q)?);
+        }
+        let mut saved = Vec::new();
+        db.save_plans(&mut saved)?;
+
+        // Tokens are handed out in a different order here, so the plans have to be renumbered
+        let mut db = GramDatabase::from_gram("(:Bot {x: 1, y: 2, age: 3, since: 4}) (:Zzz)")?;
+        assert_eq!(db.load_plans(saved.as_slice())?, queries.len());
+        let mut again = Vec::new();
+        db.save_plans(&mut again)?;
+        assert_eq!(again, saved);
+
+        let mut db = GramDatabase::from_gram(gram)?;
+        db.load_plans(saved.as_slice())?;
+        for (q, expected) in queries.iter().zip(expected) {
+            assert_eq!(rows(&mut db, q)?, expected, "{}", q);
+        }
+        // Those were all answered from the loaded plans
+        assert_eq!(db.metrics().plan_cache_hits, queries.len() as u64);
+
+        assert!(db.load_plans(&saved[..saved.len() / 2]).is_err());
+        assert!(db.load_plans("nope".as_bytes()).is_err());
+
+        // Plans made without an index might not use it, and plans made with one need it there
+        let mut db = GramDatabase::from_gram(gram)?;
+        db.create_index("Person", "name")?;
+        assert_eq!(db.load_plans(saved.as_slice())?, 0);
+        let mut db = GramDatabase::from_gram(gram)?;
+        db.create_index("Person", "name")?;
+        rows(&mut db, queries[0])?;
+        let mut with_index = Vec::new();
+        db.save_plans(&mut with_index)?;
+        db.drop_index("Person", "name")?;
+        assert_eq!(db.load_plans(with_index.as_slice())?, 0);
+        db.create_index("Person", "name")?;
+        assert_eq!(db.load_plans(with_index.as_slice())?, 1);
+        Ok(())
+    }
+}
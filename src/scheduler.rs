@@ -0,0 +1,247 @@
+//
+// Admission control for queries. A database embedded in a server may have lots of cursors with
+// queries in flight at once; the scheduler caps how many of them execute at the same time and
+// lines the rest up in a queue, so load beyond what the database can take turns into waiting,
+// and load beyond what the queue can hold turns into quick rejections, rather than every query
+// getting slower.
+//
+// Like the rest of Database this is single-threaded: queries execute as their cursors are
+// pulled, so a queued query isn't started in the background when room frees up. It's started
+// when its cursor is handed to Database::admit, which servers call for their waiting cursors
+// whenever another query finishes.
+//
+// Queries can be killed, queued or executing; see Database::kill. A killed query gives up its
+// place right away, and its cursor fails with QueryError::Cancelled the next time it's used.
+//
+use crate::metrics::Stopwatch;
+use crate::{QueryError, Result};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+pub type QueryId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryState {
+    // Waiting for other queries to finish before it's allowed to start
+    Queued,
+    // Handed to the backend, but no rows have been read from it yet
+    Running,
+    // Rows have been read from the query, but not all of them. A query keeps its place among
+    // the executing ones until its result is exhausted, or its cursor is reset, re-used or
+    // dropped, since until then it holds on to whatever the backend set aside for it.
+    Streaming,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryInfo {
+    pub id: QueryId,
+    pub query: String,
+    pub state: QueryState,
+}
+
+// A query that is executing, see Database::running_queries
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunningQuery {
+    pub id: QueryId,
+    pub query: String,
+    pub state: QueryState,
+    // Since the query started executing, which doesn't count the time it was queued; always zero
+    // on wasm32, see metrics::Stopwatch
+    pub elapsed: Duration,
+    // The operator the query last pulled rows from, which is where it picks up when the next
+    // row is read, if the backend tells
+    pub operator: Option<&'static str>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: QueryInfo,
+    // Set once the query is started
+    started: Option<Stopwatch>,
+    // See BackendCursor::operator
+    operator: Option<Rc<Cell<Option<&'static str>>>>,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    max_running: usize,
+    max_queued: usize,
+    next_id: QueryId,
+    // In the order they were submitted, so queued queries are admitted first come, first served
+    queries: Vec<Entry>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler {
+            max_running: usize::MAX,
+            max_queued: usize::MAX,
+            next_id: 0,
+            queries: Vec::new(),
+        }
+    }
+}
+
+impl Scheduler {
+    // At most max_running queries execute at a time, and at most max_queued wait for their turn;
+    // queries that don't fit either way are rejected
+    pub fn set_limits(&mut self, max_running: usize, max_queued: usize) {
+        self.max_running = max_running.max(1);
+        self.max_queued = max_queued;
+    }
+
+    // Line a query up; call start() to see if it may execute yet. Fails with
+    // QueryError::Overloaded if the query can neither run now nor fit in the queue.
+    pub fn submit(&mut self, query: &str) -> Result<QueryId> {
+        let queued = self.count(QueryState::Queued);
+        if (queued > 0 || self.executing() >= self.max_running) && queued >= self.max_queued {
+            bail!(QueryError::Overloaded)
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queries.push(Entry {
+            info: QueryInfo {
+                id,
+                query: query.to_string(),
+                state: QueryState::Queued,
+            },
+            started: None,
+            operator: None,
+        });
+        Ok(id)
+    }
+
+    // Move the query out of the queue, if there is room for it and it's first in line; tells
+    // if the query is executing
+    pub fn start(&mut self, id: QueryId) -> bool {
+        let executing = self.executing();
+        let first_queued = self
+            .queries
+            .iter()
+            .position(|q| q.info.state == QueryState::Queued);
+        match self.queries.iter().position(|q| q.info.id == id) {
+            Some(i) if self.queries[i].info.state != QueryState::Queued => true,
+            Some(i) if first_queued == Some(i) && executing < self.max_running => {
+                self.queries[i].info.state = QueryState::Running;
+                self.queries[i].started = Some(Stopwatch::start());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Keep track of which operator the query is at through the given cell, which the backend
+    // updates as the query executes
+    pub fn watch(&mut self, id: QueryId, operator: Option<Rc<Cell<Option<&'static str>>>>) {
+        if let Some(q) = self.queries.iter_mut().find(|q| q.info.id == id) {
+            q.operator = operator;
+        }
+    }
+
+    pub fn streaming(&mut self, id: QueryId) {
+        if let Some(q) = self.queries.iter_mut().find(|q| q.info.id == id) {
+            if q.info.state == QueryState::Running {
+                q.info.state = QueryState::Streaming
+            }
+        }
+    }
+
+    // The query is done, or was abandoned before it started; either way it gives up its place
+    pub fn finish(&mut self, id: QueryId) {
+        self.queries.retain(|q| q.info.id != id);
+    }
+
+    // Same as finish, for a query that isn't done; the scheduler forgets about it, which is how
+    // its cursor tells it was killed. Tells if there was such a query.
+    pub fn kill(&mut self, id: QueryId) -> bool {
+        let known = self.state(id).is_some();
+        self.finish(id);
+        known
+    }
+
+    pub fn state(&self, id: QueryId) -> Option<QueryState> {
+        self.queries
+            .iter()
+            .find(|q| q.info.id == id)
+            .map(|q| q.info.state)
+    }
+
+    pub fn queries(&self) -> Vec<QueryInfo> {
+        self.queries.iter().map(|q| q.info.clone()).collect()
+    }
+
+    // The executing queries, in the order they were submitted
+    pub fn running(&self) -> Vec<RunningQuery> {
+        self.queries
+            .iter()
+            .filter_map(|q| {
+                Some(RunningQuery {
+                    id: q.info.id,
+                    query: q.info.query.clone(),
+                    state: q.info.state,
+                    elapsed: q.started.as_ref()?.elapsed(),
+                    operator: q.operator.as_ref().and_then(|op| op.get()),
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queries.is_empty()
+    }
+
+    fn executing(&self) -> usize {
+        self.queries.len() - self.count(QueryState::Queued)
+    }
+
+    fn count(&self, state: QueryState) -> usize {
+        self.queries
+            .iter()
+            .filter(|q| q.info.state == state)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueryState, Scheduler};
+    use crate::{error, ErrorKind, Result};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn admits_queries_in_order_up_to_the_limits() -> Result<()> {
+        let mut s = Scheduler::default();
+        s.set_limits(1, 2);
+        let a = s.submit("a")?;
+        assert!(s.start(a));
+        let b = s.submit("b")?;
+        let c = s.submit("c")?;
+        // c is not first in line, and nothing can start while a is executing
+        assert!(!s.start(c));
+        assert!(!s.start(b));
+        let err = s.submit("d").unwrap_err();
+        assert_eq!(error::kind(&err), ErrorKind::Overloaded);
+
+        let operator = Rc::new(Cell::new(None));
+        s.watch(a, Some(Rc::clone(&operator)));
+        s.streaming(a);
+        operator.set(Some("NodeScan"));
+        assert_eq!(s.state(a), Some(QueryState::Streaming));
+        assert_eq!(s.running()[0].operator, Some("NodeScan"));
+        s.finish(a);
+        assert_eq!(s.state(a), None);
+        assert!(!s.start(c));
+        assert!(s.start(b));
+        assert_eq!(s.state(b), Some(QueryState::Running));
+        assert_eq!(s.state(c), Some(QueryState::Queued));
+
+        // Killing a query makes room for the next one in line
+        assert!(s.kill(b));
+        assert!(!s.kill(b));
+        assert!(s.start(c));
+        assert_eq!(s.running().len(), 1);
+        Ok(())
+    }
+}
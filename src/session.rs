@@ -0,0 +1,116 @@
+//
+// Sessions are the unit embedders hand out to each of their users: a session has its own
+// default parameters and its own cursor, so one user's queries and results don't get mixed up
+// with another's.
+//
+use crate::backend::Backend;
+use crate::{Cursor, Database, Map, Result, Val};
+
+// A session runs one query at a time. Its cursor holds the result of the last query, and that
+// query's writes stay uncommitted until the result is exhausted or the session runs its next
+// query - or finish() is called - so a session has at most one transaction open at a time. Note
+// that the gram backend also commits an open query when any other query starts.
+//
+// TODO: There's no detecting write conflicts between the transactions of different sessions,
+//       because there is nothing for them to conflict over yet. The writes there are:
+//       - CREATE, run as a query or with Statement::execute_batch, and procedures that write,
+//         like gen.graph; these only ever make new nodes and rels
+//       - DELETE of expired nodes and their rels, by the expiry sweep, see
+//         Database::sweep_expired, and not by queries. A sweep is its own transaction, and it
+//         doesn't start while any query is still running, so it never deletes from under an
+//         open transaction.
+//       On top of that, the gram backend commits one query before it starts the next. Once
+//       there are explicit transactions, or SET or DELETE in queries, a commit that writes what
+//       another transaction wrote since it began should fail with a retryable error, rather
+//       than the last writer silently winning.
+pub struct Session<T: Backend> {
+    // Parameters every query in this session gets, unless it's given a value of its own
+    params: Map,
+    // Re-used for every query, so once it has grown to fit the results it stops allocating
+    cursor: Cursor<T>,
+}
+
+impl<T: Backend> Database<T> {
+    pub fn new_session(&mut self) -> Session<T> {
+        Session {
+            params: Map::new(),
+            cursor: self.new_cursor(),
+        }
+    }
+}
+
+impl<T: Backend> Session<T> {
+    // Set a parameter for all queries this session runs from here on
+    pub fn set_param(&mut self, name: &str, val: Val) {
+        match self.params.iter_mut().find(|(k, _)| k == name) {
+            Some((_, existing)) => *existing = val,
+            None => self.params.push((name.to_string(), val)),
+        }
+    }
+
+    pub fn params(&self) -> &Map {
+        &self.params
+    }
+
+    // Run a query, finishing the previous one; the result is read from the returned cursor
+    pub fn run(&mut self, db: &mut Database<T>, query_str: &str) -> Result<&mut Cursor<T>> {
+        db.run_with_params(query_str, &self.params, &mut self.cursor)?;
+        Ok(&mut self.cursor)
+    }
+
+    // Like run, with values for parameters that take precedence over those of the session
+    pub fn run_with_params(
+        &mut self,
+        db: &mut Database<T>,
+        query_str: &str,
+        params: &Map,
+    ) -> Result<&mut Cursor<T>> {
+        let mut merged = params.clone();
+        for (k, v) in &self.params {
+            if !params.iter().any(|(name, _)| name == k) {
+                merged.push((k.clone(), v.clone()));
+            }
+        }
+        db.run_with_params(query_str, &merged, &mut self.cursor)?;
+        Ok(&mut self.cursor)
+    }
+
+    // Finish the current query without reading the rest of its result, committing its writes
+    pub fn finish(&mut self) -> Result<()> {
+        while self.cursor.next()?.is_some() {}
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use crate::gramdb::GramDatabase;
+    use crate::{Result, Val};
+
+    #[test]
+    fn sessions_have_their_own_parameters() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        let mut alice = db.new_session();
+        let mut bob = db.new_session();
+        alice.set_param("name", Val::String("alice".into()));
+        bob.set_param("name", Val::String("bob".into()));
+
+        alice.run(&mut db, "CREATE ({name: $name})")?;
+        alice.finish()?;
+        bob.run(&mut db, "CREATE ({name: $name})")?;
+        bob.finish()?;
+
+        let cursor = alice.run(
+            &mut db,
+            "MATCH (n) WHERE n.name = $name RETURN n.name AS name",
+        )?;
+        let row = cursor.next()?.unwrap();
+        assert_eq!(row.slots, vec![Val::String("alice".into())]);
+
+        let params = vec![("name".to_string(), Val::String("bob".into()))];
+        let query = "MATCH (n) WHERE n.name = $name RETURN count(n) AS n";
+        let cursor = alice.run_with_params(&mut db, query, &params)?;
+        assert_eq!(cursor.next()?.unwrap().slots, vec![Val::Int(1)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+// A parser for the subset of Gherkin the openCypher TCK feature files use: a feature with an
+// optional background, scenarios and scenario outlines, steps with doc strings and tables.
+use anyhow::Result;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+    pub name: String,
+    // Scenario outlines are expanded, there is one scenario per example row
+    pub scenarios: Vec<Scenario>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scenario {
+    pub name: String,
+    // Background steps come first
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    // The step text, without its Given/When/Then/And/But keyword
+    pub text: String,
+    pub docstring: Option<String>,
+    // Raw table rows; whether the first row is a header depends on the step
+    pub table: Vec<Vec<String>>,
+}
+
+// A scenario as written, before outline expansion
+#[derive(Debug, Default)]
+struct RawScenario {
+    name: String,
+    steps: Vec<Step>,
+    examples: Vec<Vec<String>>,
+    outline: bool,
+}
+
+const STEP_KEYWORDS: &[&str] = &["Given ", "When ", "Then ", "And ", "But "];
+
+pub fn parse(src: &str) -> Result<Feature> {
+    let mut name = String::new();
+    let mut background: Vec<Step> = Vec::new();
+    let mut scenarios: Vec<RawScenario> = Vec::new();
+    // Are we in the background, rather than a scenario?
+    let mut in_background = false;
+    // Are we in the examples of the current scenario outline?
+    let mut in_examples = false;
+    // Indentation of the opening quotes, while we are in a doc string
+    let mut docstring: Option<(usize, Vec<String>)> = None;
+
+    for (lineno, raw_line) in src.lines().enumerate() {
+        let line = raw_line.trim();
+
+        if let Some((indent, lines)) = &mut docstring {
+            if line == "\"\"\"" {
+                let text = lines.join("\n");
+                let steps = current_steps(&mut background, &mut scenarios, in_background);
+                match steps.last_mut() {
+                    Some(step) => step.docstring = Some(text),
+                    None => bail!("line {}: doc string outside of a step", lineno + 1),
+                }
+                docstring = None;
+            } else {
+                let strip = raw_line
+                    .char_indices()
+                    .take_while(|(i, c)| *i < *indent && c.is_whitespace())
+                    .count();
+                lines.push(raw_line[strip..].to_string());
+            }
+            continue;
+        }
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix("Feature:") {
+            name = rest.trim().to_string();
+        } else if line.starts_with("Background:") {
+            in_background = true;
+        } else if let Some(rest) = line.strip_prefix("Scenario Outline:") {
+            in_background = false;
+            in_examples = false;
+            scenarios.push(RawScenario {
+                name: rest.trim().to_string(),
+                outline: true,
+                ..Default::default()
+            });
+        } else if let Some(rest) = line.strip_prefix("Scenario:") {
+            in_background = false;
+            in_examples = false;
+            scenarios.push(RawScenario {
+                name: rest.trim().to_string(),
+                ..Default::default()
+            });
+        } else if line.starts_with("Examples:") {
+            in_examples = true;
+        } else if line == "\"\"\"" {
+            let indent = raw_line.len() - raw_line.trim_start().len();
+            docstring = Some((indent, Vec::new()));
+        } else if line.starts_with('|') {
+            let row = parse_row(line);
+            if in_examples {
+                match scenarios.last_mut() {
+                    Some(s) => s.examples.push(row),
+                    None => bail!("line {}: examples outside of a scenario", lineno + 1),
+                }
+            } else {
+                let steps = current_steps(&mut background, &mut scenarios, in_background);
+                match steps.last_mut() {
+                    Some(step) => step.table.push(row),
+                    None => bail!("line {}: table outside of a step", lineno + 1),
+                }
+            }
+        } else if let Some(keyword) = STEP_KEYWORDS.iter().find(|k| line.starts_with(*k)) {
+            let steps = current_steps(&mut background, &mut scenarios, in_background);
+            steps.push(Step {
+                text: line[keyword.len()..].trim().to_string(),
+                docstring: None,
+                table: Vec::new(),
+            });
+        } else {
+            bail!("line {}: don't know what to do with '{}'", lineno + 1, line)
+        }
+    }
+
+    if docstring.is_some() {
+        bail!("doc string is never closed")
+    }
+
+    let mut out = Vec::new();
+    for raw in scenarios {
+        let mut steps = background.clone();
+        if !raw.outline {
+            steps.extend(raw.steps);
+            out.push(Scenario {
+                name: raw.name,
+                steps,
+            });
+            continue;
+        }
+        // The first row of the examples names the placeholders, each following row is a scenario
+        let (header, rows) = match raw.examples.split_first() {
+            Some(split) => split,
+            None => bail!("scenario outline '{}' has no examples", raw.name),
+        };
+        for (i, row) in rows.iter().enumerate() {
+            let fill = |s: &str| {
+                let mut s = s.to_string();
+                for (key, val) in header.iter().zip(row) {
+                    s = s.replace(&format!("<{}>", key), val);
+                }
+                s
+            };
+            let mut steps = steps.clone();
+            for step in &raw.steps {
+                steps.push(Step {
+                    text: fill(&step.text),
+                    docstring: step.docstring.as_deref().map(fill),
+                    table: step
+                        .table
+                        .iter()
+                        .map(|r| r.iter().map(|c| fill(c)).collect())
+                        .collect(),
+                });
+            }
+            out.push(Scenario {
+                name: format!("{} (example {})", fill(&raw.name), i + 1),
+                steps,
+            });
+        }
+    }
+
+    Ok(Feature {
+        name,
+        scenarios: out,
+    })
+}
+
+fn current_steps<'a>(
+    background: &'a mut Vec<Step>,
+    scenarios: &'a mut [RawScenario],
+    in_background: bool,
+) -> &'a mut Vec<Step> {
+    match scenarios.last_mut() {
+        Some(s) if !in_background => &mut s.steps,
+        _ => background,
+    }
+}
+
+// Split a table row into its cells; cells can contain escaped pipes and backslashes
+fn parse_row(line: &str) -> Vec<String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut chars = line.trim().chars();
+    // Skip the leading pipe
+    chars.next();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('|') => cell.push('|'),
+                Some('\\') => cell.push('\\'),
+                Some('n') => cell.push('\n'),
+                Some(other) => {
+                    cell.push('\\');
+                    cell.push(other);
+                }
+                None => cell.push('\\'),
+            },
+            '|' => {
+                cells.push(cell.trim().to_string());
+                cell.clear();
+            }
+            c => cell.push(c),
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_outlines_and_prepends_background() -> Result<()> {
+        let feature = parse(
+            r#"
+# A comment
+Feature: Example
+
+  Background:
+    Given an empty graph
+
+  Scenario Outline: Returning <what>
+    When executing query:
+      """
+      RETURN <what> AS x
+      """
+    Then the result should be, in any order:
+      | x      |
+      | <what> |
+
+    Examples:
+      | what |
+      | 1    |
+      | 'a\|b' |
+"#,
+        )?;
+
+        assert_eq!(feature.name, "Example");
+        assert_eq!(feature.scenarios.len(), 2);
+        let s = &feature.scenarios[1];
+        assert_eq!(s.name, "Returning 'a|b' (example 2)");
+        assert_eq!(s.steps[0].text, "an empty graph");
+        assert_eq!(s.steps[1].docstring.as_deref(), Some("RETURN 'a|b' AS x"));
+        assert_eq!(s.steps[2].table, vec![vec!["x"], vec!["'a|b'"]]);
+        Ok(())
+    }
+}
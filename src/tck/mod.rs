@@ -0,0 +1,435 @@
+//
+// Support for running the openCypher Technology Compatibility Kit (TCK) against gqlite, to measure
+// how much of it we pass. This reads the TCK feature files directly, runs each scenario against a
+// fresh in-memory database and compares results using the TCK rules for value equality.
+//
+// This complements the cucumber tests in tests/cucumber.rs: those must all pass, while this is
+// for tracking how far along we are with the rest of the TCK. See examples/tck.rs, which prints a
+// conformance report for a directory of feature files.
+//
+pub mod feature;
+pub mod value;
+
+use crate::gramdb::GramDatabase;
+use crate::Val;
+use anyhow::Result;
+use feature::{Feature, Scenario, Step};
+use std::ffi::OsStr;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use value::Expected;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    // The scenario needs something the harness can't set up, like parameters or procedures
+    Unsupported(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ScenarioReport {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeatureReport {
+    pub name: String,
+    pub scenarios: Vec<ScenarioReport>,
+}
+
+impl FeatureReport {
+    pub fn passed(&self) -> usize {
+        self.count(|o| *o == Outcome::Passed)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Failed(_)))
+    }
+
+    pub fn unsupported(&self) -> usize {
+        self.count(|o| matches!(o, Outcome::Unsupported(_)))
+    }
+
+    fn count(&self, pred: impl Fn(&Outcome) -> bool) -> usize {
+        self.scenarios.iter().filter(|s| pred(&s.outcome)).count()
+    }
+}
+
+// Results for a set of features, typically a directory of them
+#[derive(Debug, Clone, Default)]
+pub struct ConformanceReport {
+    pub features: Vec<FeatureReport>,
+}
+
+impl ConformanceReport {
+    pub fn passed(&self) -> usize {
+        self.features.iter().map(|f| f.passed()).sum()
+    }
+
+    pub fn total(&self) -> usize {
+        self.features.iter().map(|f| f.scenarios.len()).sum()
+    }
+}
+
+impl Display for ConformanceReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for feature in &self.features {
+            writeln!(
+                f,
+                "{:<45} {:>4} passed {:>4} failed {:>4} unsupported",
+                feature.name,
+                feature.passed(),
+                feature.failed(),
+                feature.unsupported()
+            )?;
+        }
+        let total = self.total();
+        let pct = if total == 0 {
+            0.0
+        } else {
+            100.0 * self.passed() as f64 / total as f64
+        };
+        write!(
+            f,
+            "{} of {} scenarios passed ({:.1}%)",
+            self.passed(),
+            total,
+            pct
+        )
+    }
+}
+
+// Run every .feature file in the given directory, in name order
+pub fn run_dir(dir: impl AsRef<Path>) -> Result<ConformanceReport> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("feature")) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut report = ConformanceReport::default();
+    for path in paths {
+        let src = fs::read_to_string(&path)?;
+        let feature = feature::parse(&src)
+            .map_err(|e| anyhow!("failed to parse {}: {}", path.display(), e))?;
+        report.features.push(run_feature(&feature));
+    }
+    Ok(report)
+}
+
+pub fn run_feature(feature: &Feature) -> FeatureReport {
+    FeatureReport {
+        name: feature.name.clone(),
+        scenarios: feature
+            .scenarios
+            .iter()
+            .map(|s| ScenarioReport {
+                name: s.name.clone(),
+                outcome: run_scenario(s),
+            })
+            .collect(),
+    }
+}
+
+pub fn run_scenario(scenario: &Scenario) -> Outcome {
+    // The engine still panics on some unsupported things; that's a failure, not a reason to stop
+    match catch_unwind(AssertUnwindSafe(|| ScenarioRun::new()?.run(scenario))) {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => Outcome::Failed(e.to_string()),
+        Err(panic) => {
+            let msg = panic
+                .downcast_ref::<String>()
+                .map(|s| s.as_str())
+                .or_else(|| panic.downcast_ref::<&str>().copied())
+                .unwrap_or("unknown panic");
+            Outcome::Failed(format!("panicked: {}", msg))
+        }
+    }
+}
+
+// The result of the query under test
+struct QueryResult {
+    fields: Vec<String>,
+    rows: Vec<Vec<Val>>,
+}
+
+// Counts of things in the graph, to verify side effects with
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct GraphStats {
+    nodes: i64,
+    relationships: i64,
+    labels: i64,
+    properties: i64,
+}
+
+struct ScenarioRun {
+    db: GramDatabase,
+    result: Option<Result<QueryResult>>,
+    stats_before: GraphStats,
+}
+
+impl ScenarioRun {
+    fn new() -> Result<ScenarioRun> {
+        Ok(ScenarioRun {
+            db: GramDatabase::in_memory()?,
+            result: None,
+            stats_before: GraphStats::default(),
+        })
+    }
+
+    fn run(mut self, scenario: &Scenario) -> Result<Outcome> {
+        for step in &scenario.steps {
+            if let Some(outcome) = self.step(step)? {
+                return Ok(outcome);
+            }
+        }
+        Ok(Outcome::Passed)
+    }
+
+    // Returns an outcome if the scenario ends at this step
+    fn step(&mut self, step: &Step) -> Result<Option<Outcome>> {
+        let text = step.text.as_str();
+        let fail = |msg: String| Ok(Some(Outcome::Failed(msg)));
+        match text {
+            "an empty graph" | "any graph" => self.db = GramDatabase::in_memory()?,
+            "having executed:" => {
+                if let Err(e) = self.query(docstring(step)?) {
+                    return fail(format!("setup query failed: {}", e));
+                }
+            }
+            "executing query:" => {
+                self.stats_before = self.stats()?;
+                self.result = Some(self.query(docstring(step)?));
+            }
+            "executing control query:" => self.result = Some(self.query(docstring(step)?)),
+            "the result should be empty" => {
+                let result = self.result()?;
+                if !result.rows.is_empty() {
+                    return fail(format!("expected no rows, got {:?}", result.rows));
+                }
+            }
+            "the result should be, in any order:" => return self.expect_rows(step, false, false),
+            "the result should be, in order:" | "the result should be:" => {
+                return self.expect_rows(step, true, false)
+            }
+            "the result should be (ignoring element order for lists):" => {
+                return self.expect_rows(step, false, true)
+            }
+            "no side effects" => {
+                let stats = self.stats()?;
+                if stats != self.stats_before {
+                    return fail(format!(
+                        "expected no side effects, graph went from {:?} to {:?}",
+                        self.stats_before, stats
+                    ));
+                }
+            }
+            "the side effects should be:" => return self.expect_side_effects(step),
+            t if t.starts_with("parameters are:") => {
+                return Ok(Some(Outcome::Unsupported("parameters".to_string())))
+            }
+            t if t.starts_with("there exists a procedure") => {
+                return Ok(Some(Outcome::Unsupported("procedures".to_string())))
+            }
+            t if t.starts_with("the ") && t.ends_with(" graph") => {
+                return Ok(Some(Outcome::Unsupported(format!("named graph: {}", t))))
+            }
+            t if t.starts_with("a ") && t.contains(" should be raised at ") => {
+                // We can't yet tell one kind of error from another, so any error will do
+                if let Some(Ok(_)) = self.result {
+                    return fail(format!("expected {}", t));
+                }
+                self.result()?;
+            }
+            t => bail!("unknown step: {}", t),
+        }
+        Ok(None)
+    }
+
+    fn query(&mut self, query: &str) -> Result<QueryResult> {
+        let mut cursor = self.db.new_cursor();
+        self.db.run(query, &mut cursor)?;
+        let mut rows = Vec::new();
+        while let Some(row) = cursor.next()? {
+            rows.push(row.slots.clone());
+        }
+        Ok(QueryResult {
+            fields: cursor.fields(),
+            rows,
+        })
+    }
+
+    // The result of the query under test, if it ran
+    fn result(&self) -> Result<&QueryResult> {
+        match &self.result {
+            Some(Ok(r)) => Ok(r),
+            Some(Err(e)) => bail!("query failed: {}", e),
+            None => bail!("no query has been executed"),
+        }
+    }
+
+    fn expect_rows(
+        &self,
+        step: &Step,
+        ordered: bool,
+        ignore_list_order: bool,
+    ) -> Result<Option<Outcome>> {
+        let result = self.result()?;
+        let (header, expected) = match step.table.split_first() {
+            Some(split) => split,
+            None => bail!("expected a result table"),
+        };
+        if *header != result.fields {
+            return Ok(Some(Outcome::Failed(format!(
+                "expected columns {:?}, got {:?}",
+                header, result.fields
+            ))));
+        }
+        let mut expected_rows = Vec::with_capacity(expected.len());
+        for row in expected {
+            let row: Result<Vec<Expected>> = row.iter().map(|c| value::parse(c)).collect();
+            expected_rows.push(row?);
+        }
+
+        let row_matches = |e: &Vec<Expected>, a: &Vec<Val>| {
+            e.len() == a.len()
+                && e.iter()
+                    .zip(a.iter())
+                    .all(|(e, a)| e.matches(a, ignore_list_order))
+        };
+        let matches = if ordered {
+            expected_rows.len() == result.rows.len()
+                && expected_rows
+                    .iter()
+                    .zip(result.rows.iter())
+                    .all(|(e, a)| row_matches(e, a))
+        } else {
+            value::matches_in_any_order(&expected_rows, &result.rows, row_matches)
+        };
+        if matches {
+            Ok(None)
+        } else {
+            Ok(Some(Outcome::Failed(format!(
+                "expected rows {:?}, got {:?}",
+                expected, result.rows
+            ))))
+        }
+    }
+
+    // We only know the counts of things before and after, so additions and removals of the
+    // same kind of thing are netted out against each other
+    fn expect_side_effects(&mut self, step: &Step) -> Result<Option<Outcome>> {
+        let mut expected = self.stats_before;
+        for row in &step.table {
+            let (kind, n) = match row.as_slice() {
+                [kind, n] => (kind.as_str(), n.parse::<i64>()?),
+                _ => bail!("malformed side effect: {:?}", row),
+            };
+            let (sign, what) = kind.split_at(1);
+            let n = if sign == "-" { -n } else { n };
+            match what {
+                "nodes" => expected.nodes += n,
+                "relationships" => expected.relationships += n,
+                "labels" => expected.labels += n,
+                "properties" => expected.properties += n,
+                _ => bail!("unknown side effect: {}", kind),
+            }
+        }
+        let actual = self.stats()?;
+        if actual == expected {
+            Ok(None)
+        } else {
+            Ok(Some(Outcome::Failed(format!(
+                "expected the graph to go from {:?} to {:?}, got {:?}",
+                self.stats_before, expected, actual
+            ))))
+        }
+    }
+
+    fn stats(&mut self) -> Result<GraphStats> {
+        let db = &mut self.db;
+        let mut stats = GraphStats::default();
+        let mut cursor = db.new_cursor();
+        db.run("MATCH (n) RETURN n", &mut cursor)?;
+        while let Some(row) = cursor.next()? {
+            if let Val::Node(n) = &row.slots[0] {
+                stats.nodes += 1;
+                stats.labels += n.labels.len() as i64;
+                stats.properties += n.props.len() as i64;
+            }
+        }
+        db.run("MATCH ()-[r]->() RETURN r", &mut cursor)?;
+        while let Some(row) = cursor.next()? {
+            if let Val::Rel(r) = &row.slots[0] {
+                stats.relationships += 1;
+                stats.properties += r.props.len() as i64;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+fn docstring(step: &Step) -> Result<&str> {
+    match &step.docstring {
+        Some(s) => Ok(s),
+        None => bail!("step '{}' needs a query", step.text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_scenarios_and_reports_outcomes() -> Result<()> {
+        let feature = feature::parse(
+            r#"
+Feature: Example
+
+  Scenario: Passes
+    Given an empty graph
+    When executing query:
+      """
+      CREATE (:A {name: 'a'})
+      """
+    Then the result should be empty
+    And the side effects should be:
+      | +nodes      | 1 |
+      | +labels     | 1 |
+      | +properties | 1 |
+
+  Scenario: Fails
+    Given an empty graph
+    When executing query:
+      """
+      UNWIND [1, 2] AS x RETURN x
+      """
+    Then the result should be, in order:
+      | x |
+      | 2 |
+      | 1 |
+
+  Scenario: Unsupported
+    Given an empty graph
+    And parameters are:
+      | x | 1 |
+"#,
+        )?;
+
+        let report = run_feature(&feature);
+        assert_eq!(report.scenarios[0].outcome, Outcome::Passed);
+        assert!(matches!(report.scenarios[1].outcome, Outcome::Failed(_)));
+        assert!(matches!(
+            report.scenarios[2].outcome,
+            Outcome::Unsupported(_)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+// Values as the TCK writes them in expected results, and the TCK rules for comparing them to
+// actual results. Those are stricter than Cypher equality: 1 and 1.0 are different values, NaN
+// equals NaN, and null equals null.
+use crate::Val;
+use anyhow::Result;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Properties of a map, node or relationship
+pub type Props = Vec<(String, Expected)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Expected>),
+    Map(Props),
+    Node { labels: Vec<String>, props: Props },
+    Rel { rel_type: String, props: Props },
+    // We don't have path values, so we just keep these around to say so
+    Path(String),
+}
+
+impl Expected {
+    // Does the actual value equal this one? Lists match in order, unless ignore_list_order is set
+    pub fn matches(&self, actual: &Val, ignore_list_order: bool) -> bool {
+        match (self, actual) {
+            (Expected::Null, Val::Null) => true,
+            (Expected::Bool(e), Val::Bool(a)) => e == a,
+            (Expected::Int(e), Val::Int(a)) => e == a,
+            (Expected::Float(e), Val::Float(a)) => (e.is_nan() && a.is_nan()) || e == a,
+            (Expected::String(e), Val::String(a)) => e.as_str() == a.as_ref(),
+            (Expected::List(e), Val::List(a)) => {
+                if ignore_list_order {
+                    matches_in_any_order(e, a, |e, a| e.matches(a, true))
+                } else {
+                    e.len() == a.len() && e.iter().zip(a.iter()).all(|(e, a)| e.matches(a, false))
+                }
+            }
+            (Expected::Map(e), Val::Map(a)) => props_match(e, a, ignore_list_order),
+            (Expected::Node { labels, props }, Val::Node(n)) => {
+                matches_in_any_order(labels, &n.labels, |e, a| e == a)
+                    && props_match(props, &n.props, ignore_list_order)
+            }
+            (Expected::Rel { rel_type, props }, Val::Rel(r)) => {
+                *rel_type == r.rel_type && props_match(props, &r.props, ignore_list_order)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn props_match(expected: &[(String, Expected)], actual: &[(String, Val)], ilo: bool) -> bool {
+    // Missing properties and null properties are the same thing
+    let actual: Vec<&(String, Val)> = actual.iter().filter(|(_, v)| *v != Val::Null).collect();
+    expected.len() == actual.len()
+        && expected.iter().all(|(ek, ev)| {
+            actual
+                .iter()
+                .any(|(ak, av)| ak == ek && ev.matches(av, ilo))
+        })
+}
+
+// Is there a one-to-one pairing of expected and actual items where each pair matches?
+pub fn matches_in_any_order<E, A>(
+    expected: &[E],
+    actual: &[A],
+    matches: impl Fn(&E, &A) -> bool,
+) -> bool {
+    if expected.len() != actual.len() {
+        return false;
+    }
+    let mut used = vec![false; actual.len()];
+    for e in expected {
+        match (0..actual.len()).find(|i| !used[*i] && matches(e, &actual[*i])) {
+            Some(i) => used[i] = true,
+            None => return false,
+        }
+    }
+    true
+}
+
+pub fn parse(src: &str) -> Result<Expected> {
+    let mut chars = src.trim().chars().peekable();
+    let v = parse_value(&mut chars)?;
+    skip_whitespace(&mut chars);
+    match chars.next() {
+        None => Ok(v),
+        Some(c) => bail!("unexpected '{}' after value in '{}'", c, src),
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Expected> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('\'') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(c) => s.push(c),
+                        None => bail!("unterminated string"),
+                    },
+                    Some('\'') => return Ok(Expected::String(s)),
+                    Some(c) => s.push(c),
+                    None => bail!("unterminated string"),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            skip_whitespace(chars);
+            if let Some(':') = chars.peek() {
+                let (rel_type, props) = parse_entity(chars, ']')?;
+                return Ok(Expected::Rel {
+                    rel_type: rel_type.into_iter().next().unwrap_or_default(),
+                    props,
+                });
+            }
+            let mut items = Vec::new();
+            loop {
+                skip_whitespace(chars);
+                match chars.peek() {
+                    Some(']') => {
+                        chars.next();
+                        return Ok(Expected::List(items));
+                    }
+                    Some(',') => {
+                        chars.next();
+                    }
+                    Some(_) => items.push(parse_value(chars)?),
+                    None => bail!("unterminated list"),
+                }
+            }
+        }
+        Some('{') => Ok(Expected::Map(parse_map(chars)?)),
+        Some('(') => {
+            chars.next();
+            let (labels, props) = parse_entity(chars, ')')?;
+            Ok(Expected::Node { labels, props })
+        }
+        Some('<') => {
+            let mut path = String::new();
+            for c in chars.by_ref() {
+                path.push(c);
+                if c == '>' && !path.ends_with("->") {
+                    return Ok(Expected::Path(path));
+                }
+            }
+            bail!("unterminated path")
+        }
+        Some(c) if *c == '-' || c.is_ascii_digit() || c.is_alphabetic() => {
+            let word = take_while(chars, |c| {
+                c.is_alphanumeric() || c == '.' || c == '-' || c == '+' || c == '_'
+            });
+            match word.as_str() {
+                "null" => Ok(Expected::Null),
+                "true" => Ok(Expected::Bool(true)),
+                "false" => Ok(Expected::Bool(false)),
+                "NaN" => Ok(Expected::Float(f64::NAN)),
+                "Infinity" => Ok(Expected::Float(f64::INFINITY)),
+                "-Infinity" => Ok(Expected::Float(f64::NEG_INFINITY)),
+                w => match w.parse::<i64>() {
+                    Ok(i) => Ok(Expected::Int(i)),
+                    Err(_) => match w.parse::<f64>() {
+                        Ok(f) => Ok(Expected::Float(f)),
+                        Err(_) => bail!("don't know what value '{}' is", w),
+                    },
+                },
+            }
+        }
+        Some(c) => bail!("unexpected '{}' at start of value", c),
+        None => bail!("expected a value, found nothing"),
+    }
+}
+
+// Labels or rel type, followed by optional properties, up to and including the closing char
+fn parse_entity(chars: &mut Peekable<Chars>, close: char) -> Result<(Vec<String>, Props)> {
+    let mut names = Vec::new();
+    let mut props = Vec::new();
+    loop {
+        skip_whitespace(chars);
+        match chars.peek() {
+            Some(':') => {
+                chars.next();
+                names.push(parse_name(chars)?);
+            }
+            Some('{') => props = parse_map(chars)?,
+            Some(c) if *c == close => {
+                chars.next();
+                return Ok((names, props));
+            }
+            Some(c) => bail!("unexpected '{}' in node or relationship", c),
+            None => bail!("unterminated node or relationship"),
+        }
+    }
+}
+
+fn parse_map(chars: &mut Peekable<Chars>) -> Result<Props> {
+    // Skip the opening brace
+    chars.next();
+    let mut entries = Vec::new();
+    loop {
+        skip_whitespace(chars);
+        match chars.peek() {
+            Some('}') => {
+                chars.next();
+                return Ok(entries);
+            }
+            Some(',') => {
+                chars.next();
+            }
+            Some(_) => {
+                let key = parse_name(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    bail!("expected ':' after map key {}", key)
+                }
+                entries.push((key, parse_value(chars)?));
+            }
+            None => bail!("unterminated map"),
+        }
+    }
+}
+
+// An identifier, possibly quoted with backticks
+fn parse_name(chars: &mut Peekable<Chars>) -> Result<String> {
+    skip_whitespace(chars);
+    if let Some('`') = chars.peek() {
+        chars.next();
+        let name = take_while(chars, |c| c != '`');
+        chars.next();
+        return Ok(name);
+    }
+    let name = take_while(chars, |c| c.is_alphanumeric() || c == '_');
+    if name.is_empty() {
+        bail!("expected a name")
+    }
+    Ok(name)
+}
+
+fn take_while(chars: &mut Peekable<Chars>, pred: impl Fn(char) -> bool) -> String {
+    let mut out = String::new();
+    while let Some(c) = chars.peek() {
+        if !pred(*c) {
+            break;
+        }
+        out.push(*c);
+        chars.next();
+    }
+    out
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while let Some(c) = chars.peek() {
+        if !c.is_whitespace() {
+            break;
+        }
+        chars.next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Node;
+
+    #[test]
+    fn parses_and_compares_tck_values() -> Result<()> {
+        let node = Val::Node(Node {
+            id: 0,
+            labels: vec!["B".to_string(), "A".to_string()],
+            props: vec![
+                ("name".to_string(), Val::String("x".into())),
+                ("gone".to_string(), Val::Null),
+            ],
+        });
+        assert!(parse("(:A:B {name: 'x'})")?.matches(&node, false));
+        assert!(!parse("(:A {name: 'x'})")?.matches(&node, false));
+
+        assert!(!parse("1")?.matches(&Val::Float(1.0), false));
+        assert!(parse("1.0")?.matches(&Val::Float(1.0), false));
+        assert!(parse("NaN")?.matches(&Val::Float(f64::NAN), false));
+
+        let list = Val::List(vec![Val::Int(2), Val::String("it's".into())].into());
+        assert!(parse(r"[2, 'it\'s']")?.matches(&list, false));
+        assert!(!parse(r"['it\'s', 2]")?.matches(&list, false));
+        assert!(parse(r"['it\'s', 2]")?.matches(&list, true));
+
+        assert_eq!(
+            parse("[:T {since: 1}]")?,
+            Expected::Rel {
+                rel_type: "T".to_string(),
+                props: vec![("since".to_string(), Expected::Int(1))]
+            }
+        );
+        Ok(())
+    }
+}
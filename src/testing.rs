@@ -0,0 +1,165 @@
+//
+// Support for applications testing their use of gqlite. The main thing here is pinning the plans
+// of queries that need to stay fast: assert_plan fails when an upgrade - or a new index, or a
+// change to the query - makes the planner pick a different plan, and shows how it changed.
+//
+//   assert_plan(
+//       &mut db,
+//       "MATCH (n:Person) WHERE n.age > 30 RETURN n.name AS name",
+//       "
+//       ProduceResult %1 AS name
+//         Project %0.name => %1
+//           Selection %0.age > $auto_0
+//             NodeScan %0 :Person
+//               Argument
+//       ",
+//   );
+//
+// See frontend/describe.rs for the form plans are written in.
+//
+use crate::backend::Backend;
+use crate::{Database, Result};
+
+// The plan the database would run a query with, in the form assert_plan expects
+pub fn plan_of<T: Backend>(db: &mut Database<T>, query: &str) -> Result<String> {
+    let (planned, _) = db.plan_cached(query)?;
+    let tokens = db.frontend.tokens.borrow();
+    Ok(planned.plan.describe(&tokens))
+}
+
+// Panic unless the database plans the query as expected. The expected plan may be indented as
+// a whole and start and end with empty lines, so it can be written as a string literal lined
+// up with the code around it.
+pub fn assert_plan<T: Backend>(db: &mut Database<T>, query: &str, expected: &str) {
+    let actual = match plan_of(db, query) {
+        Ok(plan) => plan,
+        Err(e) => panic!("failed to plan {}: {}", query, e),
+    };
+    let expected = normalize(expected);
+    let actual = normalize(&actual);
+    if expected != actual {
+        panic!(
+            "unexpected plan for {}\n\ndiff, expected (-) vs actual (+):\n{}\nactual plan:\n{}\n",
+            query,
+            diff(&expected, &actual),
+            actual.join("\n")
+        )
+    }
+}
+
+// The lines of a plan, without the indentation they all have in common or trailing whitespace
+fn normalize(plan: &str) -> Vec<String> {
+    let lines: Vec<&str> = plan
+        .lines()
+        .map(|l| l.trim_end())
+        .skip_while(|l| l.is_empty())
+        .collect();
+    let end = lines
+        .iter()
+        .rposition(|l| !l.is_empty())
+        .map_or(0, |i| i + 1);
+    let lines = &lines[..end];
+    let indent = lines
+        .iter()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|l| l.get(indent..).unwrap_or("").to_string())
+        .collect()
+}
+
+// A line by line diff of two plans, by their longest common subsequence of lines; plans are a
+// few dozen lines at most, so the quadratic table is no problem
+fn diff(expected: &[String], actual: &[String]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    // common[i][j] is the length of the longest common subsequence of expected[i..], actual[j..]
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            out.push_str(&format!("  {}\n", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            out.push_str(&format!("- {}\n", expected[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+ {}\n", actual[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+#[cfg(all(test, feature = "gram"))]
+mod tests {
+    use super::*;
+    use crate::gramdb::GramDatabase;
+
+    #[test]
+    fn pins_plans_with_names_resolved() -> Result<()> {
+        let mut db = GramDatabase::in_memory()?;
+        assert_plan(
+            &mut db,
+            "MATCH (n:Person)-[:KNOWS]->(m) WHERE n.age > 30 RETURN m.name AS name ORDER BY name",
+            "
+            ProduceResult %3 AS name
+              Sort %3
+                Project %1.name => %3
+                  Selection %0.age > $auto_0
+                    Expand (%0)-[%2:KNOWS]->(%1)
+                      NodeScan %0 :Person
+                        Argument
+            ",
+        );
+        assert_plan(
+            &mut db,
+            "MATCH (n:Person) WHERE n.age > 30 RETURN n.name AS name",
+            "
+            ProduceResult %1 AS name
+              Project %0.name => %1
+                Selection %0.age > $auto_0
+                  NodeScan %0 :Person
+                    Argument
+            ",
+        );
+
+        // The plan changes once there's an index worth seeking
+        let mut cursor = db.new_cursor();
+        db.run(
+            "UNWIND range(1, 100) AS i CREATE (:Person {age: i})",
+            &mut cursor,
+        )?;
+        while cursor.next()?.is_some() {}
+        db.create_index("Person", "age")?;
+        let plan = plan_of(&mut db, "MATCH (n:Person) WHERE n.age = $age RETURN n")?;
+        assert!(
+            plan.contains("NodeIndexSeek %0 :Person(age) >= $age <= $age"),
+            "{}",
+            plan
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diffs_plans_line_by_line() {
+        let lines = |s: &str| normalize(s);
+        let expected = lines("\n    A\n      B\n        C\n");
+        assert_eq!(expected, vec!["A", "  B", "    C"]);
+        let actual = lines("A\n  X\n    C");
+        assert_eq!(diff(&expected, &actual), "  A\n-   B\n+   X\n      C\n");
+    }
+}
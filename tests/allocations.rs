@@ -0,0 +1,56 @@
+// Checks that executing queries into a warmed-up cursor doesn't allocate. This needs its own
+// test binary, since it swaps out the global allocator to count allocations.
+use gqlite::gramdb::GramDatabase;
+use gqlite::Result;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[test]
+fn rerunning_queries_into_a_cursor_does_not_allocate_per_row() -> Result<()> {
+    let mut db = GramDatabase::in_memory()?;
+    let mut cursor = db.new_cursor().with_capacity(64);
+    db.run(
+        "CREATE (:Person {name: 'a'}), (:Person {name: 'b'}), (:Person {name: 'c'})",
+        &mut cursor,
+    )?;
+    while cursor.next()?.is_some() {}
+
+    let query = "MATCH (n:Person) RETURN n.name, n";
+    for round in 0..3 {
+        // Parsing and planning allocate, executing the plan into the cursor should not
+        db.run(query, &mut cursor)?;
+        let before = ALLOCATIONS.load(Ordering::SeqCst);
+        let mut rows = 0;
+        while cursor.next()?.is_some() {
+            rows += 1;
+        }
+        let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+        assert_eq!(rows, 3);
+        // The first round warms up things like the node projections in the cursor
+        if round > 0 {
+            assert_eq!(allocations, 0, "round {} allocated", round);
+        }
+    }
+
+    cursor.reset()?;
+    assert!(cursor.next().is_err());
+    Ok(())
+}
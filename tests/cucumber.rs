@@ -87,6 +87,8 @@ mod example_steps {
         Bool(bool),
         Null,
         List(Vec<ValMatcher>),
+        // A list whose elements may come in any order
+        UnorderedList(Vec<ValMatcher>),
         Map(Vec<(String, ValMatcher)>),
         Int(i64),
         Float(f64),
@@ -103,9 +105,39 @@ mod example_steps {
         pub fn to_val(&self) -> Val {
             match self {
                 ValMatcher::Int(v) => Val::Int(*v),
+                ValMatcher::Float(v) => Val::Float(*v),
+                ValMatcher::Bool(v) => Val::Bool(*v),
+                ValMatcher::Null => Val::Null,
+                ValMatcher::String(v) => Val::String(v.as_str().into()),
+                ValMatcher::List(vs) => Val::List(vs.iter().map(|v| v.to_val()).collect()),
+                ValMatcher::Map(entries) => Val::Map(
+                    entries
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.to_val()))
+                        .collect::<Vec<_>>()
+                        .into(),
+                ),
                 v => panic!("Don't know how to convert {:?} to val", v),
             }
         }
+        // The same matcher, but not caring about the order of the elements of lists
+        pub fn ignoring_list_order(self) -> ValMatcher {
+            match self {
+                ValMatcher::List(items) | ValMatcher::UnorderedList(items) => {
+                    ValMatcher::UnorderedList(
+                        items.into_iter().map(|i| i.ignoring_list_order()).collect(),
+                    )
+                }
+                ValMatcher::Map(entries) => ValMatcher::Map(
+                    entries
+                        .into_iter()
+                        .map(|(k, v)| (k, v.ignoring_list_order()))
+                        .collect(),
+                ),
+                v => v,
+            }
+        }
+
         pub fn test_eq(&self, v: Val) -> Result<()> {
             match self {
                 ValMatcher::Int(e) => {
@@ -121,7 +153,7 @@ mod example_steps {
                 ValMatcher::Float(e) => ensure_eq!(Val::Float(*e), v),
                 ValMatcher::Bool(b) => ensure_eq!(Val::Bool(*b), v),
                 ValMatcher::Null => ensure_eq!(Val::Null, v),
-                ValMatcher::String(e) => ensure_eq!(Val::String(e.clone()), v),
+                ValMatcher::String(e) => ensure_eq!(Val::String(e.as_str().into()), v),
                 ValMatcher::Map(es) => {
                     if let Val::Map(actual) = v {
                         if es.len() != actual.len() {
@@ -129,7 +161,7 @@ mod example_steps {
                         }
                         for (k, ev) in es {
                             let mut found = false;
-                            for (ak, av) in &actual {
+                            for (ak, av) in actual.iter() {
                                 if ak == k {
                                     found = true;
                                     ev.test_eq(av.clone())?;
@@ -160,6 +192,29 @@ mod example_steps {
                     }
                     _ => bail!("Expected a list, found {:?}", v),
                 },
+                ValMatcher::UnorderedList(expected) => match v {
+                    Val::List(actual) => {
+                        if expected.len() != actual.len() {
+                            bail!(
+                                "Expected {:?}, got {:?} (not same length)",
+                                expected,
+                                actual
+                            )
+                        }
+                        // Each expected element takes the first actual one it matches
+                        let mut remaining: Vec<Val> = actual.to_vec();
+                        for e in expected {
+                            match remaining.iter().position(|a| e.test_eq(a.clone()).is_ok()) {
+                                Some(i) => {
+                                    remaining.remove(i);
+                                }
+                                None => bail!("Expected {:?} in {:?}", e, actual),
+                            }
+                        }
+                        Ok(())
+                    }
+                    _ => bail!("Expected a list, found {:?}", v),
+                },
                 ValMatcher::Node { props, labels } => {
                     if let Val::Node(n) = v {
                         if n.props.len() != props.len() {
@@ -229,9 +284,12 @@ mod example_steps {
 
     fn set_parameters(world: &mut MyWorld, step: &Step) -> Result<(), Error> {
         let mut table = step.table().unwrap().clone();
-        let pkey = table.header[0].to_string();
-        let pval = str_to_val(&mut table.header[1].chars().peekable()).to_val();
-        world.parameters.push((pkey, pval));
+        // One-row tables come with empty headers and the row in rows, see assert_result
+        if !table.header[0].is_empty() {
+            let pkey = table.header[0].to_string();
+            let pval = str_to_val(&mut table.header[1].chars().peekable()).to_val();
+            world.parameters.push((pkey, pval));
+        }
         for mut row in table.rows {
             let pkey = row[0].to_string();
             let pval = str_to_val(&mut row[1].chars().peekable()).to_val();
@@ -243,7 +301,11 @@ mod example_steps {
     fn start_query(world: &mut MyWorld, step: &Step) {
         world
             .graph
-            .run(&step.docstring().unwrap(), &mut world.result)
+            .run_with_params(
+                &step.docstring().unwrap(),
+                &world.parameters,
+                &mut world.result,
+            )
             .expect("Should not fail")
     }
 
@@ -348,7 +410,16 @@ mod example_steps {
         )
     }
 
-    fn assert_result(world: &mut MyWorld, step: &Step) {
+    fn expected_val(s: &str, lists_in_any_order: bool) -> ValMatcher {
+        let matcher = str_to_val(&mut s.chars().peekable());
+        if lists_in_any_order {
+            matcher.ignoring_list_order()
+        } else {
+            matcher
+        }
+    }
+
+    fn assert_result(world: &mut MyWorld, step: &Step, lists_in_any_order: bool) {
         let table = step.table().unwrap().clone();
 
         // So.. the rust cucumber parser treats one-row tables as having empty headers
@@ -368,10 +439,10 @@ mod example_steps {
             assert_eq!(world.result.fields(), table.header);
         }
 
-        for mut row in table.rows {
+        for row in table.rows {
             if let Some(actual) = world.result.next().unwrap() {
-                for slot in 0..row.len() {
-                    str_to_val(&mut row[slot].chars().peekable())
+                for (slot, expected) in row.iter().enumerate() {
+                    expected_val(expected, lists_in_any_order)
                         .test_eq(actual.slots[slot].clone())
                         .unwrap();
                 }
@@ -381,7 +452,7 @@ mod example_steps {
         }
     }
 
-    fn assert_result_in_any_order(world: &mut MyWorld, step: &Step) {
+    fn assert_result_in_any_order(world: &mut MyWorld, step: &Step, lists_in_any_order: bool) {
         let table = step.table().unwrap().clone();
 
         // So.. the rust cucumber parser treats one-row tables as having empty headers
@@ -404,7 +475,7 @@ mod example_steps {
         // It makes debugging way easier for the cases where there is just one result row
         // if we use the ordered assertion
         if table.rows.len() == 1 {
-            assert_result(world, step);
+            assert_result(world, step, lists_in_any_order);
             return;
         }
 
@@ -416,9 +487,8 @@ mod example_steps {
             let mut row_equal = Ok(());
             for (index, row) in expected_rows.iter().enumerate() {
                 row_equal = Ok(());
-                let mut row_copy = row.clone();
-                for slot in 0..row.len() {
-                    let slot_equal = str_to_val(&mut row_copy[slot].chars().peekable())
+                for (slot, expected) in row.iter().enumerate() {
+                    let slot_equal = expected_val(expected, lists_in_any_order)
                         .test_eq(actual.slots[slot].clone());
                     if slot_equal.is_err() {
                         row_equal = slot_equal;
@@ -665,11 +735,15 @@ mod example_steps {
         };
 
         then "the result should be, in any order:" |mut world, step| {
-            assert_result_in_any_order(&mut world, &step)
+            assert_result_in_any_order(&mut world, &step, false)
         };
 
         then "the result should be, in order:" |mut world, step| {
-            assert_result(&mut world, &step)
+            assert_result(&mut world, &step, false)
+        };
+
+        then "the result should be (ignoring element order for lists):" |world, step| {
+            assert_result_in_any_order(world, step, true)
         };
 
         then "the side effects should be:" |world, step| {